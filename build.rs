@@ -1,16 +1,35 @@
 use std::fs;
 use std::path::Path;
 
+/// Optional settings that are embedded only when present in .env
+const OPTIONAL_KEYS: &[&str] = &[
+    "EVENT_LOG_PATH",
+    "ROUTER_FALLBACK_PASSWORDS",
+    "ROUTER_LOGIN_DELAY_SECS",
+    "SELECTOR_RECOVERY",
+    "SWITCH_SLA_SECS",
+    "CANDIDATE_ORDER",
+    "MAX_CANDIDATE_CHECKS",
+    "USAGE_CACHE_TTL_MINS",
+    "FAST_PATH_MARGIN",
+    "EXPIRY_REMINDER_DAYS",
+    "ROUTER_PROXY",
+    "ROUTER_SSH_JUMP_HOST",
+    "ROUTER_SSH_KEY",
+    "WIREGUARD_INTERFACE",
+];
+
 fn main() {
-    // Read .env file at compile time
+    // Tell Cargo to rerun this build script if .env changes
+    println!("cargo:rerun-if-changed=.env");
+
+    // Read .env file at compile time. It is optional: without it the binary
+    // reads its configuration from config.toml at runtime.
     let env_path = Path::new(".env");
-    
+
     if !env_path.exists() {
-        panic!(
-            "\n\n❌ ERROR: .env file not found!\n\
-             Please create a .env file in the project root with your credentials.\n\
-             You can copy .env.example to .env and fill in your values.\n\n"
-        );
+        println!("cargo:warning=No .env file found, configuration will be read from config.toml at runtime");
+        return;
     }
 
     // Read the .env file
@@ -21,6 +40,7 @@ fn main() {
     let mut router_ip = None;
     let mut router_password = None;
     let mut pppoe_credentials = None;
+    let mut optional_settings = Vec::new();
 
     for line in env_content.lines() {
         let line = line.trim();
@@ -39,6 +59,9 @@ fn main() {
                 "ROUTER_IP" => router_ip = Some(value.to_string()),
                 "ROUTER_PASSWORD" => router_password = Some(value.to_string()),
                 "PPPOE_CREDENTIALS" => pppoe_credentials = Some(value.to_string()),
+                _ if OPTIONAL_KEYS.contains(&key) => {
+                    optional_settings.push((key.to_string(), value.to_string()))
+                }
                 _ => {} // Ignore unknown keys
             }
         }
@@ -50,13 +73,15 @@ fn main() {
     let pppoe_credentials = pppoe_credentials.expect("PPPOE_CREDENTIALS not found in .env file");
 
     // Set environment variables for the compilation
-    // These will be available via env!() macro in the source code
+    // These are read with option_env!() and used when there is no config file
     println!("cargo:rustc-env=EMBEDDED_ROUTER_IP={}", router_ip);
     println!("cargo:rustc-env=EMBEDDED_ROUTER_PASSWORD={}", router_password);
     println!("cargo:rustc-env=EMBEDDED_PPPOE_CREDENTIALS={}", pppoe_credentials);
 
-    // Tell Cargo to rerun this build script if .env changes
-    println!("cargo:rerun-if-changed=.env");
-    
+    // Optional settings are read with option_env!() so they may be left out
+    for (key, value) in optional_settings {
+        println!("cargo:rustc-env=EMBEDDED_{}={}", key, value);
+    }
+
     println!("cargo:warning=✓ Credentials loaded from .env and embedded into binary");
 }
@@ -0,0 +1,79 @@
+//! Read a PPPoE ID's usage from the ISP portal over HTTP
+//!
+//! The portal here is served by wiremock with the pages in
+//! `tests/fixtures/portal`; point `login_url` at the real one to read it
+//! instead.
+//!
+//! ```text
+//! cargo run --example check_usage
+//! ```
+
+use anyhow::Result;
+use auto_wifi::config::Config;
+use auto_wifi::notify;
+use auto_wifi::portal::{get_total_use, PortalAccount};
+use auto_wifi::state::DATA_DIR_VAR;
+use std::env;
+use tempfile::TempDir;
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const LOGIN_PAGE: &str = include_str!("../tests/fixtures/portal/login.html");
+const ACCOUNT_PAGE: &str = include_str!("../tests/fixtures/portal/account.html");
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Keep what the library remembers out of the real data directory
+    let data_dir = TempDir::new()?;
+    env::set_var(DATA_DIR_VAR, data_dir.path());
+
+    let account = run().await?;
+    println!("Used: {} minutes", account.total_use);
+    println!("Status: {}", account.status.as_deref().unwrap_or("-"));
+    if let Some(expiry) = account.expiry {
+        println!("Expires: {}", expiry);
+    }
+
+    Ok(())
+}
+
+/// Read `id1` from a portal that lets it in with `pass1`
+pub async fn run() -> Result<PortalAccount> {
+    let portal = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/index.php/home/login"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+        .mount(&portal)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/index.php/home/login"))
+        .and(body_string_contains("username=id1"))
+        .and(body_string_contains("password=pass1"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(ACCOUNT_PAGE))
+        .mount(&portal)
+        .await;
+
+    let config = Config::from_toml(&format!(
+        r#"
+[router]
+ip = "192.168.1.1"
+password = "admin"
+
+[portal]
+client = "http"
+login_url = "{}/index.php/home/login"
+
+[notifications.desktop]
+enabled = false
+
+[[credentials]]
+id = "id1"
+password = "pass1"
+"#,
+        portal.uri()
+    ))?;
+    notify::init(&config.notifications);
+
+    let credential = &config.credentials[0];
+    get_total_use(&config, &credential.id, &credential.password).await
+}
@@ -0,0 +1,145 @@
+//! Run the switching logic on your own terms: `automation::run_with` decides
+//! against any `UsageProvider` and `RouterControl`, not only the real portal
+//! and router.
+//!
+//! Here usage comes from a table, padded with a safety margin so IDs are
+//! switched away from a little before the portal would say they're used up,
+//! and the "router" is a variable that is printed when it changes.
+//!
+//! ```text
+//! cargo run --example custom_policy
+//! ```
+
+use anyhow::Result;
+use async_trait::async_trait;
+use auto_wifi::automation::{run_with, RouterControl, UsageProvider};
+use auto_wifi::config::{Config, UsageKind};
+use auto_wifi::notify;
+use auto_wifi::portal::PortalAccount;
+use auto_wifi::state::DATA_DIR_VAR;
+use std::collections::HashMap;
+use std::env;
+use tempfile::TempDir;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Keep what the library remembers out of the real data directory
+    let data_dir = TempDir::new()?;
+    env::set_var(DATA_DIR_VAR, data_dir.path());
+
+    let running_id = run().await?;
+    println!("The router runs '{}'", running_id);
+
+    Ok(())
+}
+
+/// Run the automation once with `id1` at 9500 of its 10000 minutes
+///
+/// # Returns
+/// * The PPPoE ID the router runs afterwards
+pub async fn run() -> Result<String> {
+    let config = Config::from_toml(
+        r#"
+[router]
+ip = "192.168.1.1"
+password = "admin"
+
+[thresholds]
+switch = 10000
+available = 10000
+disable = 11000
+
+[notifications.desktop]
+enabled = false
+
+[[credentials]]
+id = "id1"
+password = "pass1"
+
+[[credentials]]
+id = "id2"
+password = "pass2"
+"#,
+    )?;
+    notify::init(&config.notifications);
+
+    let usage = WithMargin {
+        inner: Table(HashMap::from([("id1", 9500), ("id2", 1200)])),
+        margin: 600,
+    };
+    let mut router = MemoryRouter {
+        running_id: "id1".to_string(),
+    };
+
+    run_with(&config, &usage, &mut router).await?;
+
+    Ok(router.running_id)
+}
+
+/// Usage in minutes by PPPoE ID, e.g. as read from a meter of your own
+struct Table(HashMap<&'static str, i32>);
+
+#[async_trait(?Send)]
+impl UsageProvider for Table {
+    async fn account(&self, pppoe_id: &str, _password: &str) -> Result<PortalAccount> {
+        let total_use = *self
+            .0
+            .get(pppoe_id)
+            .ok_or_else(|| anyhow::anyhow!("No usage known for '{}'", pppoe_id))?;
+
+        Ok(PortalAccount {
+            total_use,
+            usage_kind: UsageKind::Time,
+            status: Some("Active".to_string()),
+            expiry: None,
+            recharge_amount: None,
+            variant: "default".to_string(),
+        })
+    }
+}
+
+/// Another provider's usage plus a margin, so the thresholds are reached
+/// that much sooner
+struct WithMargin<P> {
+    inner: P,
+    margin: i32,
+}
+
+#[async_trait(?Send)]
+impl<P: UsageProvider> UsageProvider for WithMargin<P> {
+    async fn account(&self, pppoe_id: &str, password: &str) -> Result<PortalAccount> {
+        let mut account = self.inner.account(pppoe_id, password).await?;
+        account.total_use += self.margin;
+
+        Ok(account)
+    }
+}
+
+/// A router that is only a variable
+struct MemoryRouter {
+    running_id: String,
+}
+
+#[async_trait(?Send)]
+impl RouterControl for MemoryRouter {
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    async fn running_id(&mut self) -> Result<String> {
+        Ok(self.running_id.clone())
+    }
+
+    async fn set_credentials(&mut self, pppoe_id: &str, _password: &str) -> Result<bool> {
+        println!("Router: '{}' -> '{}'", self.running_id, pppoe_id);
+        self.running_id = pppoe_id.to_string();
+
+        Ok(true)
+    }
+
+    async fn set_wifi_ssid(&mut self, interface: &str, ssid: &str) -> Result<()> {
+        println!("Router: {} is now called '{}'", interface, ssid);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Switch an OpenWrt router to another PPPoE ID
+//!
+//! The router here is a fake of OpenWrt's ubus API served by wiremock, set
+//! up with `tests/fixtures/openwrt/uci.json`; set `router.ip` to a real one
+//! to switch it instead.
+//!
+//! ```text
+//! cargo run --example switch_id
+//! ```
+
+use anyhow::Result;
+use auto_wifi::automation::{Router, RouterControl};
+use auto_wifi::commands;
+use auto_wifi::config::Config;
+use auto_wifi::notify;
+use auto_wifi::state::DATA_DIR_VAR;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+const UCI: &str = include_str!("../tests/fixtures/openwrt/uci.json");
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Keep what the library remembers out of the real data directory
+    let data_dir = TempDir::new()?;
+    env::set_var(DATA_DIR_VAR, data_dir.path());
+
+    let (before, after) = run().await?;
+    println!("✓ Switched the router from '{}' to '{}'", before, after);
+
+    Ok(())
+}
+
+/// Switch the router from `id1` to `id2`
+///
+/// # Returns
+/// * The PPPoE ID the router was set to before, and after
+pub async fn run() -> Result<(String, String)> {
+    let router = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/ubus"))
+        .respond_with(Ubus {
+            uci: Arc::new(Mutex::new(serde_json::from_str(UCI)?)),
+        })
+        .mount(&router)
+        .await;
+
+    let config = Config::from_toml(&format!(
+        r#"
+[router]
+model = "openwrt"
+ip = "{}"
+password = "admin"
+login_delay_secs = 0
+connect_timeout_secs = 0
+
+[notifications.desktop]
+enabled = false
+
+[[credentials]]
+id = "id1"
+password = "pass1"
+
+[[credentials]]
+id = "id2"
+password = "pass2"
+"#,
+        router.address()
+    ))?;
+    notify::init(&config.notifications);
+
+    // Nothing is changed on the router before this, which also backs up its
+    // PPPoE settings
+    commands::init(&config, true).await?;
+
+    let mut router = Router::open(&config, false)?;
+    let before = router.running_id().await?;
+    let credential = &config.credentials[1];
+    if !router
+        .set_credentials(&credential.id, &credential.password)
+        .await?
+    {
+        anyhow::bail!("The router didn't take '{}'", credential.id);
+    }
+    let after = router.running_id().await?;
+
+    Ok((before, after))
+}
+
+/// Just enough of OpenWrt's ubus JSON-RPC endpoint to log in, read and set
+/// the uci config and reconnect
+struct Ubus {
+    uci: Arc<Mutex<Value>>,
+}
+
+impl Respond for Ubus {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let request: Value = serde_json::from_slice(&request.body).unwrap_or_default();
+        let params = &request["params"];
+        let (object, method, args) = (
+            params[1].as_str().unwrap_or_default(),
+            params[2].as_str().unwrap_or_default(),
+            &params[3],
+        );
+
+        let mut uci = self.uci.lock().unwrap();
+        let result = match (object, method) {
+            ("session", "login") if args["password"] == "admin" => {
+                json!([0, { "ubus_rpc_session": "0123456789abcdef0123456789abcdef" }])
+            }
+            // Permission denied
+            ("session", "login") => json!([6]),
+            ("uci", "get") => {
+                let config = &uci[args["config"].as_str().unwrap_or_default()];
+                match (args["section"].as_str(), args["option"].as_str()) {
+                    (Some(section), Some(option)) => {
+                        json!([0, { "value": config[section][option] }])
+                    }
+                    (Some(section), None) => json!([0, { "values": config[section] }]),
+                    _ => json!([0, { "values": config }]),
+                }
+            }
+            ("uci", "set") => {
+                let config = args["config"].as_str().unwrap_or_default();
+                let section = args["section"].as_str().unwrap_or_default();
+                if let Some(values) = args["values"].as_object() {
+                    for (option, value) in values {
+                        uci[config][section][option] = value.clone();
+                    }
+                }
+                json!([0])
+            }
+            ("network.interface.wan", "status") => json!([0, { "up": true }]),
+            ("uci", "commit")
+            | ("network", "reload")
+            | ("network.interface.wan", "down" | "up")
+            | ("session", "destroy") => json!([0]),
+            // Not found
+            _ => json!([4]),
+        };
+
+        ResponseTemplate::new(200).set_body_json(json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }))
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Check, before doing anything, that the process isn't over-privileged and can
+/// write everywhere it needs to.
+///
+/// The tool needs no special privileges: it only talks to the router and the
+/// portal over HTTP through ChromeDriver. The only paths it writes to are:
+/// * the data directory (state.json)
+/// * the cache directory, where ChromeDriver is downloaded to
+/// * the directory of the JSONL event log, if one is configured
+///
+/// Problems are reported as warnings; nothing here stops the run.
+///
+/// # Arguments
+/// * `event_log_path` - The configured event log path, if any
+pub fn run_startup_audit(event_log_path: Option<&str>) {
+    if running_elevated() {
+        #[cfg(target_os = "windows")]
+        warn!("Running as Administrator. This tool doesn't need elevated rights; consider running it as a normal user.");
+
+        #[cfg(not(target_os = "windows"))]
+        warn!("Running as root. This tool doesn't need root; consider running it as an unprivileged user, or with `run --daemon --run-as <user>`.");
+    }
+
+    let mut writable_dirs: Vec<PathBuf> = Vec::new();
+
+    match crate::state::data_dir() {
+        Ok(dir) => writable_dirs.push(dir),
+        Err(e) => warn!("{}", e),
+    }
+
+    match crate::driver::cache_dir() {
+        // Created on first download, so only its parent has to exist yet
+        Ok(dir) => writable_dirs.extend(
+            dir.ancestors()
+                .find(|dir| dir.exists())
+                .map(Path::to_path_buf),
+        ),
+        Err(e) => warn!("{}", e),
+    }
+
+    if let Some(path) = event_log_path {
+        let parent = Path::new(path)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        writable_dirs.push(parent.to_path_buf());
+    }
+
+    for dir in writable_dirs {
+        if !is_writable(&dir) {
+            warn!("{} is not writable by this user", dir.display());
+        }
+    }
+}
+
+/// Switch to an unprivileged user for good, once the daemon's listeners are
+/// bound, so ports below 1024 can be served without staying root
+///
+/// Root's home is out of reach from then on, so the data and cache
+/// directories are looked for in the user's home instead. The data
+/// directory, and the event log if there is one, are handed over to the user
+/// first in case root created them.
+///
+/// # Arguments
+/// * `user` - The user to run as from now on
+/// * `event_log_path` - The configured event log path, to hand over to the
+///   user and check again that everything that has to be written still can be
+#[cfg(unix)]
+pub fn drop_privileges(user: &str, event_log_path: Option<&str>) -> Result<()> {
+    use anyhow::{bail, Context};
+    use std::env;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::fs::chown;
+
+    if !running_elevated() {
+        warn!(
+            "Not running as root, so there are no privileges to drop for '{}'",
+            user
+        );
+        return Ok(());
+    }
+
+    let name = CString::new(user).context(format!("Invalid user name '{}'", user))?;
+    // SAFETY: the name is a valid C string; the record returned is copied
+    // from before any other call could overwrite it
+    let (uid, gid, home) = unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() {
+            bail!("There is no user named '{}' to run as", user);
+        }
+        let home = CStr::from_ptr((*passwd).pw_dir).to_string_lossy();
+        (
+            (*passwd).pw_uid,
+            (*passwd).pw_gid,
+            PathBuf::from(home.as_ref()),
+        )
+    };
+    if home == Path::new("/") || !home.is_dir() {
+        bail!("'{}' has no home directory to keep the state in", user);
+    }
+
+    // Paths under $HOME are the user's from now on, not root's
+    env::set_var("HOME", &home);
+    env::set_var("USER", user);
+    env::set_var("LOGNAME", user);
+    env::remove_var("XDG_DATA_HOME");
+    env::remove_var("XDG_CACHE_HOME");
+
+    // Along with any parents inside the home that were created with it
+    let data_dir = crate::state::data_dir()?;
+    let mut handed_over = vec![data_dir.as_path()];
+    handed_over.extend(
+        data_dir
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&home) && *dir != home.as_path()),
+    );
+    if let Some(path) = event_log_path.map(Path::new).filter(|path| path.exists()) {
+        handed_over.push(path);
+    }
+    for path in handed_over {
+        chown(path, Some(uid), Some(gid)).context(format!(
+            "Failed to hand {} over to '{}'",
+            path.display(),
+            user
+        ))?;
+    }
+
+    // Groups first, while still allowed to change them
+    // SAFETY: plain system calls on valid arguments
+    unsafe {
+        if libc::initgroups(name.as_ptr(), gid as _) != 0 {
+            bail!(
+                "Failed to set the groups of '{}': {}",
+                user,
+                io::Error::last_os_error()
+            );
+        }
+        if libc::setgid(gid) != 0 {
+            bail!(
+                "Failed to switch to group {}: {}",
+                gid,
+                io::Error::last_os_error()
+            );
+        }
+        if libc::setuid(uid) != 0 {
+            bail!(
+                "Failed to switch to user '{}': {}",
+                user,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    if running_elevated() {
+        bail!("Still running as root after switching to '{}'", user);
+    }
+
+    info!("✓ Dropped root privileges, running as '{}'", user);
+    run_startup_audit(event_log_path);
+
+    Ok(())
+}
+
+/// Switching users is only supported on Unix; a Windows service is set up to
+/// run as the right account instead
+#[cfg(not(unix))]
+pub fn drop_privileges(user: &str, _event_log_path: Option<&str>) -> Result<()> {
+    anyhow::bail!(
+        "--run-as is only supported on Unix. Run the service as '{}' instead.",
+        user
+    )
+}
+
+/// Whether the process runs as root (Unix) or with Administrator rights (Windows)
+fn running_elevated() -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(windows)]
+    {
+        // "net session" is only allowed for Administrators
+        std::process::Command::new("net")
+            .arg("session")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        false
+    }
+}
+
+/// Check that a file can be created in a directory
+///
+/// # Arguments
+/// * `dir` - The directory to test
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".write_test");
+
+    let writable = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&probe)
+        .is_ok();
+
+    let _ = fs::remove_file(&probe);
+    writable
+}
@@ -0,0 +1,1188 @@
+//! Deciding when to switch PPPoE IDs, and to which
+//!
+//! The decisions are made against `UsageProvider` and `RouterControl`, which
+//! `Portal` and `Router` implement for the real ISP portal and router. Tests
+//! run the same logic against fakes of them.
+
+use crate::config::{Config, Credential, NotificationKind, ScheduleAction};
+use crate::events::{self, Event};
+use crate::forecast;
+use crate::notify::{self, send_notification};
+use crate::policy::{ahead_of_schedule, candidate_order};
+use crate::portal::{get_total_use, PortalAccount};
+use crate::recharge;
+use crate::router::{
+    password_change_router, set_wifi_ssid, which_pppoe_id_running, RouterAccess, SwitchNotVerified,
+    DISABLED_PASSWORD,
+};
+use crate::ssid;
+use crate::state::{
+    bump_counters, clear_deferred_disables, clear_disabled, mark_in_use, mark_schedule_warned,
+    record_account_details, record_deferred_disable, record_disabled, record_rotation,
+    record_usage, State,
+};
+use crate::storage::{compact_history_if_due, record_router_action, record_usage_sample};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Local;
+use futures::stream::{self, StreamExt};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
+
+/// Where the automation reads how much of an account is used
+///
+/// Neither this nor `RouterControl` needs `Send` futures: a run happens on
+/// one task from start to finish.
+#[async_trait(?Send)]
+pub trait UsageProvider {
+    /// Read an account's usage and status
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID, which is also the portal username
+    /// * `password` - Its password
+    async fn account(&self, pppoe_id: &str, password: &str) -> Result<PortalAccount>;
+}
+
+/// What the automation does to the router
+#[async_trait(?Send)]
+pub trait RouterControl {
+    /// Whether changes are only reported, not made
+    fn dry_run(&self) -> bool;
+
+    /// The PPPoE ID the router is set to
+    async fn running_id(&mut self) -> Result<String>;
+
+    /// Set the PPPoE credentials and reconnect
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    /// * `password` - Its password, or `DISABLED_PASSWORD` to disable the
+    ///   connection
+    ///
+    /// # Returns
+    /// * `false` if the router didn't take them
+    async fn set_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<bool>;
+
+    /// Rename a wireless network
+    ///
+    /// # Arguments
+    /// * `interface` - The router's name for the wireless interface
+    /// * `ssid` - The new network name
+    async fn set_wifi_ssid(&mut self, interface: &str, ssid: &str) -> Result<()>;
+}
+
+/// The ISP portal, as set in the `[portal]` section of the config file
+pub struct Portal<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Portal<'a> {
+    /// Read from the configured portal
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    pub fn new(config: &'a Config) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait(?Send)]
+impl UsageProvider for Portal<'_> {
+    async fn account(&self, pppoe_id: &str, password: &str) -> Result<PortalAccount> {
+        get_total_use(self.config, pppoe_id, password).await
+    }
+}
+
+/// The router, as set in the `[router]` section of the config file
+pub struct Router<'a> {
+    config: &'a Config,
+    access: RouterAccess,
+}
+
+impl<'a> Router<'a> {
+    /// Bring up whatever is needed to reach the router
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    /// * `dry_run` - Leave the router's settings alone
+    pub fn open(config: &'a Config, dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            config,
+            access: RouterAccess::open(config, dry_run)?,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl RouterControl for Router<'_> {
+    fn dry_run(&self) -> bool {
+        self.access.dry_run()
+    }
+
+    async fn running_id(&mut self) -> Result<String> {
+        which_pppoe_id_running(self.config, &mut self.access).await
+    }
+
+    async fn set_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<bool> {
+        password_change_router(self.config, &mut self.access, pppoe_id, password).await
+    }
+
+    async fn set_wifi_ssid(&mut self, interface: &str, ssid: &str) -> Result<()> {
+        set_wifi_ssid(self.config, &mut self.access, interface, ssid).await
+    }
+}
+
+/// Report how long a switch took, alerting if it blew the timing budget.
+///
+/// A switch that takes far longer than usual tends to mean something is
+/// quietly going wrong (slow portal, router retries), even if it succeeded.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `from` - The PPPoE ID being switched away from
+/// * `to` - The PPPoE ID being switched to
+/// * `elapsed` - Time from the decision to switch until the router was updated
+fn check_switch_duration(config: &Config, from: &str, to: &str, elapsed: Duration) {
+    let sla_secs = config.alerts.switch_sla_secs;
+
+    info!("Switch took {} seconds", elapsed.as_secs());
+
+    if elapsed.as_secs() > sla_secs {
+        warn!(
+            "Switch exceeded the {} second budget ({} seconds)",
+            sla_secs,
+            elapsed.as_secs()
+        );
+        events::emit(Event::SwitchSlow {
+            from,
+            to,
+            seconds: elapsed.as_secs(),
+            budget_seconds: sla_secs,
+        });
+        send_notification(
+            NotificationKind::Warning,
+            "Slow WiFi Switch ⏱",
+            &format!(
+                "Switching from '{}' to '{}' took {} seconds (budget: {} seconds).\nThe portal or router may be misbehaving.",
+                from,
+                to,
+                elapsed.as_secs(),
+                sla_secs
+            ),
+        );
+    }
+}
+
+/// Remind about accounts that are about to expire or already have.
+///
+/// Runs independently of usage: an account that has expired while still under
+/// quota would otherwise go unnoticed until a switch to it fails. Each account
+/// is reminded about at most once a day.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `dry_run` - Only log the reminders, without sending them or marking
+///   the accounts as reminded about
+///
+/// # Returns
+/// * The accounts reminded about, and why, for the recharge command
+fn send_expiry_reminders(config: &Config, dry_run: bool) -> Vec<(String, recharge::Reason)> {
+    let reminder_days = config.alerts.expiry_reminder_days;
+    let today = Local::now().date_naive();
+
+    let mut state = State::load();
+    let mut reminded = Vec::new();
+
+    for Credential { id: pppoe_id, .. } in &config.credentials {
+        let Some(details) = state.accounts.get_mut(pppoe_id) else {
+            continue;
+        };
+        let Some(expiry) = details.expiry else {
+            continue;
+        };
+
+        let days_left = (expiry - today).num_days();
+        if days_left > reminder_days || details.reminded_on == Some(today) {
+            continue;
+        }
+        if dry_run {
+            info!(
+                "[dry run] Would remind about '{}' (expiry {})",
+                pppoe_id, expiry
+            );
+            continue;
+        }
+
+        let recharge = details
+            .recharge_amount
+            .as_deref()
+            .map(|amount| format!("\nRecharge amount: {}", amount))
+            .unwrap_or_default()
+            + recharge::link(config, pppoe_id).as_str();
+
+        if days_left < 0 {
+            warn!("'{}' expired on {}", pppoe_id, expiry);
+            send_notification(
+                NotificationKind::Warning,
+                "PPPoE ID Expired ⚠",
+                &format!("'{}' expired on {}.{}", pppoe_id, expiry, recharge),
+            );
+            reminded.push((pppoe_id.clone(), recharge::Reason::Expired));
+        } else {
+            warn!(
+                "'{}' expires in {} day(s) ({})",
+                pppoe_id, days_left, expiry
+            );
+            send_notification(
+                NotificationKind::Warning,
+                "PPPoE ID Expiring Soon ⏳",
+                &format!(
+                    "'{}' expires in {} day(s), on {}.{}",
+                    pppoe_id, days_left, expiry, recharge
+                ),
+            );
+            reminded.push((pppoe_id.clone(), recharge::Reason::Expiring));
+        }
+
+        details.reminded_on = Some(today);
+    }
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+
+    reminded
+}
+
+/// Phone for help if the connection has stayed disabled for too long
+///
+/// By then the household may have been offline for hours and never seen the
+/// other notifications. The call is made once per disable, and needs a route
+/// to the internet that doesn't depend on the PPPoE link, e.g. a mobile modem.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+async fn escalate_long_disable(config: &Config) {
+    let Some(voice) = &config.notifications.voice else {
+        return;
+    };
+    let mut state = State::load();
+    let Some(disabled) = &mut state.disabled else {
+        return;
+    };
+    if disabled.escalated || disabled.age_secs() < voice.after_hours * 3600 {
+        return;
+    }
+
+    let hours = disabled.age_secs() / 3600;
+    warn!(
+        "Connection disabled for {} hours and not re-enabled. Phoning for help...",
+        hours
+    );
+    let message = format!(
+        "The internet connection has been disabled for {} hours because every PPPoE ID ran out of quota. Recharge an account or switch to another ID to restore it.",
+        hours
+    );
+
+    match notify::place_calls(voice, &message).await {
+        Ok(()) => {
+            disabled.escalated = true;
+            if let Err(e) = state.save() {
+                warn!("Failed to save state: {}", e);
+            }
+        }
+        Err(e) => warn!("Emergency call failed: {}", e),
+    }
+}
+
+/// Look up a cached usage reading that makes a portal check unnecessary.
+///
+/// Only applies when `polling.usage_cache_ttl_mins` is set. A reading
+/// qualifies if it is younger than the TTL and at least
+/// `polling.fast_path_margin` minutes (or MB) below the switch threshold, i.e. the ID
+/// can't plausibly have crossed the threshold since it was taken.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The currently running PPPoE ID
+///
+/// # Returns
+/// * The cached usage in minutes or MB, if the portal check can be skipped
+fn fresh_usage_well_below_threshold(config: &Config, pppoe_id: &str) -> Option<i32> {
+    let ttl_mins = config.polling.usage_cache_ttl_mins?;
+    let margin = config.polling.fast_path_margin;
+
+    let state = State::load();
+    let reading = state.usage_cache.get(pppoe_id)?;
+
+    if reading.age_secs() <= ttl_mins * 60
+        && reading.minutes <= config.thresholds_for(pppoe_id).switch - margin
+    {
+        Some(reading.minutes)
+    } else {
+        None
+    }
+}
+
+/// What checking the other PPPoE IDs found
+struct Search<'a> {
+    /// The first usable ID at or below its available threshold, if any
+    available: Option<&'a Credential>,
+    /// How many IDs couldn't be checked
+    failed: usize,
+}
+
+/// Check the other PPPoE IDs, in `polling.candidate_order`, for one to switch to
+///
+/// Unlimited IDs are skipped; they are only switched to when this finds nothing.
+/// With `polling.concurrent_checks` above 1, every candidate is checked, that
+/// many at a time, and the first available one in candidate order is picked.
+/// Otherwise they are checked one by one until one is available.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `portal` - Where usage is read from
+/// * `current_index` - Index of the running ID in the credentials list
+/// * `on_schedule` - Only take an ID that isn't ahead of `[schedule]` either
+///
+/// # Returns
+/// * The first usable ID at or below its available threshold, if any, and
+///   how many IDs couldn't be checked on the way
+async fn find_available_id<'a>(
+    config: &'a Config,
+    portal: &dyn UsageProvider,
+    current_index: usize,
+    on_schedule: bool,
+) -> Search<'a> {
+    // Unlimited IDs are only a last resort, see `unlimited_fallback`
+    let candidates: Vec<&Credential> = candidate_order(config, current_index)
+        .into_iter()
+        .map(|index| &config.credentials[index])
+        .filter(|candidate| !candidate.unlimited)
+        .collect();
+
+    let concurrency = config.polling.concurrent_checks;
+    if concurrency > 1 && candidates.len() > 1 {
+        info!(
+            "Checking {} IDs, {} at a time...",
+            candidates.len(),
+            concurrency.min(candidates.len())
+        );
+        let accounts = check_all(portal, &candidates, concurrency).await;
+
+        let mut search = Search {
+            available: None,
+            failed: 0,
+        };
+        for (candidate, account) in candidates.into_iter().zip(accounts) {
+            search.failed += usize::from(account.is_err());
+            if record_candidate_check(config, &candidate.id, account, on_schedule)
+                && search.available.is_none()
+            {
+                search.available = Some(candidate);
+            }
+        }
+        return search;
+    }
+
+    let mut failed = 0;
+    for candidate in candidates {
+        info!("Checking '{}'...", candidate.id);
+        let account = portal.account(&candidate.id, &candidate.password).await;
+        failed += usize::from(account.is_err());
+        if record_candidate_check(config, &candidate.id, account, on_schedule) {
+            return Search {
+                available: Some(candidate),
+                failed,
+            };
+        }
+    }
+
+    Search {
+        available: None,
+        failed,
+    }
+}
+
+/// Whether to put off disabling the connection this run, because some IDs
+/// couldn't be checked and `retry.defer_disable_runs` allows another run
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The running PPPoE ID that would be disabled
+/// * `failed` - How many other IDs couldn't be checked
+/// * `dry_run` - Don't count the run towards the limit
+fn defer_disable(config: &Config, pppoe_id: &str, failed: usize, dry_run: bool) -> bool {
+    let budget = config.retry.defer_disable_runs;
+    let deferred = State::load().deferred_disables;
+    if failed == 0 || deferred >= budget {
+        return false;
+    }
+
+    let runs_in_a_row = if dry_run {
+        deferred + 1
+    } else {
+        record_deferred_disable()
+    };
+    warn!(
+        "{} other ID(s) couldn't be checked. Not disabling '{}' yet; checking again next run ({} of {})...",
+        failed, pppoe_id, runs_in_a_row, budget
+    );
+    events::emit(Event::DisableDeferred {
+        pppoe_id,
+        unchecked: failed,
+        runs_in_a_row,
+    });
+    true
+}
+
+/// Read the usage of several PPPoE IDs at once, each in its own browser session
+///
+/// # Arguments
+/// * `portal` - Where usage is read from
+/// * `candidates` - The PPPoE IDs to check
+/// * `concurrency` - How many to check at the same time
+///
+/// # Returns
+/// * The outcome of each check, in the order of `candidates`
+async fn check_all(
+    portal: &dyn UsageProvider,
+    candidates: &[&Credential],
+    concurrency: usize,
+) -> Vec<Result<PortalAccount>> {
+    stream::iter(candidates)
+        .map(|candidate| portal.account(&candidate.id, &candidate.password))
+        .buffered(concurrency)
+        .collect()
+        .await
+}
+
+/// Record what checking a candidate's usage found
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The candidate PPPoE ID
+/// * `account` - The outcome of reading it from the portal
+/// * `on_schedule` - Whether it also has to not be ahead of `[schedule]`
+///
+/// # Returns
+/// * Whether the candidate is usable and at or below its available threshold
+fn record_candidate_check(
+    config: &Config,
+    pppoe_id: &str,
+    account: Result<PortalAccount>,
+    on_schedule: bool,
+) -> bool {
+    let account = match account {
+        Ok(account) => account,
+        Err(e) => {
+            warn!("Failed to check '{}': {}", pppoe_id, e);
+            events::emit(Event::UsageCheckFailed {
+                pppoe_id,
+                error: &e.to_string(),
+            });
+            bump_counters(|counters| counters.usage_check_failures += 1);
+            return false;
+        }
+    };
+
+    record_account_details(pppoe_id, account.expiry, account.recharge_amount.clone());
+
+    if !account.is_usable() {
+        let status = account.status.as_deref().unwrap_or_default();
+        info!("'{}' is not usable (status: {})", pppoe_id, status);
+        events::emit(Event::UsageCheckFailed {
+            pppoe_id,
+            error: &format!("account status: {}", status),
+        });
+        return false;
+    }
+
+    let usage = account.total_use;
+    info!(
+        "Usage for '{}': {}",
+        pppoe_id,
+        config.format_usage(pppoe_id, usage)
+    );
+    events::emit(Event::UsageChecked {
+        pppoe_id,
+        minutes: usage,
+    });
+    bump_counters(|counters| counters.usage_checks += 1);
+    record_usage(pppoe_id, usage);
+    record_usage_sample(pppoe_id, usage);
+
+    let available = config.thresholds_for(pppoe_id).available;
+    if usage <= available {
+        let ahead = on_schedule
+            .then(|| ahead_of_schedule(config, pppoe_id, usage, Local::now().date_naive()))
+            .flatten();
+        if let Some(allowed) = ahead {
+            info!(
+                "'{}' is ahead of its schedule too ({} > {})",
+                pppoe_id,
+                config.format_usage(pppoe_id, usage),
+                config.format_usage(pppoe_id, allowed)
+            );
+            return false;
+        }
+
+        info!(
+            "✓ '{}' is available (usage: {} ≤ {})",
+            pppoe_id,
+            config.format_usage(pppoe_id, usage),
+            config.format_usage(pppoe_id, available)
+        );
+        true
+    } else {
+        info!(
+            "'{}' also exceeded limit ({})",
+            pppoe_id,
+            config.format_usage(pppoe_id, usage)
+        );
+        false
+    }
+}
+
+/// Warn that a PPPoE ID is used faster than `[schedule]` allows, notifying
+/// once a day
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `usage` - Its usage now
+/// * `allowed` - What it may have used by the end of today
+fn warn_ahead_of_schedule(config: &Config, pppoe_id: &str, usage: i32, allowed: i32) {
+    warn!(
+        "'{}' is ahead of its schedule: {} used, {} allowed by the end of today",
+        pppoe_id,
+        config.format_usage(pppoe_id, usage),
+        config.format_usage(pppoe_id, allowed)
+    );
+    events::emit(Event::AheadOfSchedule {
+        pppoe_id,
+        minutes: usage,
+        allowed,
+    });
+
+    if mark_schedule_warned(pppoe_id) {
+        send_notification(
+            NotificationKind::Warning,
+            "Quota Use Ahead of Schedule ⚠",
+            &format!(
+                "'{}' has used {}, but only {} was planned by the end of today.",
+                pppoe_id,
+                config.format_usage(pppoe_id, usage),
+                config.format_usage(pppoe_id, allowed)
+            ),
+        );
+    }
+}
+
+/// Pick an unlimited ID to switch to when no other ID has quota left
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `current_id` - The PPPoE ID running now
+///
+/// # Returns
+/// * The first unlimited ID other than `current_id`, if any
+fn unlimited_fallback<'a>(config: &'a Config, current_id: &str) -> Option<&'a Credential> {
+    let fallback = config
+        .credentials
+        .iter()
+        .find(|credential| credential.unlimited && credential.id != current_id)?;
+
+    info!(
+        "No quota ID available, falling back to unlimited '{}'",
+        fallback.id
+    );
+    Some(fallback)
+}
+
+/// Switch the router to another PPPoE ID and report how it went
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - The router to switch
+/// * `from` - The PPPoE ID running now
+/// * `next` - The PPPoE ID to switch to
+/// * `old_usage` - Usage of `from`, unless it is unlimited
+/// * `decision_time` - When it was decided to switch, for the timing budget
+#[instrument(skip_all, fields(from = %from, to = %next.id))]
+async fn switch_to(
+    config: &Config,
+    router: &mut dyn RouterControl,
+    from: &str,
+    next: &Credential,
+    old_usage: Option<i32>,
+    decision_time: Instant,
+) {
+    if router.dry_run() {
+        info!("[dry run] Would switch from '{}' to '{}'", from, next.id);
+        send_notification(
+            NotificationKind::Status,
+            "WiFi Switch Planned (dry run)",
+            &format!("Would switch from '{}' to '{}'", from, next.id),
+        );
+        return;
+    }
+
+    info!("Switching from '{}' to '{}'...", from, next.id);
+    events::emit(Event::SwitchStarted { from, to: &next.id });
+
+    let switch_result = router.set_credentials(&next.id, &next.password).await;
+
+    check_switch_duration(config, from, &next.id, decision_time.elapsed());
+
+    match switch_result {
+        Ok(true) => {
+            info!("✓ Successfully switched to '{}'.", next.id);
+            events::emit(Event::SwitchSucceeded {
+                from,
+                to: &next.id,
+                old_usage,
+            });
+            bump_counters(|counters| counters.switches += 1);
+            record_router_action("switch", from, Some(&next.id), old_usage);
+            mark_in_use(&next.id);
+            record_rotation(&next.id);
+            clear_disabled();
+            // Only an ID switched away from for being used up needs recharging
+            let recharge = match old_usage {
+                Some(usage) if usage > config.thresholds_for(from).switch => {
+                    recharge::link(config, from)
+                }
+                _ => String::new(),
+            };
+            send_notification(
+                NotificationKind::SwitchSucceeded,
+                "WiFi ID Switched ✓",
+                &format!(
+                    "Successfully switched from '{}' to '{}'\nOld usage: {}{}",
+                    from,
+                    next.id,
+                    old_usage.map_or("unlimited".to_string(), |usage| config
+                        .format_usage(from, usage)),
+                    recharge
+                ),
+            );
+        }
+        Ok(false) => {
+            error!("Failed to switch to '{}'.", next.id);
+            events::emit(Event::SwitchFailed {
+                from,
+                to: &next.id,
+                error: "router rejected the change",
+                rolled_back_to: None,
+            });
+            bump_counters(|counters| counters.switch_failures += 1);
+            send_notification(
+                NotificationKind::SwitchFailed,
+                "WiFi Switch Failed ✗",
+                &format!("Failed to switch from '{}' to '{}'", from, next.id),
+            );
+        }
+        Err(e) => {
+            error!("Failed to switch to '{}': {}", next.id, e);
+            events::emit(Event::SwitchFailed {
+                from,
+                to: &next.id,
+                error: &e.to_string(),
+                rolled_back_to: e
+                    .downcast_ref::<SwitchNotVerified>()
+                    .and_then(|failure| failure.rolled_back_to.as_deref()),
+            });
+            bump_counters(|counters| counters.switch_failures += 1);
+            send_notification(
+                NotificationKind::SwitchFailed,
+                "WiFi Switch Error",
+                &format!("Error switching WiFi ID: {}", e),
+            );
+        }
+    }
+}
+
+/// Bring back a connection that an earlier run disabled, once an ID has quota
+/// again, e.g. after the billing cycle reset
+///
+/// The disabled ID gets its real password back as soon as its usage is at or
+/// below its available threshold. Otherwise the other IDs are checked as for a
+/// switch. While none has quota, the connection stays disabled.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `portal` - Where usage is read from
+/// * `router` - The router to re-enable the connection on
+/// * `index` - Index of the disabled ID in `config.credentials`
+async fn re_enable(
+    config: &Config,
+    portal: &dyn UsageProvider,
+    router: &mut dyn RouterControl,
+    index: usize,
+) {
+    let credential = &config.credentials[index];
+    let pppoe_id = credential.id.as_str();
+    let decision_time = Instant::now();
+    info!(
+        "The connection of '{}' was disabled by an earlier run. Checking whether it has quota again...",
+        pppoe_id
+    );
+
+    let account = portal.account(pppoe_id, &credential.password).await;
+    if !record_candidate_check(config, pppoe_id, account, false) {
+        match find_available_id(config, portal, index, false)
+            .await
+            .available
+        {
+            Some(next) => {
+                let usage = State::load()
+                    .usage_cache
+                    .get(pppoe_id)
+                    .map(|reading| reading.minutes);
+                switch_to(config, router, pppoe_id, next, usage, decision_time).await;
+            }
+            None => info!("No PPPoE ID has quota yet. The connection stays disabled."),
+        }
+        return;
+    }
+
+    if router.dry_run() {
+        info!("[dry run] Would re-enable the connection of '{}'", pppoe_id);
+        send_notification(
+            NotificationKind::Status,
+            "PPPoE Re-enable Planned (dry run)",
+            &format!(
+                "'{}' has quota again.\nWould re-enable its connection.",
+                pppoe_id
+            ),
+        );
+        return;
+    }
+
+    match router.set_credentials(pppoe_id, &credential.password).await {
+        Ok(true) => {
+            info!("✓ Re-enabled the connection of '{}'.", pppoe_id);
+            events::emit(Event::ConnectionReEnabled { pppoe_id });
+            record_router_action("enable", pppoe_id, None, None);
+            clear_disabled();
+            send_notification(
+                NotificationKind::Disabled,
+                "PPPoE Connection Re-enabled ✓",
+                &format!("'{}' has quota again.\nConnection re-enabled.", pppoe_id),
+            );
+        }
+        failed => {
+            let error = match failed {
+                Err(e) => format!("{:#}", e),
+                _ => "router did not accept the password".to_string(),
+            };
+            error!(
+                "Failed to re-enable the connection of '{}': {}",
+                pppoe_id, error
+            );
+            send_notification(
+                NotificationKind::Disabled,
+                "Failed to Re-enable PPPoE ✗",
+                &format!(
+                    "'{}' has quota again, but its password couldn't be restored on the router.\nError: {}",
+                    pppoe_id, error
+                ),
+            );
+        }
+    }
+}
+
+/// Check the running ID against the real router and ISP portal, and switch
+/// or disable as needed
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `dry_run` - Do every check, but only report the switch or disable that
+///   would be made. The router is still logged in to, to read the running ID.
+#[instrument(name = "run", skip(config))]
+pub async fn run_automation(config: &Config, dry_run: bool) -> Result<()> {
+    let mut router = Router::open(config, dry_run)?;
+    if dry_run {
+        info!("Dry run: switches and disables are only reported, the router is left alone");
+    }
+
+    run_with(config, &Portal::new(config), &mut router).await
+}
+
+/// Main automation logic
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `portal` - Where usage is read from
+/// * `router` - The router to check and switch. In a dry run it only reports
+///   the switch or disable that would be made.
+pub async fn run_with(
+    config: &Config,
+    portal: &dyn UsageProvider,
+    router: &mut dyn RouterControl,
+) -> Result<()> {
+    let dry_run = router.dry_run();
+    let mut deferred = false;
+
+    // Check which PPPoE ID is currently running
+    let current_running_id = router.running_id().await?;
+    info!(
+        "Currently running PPPoE ID from router: '{}'",
+        current_running_id
+    );
+    events::emit(Event::CurrentId {
+        pppoe_id: &current_running_id,
+    });
+
+    // Someone else put a working ID on the router, e.g. by hand
+    if let Some(disabled) = State::load()
+        .disabled
+        .filter(|disabled| disabled.pppoe_id != current_running_id)
+    {
+        info!(
+            "'{}' is no longer disabled on the router",
+            disabled.pppoe_id
+        );
+        clear_disabled();
+    }
+
+    // Find the currently running ID and check its usage
+    for (index, credential) in config.credentials.iter().enumerate() {
+        let (pppoe_id_name, pppoe_id_password) = (&credential.id, &credential.password);
+        debug!(
+            "Checking if '{}' == '{}'",
+            current_running_id, pppoe_id_name
+        );
+
+        if current_running_id == *pppoe_id_name {
+            if State::load().disabled.is_some() {
+                re_enable(config, portal, router, index).await;
+                break;
+            }
+
+            info!("✓ PPPoE ID '{}' is currently running.", pppoe_id_name);
+            mark_in_use(pppoe_id_name);
+
+            if credential.unlimited {
+                info!(
+                    "'{}' is unlimited. Looking for a quota ID to switch back to...",
+                    pppoe_id_name
+                );
+                let decision_time = Instant::now();
+
+                match find_available_id(config, portal, index, false)
+                    .await
+                    .available
+                {
+                    Some(next) => {
+                        switch_to(config, router, pppoe_id_name, next, None, decision_time).await;
+                    }
+                    None => {
+                        info!(
+                            "✓ No quota ID available. Staying on unlimited '{}'.",
+                            pppoe_id_name
+                        );
+                        send_notification(
+                            NotificationKind::Status,
+                            "WiFi Status OK ✓",
+                            &format!(
+                                "Current ID: '{}'\nUnlimited, no quota ID available yet",
+                                pppoe_id_name
+                            ),
+                        );
+                    }
+                }
+                break;
+            }
+
+            // An early switch needs a fresh reading to forecast from
+            if let Some(cached_usage) = fresh_usage_well_below_threshold(config, pppoe_id_name)
+                .filter(|_| !forecast::in_early_switch_window(config))
+            {
+                info!(
+                    "✓ Cached usage for '{}' is {}, well within limit. Skipping portal check.",
+                    pppoe_id_name,
+                    config.format_usage(pppoe_id_name, cached_usage)
+                );
+                events::emit(Event::WithinLimit {
+                    pppoe_id: pppoe_id_name,
+                    minutes: cached_usage,
+                });
+                send_notification(
+                    NotificationKind::Status,
+                    "WiFi Status OK ✓",
+                    &format!(
+                        "Current ID: '{}'\nUsage: {} (within limit, cached)",
+                        pppoe_id_name,
+                        config.format_usage(pppoe_id_name, cached_usage)
+                    ),
+                );
+                break;
+            }
+
+            let current_account = portal.account(pppoe_id_name, pppoe_id_password).await?;
+            record_account_details(
+                pppoe_id_name,
+                current_account.expiry,
+                current_account.recharge_amount.clone(),
+            );
+            let current_usage = current_account.total_use;
+            let current_thresholds = config.thresholds_for(pppoe_id_name);
+            let ahead = ahead_of_schedule(
+                config,
+                pppoe_id_name,
+                current_usage,
+                Local::now().date_naive(),
+            );
+            let throttle = config
+                .schedule
+                .as_ref()
+                .is_some_and(|schedule| schedule.action == ScheduleAction::Throttle);
+            info!(
+                "Current usage: {}",
+                config.format_usage(pppoe_id_name, current_usage)
+            );
+            events::emit(Event::UsageChecked {
+                pppoe_id: pppoe_id_name,
+                minutes: current_usage,
+            });
+            bump_counters(|counters| counters.usage_checks += 1);
+            record_usage(pppoe_id_name, current_usage);
+            record_usage_sample(pppoe_id_name, current_usage);
+            ssid::update(config, router, pppoe_id_name, current_usage).await;
+
+            if current_usage > current_thresholds.switch {
+                info!(
+                    "Total use exceeded for '{}' ({} > {}). Looking for next available ID...",
+                    pppoe_id_name,
+                    config.format_usage(pppoe_id_name, current_usage),
+                    config.format_usage(pppoe_id_name, current_thresholds.switch)
+                );
+                if !dry_run {
+                    recharge::run_command(config, pppoe_id_name, recharge::Reason::Exhausted).await;
+                }
+
+                // The switch timing budget starts at the decision to switch
+                let decision_time = Instant::now();
+
+                // Fall back to an unlimited ID before disabling the connection
+                let search = find_available_id(config, portal, index, false).await;
+                let next = match search.available {
+                    Some(next) => Some(next),
+                    None => unlimited_fallback(config, pppoe_id_name),
+                };
+
+                if let Some(next) = next {
+                    switch_to(
+                        config,
+                        router,
+                        pppoe_id_name,
+                        next,
+                        Some(current_usage),
+                        decision_time,
+                    )
+                    .await;
+                } else {
+                    warn!("All PPPoE IDs have exceeded their limits!");
+                    events::emit(Event::AllIdsExhausted {
+                        pppoe_id: pppoe_id_name,
+                        minutes: current_usage,
+                    });
+
+                    // If current ID has exceeded the disable threshold, disable PPPoE by setting dummy password
+                    if current_usage > current_thresholds.disable
+                        && defer_disable(config, pppoe_id_name, search.failed, dry_run)
+                    {
+                        deferred = true;
+                    } else if current_usage > current_thresholds.disable {
+                        warn!(
+                            "Current ID '{}' has {} (>{}). Disabling PPPoE connection...",
+                            pppoe_id_name,
+                            config.format_usage(pppoe_id_name, current_usage),
+                            config.format_usage(pppoe_id_name, current_thresholds.disable)
+                        );
+
+                        if router.dry_run() {
+                            info!(
+                                "[dry run] Would disable the PPPoE connection of '{}'",
+                                pppoe_id_name
+                            );
+                            send_notification(
+                                NotificationKind::Status,
+                                "PPPoE Disable Planned (dry run)",
+                                &format!(
+                                    "All IDs exceeded their limits.\nWould disable the connection of '{}' at {} (>{}).",
+                                    pppoe_id_name,
+                                    config.format_usage(pppoe_id_name, current_usage),
+                                    config.format_usage(pppoe_id_name, current_thresholds.disable)
+                                ),
+                            );
+                        } else {
+                            match router
+                                .set_credentials(pppoe_id_name, DISABLED_PASSWORD)
+                                .await
+                            {
+                                Ok(true) => {
+                                    info!("✓ PPPoE connection disabled to prevent further usage.");
+                                    events::emit(Event::ConnectionDisabled {
+                                        pppoe_id: pppoe_id_name,
+                                        minutes: current_usage,
+                                    });
+                                    bump_counters(|counters| counters.disables += 1);
+                                    record_disabled(pppoe_id_name);
+                                    record_router_action(
+                                        "disable",
+                                        pppoe_id_name,
+                                        None,
+                                        Some(current_usage),
+                                    );
+                                    send_notification(
+                                        NotificationKind::Disabled,
+                                        "PPPoE Connection Disabled 🛑",
+                                        &format!(
+                                            "All IDs exceeded their limits.\nCurrent ID '{}' has {} (>{}).\nConnection disabled to prevent charges.{}",
+                                            pppoe_id_name,
+                                            config.format_usage(pppoe_id_name, current_usage),
+                                            config.format_usage(pppoe_id_name, current_thresholds.disable),
+                                            recharge::link(config, pppoe_id_name)
+                                        ),
+                                    );
+                                }
+                                failed => {
+                                    let error = match failed {
+                                        Err(e) => format!("{:#}", e),
+                                        _ => "router did not accept the dummy password".to_string(),
+                                    };
+                                    error!("Failed to disable PPPoE connection: {}", error);
+                                    events::emit(Event::DisableFailed {
+                                        pppoe_id: pppoe_id_name,
+                                        error: &error,
+                                    });
+                                    send_notification(
+                                        NotificationKind::Disabled,
+                                        "Failed to Disable PPPoE ✗",
+                                        &format!(
+                                            "All IDs exceeded limit but couldn't disable connection.\nCurrent usage: {}\nError: {}",
+                                            config.format_usage(pppoe_id_name, current_usage),
+                                            error
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    } else {
+                        send_notification(
+                            NotificationKind::AllExhausted,
+                            "No WiFi IDs Available ⚠",
+                            &format!(
+                                "All PPPoE IDs have exceeded their limits!\nCurrent ID: '{}' - {} (≤{} to avoid disconnect){}",
+                                pppoe_id_name,
+                                config.format_usage(pppoe_id_name, current_usage),
+                                config.format_usage(pppoe_id_name, current_thresholds.disable),
+                                recharge::link(config, pppoe_id_name)
+                            ),
+                        );
+                    }
+                }
+            } else if let (Some(allowed), true) = (ahead, throttle) {
+                warn_ahead_of_schedule(config, pppoe_id_name, current_usage, allowed);
+                let decision_time = Instant::now();
+
+                match find_available_id(config, portal, index, true)
+                    .await
+                    .available
+                {
+                    Some(next) => {
+                        switch_to(
+                            config,
+                            router,
+                            pppoe_id_name,
+                            next,
+                            Some(current_usage),
+                            decision_time,
+                        )
+                        .await;
+                    }
+                    None => info!(
+                        "No other ID is on schedule. Staying on '{}'.",
+                        pppoe_id_name
+                    ),
+                }
+            } else if let Some(switch_at) = forecast::early_switch_due(config, pppoe_id_name) {
+                info!(
+                    "'{}' is forecast to reach its switch threshold around {}. Switching early, in the low-usage window...",
+                    pppoe_id_name,
+                    switch_at.format("%Y-%m-%d %H:%M")
+                );
+                events::emit(Event::EarlySwitchDue {
+                    pppoe_id: pppoe_id_name,
+                    minutes: current_usage,
+                    switch_at: switch_at.timestamp(),
+                });
+                let decision_time = Instant::now();
+
+                match find_available_id(config, portal, index, false)
+                    .await
+                    .available
+                {
+                    Some(next) => {
+                        switch_to(
+                            config,
+                            router,
+                            pppoe_id_name,
+                            next,
+                            Some(current_usage),
+                            decision_time,
+                        )
+                        .await;
+                    }
+                    None => info!(
+                        "No other ID available to switch to early. Staying on '{}'.",
+                        pppoe_id_name
+                    ),
+                }
+            } else {
+                if let Some(allowed) = ahead {
+                    warn_ahead_of_schedule(config, pppoe_id_name, current_usage, allowed);
+                }
+                info!(
+                    "✓ Total use within limit for '{}'. No action taken.",
+                    pppoe_id_name
+                );
+                events::emit(Event::WithinLimit {
+                    pppoe_id: pppoe_id_name,
+                    minutes: current_usage,
+                });
+                send_notification(
+                    NotificationKind::Status,
+                    "WiFi Status OK ✓",
+                    &format!(
+                        "Current ID: '{}'\nUsage: {} (within limit)",
+                        pppoe_id_name,
+                        config.format_usage(pppoe_id_name, current_usage)
+                    ),
+                );
+            }
+
+            break; // Exit loop once we find the currently running ID
+        }
+    }
+
+    // Only runs in a row count towards `retry.defer_disable_runs`
+    if !deferred && !dry_run {
+        clear_deferred_disables();
+    }
+
+    compact_history_if_due(&config.history);
+
+    let reminded = send_expiry_reminders(config, dry_run);
+    if !dry_run {
+        for (pppoe_id, reason) in reminded {
+            recharge::run_command(config, &pppoe_id, reason).await;
+        }
+        escalate_long_disable(config).await;
+    }
+
+    Ok(())
+}
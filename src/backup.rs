@@ -0,0 +1,231 @@
+use crate::config::default_config_path;
+use crate::secrets::{self, SECRETS_FILE_NAME};
+use crate::state::{data_dir, STATE_FILE_NAME};
+use crate::storage::{HistoryStore, SqliteStore, HISTORY_FILE_NAME};
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Start of an encrypted backup, to recognise it and its format
+const MAGIC: &[u8] = b"auto-wifi backup v1\n";
+
+/// Environment variable holding the passphrase of an encrypted backup, for
+/// running unattended
+const PASSPHRASE_VAR: &str = "AUTO_WIFI_BACKUP_PASSPHRASE";
+
+/// Name of the config file in the archive, wherever it was read from
+const CONFIG_ENTRY: &str = "config.toml";
+
+/// Files of the data directory that are backed up, under the same names in
+/// the archive
+const DATA_FILES: &[&str] = &[STATE_FILE_NAME, HISTORY_FILE_NAME, SECRETS_FILE_NAME];
+
+/// Bundle the config file, the state, the usage history and the secrets file
+/// into one zip archive, optionally encrypted with a passphrase
+///
+/// The history database is copied consistently even while the daemon runs.
+/// Log files and the downloaded ChromeDriver are left out.
+///
+/// # Arguments
+/// * `config_path` - Config file given on the command line, if any
+/// * `path` - Where to write the archive
+/// * `encrypt` - Encrypt the archive, with a passphrase asked for (or taken
+///   from `AUTO_WIFI_BACKUP_PASSPHRASE`)
+pub fn create(config_path: Option<&Path>, path: &Path, encrypt: bool) -> Result<()> {
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut added = Vec::new();
+
+    let config_path = match config_path {
+        Some(config_path) if !config_path.exists() => {
+            anyhow::bail!("There is no config file at {}", config_path.display());
+        }
+        Some(config_path) => config_path.to_path_buf(),
+        None => default_config_path()?,
+    };
+    if config_path.exists() {
+        let content =
+            fs::read(&config_path).context(format!("Failed to read {}", config_path.display()))?;
+        add(&mut archive, CONFIG_ENTRY, &content)?;
+        added.push(config_path);
+    }
+
+    let data_dir = data_dir()?;
+    for name in DATA_FILES {
+        let file = data_dir.join(name);
+        if !file.exists() {
+            continue;
+        }
+
+        let content = if *name == HISTORY_FILE_NAME {
+            history_snapshot(&data_dir)?
+        } else {
+            fs::read(&file).context(format!("Failed to read {}", file.display()))?
+        };
+        add(&mut archive, name, &content)?;
+        added.push(file);
+    }
+
+    if added.is_empty() {
+        anyhow::bail!("There is nothing to back up yet");
+    }
+
+    let mut data = archive
+        .finish()
+        .context("Failed to build the archive")?
+        .into_inner();
+    if encrypt {
+        let passphrase = secrets::new_passphrase(PASSPHRASE_VAR, "Backup passphrase: ")?;
+        data = secrets::seal(MAGIC, &data, &passphrase)?;
+    }
+
+    write_private(path, &data)?;
+    for file in &added {
+        println!("Backed up {}", file.display());
+    }
+    println!("✓ Wrote {}", path.display());
+
+    Ok(())
+}
+
+/// Put the files of a backup made by `create` back in place: the config file
+/// where it is looked for, the rest in the data directory
+///
+/// The daemon should not be running meanwhile, as it would write over the
+/// restored state.
+///
+/// # Arguments
+/// * `config_path` - Config file given on the command line, if any; where the
+///   config file is restored to
+/// * `path` - The archive
+/// * `force` - Replace files that already exist
+pub fn restore(config_path: Option<&Path>, path: &Path, force: bool) -> Result<()> {
+    let mut data = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    if data.starts_with(MAGIC) {
+        let passphrase = secrets::passphrase(PASSPHRASE_VAR, "Backup passphrase: ")?;
+        data = secrets::unseal(MAGIC, &data, &passphrase)
+            .context(format!("Failed to decrypt {}", path.display()))?;
+    }
+    let mut archive = ZipArchive::new(Cursor::new(data)).context(format!(
+        "{} isn't a backup made by `auto-wifi backup`",
+        path.display()
+    ))?;
+
+    let config_path = match config_path {
+        Some(config_path) => config_path.to_path_buf(),
+        None => default_config_path()?,
+    };
+    let data_dir = data_dir()?;
+
+    // Only the known files are restored, so nothing in the archive can point
+    // outside these places
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    for name in archive.file_names() {
+        if name == CONFIG_ENTRY {
+            files.push((name.to_string(), config_path.clone()));
+        } else if DATA_FILES.contains(&name) {
+            files.push((name.to_string(), data_dir.join(name)));
+        }
+    }
+    if files.is_empty() {
+        anyhow::bail!("{} holds nothing to restore", path.display());
+    }
+
+    if !force {
+        let existing: Vec<_> = files
+            .iter()
+            .filter(|(_, file)| file.exists())
+            .map(|(_, file)| file.display().to_string())
+            .collect();
+        if !existing.is_empty() {
+            anyhow::bail!(
+                "These files already exist: {}. Restore with --force to replace them.",
+                existing.join(", ")
+            );
+        }
+    }
+
+    for (name, file) in &files {
+        let mut content = Vec::new();
+        archive
+            .by_name(name)?
+            .read_to_end(&mut content)
+            .context(format!("Failed to read {} from the archive", name))?;
+
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+        }
+        write_private(file, &content)?;
+        println!("Restored {}", file.display());
+    }
+    println!("✓ Restored {} file(s) from {}", files.len(), path.display());
+
+    Ok(())
+}
+
+/// Add a file to the archive
+///
+/// # Arguments
+/// * `archive` - The archive being written
+/// * `name` - The file's name in the archive
+/// * `content` - The file
+fn add(archive: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, content: &[u8]) -> Result<()> {
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o600);
+
+    archive
+        .start_file(name, options)
+        .context(format!("Failed to add {} to the archive", name))?;
+    archive.write_all(content)?;
+
+    Ok(())
+}
+
+/// A consistent copy of the history database
+///
+/// # Arguments
+/// * `data_dir` - The data directory, where the copy is made
+fn history_snapshot(data_dir: &Path) -> Result<Vec<u8>> {
+    let snapshot = data_dir.join(format!("{}.backup", HISTORY_FILE_NAME));
+    // Left over from an interrupted backup
+    let _ = fs::remove_file(&snapshot);
+
+    SqliteStore::open()?.snapshot(&snapshot)?;
+    let content = fs::read(&snapshot).context(format!("Failed to read {}", snapshot.display()));
+    let _ = fs::remove_file(&snapshot);
+
+    content
+}
+
+/// Write a file readable by its owner only, since backups and the secrets
+/// file hold passwords
+///
+/// It is written next to its place first, so a failure leaves the old file
+/// as it was.
+///
+/// # Arguments
+/// * `path` - Where the file goes
+/// * `content` - The file
+pub fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    fs::write(&partial, content).context(format!("Failed to write {}", partial.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o600)).context(format!(
+            "Failed to restrict access to {}",
+            partial.display()
+        ))?;
+    }
+
+    fs::rename(&partial, path).context(format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
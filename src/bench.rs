@@ -0,0 +1,172 @@
+//! How long each way of reading the portal and the router takes against the
+//! live endpoints, to help pick the fastest `portal.client` and
+//! `router.model`. Nothing is changed: the portal is only read, and the router
+//! only logged in to for its running ID.
+
+use crate::config::{Config, PortalClient, RouterModel};
+use crate::portal::read_account_via;
+use crate::router::{which_pppoe_id_running, RouterAccess};
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long one way of reading took each round, or why it didn't work
+#[derive(Debug)]
+pub struct Timing {
+    /// `"portal"` or `"router"`
+    pub target: &'static str,
+    /// The `portal.client` or `router.model` it was read with
+    pub path: &'static str,
+    /// How long each round took, or the error of the first that failed
+    pub result: Result<Vec<Duration>, String>,
+}
+
+impl Timing {
+    /// The middle of the rounds' times, if it worked
+    pub fn median(&self) -> Option<Duration> {
+        let mut rounds = self.result.as_ref().ok()?.clone();
+        rounds.sort();
+        rounds.get(rounds.len() / 2).copied()
+    }
+}
+
+/// Time every way of reading the portal and the router
+///
+/// The browser ways are left out of a `lightweight` config. A way that fails
+/// isn't tried again, so a router model that doesn't fit the router costs one
+/// round only.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to read from the portal; the first one if not given
+/// * `rounds` - How many times to read each way
+pub async fn measure(config: &Config, pppoe_id: Option<&str>, rounds: u32) -> Result<Vec<Timing>> {
+    let credential = match pppoe_id {
+        Some(pppoe_id) => config
+            .credentials
+            .iter()
+            .find(|credential| credential.id == pppoe_id)
+            .context(format!("'{}' is not a configured PPPoE ID", pppoe_id))?,
+        None => config
+            .credentials
+            .first()
+            .context("No PPPoE IDs are configured")?,
+    };
+
+    let mut clients = vec![(PortalClient::Http, "http")];
+    let mut models = vec![(RouterModel::OpenWrt, "openwrt")];
+    if !config.lightweight {
+        clients.push((PortalClient::Browser, "browser"));
+        models.push((RouterModel::DLink, "dlink"));
+    }
+
+    let mut timings = Vec::new();
+    for (client, path) in clients {
+        info!(
+            "Reading '{}' from the portal with {}...",
+            credential.id, path
+        );
+        let result = time(rounds, || async {
+            read_account_via(config, client, &credential.id, &credential.password).await
+        })
+        .await;
+        timings.push(Timing {
+            target: "portal",
+            path,
+            result,
+        });
+    }
+    for (model, path) in models {
+        info!("Reading the running ID from the router as {}...", path);
+        let mut config = config.clone();
+        config.router.model = model;
+        let config = &config;
+        let result = time(rounds, || async {
+            let mut router = RouterAccess::open(config, true)?;
+            which_pppoe_id_running(config, &mut router).await
+        })
+        .await;
+        timings.push(Timing {
+            target: "router",
+            path,
+            result,
+        });
+    }
+
+    Ok(timings)
+}
+
+/// Time every way of reading the portal and the router and print how they
+/// compare
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to read from the portal; the first one if not given
+/// * `rounds` - How many times to read each way
+pub async fn run(config: &Config, pppoe_id: Option<&str>, rounds: u32) -> Result<()> {
+    let timings = measure(config, pppoe_id, rounds.max(1)).await?;
+
+    println!(
+        "{:<8}  {:<8}  {:>8}  {:>8}  {:>8}",
+        "", "", "min", "median", "max"
+    );
+    for timing in &timings {
+        match &timing.result {
+            Ok(rounds) => println!(
+                "{:<8}  {:<8}  {:>8}  {:>8}  {:>8}",
+                timing.target,
+                timing.path,
+                millis(rounds.iter().min().copied()),
+                millis(timing.median()),
+                millis(rounds.iter().max().copied()),
+            ),
+            Err(e) => println!("{:<8}  {:<8}  failed: {}", timing.target, timing.path, e),
+        }
+    }
+
+    for (target, setting) in [("portal", "portal.client"), ("router", "router.model")] {
+        let fastest = timings
+            .iter()
+            .filter(|timing| timing.target == target)
+            .filter_map(|timing| Some((timing.median()?, timing.path)))
+            .min();
+        match fastest {
+            Some((_, path)) => println!("✓ Fastest {}: {} = \"{}\"", target, setting, path),
+            None => println!("No way of reading the {} worked", target),
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a read `rounds` times, stopping at the first that fails
+///
+/// # Arguments
+/// * `rounds` - How many times to run it
+/// * `read` - The read
+async fn time<T, F, Fut>(rounds: u32, mut read: F) -> Result<Vec<Duration>, String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut times = Vec::new();
+    for _ in 0..rounds {
+        let started = Instant::now();
+        read().await.map_err(|e| format!("{:#}", e))?;
+        times.push(started.elapsed());
+    }
+
+    Ok(times)
+}
+
+/// A time in whole milliseconds, for the table
+///
+/// # Arguments
+/// * `duration` - The time
+fn millis(duration: Option<Duration>) -> String {
+    duration.map_or_else(
+        || "-".to_string(),
+        |duration| format!("{} ms", duration.as_millis()),
+    )
+}
@@ -0,0 +1,234 @@
+use crate::config::{BrowserConfig, BrowserName};
+use crate::driver;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thirtyfour::extensions::cdp::ChromeDevTools;
+use thirtyfour::prelude::*;
+use thirtyfour::{CapabilitiesHelper, ChromeCapabilities, FirefoxCapabilities, Proxy};
+use tracing::{debug, warn};
+
+/// What Chrome is kept from downloading when `browser.block_assets` is set:
+/// images, fonts and media, and the usual trackers and font hosts
+const BLOCKED_URLS: &[&str] = &[
+    "*.png*",
+    "*.jpg*",
+    "*.jpeg*",
+    "*.gif*",
+    "*.webp*",
+    "*.svg*",
+    "*.ico*",
+    "*.woff*",
+    "*.ttf*",
+    "*.otf*",
+    "*.eot*",
+    "*.mp4*",
+    "*.webm*",
+    "*.mp3*",
+    "*fonts.googleapis.com*",
+    "*fonts.gstatic.com*",
+    "*google-analytics.com*",
+    "*googletagmanager.com*",
+    "*doubleclick.net*",
+    "*connect.facebook.net*",
+    "*hotjar.com*",
+];
+
+/// Key in the page's `sessionStorage` the bytes of the pages left so far are
+/// added up under
+const TRANSFERRED_KEY: &str = "__auto_wifi_transferred";
+
+/// Bytes downloaded by the page and everything it loaded, as the browser's
+/// resource timing has them
+const PAGE_BYTES: &str = "performance.getEntriesByType('navigation') \
+    .concat(performance.getEntriesByType('resource')) \
+    .reduce((total, entry) => total + (entry.transferSize || 0), 0)";
+
+/// Browser sessions closed since the counters were last taken
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes those sessions downloaded
+static TRANSFERRED: AtomicU64 = AtomicU64::new(0);
+
+/// Open a browser session through the WebDriver server
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+/// * `proxy` - Proxy to send all traffic through (e.g. `socks5://127.0.0.1:1080`)
+pub async fn connect(browser: &BrowserConfig, proxy: Option<&str>) -> Result<WebDriver> {
+    let caps: Capabilities = match browser.name {
+        BrowserName::Chrome => chrome_capabilities(browser, proxy)?.into(),
+        BrowserName::Firefox => firefox_capabilities(browser, proxy)?.into(),
+    };
+
+    let url = driver::webdriver_url(browser);
+    let driver = WebDriver::new(&url, caps).await.context(format!(
+        "Failed to open a browser session. Is the WebDriver server running at {}?",
+        url
+    ))?;
+
+    if browser.name == BrowserName::Chrome {
+        if let Err(e) = limit_chrome_traffic(browser, &driver).await {
+            warn!("Failed to set up Chrome's network rules: {:#}", e);
+        }
+    }
+
+    Ok(driver)
+}
+
+/// Close a browser session, adding what it downloaded to the counters
+/// `take_traffic` reads
+///
+/// Chrome counts every page the session loaded; other browsers only the
+/// last one. Resources from other sites that don't allow timing them are
+/// counted as nothing, so the figure is a lower bound.
+///
+/// # Arguments
+/// * `driver` - The session to close
+pub async fn quit(driver: WebDriver) {
+    let script = format!(
+        "return Number(sessionStorage.getItem('{}') || 0) + {};",
+        TRANSFERRED_KEY, PAGE_BYTES
+    );
+    match driver.execute(script, Vec::new()).await {
+        Ok(ret) => {
+            let bytes = ret.json().as_f64().unwrap_or_default() as u64;
+            debug!("Browser session downloaded {} bytes", bytes);
+            TRANSFERRED.fetch_add(bytes, Ordering::Relaxed);
+        }
+        Err(e) => debug!("Failed to read what the browser downloaded: {}", e),
+    }
+    SESSIONS.fetch_add(1, Ordering::Relaxed);
+
+    let _ = driver.quit().await;
+}
+
+/// Take the counts of browser sessions closed and the bytes they downloaded
+/// since last taken, starting them over
+///
+/// # Returns
+/// * The number of sessions, and the bytes
+pub fn take_traffic() -> (u64, u64) {
+    (
+        SESSIONS.swap(0, Ordering::Relaxed),
+        TRANSFERRED.swap(0, Ordering::Relaxed),
+    )
+}
+
+/// Keep Chrome from downloading what a check doesn't need, and have each
+/// page add what it downloaded to the session's total as it is left
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+/// * `driver` - The Chrome session
+async fn limit_chrome_traffic(browser: &BrowserConfig, driver: &WebDriver) -> Result<()> {
+    let dev_tools = ChromeDevTools::new(driver.handle.clone());
+
+    dev_tools
+        .execute_cdp_with_params(
+            "Page.addScriptToEvaluateOnNewDocument",
+            json!({
+                "source": format!(
+                    "addEventListener('pagehide', () => sessionStorage.setItem('{key}', \
+                     Number(sessionStorage.getItem('{key}') || 0) + {bytes}));",
+                    key = TRANSFERRED_KEY,
+                    bytes = PAGE_BYTES
+                )
+            }),
+        )
+        .await
+        .context("Failed to add the page script counting downloads")?;
+
+    let mut urls: Vec<&str> = browser.blocked_urls.iter().map(String::as_str).collect();
+    if browser.block_assets {
+        urls.extend(BLOCKED_URLS);
+    }
+    if urls.is_empty() {
+        return Ok(());
+    }
+
+    dev_tools
+        .execute_cdp("Network.enable")
+        .await
+        .context("Failed to enable the network domain")?;
+    dev_tools
+        .execute_cdp_with_params("Network.setBlockedURLs", json!({ "urls": urls }))
+        .await
+        .context("Failed to block URLs")?;
+
+    Ok(())
+}
+
+/// Build the capabilities for a Chrome session
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+/// * `proxy` - Proxy to send all traffic through, if any
+fn chrome_capabilities(browser: &BrowserConfig, proxy: Option<&str>) -> Result<ChromeCapabilities> {
+    let mut caps = DesiredCapabilities::chrome();
+    if browser.headless {
+        caps.add_arg("--headless=new")?;
+    }
+    caps.add_arg("--no-sandbox")?;
+    caps.add_arg("--disable-dev-shm-usage")?;
+    if browser.block_assets {
+        caps.add_arg("--blink-settings=imagesEnabled=false")?;
+    }
+
+    if let Some(proxy) = proxy {
+        caps.add_arg(&format!("--proxy-server={}", proxy))?;
+    }
+
+    Ok(caps)
+}
+
+/// Build the capabilities for a Firefox session
+///
+/// Firefox has no command-line switch for a proxy, so it is set through the
+/// standard WebDriver proxy capability instead.
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+/// * `proxy` - Proxy to send all traffic through, if any
+fn firefox_capabilities(
+    browser: &BrowserConfig,
+    proxy: Option<&str>,
+) -> Result<FirefoxCapabilities> {
+    let mut caps = DesiredCapabilities::firefox();
+    if browser.headless {
+        caps.set_headless()?;
+    }
+
+    if let Some(proxy) = proxy {
+        caps.set_proxy(manual_proxy(proxy)?)?;
+    }
+
+    Ok(caps)
+}
+
+/// Turn a proxy URL into the WebDriver proxy capability
+///
+/// # Arguments
+/// * `proxy` - e.g. `socks5://127.0.0.1:1080` or `http://proxy:3128`
+fn manual_proxy(proxy: &str) -> Result<Proxy> {
+    let (scheme, address) = proxy.split_once("://").unwrap_or(("http", proxy));
+    let address = address.trim_end_matches('/').to_string();
+
+    let (http_proxy, ssl_proxy, socks_proxy, socks_version) = match scheme {
+        "http" | "https" => (Some(address.clone()), Some(address), None, None),
+        "socks5" | "socks5h" => (None, None, Some(address), Some(5)),
+        "socks4" => (None, None, Some(address), Some(4)),
+        _ => anyhow::bail!("Unsupported proxy '{}' for Firefox", proxy),
+    };
+
+    Ok(Proxy::Manual {
+        ftp_proxy: None,
+        http_proxy,
+        ssl_proxy,
+        socks_proxy,
+        socks_version,
+        socks_username: None,
+        socks_password: None,
+        no_proxy: None,
+    })
+}
@@ -0,0 +1,136 @@
+//! A read-only pass over everything this tool depends on, run after the
+//! binary or the config file changed: log in to the router and read which
+//! ID is running, and read every ID's usage from the portal. A broken
+//! selector or credential shows up right away instead of at the next switch,
+//! when it matters.
+
+use crate::config::{Config, NotificationKind};
+use crate::events::{self, Event};
+use crate::notify::send_notification;
+use crate::portal::get_total_use;
+use crate::router::{which_pppoe_id_running, RouterAccess};
+use crate::state::State;
+use anyhow::Result;
+use std::env;
+use std::fs;
+use std::time::UNIX_EPOCH;
+use tracing::{info, warn};
+
+/// Whether the binary or the config file changed since the last canary
+/// check that passed
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn is_due(config: &Config) -> bool {
+    State::load().canary_fingerprint != Some(fingerprint(config))
+}
+
+/// Log in to the router and read every ID from the portal, changing nothing,
+/// and report the outcome as a notification
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+///
+/// # Returns
+/// * Fails with everything that went wrong, if anything did
+pub async fn run(config: &Config) -> Result<()> {
+    info!("Canary check: reading the router and the portal, changing nothing...");
+    let mut failures = Vec::new();
+
+    let router = async {
+        let mut router = RouterAccess::open(config, true)?;
+        which_pppoe_id_running(config, &mut router).await
+    };
+    match router.await {
+        Ok(running_id) => info!("✓ Logged in to the router; '{}' is running", running_id),
+        Err(e) => failures.push(format!("Router: {:#}", e)),
+    }
+
+    for credential in &config.credentials {
+        match get_total_use(config, &credential.id, &credential.password).await {
+            Ok(account) => info!(
+                "✓ Read '{}' from the portal: {}",
+                credential.id,
+                config.format_usage(&credential.id, account.total_use)
+            ),
+            Err(e) => failures.push(format!("Portal, '{}': {:#}", credential.id, e)),
+        }
+    }
+
+    events::emit(Event::CanaryFinished {
+        passed: failures.is_empty(),
+        failures: &failures,
+    });
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            warn!("Canary check: {}", failure);
+        }
+        send_notification(
+            NotificationKind::Error,
+            "Canary Check Failed ⚠",
+            &format!(
+                "After the last update, the read-only check found:\n{}",
+                failures.join("\n")
+            ),
+        );
+        anyhow::bail!("The canary check failed:\n{}", failures.join("\n"));
+    }
+
+    let mut state = State::load();
+    state.canary_fingerprint = Some(fingerprint(config));
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+
+    info!("✓ Canary check passed");
+    send_notification(
+        NotificationKind::Status,
+        "Canary Check Passed ✓",
+        &format!(
+            "The router and all {} PPPoE ID(s) were read fine after the last update.",
+            config.credentials.len()
+        ),
+    );
+
+    Ok(())
+}
+
+/// What identifies this binary and config file: the version, the binary's
+/// size and modification time, and a hash of the config file
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+fn fingerprint(config: &Config) -> String {
+    let binary = env::current_exe()
+        .and_then(fs::metadata)
+        .ok()
+        .map(|metadata| {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_secs());
+            format!("{}-{}", metadata.len(), modified)
+        })
+        .unwrap_or_default();
+    let config_file = config
+        .path
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .map(|content| format!("{:016x}", fnv1a(&content)))
+        .unwrap_or_default();
+
+    format!("{}/{}/{}", env!("CARGO_PKG_VERSION"), binary, config_file)
+}
+
+/// The 64-bit FNV-1a hash of some bytes, which unlike `DefaultHasher` is the
+/// same from one build to the next
+///
+/// # Arguments
+/// * `bytes` - What to hash
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,1033 @@
+use crate::config::{Config, Credential};
+use crate::events::{self, Event};
+use crate::forecast::{self, Forecast, Seasonality};
+use crate::overview;
+use crate::portal::{self, get_total_use, PortalAccount};
+use crate::router::{
+    check_risks_acknowledged, password_change_router, read_pppoe_credentials, upnp,
+    which_pppoe_id_running, RouterAccess, DISABLED_PASSWORD,
+};
+use crate::secrets;
+use crate::state::{
+    bump_counters, clear_disabled, mark_in_use, record_account_details, record_disabled,
+    record_usage, unix_now, OriginalPppoe, State,
+};
+use crate::storage::{record_router_action, record_usage_sample, History, Session};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike, Utc};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// What `init --acknowledge-risks` accepts
+const RISKS: &str = "\
+This tool changes the PPPoE username and password on your router by itself:
+  - A switch that goes wrong can leave you offline until the router is set
+    up again by hand.
+  - Once every PPPoE ID is used up, it disconnects you on purpose.
+  - Your ISP may not allow moving between accounts like this.
+The router's PPPoE settings as they are now are backed up first, and
+`auto-wifi restore-original` puts them back.";
+
+/// Accept the risks of this tool changing the router, which it won't do
+/// before, backing up the router's PPPoE settings first
+///
+/// The settings are read twice and only kept if both reads agree. A backup
+/// taken by an earlier `init` is kept as it is, so it stays what the router
+/// had before this tool changed anything.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `acknowledge_risks` - Whether the user accepted the risks
+pub async fn init(config: &Config, acknowledge_risks: bool) -> Result<()> {
+    println!("{}", RISKS);
+    if !acknowledge_risks {
+        anyhow::bail!("Run `auto-wifi init --acknowledge-risks` to accept these risks");
+    }
+
+    let (original, backed_up) = match State::load().original_pppoe {
+        Some(original) => {
+            println!(
+                "✓ Keeping the backup of the router's PPPoE settings ('{}') taken before",
+                original.username
+            );
+            (original, false)
+        }
+        None => {
+            let mut router = RouterAccess::open(config, true)?;
+            let (username, password) = read_pppoe_credentials(config, &mut router).await?;
+            if read_pppoe_credentials(config, &mut router).await?
+                != (username.clone(), password.clone())
+            {
+                anyhow::bail!("The router showed different PPPoE settings on two reads; try again");
+            }
+            if username.is_empty() || password.is_empty() {
+                anyhow::bail!("The router didn't show its PPPoE username and password, so they can't be backed up");
+            }
+            if password == DISABLED_PASSWORD {
+                anyhow::bail!(
+                    "The connection is disabled by this tool; set the router's PPPoE settings by hand, then run this again"
+                );
+            }
+            let original = OriginalPppoe {
+                username,
+                password,
+                read_at: unix_now(),
+            };
+            (original, true)
+        }
+    };
+
+    let mut state = State::load();
+    state.original_pppoe = Some(original.clone());
+    state.risks_acknowledged_at = Some(unix_now());
+    state.save().context("Failed to save state")?;
+    if State::load().original_pppoe.as_ref() != Some(&original) {
+        anyhow::bail!(
+            "The backup of the router's PPPoE settings couldn't be read back from the state file"
+        );
+    }
+
+    if backed_up {
+        println!(
+            "✓ Backed up the router's PPPoE settings ('{}')",
+            original.username
+        );
+    }
+    println!("✓ Risks acknowledged; the router's PPPoE settings may now be changed");
+
+    Ok(())
+}
+
+/// Show which PPPoE ID the router is using and how much of it is used up
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub async fn status(config: &Config) -> Result<()> {
+    let mut router = RouterAccess::open(config, false)?;
+    let running_id = which_pppoe_id_running(config, &mut router).await?;
+
+    if running_id.is_empty() {
+        println!("No PPPoE ID is set on the router");
+        return Ok(());
+    }
+    println!("Running PPPoE ID: {}", running_id);
+    if let Some(wan) = upnp::wan_status(config).await {
+        match wan.external_ip {
+            Some(ip) => println!("WAN: {} ({})", wan.status, ip),
+            None => println!("WAN: {}", wan.status),
+        }
+    }
+
+    let Some(credential) = config
+        .credentials
+        .iter()
+        .find(|credential| credential.id == running_id)
+    else {
+        warn!(
+            "'{}' is not in the configuration, can't check its usage",
+            running_id
+        );
+        return Ok(());
+    };
+
+    mark_in_use(&credential.id);
+    let account = check_account(config, credential).await?;
+    print_account(config, &credential.id, &account);
+
+    match forecast::load(config, &credential.id) {
+        Ok(Some(Forecast {
+            switch_at: Some(switch_at),
+            ..
+        })) => println!(
+            "Days left: about {:.1}, until around {}",
+            days_until(switch_at),
+            switch_at.format("%Y-%m-%d %H:%M")
+        ),
+        Ok(_) => {}
+        Err(e) => warn!(
+            "Failed to forecast the usage of '{}': {:#}",
+            credential.id, e
+        ),
+    }
+
+    Ok(())
+}
+
+/// Read every configured PPPoE ID from the portal and show them as a table,
+/// colored by how much of each quota is used
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `html` - Also write the table as an HTML page here, e.g. to email
+pub async fn status_all(config: &Config, html: Option<&Path>) -> Result<()> {
+    // The table is worth having even if the router can't be reached
+    let running_id = match RouterAccess::open(config, false) {
+        Ok(mut router) => which_pppoe_id_running(config, &mut router).await,
+        Err(e) => Err(e),
+    }
+    .unwrap_or_else(|e| {
+        warn!(
+            "Couldn't read the running PPPoE ID from the router: {:#}",
+            e
+        );
+        String::new()
+    });
+    if !running_id.is_empty() {
+        mark_in_use(&running_id);
+    }
+
+    let mut rows = Vec::new();
+    for credential in &config.credentials {
+        let account = check_account(config, credential).await.map_err(|e| {
+            warn!("Failed to check '{}': {:#}", credential.id, e);
+            format!("{:#}", e)
+        });
+        rows.push(overview::Row::new(
+            config,
+            credential,
+            credential.id == running_id,
+            account.as_ref().map_err(String::clone),
+        ));
+    }
+
+    let color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+    print!("{}", overview::text(&rows, color));
+
+    if let Some(path) = html {
+        fs::write(path, overview::html(&rows, Local::now()))
+            .context(format!("Failed to write {}", path.display()))?;
+        info!("✓ Wrote the table to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Query the portal for one configured PPPoE ID
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to check
+pub async fn check(config: &Config, pppoe_id: &str) -> Result<()> {
+    let credential = find_credential(config, pppoe_id)?;
+    let account = check_account(config, credential).await?;
+
+    println!("PPPoE ID: {}", credential.id);
+    print_account(config, &credential.id, &account);
+
+    Ok(())
+}
+
+/// Switch the router to a configured PPPoE ID, whatever its usage
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to switch to
+pub async fn switch(config: &Config, pppoe_id: &str) -> Result<()> {
+    let credential = find_credential(config, pppoe_id)?;
+    let mut router = RouterAccess::open(config, false)?;
+    let running_id = which_pppoe_id_running(config, &mut router).await?;
+
+    info!("Switching from '{}' to '{}'...", running_id, credential.id);
+    if !password_change_router(config, &mut router, &credential.id, &credential.password).await? {
+        bump_counters(|counters| counters.switch_failures += 1);
+        anyhow::bail!("Router rejected the switch to '{}'", credential.id);
+    }
+
+    info!("✓ Switched to '{}'", credential.id);
+    bump_counters(|counters| counters.switches += 1);
+    record_router_action(
+        "switch",
+        &running_id,
+        Some(&credential.id),
+        cached_usage(&running_id),
+    );
+    mark_in_use(&credential.id);
+    clear_disabled();
+
+    Ok(())
+}
+
+/// Disable the connection by setting a dummy PPPoE password on the router
+///
+/// The PPPoE ID is left as it is and recorded as disabled, so the next `run`
+/// re-enables it once an ID has quota left again, and escalates if the
+/// connection stays disabled for too long.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub async fn disable(config: &Config) -> Result<()> {
+    let mut router = RouterAccess::open(config, false)?;
+    let running_id = which_pppoe_id_running(config, &mut router).await?;
+
+    info!("Disabling PPPoE connection for '{}'...", running_id);
+    if !password_change_router(config, &mut router, &running_id, DISABLED_PASSWORD).await? {
+        anyhow::bail!("Router did not accept the dummy password");
+    }
+
+    info!("✓ PPPoE connection disabled");
+    bump_counters(|counters| counters.disables += 1);
+    record_disabled(&running_id);
+    record_router_action("disable", &running_id, None, cached_usage(&running_id));
+
+    Ok(())
+}
+
+/// Put the PPPoE settings the router had before this tool changed anything,
+/// as backed up by `init`, back on the router
+///
+/// The change is verified like a switch, and rolled back if the connection
+/// doesn't come up. Once it worked the risks count as not accepted anymore, so
+/// a daemon that is still running leaves the router alone until `init
+/// --acknowledge-risks` is run again.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub async fn restore_original(config: &Config) -> Result<()> {
+    let original = State::load().original_pppoe.context(
+        "The router's original PPPoE settings were never backed up; `auto-wifi init --acknowledge-risks` does that",
+    )?;
+    let mut router = RouterAccess::open(config, false)?;
+    let running_id = which_pppoe_id_running(config, &mut router).await?;
+
+    info!(
+        "Restoring the original PPPoE settings ('{}') over '{}'...",
+        original.username, running_id
+    );
+    if !password_change_router(config, &mut router, &original.username, &original.password).await? {
+        anyhow::bail!("Router rejected the original PPPoE settings");
+    }
+
+    record_router_action(
+        "switch",
+        &running_id,
+        Some(&original.username),
+        cached_usage(&running_id),
+    );
+    clear_disabled();
+    let mut state = State::load();
+    state.risks_acknowledged_at = None;
+    state.save().context("Failed to save state")?;
+
+    println!(
+        "✓ Restored the router's original PPPoE settings ('{}')",
+        original.username
+    );
+    println!("The router is left alone until `auto-wifi init --acknowledge-risks` is run again");
+
+    Ok(())
+}
+
+/// Change the password of a PPPoE ID to a new random one, everywhere it is
+/// used: at the ISP portal, where the old one was kept, and on the router if
+/// the ID is running
+///
+/// Where the password is kept and the router are checked before anything is
+/// changed. After the portal takes the new password it is stored right away,
+/// then logged in to the portal with, then set on the router and verified
+/// like a switch. If storing it fails, the new password is printed so it
+/// isn't lost. A daemon that is running keeps the old password until it is
+/// restarted.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID whose password to change
+pub async fn rotate_pppoe_password(config: &Config, pppoe_id: &str) -> Result<()> {
+    let credential = find_credential(config, pppoe_id)?;
+    let change = config.portal.password_change.as_ref().context(
+        "The portal's change password page isn't configured; set [portal.password_change]",
+    )?;
+    let store = config.password_store(&credential.id)?;
+    let mut router = RouterAccess::open(config, false)?;
+    let running_id = which_pppoe_id_running(config, &mut router).await?;
+    let update_router = running_id == credential.id && State::load().disabled.is_none();
+    if update_router {
+        check_risks_acknowledged()?;
+    }
+
+    let password = secrets::generate_password(change.length);
+    info!("Changing the portal password of '{}'...", credential.id);
+    if let Err(e) =
+        portal::change_password(config, &credential.id, &credential.password, &password).await
+    {
+        // A form that was submitted may have worked without saying so
+        if get_total_use(config, &credential.id, &password)
+            .await
+            .is_err()
+        {
+            return Err(e.context(format!(
+                "Failed to change the portal password of '{}'",
+                credential.id
+            )));
+        }
+        warn!(
+            "The portal didn't confirm the change, but takes the new password: {:#}",
+            e
+        );
+    }
+    info!("✓ Changed the portal password of '{}'", credential.id);
+
+    if let Err(e) = store.save(&credential.id, &password) {
+        println!("New password of '{}': {}", credential.id, password);
+        return Err(e.context(format!(
+            "The portal password of '{}' was changed but couldn't be stored; store the one shown above in its place",
+            credential.id
+        )));
+    }
+    info!(
+        "✓ Stored the new password of '{}' in {}",
+        credential.id, store
+    );
+
+    let rotated = Credential {
+        password: password.clone(),
+        ..credential.clone()
+    };
+    check_account(config, &rotated)
+        .await
+        .context("The portal doesn't take the new password")?;
+    info!("✓ Logged in to the portal with the new password");
+
+    if update_router {
+        info!("'{}' is running, updating the router...", credential.id);
+        let updated = password_change_router(config, &mut router, &credential.id, &password)
+            .await
+            .context(format!(
+                "The router couldn't be updated; run `auto-wifi switch {}` to try again",
+                credential.id
+            ))?;
+        if !updated {
+            anyhow::bail!("Router rejected the new password of '{}'", credential.id);
+        }
+        info!("✓ The router is connected with the new password");
+    } else {
+        info!(
+            "'{}' isn't running, so the router gets the new password when it is switched to",
+            credential.id
+        );
+    }
+
+    events::emit(Event::PasswordRotated {
+        pppoe_id: &credential.id,
+        router_updated: update_router,
+    });
+    println!("✓ Rotated the password of '{}'", credential.id);
+    println!("Restart the daemon, if it is running, so it uses the new password");
+
+    Ok(())
+}
+
+/// List the configured PPPoE IDs with what is known about them from earlier
+/// runs. Nothing is fetched, so this works without the router or the portal.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn list(config: &Config) {
+    let state = State::load();
+
+    for credential in &config.credentials {
+        let usage = match state.usage_cache.get(&credential.id) {
+            Some(reading) => format!(
+                "{} ({} ago)",
+                config.format_usage(&credential.id, reading.minutes),
+                format_age(reading.age_secs())
+            ),
+            None => "usage unknown".to_string(),
+        };
+
+        let expiry = state
+            .accounts
+            .get(&credential.id)
+            .and_then(|details| details.expiry)
+            .map(|expiry| format!(", expires {}", expiry))
+            .unwrap_or_default();
+
+        println!("{:<20} {}{}", credential.id, usage, expiry);
+    }
+}
+
+/// Show how usage developed over the last days, how fast each ID is being
+/// used up and when the running one will reach the switch threshold.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - Only show this PPPoE ID
+/// * `days` - How many days back to look
+pub fn history(config: &Config, pppoe_id: Option<&str>, days: u32) -> Result<()> {
+    if let Some(pppoe_id) = pppoe_id {
+        find_credential(config, pppoe_id)?;
+    }
+
+    let history = History::open()?;
+    let since = Utc::now().timestamp() - i64::from(days) * 86400;
+    let samples = history.usage_since(since)?;
+
+    let state = State::load();
+    let running_id = state.last_running_id();
+
+    println!("Usage over the last {} day(s):", days);
+
+    for credential in &config.credentials {
+        if pppoe_id.is_some_and(|pppoe_id| pppoe_id != credential.id) {
+            continue;
+        }
+
+        let running = running_id == Some(credential.id.as_str());
+        println!(
+            "\n{}{}",
+            credential.id,
+            if running { " (running)" } else { "" }
+        );
+
+        let id_samples: Vec<_> = samples
+            .iter()
+            .filter(|sample| sample.pppoe_id == credential.id)
+            .cloned()
+            .collect();
+        if id_samples.is_empty() {
+            println!("  No readings");
+            continue;
+        }
+
+        // The last reading of each day, and how much was used since the day before
+        let mut previous: Option<i32> = None;
+        for (index, sample) in id_samples.iter().enumerate() {
+            let date = local_time(sample.timestamp).date_naive();
+            let last_of_day = id_samples
+                .get(index + 1)
+                .is_none_or(|next| local_time(next.timestamp).date_naive() != date);
+            if !last_of_day {
+                continue;
+            }
+
+            let change = match previous {
+                Some(previous) if sample.minutes >= previous => format!(
+                    "+{}",
+                    config.format_usage(&credential.id, sample.minutes - previous)
+                ),
+                Some(_) => "reset".to_string(),
+                None => String::new(),
+            };
+            println!(
+                "  {}  {:>15}  {}",
+                date,
+                config.format_usage(&credential.id, sample.minutes),
+                change
+            );
+            previous = Some(sample.minutes);
+        }
+
+        let seasonality = forecast::load_seasonality(config, &credential.id)?;
+        let Some(forecast) =
+            forecast::forecast(config, &credential.id, &id_samples, seasonality.as_ref())
+        else {
+            println!("  Not enough readings to estimate a daily rate");
+            continue;
+        };
+        println!(
+            "  Rate: {}/day",
+            config.format_usage(&credential.id, forecast.daily_rate.round() as i32)
+        );
+
+        if let (true, Some(switch_at)) = (running, forecast.switch_at) {
+            print_switch_at(config, &credential.id, switch_at);
+        }
+    }
+
+    let actions = history.actions_since(since)?;
+    if !actions.is_empty() {
+        println!("\nSwitches and disables:");
+    }
+    for action in actions {
+        if pppoe_id.is_some_and(|pppoe_id| {
+            pppoe_id != action.from_id && Some(pppoe_id) != action.to_id.as_deref()
+        }) {
+            continue;
+        }
+
+        let usage = action
+            .minutes
+            .map(|minutes| format!(" at {}", config.format_usage(&action.from_id, minutes)))
+            .unwrap_or_default();
+        let time = local_time(action.timestamp).format("%Y-%m-%d %H:%M");
+        match action.to_id {
+            Some(to_id) => println!(
+                "  {}  switched '{}' -> '{}'{}",
+                time, action.from_id, to_id, usage
+            ),
+            None => println!("  {}  {} '{}'{}", time, action.kind, action.from_id, usage),
+        }
+    }
+
+    // Not tied to a PPPoE ID, so only shown for all of them
+    let link_events = match pppoe_id {
+        Some(_) => Vec::new(),
+        None => history.link_events_since(since)?,
+    };
+    if !link_events.is_empty() {
+        println!("\nConnection events logged by the router:");
+    }
+    for event in link_events {
+        println!(
+            "  {}  {} {:<12}  {}",
+            local_time(event.timestamp).format("%Y-%m-%d %H:%M"),
+            event.router,
+            event.kind,
+            event.line
+        );
+    }
+
+    let external_ips: Vec<_> = history
+        .external_ips_since(since)?
+        .into_iter()
+        .filter(|external_ip| pppoe_id.is_none_or(|pppoe_id| pppoe_id == external_ip.pppoe_id))
+        .collect();
+    if !external_ips.is_empty() {
+        println!("\nExternal IP addresses:");
+    }
+    for external_ip in external_ips {
+        println!(
+            "  {}  {:<39}  '{}'{}",
+            local_time(external_ip.timestamp).format("%Y-%m-%d %H:%M"),
+            external_ip.ip,
+            external_ip.pppoe_id,
+            if external_ip.kind == "changed" {
+                "  changed by the ISP"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(())
+}
+
+/// Thin out and expire the usage history now, instead of waiting for the
+/// daily compaction
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn compact_history(config: &Config) -> Result<()> {
+    let compaction = History::open()?.compact(&config.history)?;
+
+    println!(
+        "✓ {} reading(s) downsampled, {} record(s) past retention deleted",
+        compaction.downsampled, compaction.expired
+    );
+
+    Ok(())
+}
+
+/// Show at what times of day usage happens, from the sessions collected off
+/// the portal's sessions page
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - Only count this PPPoE ID
+/// * `days` - How many days back to look
+pub fn sessions(config: &Config, pppoe_id: Option<&str>, days: u32) -> Result<()> {
+    if let Some(pppoe_id) = pppoe_id {
+        find_credential(config, pppoe_id)?;
+    }
+
+    let since = Utc::now().timestamp() - i64::from(days) * 86400;
+    let sessions: Vec<Session> = History::open()?
+        .sessions_since(since)?
+        .into_iter()
+        .filter(|session| pppoe_id.is_none_or(|pppoe_id| pppoe_id == session.pppoe_id))
+        .collect();
+
+    if sessions.is_empty() {
+        println!(
+            "No sessions recorded in the last {} day(s). Set [portal.sessions] in the config file to collect them.",
+            days
+        );
+        return Ok(());
+    }
+
+    let mut by_hour = [0.0; 24];
+    for session in &sessions {
+        spread_by_hour(session, &mut by_hour);
+    }
+    let total: f64 = by_hour.iter().sum();
+
+    let mut addresses: Vec<&str> = sessions
+        .iter()
+        .filter_map(|session| session.ip.as_deref())
+        .collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    println!(
+        "{} session(s) over the last {} day(s), {} different IP address(es)\n",
+        sessions.len(),
+        days,
+        addresses.len()
+    );
+    println!("Share of usage by hour of day:");
+    for (hour, used) in by_hour.iter().enumerate() {
+        let share = if total > 0.0 { used / total } else { 0.0 };
+        println!(
+            "  {:02}:00  {:>5.1}%  {}",
+            hour,
+            share * 100.0,
+            "#".repeat((share * 100.0).round() as usize)
+        );
+    }
+
+    if let Some((start, hours)) = busiest_window(&by_hour) {
+        println!(
+            "\nHalf of the usage happens between {:02}:00 and {:02}:00",
+            start,
+            (start + hours) % 24
+        );
+    }
+
+    Ok(())
+}
+
+/// Add a session's usage to the hours of the day it ran in, in proportion to
+/// how long it ran in each
+///
+/// # Arguments
+/// * `session` - The session
+/// * `by_hour` - Usage per local hour of the day
+fn spread_by_hour(session: &Session, by_hour: &mut [f64; 24]) {
+    let minutes = f64::from(session.minutes);
+    let Some(end) = session.end.filter(|&end| end > session.start) else {
+        by_hour[local_time(session.start).hour() as usize] += minutes;
+        return;
+    };
+
+    let duration = (end - session.start) as f64;
+    let mut chunk_start = session.start;
+    while chunk_start < end {
+        let time = local_time(chunk_start);
+        let to_next_hour = 3600 - i64::from(time.minute() * 60 + time.second());
+        let chunk_end = (chunk_start + to_next_hour).min(end);
+        by_hour[time.hour() as usize] += minutes * (chunk_end - chunk_start) as f64 / duration;
+        chunk_start = chunk_end;
+    }
+}
+
+/// The shortest run of hours, wrapping past midnight, holding at least half
+/// of the usage
+///
+/// # Arguments
+/// * `by_hour` - Usage per hour of the day
+///
+/// # Returns
+/// * The first hour of the run and its length in hours, if there was usage
+fn busiest_window(by_hour: &[f64; 24]) -> Option<(usize, usize)> {
+    let total: f64 = by_hour.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    (1..=24).find_map(|hours| {
+        let windows = (0..24).map(|start| {
+            let used: f64 = (start..start + hours).map(|hour| by_hour[hour % 24]).sum();
+            (start, used)
+        });
+        windows
+            .filter(|&(_, used)| used >= total / 2.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(start, _)| (start, hours))
+    })
+}
+
+/// Backfill the usage history of a PPPoE ID from the portal's history page
+///
+/// The page lists how much was used each day, while the history holds the
+/// total use over time, so totals are worked out backwards from the current
+/// one: the total at the end of a day is the current total minus what was used
+/// on the days after it. Days before the current billing cycle (where that
+/// would go below zero) and days already covered by the history are skipped.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to import the history of
+pub async fn import_history(config: &Config, pppoe_id: &str) -> Result<()> {
+    let credential = find_credential(config, pppoe_id)?;
+    info!("Reading the usage history of '{}'...", credential.id);
+
+    let (account, days) =
+        portal::read_daily_usage(config, &credential.id, &credential.password).await?;
+    if account.usage_kind != config.quota_unit(&credential.id).kind() {
+        anyhow::bail!(
+            "The portal's usage for '{}' doesn't match its configured quota unit",
+            credential.id
+        );
+    }
+
+    let history = History::open()?;
+    let first_recorded = history.first_usage_at(&credential.id)?;
+    let today = Local::now().date_naive();
+
+    let mut total = account.total_use;
+    let mut imported = 0;
+    for (date, used) in days.iter().rev() {
+        // Today is still running; the current reading below covers it
+        if *date >= today {
+            total -= used;
+            continue;
+        }
+        if total < 0 {
+            break;
+        }
+
+        let end_of_day = date
+            .and_hms_opt(23, 59, 59)
+            .and_then(|time| time.and_local_timezone(Local).earliest())
+            .map(|time| time.timestamp());
+        if let Some(timestamp) = end_of_day {
+            if first_recorded.is_none_or(|first| timestamp < first) {
+                history.add_usage_at(timestamp, &credential.id, total)?;
+                imported += 1;
+            }
+        }
+        total -= used;
+    }
+
+    record_usage(&credential.id, account.total_use);
+    record_usage_sample(&credential.id, account.total_use);
+
+    info!(
+        "✓ Imported {} day(s) of history for '{}' ({} day(s) listed)",
+        imported,
+        credential.id,
+        days.len()
+    );
+
+    Ok(())
+}
+
+/// Look up a configured PPPoE ID
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID given on the command line
+fn find_credential<'a>(config: &'a Config, pppoe_id: &str) -> Result<&'a Credential> {
+    config
+        .credentials
+        .iter()
+        .find(|credential| credential.id == pppoe_id)
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a configured PPPoE ID", pppoe_id))
+}
+
+/// Read an account from the portal and remember what it showed
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `credential` - The account to read
+async fn check_account(config: &Config, credential: &Credential) -> Result<PortalAccount> {
+    let account = get_total_use(config, &credential.id, &credential.password).await?;
+
+    bump_counters(|counters| counters.usage_checks += 1);
+    record_usage(&credential.id, account.total_use);
+    record_usage_sample(&credential.id, account.total_use);
+    record_account_details(
+        &credential.id,
+        account.expiry,
+        account.recharge_amount.clone(),
+    );
+
+    Ok(account)
+}
+
+/// Show where each PPPoE ID's usage is heading: its daily rate, when it
+/// reaches the switch threshold, and when the running one would be switched
+/// away from early.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - Only show this PPPoE ID
+pub fn forecast(config: &Config, pppoe_id: Option<&str>) -> Result<()> {
+    if let Some(pppoe_id) = pppoe_id {
+        find_credential(config, pppoe_id)?;
+    }
+
+    let state = State::load();
+    let running_id = state.last_running_id();
+
+    println!(
+        "Forecast from the last {} day(s) of readings:",
+        config.forecast.lookback_days
+    );
+
+    for credential in &config.credentials {
+        if pppoe_id.is_some_and(|pppoe_id| pppoe_id != credential.id) {
+            continue;
+        }
+
+        let running = running_id == Some(credential.id.as_str());
+        println!(
+            "\n{}{}",
+            credential.id,
+            if running { " (running)" } else { "" }
+        );
+
+        let Some(forecast) = forecast::load(config, &credential.id)? else {
+            println!("  Not enough readings to estimate a daily rate");
+            continue;
+        };
+        println!(
+            "  Usage: {} (read {})",
+            config.format_usage(&credential.id, forecast.usage),
+            forecast.read_at.format("%Y-%m-%d %H:%M")
+        );
+        println!(
+            "  Rate: {}/day",
+            config.format_usage(&credential.id, forecast.daily_rate.round() as i32)
+        );
+        if let Some(seasonality) = forecast::load_seasonality(config, &credential.id)? {
+            print_seasonality(&seasonality);
+        }
+
+        let switch_threshold = config.thresholds_for(&credential.id).switch;
+        match forecast.switch_at {
+            Some(switch_at) => {
+                print_switch_at(config, &credential.id, switch_at);
+                if running {
+                    match forecast::planned_early_switch(config, switch_at) {
+                        Some(at) if at <= Local::now() => {
+                            println!("  Due to be switched away from early, now")
+                        }
+                        Some(at) => println!(
+                            "  Due to be switched away from early, around {}",
+                            at.format("%Y-%m-%d %H:%M")
+                        ),
+                        None => {}
+                    }
+                }
+            }
+            None if credential.unlimited => println!("  Unlimited"),
+            None if forecast.usage > switch_threshold => println!(
+                "  Above the switch threshold ({})",
+                config.format_usage(&credential.id, switch_threshold)
+            ),
+            None => println!("  Usage isn't rising"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Print how much busier or quieter than average each day of the week, and
+/// each month, is
+///
+/// # Arguments
+/// * `seasonality` - The pattern of usage
+fn print_seasonality(seasonality: &Seasonality) {
+    let weekdays = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]
+        .iter()
+        .zip(seasonality.weekday)
+        .map(|(day, factor)| format!("{} {:.1}x", day, factor))
+        .collect::<Vec<_>>();
+    println!("  By day of the week: {}", weekdays.join(", "));
+
+    if let Some(month) = seasonality.month {
+        let months = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ]
+        .iter()
+        .zip(month)
+        .map(|(month, factor)| format!("{} {:.1}x", month, factor))
+        .collect::<Vec<_>>();
+        println!("  By month: {}", months.join(", "));
+    }
+}
+
+/// Print when a PPPoE ID is forecast to reach its switch threshold
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `switch_at` - When it reaches the threshold
+fn print_switch_at(config: &Config, pppoe_id: &str, switch_at: DateTime<Local>) {
+    println!(
+        "  Reaches the switch threshold ({}) in about {:.1} day(s), around {}",
+        config.format_usage(pppoe_id, config.thresholds_for(pppoe_id).switch),
+        days_until(switch_at),
+        switch_at.format("%Y-%m-%d %H:%M")
+    );
+}
+
+/// How many days from now until a time, in fractions of a day
+///
+/// # Arguments
+/// * `at` - The time
+fn days_until(at: DateTime<Local>) -> f64 {
+    (at - Local::now()).num_minutes().max(0) as f64 / 1440.0
+}
+
+/// Print what the portal showed for an account
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID the account is for
+/// * `account` - The account as read from the portal
+pub fn print_account(config: &Config, pppoe_id: &str, account: &PortalAccount) {
+    if config.is_unlimited(pppoe_id) {
+        println!(
+            "Usage: {} (unlimited)",
+            config.format_usage(pppoe_id, account.total_use)
+        );
+    } else {
+        let thresholds = config.thresholds_for(pppoe_id);
+        println!(
+            "Usage: {} (switch above {}, disable above {})",
+            config.format_usage(pppoe_id, account.total_use),
+            config.format_usage(pppoe_id, thresholds.switch),
+            config.format_usage(pppoe_id, thresholds.disable)
+        );
+    }
+    if let Some(status) = &account.status {
+        println!("Status: {}", status);
+    }
+    if let Some(expiry) = account.expiry {
+        println!("Expires: {}", expiry);
+    }
+    if let Some(recharge_amount) = &account.recharge_amount {
+        println!("Recharge: {}", recharge_amount);
+    }
+    if !config.portal.variants.is_empty() {
+        println!("Portal layout: {}", account.variant);
+    }
+}
+
+/// The last usage reading stored for a PPPoE ID, if any
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID
+fn cached_usage(pppoe_id: &str) -> Option<i32> {
+    State::load()
+        .usage_cache
+        .get(pppoe_id)
+        .map(|reading| reading.minutes)
+}
+
+/// Convert unix seconds to local time
+///
+/// # Arguments
+/// * `timestamp` - Unix seconds
+fn local_time(timestamp: i64) -> DateTime<Local> {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .with_timezone(&Local)
+}
+
+/// Format a number of seconds as a rough age, e.g. "5m" or "3h"
+///
+/// # Arguments
+/// * `secs` - The age in seconds
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
@@ -0,0 +1,2200 @@
+use crate::{migrate, secrets};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use tracing::info;
+
+/// Name of the directory (under the platform's config dir) holding the config file
+const APP_DIR_NAME: &str = "auto_pppoe_quota_manager";
+
+/// Name of the config file inside that directory
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Version of the config file format this binary writes and reads; older
+/// files are upgraded by `crate::migrate`
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Name `portal.usage_total` gives the figure next to `portal.usage_label`
+pub const USAGE_TERM: &str = "usage";
+
+// ============================================================================
+// EMBEDDED CONFIGURATION - Optional fallback loaded at compile time from .env
+// ============================================================================
+// If a .env file is present when building, build.rs embeds its values into
+// the binary. They are only used when no config file exists at runtime and
+// the same settings aren't set in the environment (e.g. in a container).
+const ROUTER_IP: Option<&str> = option_env!("EMBEDDED_ROUTER_IP");
+const ROUTER_PASSWORD: Option<&str> = option_env!("EMBEDDED_ROUTER_PASSWORD");
+const PPPOE_CREDENTIALS: Option<&str> = option_env!("EMBEDDED_PPPOE_CREDENTIALS");
+const EVENT_LOG_PATH: Option<&str> = option_env!("EMBEDDED_EVENT_LOG_PATH");
+const ROUTER_FALLBACK_PASSWORDS: Option<&str> = option_env!("EMBEDDED_ROUTER_FALLBACK_PASSWORDS");
+const ROUTER_LOGIN_DELAY_SECS: Option<&str> = option_env!("EMBEDDED_ROUTER_LOGIN_DELAY_SECS");
+const SELECTOR_RECOVERY: Option<&str> = option_env!("EMBEDDED_SELECTOR_RECOVERY");
+const SWITCH_SLA_SECS: Option<&str> = option_env!("EMBEDDED_SWITCH_SLA_SECS");
+const CANDIDATE_ORDER: Option<&str> = option_env!("EMBEDDED_CANDIDATE_ORDER");
+const MAX_CANDIDATE_CHECKS: Option<&str> = option_env!("EMBEDDED_MAX_CANDIDATE_CHECKS");
+const USAGE_CACHE_TTL_MINS: Option<&str> = option_env!("EMBEDDED_USAGE_CACHE_TTL_MINS");
+const FAST_PATH_MARGIN: Option<&str> = option_env!("EMBEDDED_FAST_PATH_MARGIN");
+const EXPIRY_REMINDER_DAYS: Option<&str> = option_env!("EMBEDDED_EXPIRY_REMINDER_DAYS");
+const ROUTER_PROXY: Option<&str> = option_env!("EMBEDDED_ROUTER_PROXY");
+const ROUTER_SSH_JUMP_HOST: Option<&str> = option_env!("EMBEDDED_ROUTER_SSH_JUMP_HOST");
+const ROUTER_SSH_KEY: Option<&str> = option_env!("EMBEDDED_ROUTER_SSH_KEY");
+const WIREGUARD_INTERFACE: Option<&str> = option_env!("EMBEDDED_WIREGUARD_INTERFACE");
+// ============================================================================
+
+/// All runtime settings, normally read from `config.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The file this was read from, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Version of the file's format; files without one are taken as the first
+    /// TOML version
+    #[serde(default = "default_config_version")]
+    pub version: u32,
+    /// Write every event as newline-delimited JSON to this file or FIFO
+    #[serde(default)]
+    pub event_log_path: Option<String>,
+    /// When a page element can't be found, use the most similar-looking one
+    #[serde(default)]
+    pub selector_recovery: bool,
+    /// Never start a browser: read the portal over HTTP and talk to the router
+    /// through its API, for devices too small for a headless browser
+    #[serde(default)]
+    pub lightweight: bool,
+    /// When the daemon starts with a new binary or config file, check the
+    /// router and every ID's portal login once, changing nothing
+    #[serde(default = "default_canary_on_change")]
+    pub canary_on_change: bool,
+    /// Keep the daemon's browser driver running between cycles, instead of
+    /// starting it for each cycle and stopping it once the cycle is done
+    #[serde(default)]
+    pub keep_browser_warm: bool,
+    /// How to reach and log in to the router
+    pub router: RouterConfig,
+    /// Where and how to read usage from the ISP's portal
+    #[serde(default)]
+    pub portal: PortalConfig,
+    /// Which browser reads the portal and drives the router's web interface
+    #[serde(default)]
+    pub browser: BrowserConfig,
+    /// The PPPoE IDs to rotate between
+    pub credentials: Vec<Credential>,
+    /// Usage limits that trigger switching and disabling
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// How usage is checked
+    #[serde(default)]
+    pub polling: PollingConfig,
+    /// When to raise alerts
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    /// How usage is forecast, and when to switch ahead of the threshold
+    #[serde(default)]
+    pub forecast: ForecastConfig,
+    /// How much of each ID's quota may be used by each day of the billing
+    /// cycle, see `crate::policy::ahead_of_schedule`
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+    /// How accounts are recharged once they run out or near expiry
+    #[serde(default)]
+    pub recharge: RechargeConfig,
+    /// How long usage history is kept, and in how much detail
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Where usage history and state are kept
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Where notifications are sent
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// How failed portal checks and router operations are retried
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Where diagnostics are logged, and how much of them
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Show the running ID's remaining quota in a guest WiFi network's name
+    #[serde(default)]
+    pub guest_ssid: Option<GuestSsidConfig>,
+    /// Take the routers' PPPoE log lines, forwarded over syslog
+    #[serde(default)]
+    pub syslog: Option<SyslogConfig>,
+    /// Take usage readings from satellite instances, on the daemon's metrics
+    /// address
+    #[serde(default)]
+    pub satellites: Option<SatellitesConfig>,
+    /// Where `submit` sends this instance's usage readings, as a satellite
+    #[serde(default)]
+    pub satellite: Option<SatelliteConfig>,
+}
+
+/// How to reach and log in to the router
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    /// Which kind of router this is, i.e. how to talk to it
+    #[serde(default)]
+    pub model: RouterModel,
+    /// The IP address of the router
+    pub ip: String,
+    /// The admin username, for routers that ask for one
+    #[serde(default = "default_router_username")]
+    pub username: String,
+    /// The admin password for the router
+    pub password: String,
+    /// Admin passwords to try if `password` is rejected, in priority order
+    #[serde(default)]
+    pub fallback_passwords: Vec<String>,
+    /// Seconds to wait between router login attempts
+    #[serde(default = "default_login_delay_secs")]
+    pub login_delay_secs: u64,
+    /// Proxy to reach the router through (e.g. `socks5://127.0.0.1:1080`)
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Open an SSH SOCKS tunnel through this host to reach the router
+    #[serde(default)]
+    pub ssh_jump_host: Option<String>,
+    /// Private key for the SSH tunnel
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    /// WireGuard interface to bring up when the router isn't reachable directly
+    #[serde(default)]
+    pub wireguard_interface: Option<String>,
+    /// Name of the PPPoE interface, for routers that have several (OpenWrt)
+    #[serde(default = "default_wan_interface")]
+    pub wan_interface: String,
+    /// Seconds to wait for the connection to come up after a switch before
+    /// rolling it back
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// URL that must answer after a switch for it to count as working
+    #[serde(default)]
+    pub connectivity_check_url: Option<String>,
+    /// Read the WAN connection's state and external IP address from the
+    /// router's UPnP Internet Gateway Device service, without logging in, if
+    /// it has one. See `crate::router::upnp`.
+    #[serde(default = "default_upnp")]
+    pub upnp: bool,
+    /// The UPnP device description URL (e.g. `http://192.168.0.1:1900/igd.xml`),
+    /// for when it can't be discovered
+    #[serde(default)]
+    pub upnp_url: Option<String>,
+    /// Other routers that get the same PPPoE credentials on every switch
+    #[serde(default)]
+    pub secondary: Vec<SecondaryRouter>,
+}
+
+/// Another router that is given the same PPPoE credentials as `[router]`,
+/// e.g. a backup access point that takes over the connection when the main
+/// router is down. It is reached the same way as `[router]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecondaryRouter {
+    /// Name used in logs and notifications; the IP address if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Which kind of router this is
+    #[serde(default)]
+    pub model: RouterModel,
+    /// The IP address of the router
+    pub ip: String,
+    /// The admin username, for routers that ask for one
+    #[serde(default = "default_router_username")]
+    pub username: String,
+    /// The admin password for the router
+    pub password: String,
+    /// Name of the PPPoE interface, for routers that have several (OpenWrt)
+    #[serde(default = "default_wan_interface")]
+    pub wan_interface: String,
+}
+
+impl SecondaryRouter {
+    /// How the router is referred to in logs and notifications
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.ip)
+    }
+}
+
+/// The router models that can be driven, see `crate::router`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum RouterModel {
+    /// The D-Link web interface, driven through ChromeDriver
+    #[default]
+    #[serde(rename = "dlink")]
+    DLink,
+    /// OpenWrt's ubus JSON-RPC API
+    #[serde(rename = "openwrt")]
+    OpenWrt,
+}
+
+/// Where and how to read usage from the ISP's portal
+///
+/// The defaults fit the portal this tool was first written for.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortalConfig {
+    /// How to talk to the portal
+    pub client: PortalClient,
+    /// The portal's login page
+    pub login_url: String,
+    /// `name` attribute of the username input
+    pub username_field: String,
+    /// `name` attribute of the password input
+    pub password_field: String,
+    /// CSS selector of the sign-in button. Enter is pressed if there is none.
+    pub submit_button: String,
+    /// Where to go from the page shown after logging in to get to the
+    /// account table, e.g. a dashboard's "Usage Details" link
+    pub after_login: Vec<NavigationStep>,
+    /// Text of the table cell labelling the usage figure
+    pub usage_label: String,
+    /// Unit the usage figure is shown in
+    pub usage_unit: UsageUnit,
+    /// Other figures making up the usage, by name, for portals that split it
+    /// across rows, tables or pages (e.g. the month's quota and a bonus)
+    pub usage_parts: HashMap<String, UsagePart>,
+    /// How the usage is worked out from `usage_parts`, e.g.
+    /// `"usage + bonus_used"`, where `usage` is the figure next to
+    /// `usage_label`. Just that figure if not set.
+    pub usage_total: Option<String>,
+    /// Label of the account status row, empty if the portal has none
+    pub status_label: String,
+    /// Label of the expiry date row, empty if the portal has none
+    pub expiry_label: String,
+    /// Label of the recharge amount row, empty if the portal has none
+    pub recharge_label: String,
+    /// Other post-login layouts the portal may show (e.g. per account type),
+    /// tried in order when the labels above don't match
+    pub variants: Vec<PortalVariant>,
+    /// What to do with a usage figure that isn't a number
+    pub parse_mode: ParseMode,
+    /// Usage in minutes (or MB for data) to assume for non-numeric usage
+    /// figures (matched ignoring case) in lenient mode, e.g. "Unlimited" = 0
+    pub sentinels: HashMap<String, i32>,
+    /// The per-day usage history page, for `import-history`
+    pub history: Option<HistoryPageConfig>,
+    /// The page listing recent sessions, collected on every browser check
+    pub sessions: Option<SessionPageConfig>,
+    /// The page changing an account's password, for `rotate-pppoe-password`
+    pub password_change: Option<PasswordChangeConfig>,
+}
+
+impl Default for PortalConfig {
+    fn default() -> Self {
+        Self {
+            client: PortalClient::default(),
+            login_url: "http://10.220.20.12/index.php/home/login".to_string(),
+            username_field: "username".to_string(),
+            password_field: "password".to_string(),
+            submit_button: "button[type='submit'], input[type='submit']".to_string(),
+            after_login: Vec::new(),
+            usage_label: "Total Use:".to_string(),
+            usage_unit: UsageUnit::default(),
+            usage_parts: HashMap::new(),
+            usage_total: None,
+            status_label: "Status".to_string(),
+            expiry_label: "Expir".to_string(),
+            recharge_label: "Recharge".to_string(),
+            variants: Vec::new(),
+            parse_mode: ParseMode::default(),
+            sentinels: HashMap::new(),
+            history: None,
+            sessions: None,
+            password_change: None,
+        }
+    }
+}
+
+impl PortalConfig {
+    /// The terms of `usage_total`: the name of each figure, and whether it is
+    /// subtracted rather than added
+    pub fn usage_terms(&self) -> Result<Vec<(bool, &str)>> {
+        let Some(expression) = &self.usage_total else {
+            return Ok(vec![(false, USAGE_TERM)]);
+        };
+        let invalid = || {
+            anyhow::anyhow!(
+                "portal.usage_total = \"{}\" isn't a sum of names, e.g. \"usage + bonus_used\"",
+                expression
+            )
+        };
+
+        let mut terms = Vec::new();
+        let mut subtract = false;
+        let mut rest = expression.trim_start();
+        loop {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            terms.push((subtract, &rest[..end]));
+
+            rest = rest[end..].trim_start();
+            subtract = match rest.chars().next() {
+                None => return Ok(terms),
+                Some('+') => false,
+                Some('-') => true,
+                Some(_) => return Err(invalid()),
+            };
+            rest = rest[1..].trim_start();
+        }
+    }
+}
+
+/// A figure making up an account's usage, besides the one next to
+/// `portal.usage_label`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsagePart {
+    /// Text of the table cell labelling the figure
+    pub label: String,
+    /// The page showing it, if not the account page; a relative URL is taken
+    /// from the account page
+    #[serde(default)]
+    pub page: Option<String>,
+    /// Unit the figure is shown in, if not the layout's usage unit
+    #[serde(default)]
+    pub unit: Option<UsageUnit>,
+}
+
+/// One step from the page shown after logging in towards the account table
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum NavigationStep {
+    /// Open a page; a relative URL is taken from the page shown
+    Goto(String),
+    /// Click the element matching a CSS selector. Over HTTP the element has
+    /// to be a link, whose `href` is followed.
+    Click(String),
+}
+
+/// An alternative post-login layout of the portal. Labels that aren't set are
+/// taken from the main `[portal]` section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortalVariant {
+    /// Name shown in diagnostics, e.g. "prepaid"
+    pub name: String,
+    /// Text of the table cell labelling the usage figure on this layout
+    pub usage_label: String,
+    /// Unit the usage figure is shown in on this layout
+    #[serde(default)]
+    pub usage_unit: Option<UsageUnit>,
+    /// Label of the account status row
+    #[serde(default)]
+    pub status_label: Option<String>,
+    /// Label of the expiry date row
+    #[serde(default)]
+    pub expiry_label: Option<String>,
+    /// Label of the recharge amount row
+    #[serde(default)]
+    pub recharge_label: Option<String>,
+}
+
+/// Where the portal lists usage per day (or per session), and how to page
+/// through it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryPageConfig {
+    /// The history page, opened after logging in
+    pub url: String,
+    /// CSS selector of the table rows; rows that don't parse (e.g. the
+    /// header) are skipped
+    #[serde(default = "default_history_rows")]
+    pub rows: String,
+    /// Index of the column holding the date, from 0
+    #[serde(default)]
+    pub date_column: usize,
+    /// Index of the column holding the usage, from 0
+    #[serde(default = "default_history_usage_column")]
+    pub usage_column: usize,
+    /// CSS selector of the link to the next page, if the table is paginated
+    #[serde(default)]
+    pub next_page: Option<String>,
+    /// Stop after this many pages
+    #[serde(default = "default_history_max_pages")]
+    pub max_pages: u32,
+}
+
+/// Where the portal lists recent sessions (connections), and which column
+/// holds what
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionPageConfig {
+    /// The sessions page, opened after logging in
+    pub url: String,
+    /// CSS selector of the table rows; rows that don't parse are skipped
+    #[serde(default = "default_history_rows")]
+    pub rows: String,
+    /// Index of the column holding the start time, from 0
+    #[serde(default)]
+    pub start_column: usize,
+    /// Index of the column holding the end time, if the portal shows it
+    #[serde(default)]
+    pub end_column: Option<usize>,
+    /// Index of the column holding the session's usage; without it the usage
+    /// is taken to be the session's duration
+    #[serde(default)]
+    pub usage_column: Option<usize>,
+    /// Index of the column holding the IP address, if the portal shows it
+    #[serde(default)]
+    pub ip_column: Option<usize>,
+}
+
+/// Where the portal changes an account's password, and the names of the
+/// form's fields
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordChangeConfig {
+    /// The change password page, opened after logging in
+    pub url: String,
+    /// `name` attribute of the current password input
+    #[serde(default = "default_current_password_field")]
+    pub current_field: String,
+    /// `name` attribute of the new password input
+    #[serde(default = "default_new_password_field")]
+    pub new_field: String,
+    /// `name` attribute of the input repeating the new password, if the form
+    /// has one
+    #[serde(default)]
+    pub confirm_field: Option<String>,
+    /// CSS selector of the form's submit button, if not `portal.submit_button`
+    #[serde(default)]
+    pub submit_button: Option<String>,
+    /// Text the portal shows once the password is changed. Without it, only
+    /// logging in with the new password tells whether it was.
+    #[serde(default)]
+    pub success_text: Option<String>,
+    /// Length of the passwords made up, within what the portal accepts
+    #[serde(default = "default_password_length")]
+    pub length: usize,
+}
+
+/// How a usage figure that isn't a number (e.g. "Unlimited", "-" or blank)
+/// is handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseMode {
+    /// Fail the check and notify
+    #[default]
+    Strict,
+    /// Use the configured sentinel for it, and only fail if there is none
+    Lenient,
+}
+
+/// Ways to read the ISP portal, see `crate::portal`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortalClient {
+    /// A headless Chrome session through ChromeDriver
+    #[default]
+    Browser,
+    /// Plain HTTP requests, falling back to the browser if they fail
+    Http,
+}
+
+/// Unit a portal shows usage in, when the figure has no unit of its own.
+/// Time is always handled in minutes and data in megabytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageUnit {
+    Seconds,
+    #[default]
+    Minutes,
+    Hours,
+    #[serde(rename = "mb")]
+    Megabytes,
+    #[serde(rename = "gb")]
+    Gigabytes,
+}
+
+impl UsageUnit {
+    /// What the unit measures
+    pub fn kind(self) -> UsageKind {
+        match self {
+            Self::Seconds | Self::Minutes | Self::Hours => UsageKind::Time,
+            Self::Megabytes | Self::Gigabytes => UsageKind::Data,
+        }
+    }
+
+    /// Convert an amount in this unit to minutes or megabytes
+    ///
+    /// # Arguments
+    /// * `amount` - The amount in this unit
+    pub fn to_base(self, amount: f64) -> f64 {
+        match self {
+            Self::Seconds => amount / 60.0,
+            Self::Minutes | Self::Megabytes => amount,
+            Self::Hours => amount * 60.0,
+            Self::Gigabytes => amount * 1024.0,
+        }
+    }
+}
+
+/// What a quota limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageKind {
+    /// Connection time, counted in minutes
+    Time,
+    /// Traffic, counted in megabytes
+    Data,
+}
+
+/// Unit a PPPoE ID's quota and thresholds are given in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaUnit {
+    #[default]
+    Minutes,
+    Mb,
+    Gb,
+}
+
+impl QuotaUnit {
+    /// What the quota limits
+    pub fn kind(self) -> UsageKind {
+        match self {
+            Self::Minutes => UsageKind::Time,
+            Self::Mb | Self::Gb => UsageKind::Data,
+        }
+    }
+
+    /// Convert an amount in this unit to minutes or megabytes
+    ///
+    /// # Arguments
+    /// * `amount` - The amount in this unit
+    fn to_base(self, amount: i32) -> i32 {
+        match self {
+            Self::Minutes | Self::Mb => amount,
+            Self::Gb => amount.saturating_mul(1024),
+        }
+    }
+
+    /// Format usage for messages
+    ///
+    /// # Arguments
+    /// * `amount` - Usage in minutes or megabytes
+    pub fn format(self, amount: i32) -> String {
+        match self {
+            Self::Minutes => format!("{} minutes", amount),
+            Self::Mb => format!("{} MB", amount),
+            Self::Gb => format!("{:.1} GB", f64::from(amount) / 1024.0),
+        }
+    }
+}
+
+/// Where a PPPoE ID's password is kept, see `Config::password_store`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordStore {
+    /// In this config file, as it is
+    ConfigFile(PathBuf),
+    /// In the secrets file, under this name
+    Secret(String),
+}
+
+impl PasswordStore {
+    /// Store a new password for a PPPoE ID in place of the old one
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    /// * `password` - Its new password
+    pub fn save(&self, pppoe_id: &str, password: &str) -> Result<()> {
+        match self {
+            Self::Secret(name) => secrets::replace(name, password),
+            Self::ConfigFile(path) => {
+                let content = fs::read_to_string(path)
+                    .context(format!("Failed to read config file {}", path.display()))?;
+                let content = with_password(&content, pppoe_id, password)
+                    .context(format!("Can't store a new password in {}", path.display()))?;
+
+                // Written aside first, so a failure never leaves half a file,
+                // and only as readable as the config file was
+                migrate::write_like(path, &content, path)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PasswordStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConfigFile(path) => write!(f, "{}", path.display()),
+            Self::Secret(name) => write!(f, "secret '{}'", name),
+        }
+    }
+}
+
+/// A PPPoE ID and its password
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    /// The PPPoE ID, which is also the ISP portal username
+    pub id: String,
+    /// The PPPoE password, which is also the ISP portal password
+    pub password: String,
+    /// Unit of this ID's quota and thresholds
+    #[serde(default)]
+    pub unit: QuotaUnit,
+    /// Limits for this ID, in `unit`, instead of the global `[thresholds]`
+    #[serde(default)]
+    pub thresholds: Option<Thresholds>,
+    /// Exempt from thresholds (postpaid/unlimited plan); used as the last
+    /// resort before disabling the connection
+    #[serde(default)]
+    pub unlimited: bool,
+    /// Rank for `polling.candidate_order = "priority"`, lowest first; IDs
+    /// without one come after those with one
+    #[serde(default)]
+    pub priority: Option<u32>,
+}
+
+/// Usage limits, in minutes unless given for a PPPoE ID with another unit
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// Start looking for another ID when the current one is above this
+    pub switch: i32,
+    /// Consider IDs at or below this as available to switch to
+    pub available: i32,
+    /// Disable the connection when every ID is exhausted and the current one is above this
+    pub disable: i32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            switch: 10000,
+            available: 10000,
+            disable: 11000,
+        }
+    }
+}
+
+/// Where notifications are sent. Every channel gets every kind of
+/// notification unless it lists the `events` it wants.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Desktop notifications, on by default
+    pub desktop: DesktopNotifications,
+    /// Messages from a Telegram bot
+    pub telegram: Option<TelegramConfig>,
+    /// Email through an SMTP server
+    pub email: Option<EmailConfig>,
+    /// HTTP POSTs, e.g. to a Discord or Slack incoming webhook
+    pub webhooks: Vec<WebhookConfig>,
+    /// Phone calls when the connection has stayed disabled for too long
+    pub voice: Option<VoiceConfig>,
+    /// People who get notifications on channels of their own, each with
+    /// their own preferences
+    pub users: Vec<UserConfig>,
+}
+
+/// Someone who is notified, through the Telegram bot, the SMTP server and
+/// webhooks set up in `[notifications]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Who this is, for log messages
+    pub name: String,
+    /// Telegram chat to message, through `[notifications.telegram]`'s bot
+    #[serde(default)]
+    pub telegram_chat_id: Option<String>,
+    /// Addresses to email, through `[notifications.email]`'s server
+    #[serde(default)]
+    pub email: Vec<String>,
+    /// Endpoints of their own to POST to
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Language of the notification titles
+    #[serde(default)]
+    pub language: Language,
+    /// Leave out notifications less severe than this
+    #[serde(default)]
+    pub min_severity: Severity,
+    /// Only send these kinds, instead of all of them
+    #[serde(default)]
+    pub events: Option<Vec<NotificationKind>>,
+    /// Daily time window (e.g. `"23:00-07:00"`) in which only critical
+    /// notifications are sent
+    #[serde(default)]
+    pub quiet_hours: Option<TimeWindow>,
+}
+
+/// Languages notifications can be sent in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    /// English
+    #[default]
+    En,
+    /// Bengali: the title is headed by what kind of notification it is.
+    /// The details stay in English.
+    Bn,
+}
+
+/// How urgent a notification is, for leaving out the less urgent ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Status reports and successful switches
+    #[default]
+    Info,
+    /// Failed switches and anything else worth a look
+    Warning,
+    /// The connection is down or about to be, or runs are failing
+    Critical,
+}
+
+/// Kinds of notification, for choosing which channels get which
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The running ID is within its limit
+    Status,
+    /// Switched to another ID
+    SwitchSucceeded,
+    /// A switch was attempted and failed
+    SwitchFailed,
+    /// Every ID is over the limit, but the connection was left up
+    AllExhausted,
+    /// The connection was disabled or re-enabled, or disabling it failed
+    Disabled,
+    /// A run failed, or runs started succeeding again
+    Error,
+    /// Anything else worth knowing: expiring accounts, slow switches, router changes
+    Warning,
+}
+
+impl NotificationKind {
+    /// How urgent notifications of this kind are
+    pub fn severity(self) -> Severity {
+        match self {
+            Self::Status | Self::SwitchSucceeded => Severity::Info,
+            Self::SwitchFailed | Self::Warning => Severity::Warning,
+            Self::AllExhausted | Self::Disabled | Self::Error => Severity::Critical,
+        }
+    }
+}
+
+/// Desktop notification settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DesktopNotifications {
+    /// Show desktop notifications at all
+    pub enabled: bool,
+    /// Only show these kinds, instead of all of them
+    pub events: Option<Vec<NotificationKind>>,
+}
+
+impl Default for DesktopNotifications {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            events: None,
+        }
+    }
+}
+
+/// A Telegram bot to send notifications through
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelegramConfig {
+    /// The token @BotFather gave for the bot
+    pub bot_token: String,
+    /// The chat (user, group or channel) to message with every notification.
+    /// May be left out if only `[[notifications.users]]` are messaged.
+    #[serde(default)]
+    pub chat_id: Option<String>,
+    /// Only send these kinds, instead of all of them
+    #[serde(default)]
+    pub events: Option<Vec<NotificationKind>>,
+}
+
+/// An SMTP server to email notifications through
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    /// The SMTP server's host name
+    pub smtp_host: String,
+    /// The SMTP port; defaults to the usual one for `security`
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    /// How the connection to the server is secured
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// Login for the SMTP server, if it needs one
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password for the SMTP server
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Sender address, e.g. `Auto WiFi <wifi@example.com>`
+    pub from: String,
+    /// Addresses to send every notification to. May be left out if only
+    /// `[[notifications.users]]` are emailed.
+    #[serde(default)]
+    pub to: Vec<String>,
+    /// Only send these kinds, instead of all of them
+    #[serde(default)]
+    pub events: Option<Vec<NotificationKind>>,
+}
+
+/// How an SMTP connection is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgrade a plain connection with STARTTLS (usually port 587)
+    #[default]
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+    /// No encryption, e.g. for a relay on the local network (usually port 25)
+    None,
+}
+
+/// Twilio Voice, for phoning someone when the connection was disabled and
+/// nobody re-enabled it. Unlike the other channels it gets no notification
+/// kinds; it is only used for that one situation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VoiceConfig {
+    /// The Twilio account SID, e.g. `ACxxxxxxxx`
+    pub account_sid: String,
+    /// The Twilio auth token
+    pub auth_token: String,
+    /// The Twilio number to call from, in E.164 format
+    pub from: String,
+    /// The numbers to call, in E.164 format
+    pub to: Vec<String>,
+    /// How long the connection must have stayed disabled before calling
+    #[serde(default = "default_call_after_hours")]
+    pub after_hours: u64,
+}
+
+/// An HTTP endpoint notifications are POSTed to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Where to POST
+    pub url: String,
+    /// Shape of the JSON body
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Only send these kinds, instead of all of them
+    #[serde(default)]
+    pub events: Option<Vec<NotificationKind>>,
+}
+
+/// JSON body a webhook expects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// `{"kind": ..., "title": ..., "message": ...}`
+    #[default]
+    Json,
+    /// A Discord incoming webhook: `{"content": ...}`
+    Discord,
+    /// A Slack incoming webhook: `{"text": ...}`
+    Slack,
+}
+
+/// Order in which other IDs are checked when looking for one to switch to,
+/// see `crate::policy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateOrder {
+    /// The IDs listed after the current one, wrapping around
+    #[default]
+    Next,
+    /// By each ID's `priority`, lowest first, then in the order they are listed
+    Priority,
+    /// Least recently used first, so usage is spread across IDs
+    Lru,
+    /// Lowest last known usage first
+    LeastUsed,
+    /// The IDs after the one last switched to, wrapping around, even when
+    /// the router was switched by hand in between
+    RoundRobin,
+    /// Lowest last known usage relative to its switch threshold first, so
+    /// every ID is used up at about the same pace
+    Balanced,
+}
+
+/// How usage is checked
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    /// Order in which other IDs are checked when the current one is over quota
+    pub candidate_order: CandidateOrder,
+    /// Check at most this many other IDs per run
+    pub max_candidate_checks: Option<usize>,
+    /// Skip the portal when the running ID's last reading is younger than this
+    pub usage_cache_ttl_mins: Option<u64>,
+    /// ...and at least this many minutes (or MB) below the switch threshold
+    pub fast_path_margin: i32,
+    /// Check up to this many other IDs at once, each in its own browser
+    /// session; 1 checks them one at a time
+    pub concurrent_checks: usize,
+    /// Have the daemon check right away when this machine's network changes,
+    /// e.g. an interface goes up or down
+    pub check_on_network_change: bool,
+    /// A reading lower than the last one within a billing cycle is taken for
+    /// a portal glitch until the portal has shown it this many times in a
+    /// row; 1 believes it at once
+    pub drop_confirmations: u32,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            candidate_order: CandidateOrder::default(),
+            max_candidate_checks: None,
+            usage_cache_ttl_mins: None,
+            fast_path_margin: 1000,
+            concurrent_checks: 1,
+            check_on_network_change: true,
+            drop_confirmations: 2,
+        }
+    }
+}
+
+/// When to raise alerts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Alert when a switch takes longer than this many seconds
+    pub switch_sla_secs: u64,
+    /// Remind this many days before an account expires
+    pub expiry_reminder_days: i64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            switch_sla_secs: 180,
+            expiry_reminder_days: 3,
+        }
+    }
+}
+
+/// How accounts are recharged, see `crate::recharge`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RechargeConfig {
+    /// The ISP's recharge page, added to notifications about an account
+    /// running out or expiring. `{id}` is replaced by the PPPoE ID.
+    pub url: Option<String>,
+    /// Program run when an account runs out or nears expiry, at most once a
+    /// day per account, with the details in `AUTO_WIFI_*` environment variables
+    pub command: Option<String>,
+    /// Kill the program if it hasn't finished after this many seconds
+    pub timeout_secs: u64,
+}
+
+impl Default for RechargeConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            command: None,
+            timeout_secs: 300,
+        }
+    }
+}
+
+/// How long the usage history keeps its readings, see `History::compact`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Keep every reading for this many days
+    pub raw_days: u32,
+    /// After that, keep the last reading of each hour until readings are this
+    /// many days old, and the last of each day from then on
+    pub hourly_days: u32,
+    /// Delete readings, switches, sessions, connection events and external
+    /// IPs older than this many days. `None` keeps them forever.
+    pub retention_days: Option<u32>,
+    /// Keep the JSON report of each run (see `crate::report`) for this many
+    /// days. 0 writes none.
+    pub report_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            raw_days: 7,
+            hourly_days: 90,
+            retention_days: None,
+            report_days: 30,
+        }
+    }
+}
+
+/// Where usage history and state are kept, see `crate::storage`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// `sqlite` to keep them in the data directory, or `postgres` for a
+    /// PostgreSQL database that several instances can share (needs a build
+    /// with the `postgres` feature)
+    pub backend: StorageBackend,
+    /// For `postgres`, the database to connect to, e.g.
+    /// `host=db.example.com user=auto_wifi dbname=auto_wifi`
+    pub url: Option<String>,
+    /// For `postgres`, the database user's password, if not in `url`; may
+    /// be `secret:<name>`
+    pub password: Option<String>,
+    /// For `postgres`, the name of this instance, which keeps its history
+    /// and state apart from other instances' in the shared database
+    pub site: Option<String>,
+}
+
+/// What usage history and state are kept in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// `history.db` and `state.json` in the data directory
+    #[default]
+    Sqlite,
+    /// A PostgreSQL database
+    Postgres,
+}
+
+/// How usage is forecast from the recorded readings, see `crate::forecast`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForecastConfig {
+    /// Estimate the daily rate from this many days of readings
+    pub lookback_days: u32,
+    /// Switch away from the running ID during this daily window, e.g.
+    /// `04:00-05:00`, if it is forecast to reach its switch threshold before
+    /// the window comes round again
+    pub early_switch_window: Option<TimeWindow>,
+    /// Learn which days of the week, and of the year, are busier than others
+    /// from this many days of readings, and forecast with that rather than
+    /// the same rate every day. 0 turns it off.
+    pub seasonality_days: u32,
+}
+
+impl Default for ForecastConfig {
+    fn default() -> Self {
+        Self {
+            lookback_days: 7,
+            early_switch_window: None,
+            seasonality_days: 365,
+        }
+    }
+}
+
+/// A curve of how much of each ID's quota may be used by each day of the
+/// billing cycle, e.g. no more than 40% by day 15
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Day of the month the quotas reset, 1-28
+    #[serde(default = "default_cycle_start_day")]
+    pub cycle_start_day: u32,
+    /// The share of its switch threshold an ID may have used by the end of
+    /// each of these days of the cycle, in order. In between, the allowance
+    /// grows evenly, from nothing at the start of the cycle to all of it at
+    /// its end.
+    pub points: Vec<SchedulePoint>,
+    /// What to do about the running ID being ahead of schedule
+    #[serde(default)]
+    pub action: ScheduleAction,
+}
+
+/// How much of its quota an ID may have used by a day of the billing cycle
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulePoint {
+    /// The day of the cycle, 1 being the day the quotas reset
+    pub day: u32,
+    /// Percent of the switch threshold
+    pub percent: u32,
+}
+
+/// What to do about an ID being used faster than `[schedule]` allows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    /// Warn, once a day
+    #[default]
+    Warn,
+    /// Warn, and switch to an ID that is on schedule if there is one, so
+    /// the IDs are used up evenly over the cycle
+    Throttle,
+}
+
+/// A daily span of local time such as `04:00-05:00`, which may run past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        let window = value
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse(start)?, parse(end)?)));
+
+        match window {
+            Some((start, end)) if start != end => Ok(Self { start, end }),
+            _ => Err(format!(
+                "invalid time window '{}', expected e.g. \"04:00-05:00\"",
+                value
+            )),
+        }
+    }
+}
+
+impl TimeWindow {
+    /// Whether a time of day falls in the window
+    ///
+    /// # Arguments
+    /// * `time` - The local time of day
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// When the window next opens, after `now`
+    ///
+    /// # Arguments
+    /// * `now` - The current local time
+    pub fn next_start(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let mut date = now.date_naive();
+        if now.time() >= self.start {
+            date = date.succ_opt().unwrap_or(date);
+        }
+
+        // A start skipped by a DST change opens the window an hour later
+        let start = date.and_time(self.start);
+        start
+            .and_local_timezone(Local)
+            .earliest()
+            .or_else(|| {
+                (start + chrono::Duration::hours(1))
+                    .and_local_timezone(Local)
+                    .earliest()
+            })
+            .unwrap_or(now + chrono::Duration::days(1))
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// How failed portal checks and router operations are retried
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per operation, including the first; 1 disables retrying
+    pub max_attempts: u32,
+    /// Seconds to wait before the first retry, doubled for each one after
+    pub initial_delay_secs: u64,
+    /// Never wait longer than this many seconds between attempts
+    pub max_delay_secs: u64,
+    /// Vary each wait randomly by up to this fraction of it (0.0 to 1.0)
+    pub jitter: f64,
+    /// In daemon mode, restart the browser driver and its browsers after this
+    /// many cycles in a row failed with WebDriver errors; 0 never does
+    pub restart_browser_after: u32,
+    /// Put off disabling the connection for up to this many runs in a row
+    /// while some other IDs couldn't be checked, as one of them may still
+    /// have quota; 0 disables on what the run could read
+    pub defer_disable_runs: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay_secs: 5,
+            max_delay_secs: 60,
+            jitter: 0.2,
+            restart_browser_after: 3,
+            defer_disable_runs: 0,
+        }
+    }
+}
+
+/// Diagnostic logging. Everything goes to the console, and unless turned off
+/// also to log files that are rotated and pruned as they age.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Least severe level logged: error, warn, info, debug or trace, or a
+    /// filter such as `info,auto_wifi::router=debug`
+    pub level: String,
+    /// Also write the log to files
+    pub file: bool,
+    /// Where log files go; defaults to `logs` in the data directory
+    pub dir: Option<PathBuf>,
+    /// How often a new log file is started
+    pub rotation: LogRotation,
+    /// Log files kept; older ones are deleted
+    pub keep: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            file: true,
+            dir: None,
+            rotation: LogRotation::Daily,
+            keep: 14,
+        }
+    }
+}
+
+/// How often a new log file is started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// A file per hour
+    Hourly,
+    /// A file per day
+    #[default]
+    Daily,
+    /// Keep writing to a single file
+    Never,
+}
+
+/// A guest WiFi network whose name shows what is left of the running ID, for
+/// everyone in the house to see without an app. See `crate::ssid`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestSsidConfig {
+    /// The network name, with `{left}` replaced by what is left before the
+    /// switch threshold (e.g. `2300min`) and `{id}` by the running ID
+    pub template: String,
+    /// The router's wireless interface to rename; on OpenWrt the `wifi-iface`
+    /// section in `/etc/config/wireless`
+    #[serde(default = "default_guest_wifi_interface")]
+    pub interface: String,
+    /// Only rename the network once what is left has changed by this many
+    /// minutes (or MB)
+    #[serde(default = "default_guest_ssid_min_change")]
+    pub min_change: i32,
+}
+
+/// A UDP syslog listener for the log lines routers forward, which tell right
+/// away when the PPPoE connection comes up, goes down or fails to log in.
+/// See `crate::syslog`.
+///
+/// Lines are matched ignoring case and runs of spaces; the defaults are what
+/// pppd (e.g. on OpenWrt) logs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// Where to listen, e.g. `0.0.0.0:5514`
+    pub listen: SocketAddr,
+    /// Only take lines from these addresses; `[router]` and the secondary
+    /// routers if empty
+    #[serde(default)]
+    pub senders: Vec<IpAddr>,
+    /// Text of a line saying the connection came up
+    #[serde(default = "default_syslog_up")]
+    pub up: Vec<String>,
+    /// Text of a line saying the connection went down
+    #[serde(default = "default_syslog_down")]
+    pub down: Vec<String>,
+    /// Text of a line saying the PPPoE login was rejected
+    #[serde(default = "default_syslog_login_failed")]
+    pub login_failed: Vec<String>,
+}
+
+/// Usage readings taken from other instances ("satellites") that can reach
+/// the portal when this one can't. See `crate::satellite`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SatellitesConfig {
+    /// What satellites send as a bearer token
+    pub token: String,
+    /// Readings taken longer ago than this are refused, in minutes
+    #[serde(default = "default_satellite_max_age_mins")]
+    pub max_age_mins: u64,
+}
+
+/// The main daemon a satellite sends its usage readings to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SatelliteConfig {
+    /// The daemon's metrics address, e.g. `http://192.168.1.10:9184`
+    pub url: String,
+    /// The daemon's `satellites.token`
+    pub token: String,
+    /// What the daemon calls this satellite in its log; the host name if not
+    /// set
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The browser used where pages have to be rendered
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrowserConfig {
+    /// Which browser to use
+    pub name: BrowserName,
+    /// A WebDriver server to use instead of starting the browser's driver,
+    /// e.g. a Selenium Grid on another machine. `WEBDRIVER_URL` wins over this.
+    pub webdriver_url: Option<String>,
+    /// Run the browser without a window
+    pub headless: bool,
+    /// Block images, fonts, media and trackers in Chrome sessions, so checks
+    /// over the metered link use as little of it as possible
+    pub block_assets: bool,
+    /// More URL patterns to block as well, e.g. `*.example.com/ads/*`
+    pub blocked_urls: Vec<String>,
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        Self {
+            name: BrowserName::default(),
+            webdriver_url: None,
+            headless: true,
+            block_assets: true,
+            blocked_urls: Vec::new(),
+        }
+    }
+}
+
+/// Browsers that can be driven
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserName {
+    /// Chrome or Chromium through ChromeDriver, downloaded to match if needed
+    #[default]
+    Chrome,
+    /// Firefox through geckodriver, which must be on the PATH
+    Firefox,
+}
+
+impl BrowserName {
+    /// Name of the browser's WebDriver server, for log messages
+    pub fn driver_name(self) -> &'static str {
+        match self {
+            Self::Chrome => "ChromeDriver",
+            Self::Firefox => "geckodriver",
+        }
+    }
+}
+
+fn default_history_rows() -> String {
+    "table tr".to_string()
+}
+
+fn default_history_usage_column() -> usize {
+    1
+}
+
+fn default_history_max_pages() -> u32 {
+    20
+}
+
+fn default_current_password_field() -> String {
+    "old_password".to_string()
+}
+
+fn default_new_password_field() -> String {
+    "new_password".to_string()
+}
+
+fn default_password_length() -> usize {
+    16
+}
+
+fn default_call_after_hours() -> u64 {
+    12
+}
+
+fn default_login_delay_secs() -> u64 {
+    10
+}
+
+fn default_router_username() -> String {
+    "root".to_string()
+}
+
+fn default_wan_interface() -> String {
+    "wan".to_string()
+}
+
+fn default_cycle_start_day() -> u32 {
+    1
+}
+
+fn default_upnp() -> bool {
+    true
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    90
+}
+
+fn default_guest_wifi_interface() -> String {
+    "guest".to_string()
+}
+
+fn default_guest_ssid_min_change() -> i32 {
+    100
+}
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_canary_on_change() -> bool {
+    true
+}
+
+fn default_satellite_max_age_mins() -> u64 {
+    60
+}
+
+fn default_syslog_up() -> Vec<String> {
+    vec!["local IP address".to_string()]
+}
+
+fn default_syslog_down() -> Vec<String> {
+    vec![
+        "Connection terminated".to_string(),
+        "Modem hangup".to_string(),
+        "LCP terminated by peer".to_string(),
+    ]
+}
+
+fn default_syslog_login_failed() -> Vec<String> {
+    vec!["authentication failed".to_string()]
+}
+
+/// Get the default location of the config file
+///
+/// # Returns
+/// * e.g. `~/.config/auto_pppoe_quota_manager/config.toml` on Linux or
+///   `%APPDATA%\auto_pppoe_quota_manager\config.toml` on Windows
+pub fn default_config_path() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("Could not determine the config directory")?
+        .join(APP_DIR_NAME)
+        .join(CONFIG_FILE_NAME))
+}
+
+impl Config {
+    /// Load the configuration
+    ///
+    /// The config file is read from `path` if given, otherwise from the default
+    /// location. If there is no config file at the default location, the
+    /// settings of .env are read from the environment (`ROUTER_IP` and so on),
+    /// and failing that the values embedded from .env at build time are used,
+    /// if there are any. Passwords given as `secret:<name>` are then read from
+    /// the secrets file.
+    ///
+    /// # Arguments
+    /// * `path` - Config file given on the command line, if any
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = Self::load_settings(path)?;
+        secrets::resolve(&mut config)?;
+
+        Ok(config)
+    }
+
+    /// Load the configuration from wherever it is found, as given
+    ///
+    /// # Arguments
+    /// * `path` - Config file given on the command line, if any
+    fn load_settings(path: Option<&Path>) -> Result<Self> {
+        if let Some(path) = path {
+            return Self::from_file(path);
+        }
+
+        let default_path = default_config_path()?;
+        if default_path.exists() {
+            return Self::from_file(&default_path);
+        }
+
+        if let Some(config) = Self::from_settings(environment_setting)? {
+            info!(
+                "No config file at {}, using settings from the environment",
+                default_path.display()
+            );
+            return Ok(config);
+        }
+
+        match Self::from_settings(embedded_setting)? {
+            Some(config) => {
+                info!(
+                    "No config file at {}, using values embedded at build time",
+                    default_path.display()
+                );
+                Ok(config)
+            }
+            None => anyhow::bail!(
+                "No configuration found. Create {} (see config.example.toml), pass --config <path> or set ROUTER_IP, ROUTER_PASSWORD and PPPOE_CREDENTIALS",
+                default_path.display()
+            ),
+        }
+    }
+
+    /// Read and validate a TOML config file
+    ///
+    /// # Arguments
+    /// * `path` - The config file
+    fn from_file(path: &Path) -> Result<Self> {
+        let config = Self::read_file(path)?;
+        info!("Loaded configuration from {}", path.display());
+
+        Ok(config)
+    }
+
+    /// Read and validate a TOML config file, without logging. A file in an
+    /// older format is upgraded first.
+    ///
+    /// # Arguments
+    /// * `path` - The config file
+    fn read_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read config file {}", path.display()))?;
+        let content = migrate::config_file(path, content)
+            .context(format!("Failed to upgrade config file {}", path.display()))?;
+
+        let mut config =
+            Self::from_toml(&content).context(format!("Invalid config file {}", path.display()))?;
+        config.path = Some(path.to_path_buf());
+
+        Ok(config)
+    }
+
+    /// Where the password of a PPPoE ID is kept, so a new one can be stored in
+    /// its place
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    ///
+    /// # Returns
+    /// * Fails if the password can't be written back, e.g. because it came
+    ///   from the environment or isn't on a line of its own
+    pub fn password_store(&self, pppoe_id: &str) -> Result<PasswordStore> {
+        let path = self.path.as_ref().context(
+            "The configuration wasn't read from a config file, so a new password can't be stored. Write one with `auto-wifi setup` first.",
+        )?;
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read config file {}", path.display()))?;
+        let table: Table =
+            toml::from_str(&content).context(format!("Invalid config file {}", path.display()))?;
+
+        let stored = credential_table(&table, pppoe_id)
+            .and_then(|credential| credential.get("password"))
+            .and_then(Value::as_str)
+            .context(format!(
+                "'{}' has no password in {}",
+                pppoe_id,
+                path.display()
+            ))?;
+        if let Some(name) = secrets::reference(stored) {
+            return Ok(PasswordStore::Secret(name.to_string()));
+        }
+
+        // Make sure the line can be found now, not after the portal changed
+        with_password(&content, pppoe_id, "placeholder")
+            .context(format!("Can't store a new password in {}", path.display()))?;
+
+        Ok(PasswordStore::ConfigFile(path.clone()))
+    }
+
+    /// Parse and validate the contents of a config file
+    ///
+    /// # Arguments
+    /// * `content` - The TOML text
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check that the file this config was read from still reads and
+    /// validates, e.g. after an edit that the next start would trip over.
+    /// A config that wasn't read from a file always passes.
+    pub fn check_file(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => Self::read_file(path).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    /// Build the configuration from settings named as in .env
+    ///
+    /// # Arguments
+    /// * `setting` - Looks up a setting by its .env name
+    ///
+    /// # Returns
+    /// * `None` if `ROUTER_IP`, `ROUTER_PASSWORD` or `PPPOE_CREDENTIALS` isn't set
+    fn from_settings(setting: impl Fn(&str) -> Option<String>) -> Result<Option<Self>> {
+        let Some(table) = Self::settings_table(setting)? else {
+            return Ok(None);
+        };
+
+        let config: Self = table.try_into()?;
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Settings named as in .env, laid out as in the config file. Values that
+    /// don't parse are left out, so their defaults apply.
+    ///
+    /// # Arguments
+    /// * `setting` - Looks up a setting by its .env name
+    ///
+    /// # Returns
+    /// * `None` if `ROUTER_IP`, `ROUTER_PASSWORD` or `PPPOE_CREDENTIALS` isn't set
+    pub fn settings_table(setting: impl Fn(&str) -> Option<String>) -> Result<Option<Table>> {
+        let (Some(ip), Some(password), Some(pppoe_credentials)) = (
+            setting("ROUTER_IP"),
+            setting("ROUTER_PASSWORD"),
+            setting("PPPOE_CREDENTIALS"),
+        ) else {
+            return Ok(None);
+        };
+
+        // Parse PPPoE credentials (format: "id1:pass1,id2:pass2,..."). The
+        // password may itself hold a colon, as in "id1:secret:name".
+        let mut credentials = Vec::new();
+        for pair in pppoe_credentials.split(',') {
+            let Some((id, password)) = pair.trim().split_once(':') else {
+                anyhow::bail!(
+                    "Invalid PPPOE_CREDENTIALS format. Expected 'id1:pass1,id2:pass2,...'"
+                );
+            };
+            let mut credential = Table::new();
+            credential.insert("id".to_string(), id.into());
+            credential.insert("password".to_string(), password.into());
+            credentials.push(credential);
+        }
+
+        let mut router = Table::new();
+        router.insert("ip".to_string(), ip.into());
+        router.insert("password".to_string(), password.into());
+        if let Some(fallbacks) = setting("ROUTER_FALLBACK_PASSWORDS") {
+            let fallbacks: Vec<&str> = fallbacks
+                .split(',')
+                .map(str::trim)
+                .filter(|password| !password.is_empty())
+                .collect();
+            router.insert("fallback_passwords".to_string(), fallbacks.into());
+        }
+        insert_setting(
+            &mut router,
+            "login_delay_secs",
+            number_setting::<u64>(setting("ROUTER_LOGIN_DELAY_SECS")),
+        );
+        for (key, name) in [
+            ("proxy", "ROUTER_PROXY"),
+            ("ssh_jump_host", "ROUTER_SSH_JUMP_HOST"),
+            ("ssh_key", "ROUTER_SSH_KEY"),
+            ("wireguard_interface", "WIREGUARD_INTERFACE"),
+        ] {
+            insert_setting(&mut router, key, setting(name).map(Value::from));
+        }
+
+        let mut polling = Table::new();
+        let candidate_order = setting("CANDIDATE_ORDER").filter(|order| {
+            [
+                "next",
+                "priority",
+                "lru",
+                "least_used",
+                "round_robin",
+                "balanced",
+            ]
+            .contains(&order.as_str())
+        });
+        insert_setting(
+            &mut polling,
+            "candidate_order",
+            candidate_order.map(Value::from),
+        );
+        insert_setting(
+            &mut polling,
+            "max_candidate_checks",
+            number_setting::<usize>(setting("MAX_CANDIDATE_CHECKS")),
+        );
+        insert_setting(
+            &mut polling,
+            "usage_cache_ttl_mins",
+            number_setting::<u64>(setting("USAGE_CACHE_TTL_MINS")),
+        );
+        insert_setting(
+            &mut polling,
+            "fast_path_margin",
+            number_setting::<i32>(setting("FAST_PATH_MARGIN")),
+        );
+
+        let mut alerts = Table::new();
+        insert_setting(
+            &mut alerts,
+            "switch_sla_secs",
+            number_setting::<u64>(setting("SWITCH_SLA_SECS")),
+        );
+        insert_setting(
+            &mut alerts,
+            "expiry_reminder_days",
+            number_setting::<i64>(setting("EXPIRY_REMINDER_DAYS")),
+        );
+
+        let mut table = Table::new();
+        insert_setting(
+            &mut table,
+            "event_log_path",
+            setting("EVENT_LOG_PATH").map(Value::from),
+        );
+        if matches!(
+            setting("SELECTOR_RECOVERY").as_deref(),
+            Some("true") | Some("1")
+        ) {
+            table.insert("selector_recovery".to_string(), true.into());
+        }
+        table.insert("router".to_string(), router.into());
+        table.insert("credentials".to_string(), credentials.into());
+        for (key, section) in [("polling", polling), ("alerts", alerts)] {
+            if !section.is_empty() {
+                table.insert(key.to_string(), section.into());
+            }
+        }
+
+        Ok(Some(table))
+    }
+
+    /// Check for settings that would make every run fail
+    fn validate(&self) -> Result<()> {
+        if self.credentials.is_empty() {
+            anyhow::bail!("No PPPoE credentials configured");
+        }
+        if self.thresholds.available > self.thresholds.switch {
+            anyhow::bail!(
+                "thresholds.available ({}) must not be above thresholds.switch ({})",
+                self.thresholds.available,
+                self.thresholds.switch
+            );
+        }
+        for credential in &self.credentials {
+            match &credential.thresholds {
+                Some(thresholds) if thresholds.available > thresholds.switch => anyhow::bail!(
+                    "'{}': thresholds.available ({}) must not be above thresholds.switch ({})",
+                    credential.id,
+                    thresholds.available,
+                    thresholds.switch
+                ),
+                None if credential.unit.kind() == UsageKind::Data && !credential.unlimited => {
+                    anyhow::bail!(
+                        "'{}' has a data quota, so it needs its own thresholds",
+                        credential.id
+                    )
+                }
+                _ => {}
+            }
+        }
+        if let Some(sessions) = &self.portal.sessions {
+            if sessions.end_column.is_none() && sessions.usage_column.is_none() {
+                anyhow::bail!("portal.sessions needs an end_column or a usage_column");
+            }
+        }
+        if let Some(change) = &self.portal.password_change {
+            if !(8..=64).contains(&change.length) {
+                anyhow::bail!("portal.password_change.length must be between 8 and 64");
+            }
+        }
+        for (_, name) in self.portal.usage_terms()? {
+            if name != USAGE_TERM && !self.portal.usage_parts.contains_key(name) {
+                anyhow::bail!(
+                    "portal.usage_total uses '{}', which isn't one of portal.usage_parts",
+                    name
+                );
+            }
+        }
+        if self.portal.usage_parts.contains_key(USAGE_TERM) {
+            anyhow::bail!(
+                "'{}' stands for the figure next to portal.usage_label; name the usage part otherwise",
+                USAGE_TERM
+            );
+        }
+        for variant in &self.portal.variants {
+            if variant.name.is_empty() || variant.usage_label.is_empty() {
+                anyhow::bail!("Every portal variant needs a name and a usage_label");
+            }
+        }
+        if self.retry.max_attempts == 0 {
+            anyhow::bail!("retry.max_attempts must be at least 1");
+        }
+        if self.lightweight {
+            self.validate_lightweight()?;
+        }
+        if self.polling.concurrent_checks == 0 {
+            anyhow::bail!("polling.concurrent_checks must be at least 1");
+        }
+        if self.forecast.lookback_days == 0 {
+            anyhow::bail!("forecast.lookback_days must be at least 1");
+        }
+        if self.history.hourly_days < self.history.raw_days {
+            anyhow::bail!(
+                "history.hourly_days ({}) must not be below history.raw_days ({})",
+                self.history.hourly_days,
+                self.history.raw_days
+            );
+        }
+        if self.history.retention_days == Some(0) {
+            anyhow::bail!("history.retention_days must be at least 1");
+        }
+        if self.storage.backend == StorageBackend::Postgres {
+            if self.storage.url.as_deref().is_none_or(str::is_empty) {
+                anyhow::bail!("storage.url must be set for the postgres backend");
+            }
+            if self.storage.site.as_deref().is_none_or(str::is_empty) {
+                anyhow::bail!("storage.site must be set for the postgres backend, to tell this instance's rows from others'");
+            }
+        }
+        if let Some(schedule) = &self.schedule {
+            if !(1..=28).contains(&schedule.cycle_start_day) {
+                anyhow::bail!("schedule.cycle_start_day must be between 1 and 28");
+            }
+            if schedule.points.is_empty() {
+                anyhow::bail!("schedule.points must not be empty");
+            }
+            let mut previous = SchedulePoint { day: 0, percent: 0 };
+            for point in &schedule.points {
+                if point.day <= previous.day
+                    || point.day > 31
+                    || point.percent < previous.percent
+                    || point.percent > 100
+                {
+                    anyhow::bail!(
+                        "schedule.points must go up by day (1-31) and not down by percent (0-100)"
+                    );
+                }
+                previous = *point;
+            }
+        }
+        if let Some(guest_ssid) = &self.guest_ssid {
+            if guest_ssid.template.is_empty() {
+                anyhow::bail!("guest_ssid.template must not be empty");
+            }
+            if self.router.model == RouterModel::DLink {
+                anyhow::bail!(
+                    "[guest_ssid] needs a router that can rename WiFi networks; use router.model = \"openwrt\""
+                );
+            }
+        }
+        if self
+            .satellites
+            .as_ref()
+            .is_some_and(|satellites| satellites.token.len() < 16)
+        {
+            anyhow::bail!("satellites.token must be at least 16 characters long");
+        }
+        if let Some(syslog) = &self.syslog {
+            for (name, patterns) in [
+                ("up", &syslog.up),
+                ("down", &syslog.down),
+                ("login_failed", &syslog.login_failed),
+            ] {
+                if patterns.iter().any(|pattern| pattern.trim().is_empty()) {
+                    anyhow::bail!("syslog.{} must not hold empty text", name);
+                }
+            }
+        }
+        for secondary in &self.router.secondary {
+            if secondary.ip.is_empty() {
+                anyhow::bail!("Every [[router.secondary]] needs an ip");
+            }
+        }
+        for user in &self.notifications.users {
+            if user.name.is_empty() {
+                anyhow::bail!("Every [[notifications.users]] needs a name");
+            }
+            if user.telegram_chat_id.is_some() && self.notifications.telegram.is_none() {
+                anyhow::bail!(
+                    "'{}' has a telegram_chat_id, but there is no [notifications.telegram] bot to message it with",
+                    user.name
+                );
+            }
+            if !user.email.is_empty() && self.notifications.email.is_none() {
+                anyhow::bail!(
+                    "'{}' has an email address, but there is no [notifications.email] server to send through",
+                    user.name
+                );
+            }
+        }
+        if self.logging.keep == 0 {
+            anyhow::bail!("logging.keep must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.retry.jitter) {
+            anyhow::bail!("retry.jitter must be between 0.0 and 1.0");
+        }
+        Ok(())
+    }
+
+    /// Check that nothing in a `lightweight` config needs the browser
+    fn validate_lightweight(&self) -> Result<()> {
+        if self.portal.client != PortalClient::Http {
+            anyhow::bail!("lightweight mode needs portal.client = \"http\"");
+        }
+        if self.router.model == RouterModel::DLink {
+            anyhow::bail!(
+                "lightweight mode can't drive the dlink router, which needs the browser; use router.model = \"openwrt\""
+            );
+        }
+        if let Some(secondary) = self
+            .router
+            .secondary
+            .iter()
+            .find(|secondary| secondary.model == RouterModel::DLink)
+        {
+            anyhow::bail!(
+                "lightweight mode can't drive the dlink router '{}', which needs the browser",
+                secondary.name()
+            );
+        }
+        if self.portal.sessions.is_some() {
+            anyhow::bail!("lightweight mode can't read [portal.sessions], which needs the browser");
+        }
+        if self.portal.history.is_some() {
+            anyhow::bail!("lightweight mode can't read [portal.history], which needs the browser");
+        }
+        Ok(())
+    }
+
+    /// Unit of a PPPoE ID's quota
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    pub fn quota_unit(&self, pppoe_id: &str) -> QuotaUnit {
+        self.credentials
+            .iter()
+            .find(|credential| credential.id == pppoe_id)
+            .map(|credential| credential.unit)
+            .unwrap_or_default()
+    }
+
+    /// Whether a PPPoE ID is unlimited, i.e. exempt from thresholds
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    pub fn is_unlimited(&self, pppoe_id: &str) -> bool {
+        self.credentials
+            .iter()
+            .any(|credential| credential.id == pppoe_id && credential.unlimited)
+    }
+
+    /// The limits that apply to a PPPoE ID, in minutes or megabytes
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    pub fn thresholds_for(&self, pppoe_id: &str) -> Thresholds {
+        let credential = self
+            .credentials
+            .iter()
+            .find(|credential| credential.id == pppoe_id);
+
+        match credential {
+            Some(Credential {
+                unit,
+                thresholds: Some(thresholds),
+                ..
+            }) => Thresholds {
+                switch: unit.to_base(thresholds.switch),
+                available: unit.to_base(thresholds.available),
+                disable: unit.to_base(thresholds.disable),
+            },
+            _ => self.thresholds.clone(),
+        }
+    }
+
+    /// Format a PPPoE ID's usage in the unit of its quota, e.g. "3577 minutes"
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    /// * `amount` - Usage in minutes or megabytes
+    pub fn format_usage(&self, pppoe_id: &str, amount: i32) -> String {
+        self.quota_unit(pppoe_id).format(amount)
+    }
+
+    /// The configuration as seen when driving one of `router.secondary`: the
+    /// same, except that `[router]` holds that router's address and login
+    ///
+    /// # Arguments
+    /// * `secondary` - The secondary router
+    pub fn for_secondary_router(&self, secondary: &SecondaryRouter) -> Self {
+        let mut config = self.clone();
+        config.router.model = secondary.model;
+        config.router.ip = secondary.ip.clone();
+        config.router.username = secondary.username.clone();
+        config.router.password = secondary.password.clone();
+        config.router.fallback_passwords = Vec::new();
+        config.router.wan_interface = secondary.wan_interface.clone();
+        config.router.connectivity_check_url = None;
+        config.router.upnp_url = None;
+        config.router.secondary = Vec::new();
+
+        config
+    }
+
+    /// All router admin passwords, primary first
+    pub fn router_passwords(&self) -> Vec<String> {
+        let mut passwords = vec![self.router.password.clone()];
+        passwords.extend(self.router.fallback_passwords.iter().cloned());
+        passwords
+    }
+}
+
+/// Parse an optional setting, ignoring values that don't parse
+fn parse_setting<T: std::str::FromStr>(value: Option<String>) -> Option<T> {
+    value.and_then(|value| value.parse().ok())
+}
+
+/// An optional whole number setting as a config file value, ignoring values
+/// that don't parse as `T` (e.g. negative ones for an unsigned setting)
+fn number_setting<T: std::str::FromStr + TryInto<i64>>(value: Option<String>) -> Option<Value> {
+    parse_setting::<T>(value)
+        .and_then(|value| value.try_into().ok())
+        .map(Value::Integer)
+}
+
+/// The `[[credentials]]` table of a PPPoE ID in a config file
+///
+/// # Arguments
+/// * `table` - The config file
+/// * `pppoe_id` - The PPPoE ID
+fn credential_table<'a>(table: &'a Table, pppoe_id: &str) -> Option<&'a Table> {
+    table
+        .get("credentials")?
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_table)
+        .find(|credential| credential.get("id").and_then(Value::as_str) == Some(pppoe_id))
+}
+
+/// A config file with the password of a PPPoE ID replaced, and everything
+/// else, comments included, as it was
+///
+/// The `password = ...` line whose replacement changes that password and
+/// nothing else is the one replaced.
+///
+/// # Arguments
+/// * `content` - The config file
+/// * `pppoe_id` - The PPPoE ID
+/// * `password` - Its new password
+fn with_password(content: &str, pppoe_id: &str, password: &str) -> Result<String> {
+    let mut expected: Table = toml::from_str(content)?;
+    let credential = expected
+        .get_mut("credentials")
+        .and_then(Value::as_array_mut)
+        .and_then(|credentials| {
+            credentials
+                .iter_mut()
+                .filter_map(Value::as_table_mut)
+                .find(|credential| credential.get("id").and_then(Value::as_str) == Some(pppoe_id))
+        })
+        .context(format!("'{}' isn't in the config file", pppoe_id))?;
+    credential.insert("password".to_string(), Value::from(password));
+
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    for (index, line) in lines.iter().enumerate() {
+        if line.split_once('=').map(|(key, _)| key.trim()) != Some("password") {
+            continue;
+        }
+
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let ending = &line[line.trim_end_matches(['\r', '\n']).len()..];
+        let replaced = format!("{}password = {}{}", indent, Value::from(password), ending);
+        let mut candidate = lines.clone();
+        candidate[index] = &replaced;
+        let candidate = candidate.concat();
+
+        if toml::from_str::<Table>(&candidate).is_ok_and(|table| table == expected) {
+            return Ok(candidate);
+        }
+    }
+
+    anyhow::bail!(
+        "The password of '{}' isn't on a line of its own, as `password = \"...\"`",
+        pppoe_id
+    )
+}
+
+/// Add a setting to a config file table, if it is set
+///
+/// # Arguments
+/// * `table` - The table, e.g. `[router]`
+/// * `key` - The setting's name in the table
+/// * `value` - Its value, if any
+fn insert_setting(table: &mut Table, key: &str, value: Option<Value>) {
+    if let Some(value) = value {
+        table.insert(key.to_string(), value);
+    }
+}
+
+/// A setting embedded from .env at build time
+///
+/// # Arguments
+/// * `key` - The setting's name in .env, e.g. `ROUTER_IP`
+fn embedded_setting(key: &str) -> Option<String> {
+    let value = match key {
+        "ROUTER_IP" => ROUTER_IP,
+        "ROUTER_PASSWORD" => ROUTER_PASSWORD,
+        "PPPOE_CREDENTIALS" => PPPOE_CREDENTIALS,
+        "EVENT_LOG_PATH" => EVENT_LOG_PATH,
+        "ROUTER_FALLBACK_PASSWORDS" => ROUTER_FALLBACK_PASSWORDS,
+        "ROUTER_LOGIN_DELAY_SECS" => ROUTER_LOGIN_DELAY_SECS,
+        "SELECTOR_RECOVERY" => SELECTOR_RECOVERY,
+        "SWITCH_SLA_SECS" => SWITCH_SLA_SECS,
+        "CANDIDATE_ORDER" => CANDIDATE_ORDER,
+        "MAX_CANDIDATE_CHECKS" => MAX_CANDIDATE_CHECKS,
+        "USAGE_CACHE_TTL_MINS" => USAGE_CACHE_TTL_MINS,
+        "FAST_PATH_MARGIN" => FAST_PATH_MARGIN,
+        "EXPIRY_REMINDER_DAYS" => EXPIRY_REMINDER_DAYS,
+        "ROUTER_PROXY" => ROUTER_PROXY,
+        "ROUTER_SSH_JUMP_HOST" => ROUTER_SSH_JUMP_HOST,
+        "ROUTER_SSH_KEY" => ROUTER_SSH_KEY,
+        "WIREGUARD_INTERFACE" => WIREGUARD_INTERFACE,
+        _ => None,
+    };
+    value.map(String::from)
+}
+
+/// A setting from the environment, named as in .env. Empty values count as unset.
+///
+/// # Arguments
+/// * `key` - The setting's name, e.g. `ROUTER_IP`
+fn environment_setting(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
@@ -0,0 +1,23 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Where `/metrics` and the health checks are served in a container when
+/// `--metrics` isn't given: every interface, so that a published port reaches them
+pub const DEFAULT_METRICS_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 9184);
+
+/// Whether the process runs in a container (Docker, Podman and the like)
+///
+/// Docker creates `/.dockerenv` and Podman `/run/.containerenv`; systemd-nspawn
+/// and LXC set the `container` environment variable.
+pub fn detected() -> bool {
+    static DETECTED: OnceLock<bool> = OnceLock::new();
+
+    *DETECTED.get_or_init(|| {
+        Path::new("/.dockerenv").exists()
+            || Path::new("/run/.containerenv").exists()
+            || env::var_os("container").is_some()
+    })
+}
@@ -0,0 +1,490 @@
+use crate::audit;
+use crate::automation;
+use crate::canary;
+use crate::config::{Config, NotificationKind};
+use crate::container;
+use crate::driver;
+use crate::events::{self, Event};
+use crate::forecast;
+use crate::metrics;
+use crate::netwatch;
+use crate::notify::send_notification;
+use crate::portal::get_total_use;
+use crate::router::upnp;
+use crate::state::{record_usage, State};
+use crate::storage::record_usage_sample;
+use crate::syslog;
+use crate::wanip;
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::process::Child;
+use std::time::{Duration, Instant};
+use thirtyfour::error::WebDriverError;
+#[cfg(target_os = "windows")]
+use tokio::sync::Notify;
+use tokio::sync::{mpsc, watch};
+use tokio::time::sleep;
+use tracing::{info, instrument, warn};
+
+/// How soon to try again after a failed cycle, if sooner than the regular interval
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(5 * 60);
+
+/// Intervals without a finished cycle after which `/readyz` reports the daemon not ready
+const NOT_READY_AFTER_INTERVALS: u32 = 2;
+
+/// Notified by `request_shutdown`
+#[cfg(target_os = "windows")]
+static SHUTDOWN: Notify = Notify::const_new();
+
+/// Run the automation every `interval` until SIGINT or SIGTERM is received.
+///
+/// The browser driver is started for each cycle and stopped once it is done,
+/// unless `keep_browser_warm` keeps it up between cycles; it is then
+/// restarted if it dies. A failed cycle doesn't stop the daemon, it is
+/// reported like a failed one-shot run and retried sooner than usual. A
+/// shutdown request never interrupts a cycle (which could leave the router
+/// half-configured): the current cycle finishes, then the browser driver is
+/// stopped and the daemon exits.
+///
+/// With a `decision_interval`, most cycles only poll the portal for the
+/// running ID's usage, without touching the router. A full run (router check
+/// and switch decision) happens once per decision interval, or as soon as a
+/// poll finds the running ID over the switch threshold or due for an early
+/// switch. With `forecast.early_switch_window` set, the daemon also wakes up
+/// when the window opens.
+///
+/// With `polling.check_on_network_change`, a change to this machine's network
+/// (see `crate::netwatch`) starts a full run right away instead of waiting
+/// for the interval. Changes during a cycle, which are often its own doing,
+/// are ignored.
+///
+/// With `[syslog]`, the routers' PPPoE log lines are taken meanwhile, see
+/// `crate::syslog`.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `interval` - Time to wait after a cycle before starting the next
+/// * `decision_interval` - Minimum time between full runs, if polls should
+///   happen in between
+/// * `metrics` - Where to serve metrics and the health checks, if anywhere.
+///   In a container they are served on `container::DEFAULT_METRICS_ADDR`
+///   when this isn't given.
+/// * `run_as` - The user to switch to once the listeners are bound, see
+///   `audit::drop_privileges`
+/// * `dry_run` - Only report switches and disables, see `automation::run_automation`
+pub async fn run(
+    config: &Config,
+    interval: Duration,
+    decision_interval: Option<Duration>,
+    metrics: Option<SocketAddr>,
+    run_as: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    info!(
+        "Running as a daemon, checking every {}",
+        format_duration(interval)
+    );
+    if let Some(decision_interval) = decision_interval {
+        info!(
+            "Switch decisions every {}, or when the threshold is crossed",
+            format_duration(decision_interval)
+        );
+    }
+
+    let metrics =
+        metrics.or_else(|| container::detected().then_some(container::DEFAULT_METRICS_ADDR));
+    if let Some(addr) = metrics {
+        metrics::serve(addr, config, interval * NOT_READY_AFTER_INTERVALS).await?;
+    }
+    if let Some(syslog) = &config.syslog {
+        syslog::listen(config, syslog).await?;
+    }
+    // Nothing after this needs root
+    if let Some(user) = run_as {
+        audit::drop_privileges(user, config.event_log_path.as_deref())?;
+    }
+
+    if State::load().risks_acknowledged_at.is_none() {
+        warn!("The router won't be switched until `auto-wifi init --acknowledge-risks` is run");
+    }
+
+    let mut shutdown = shutdown_requested();
+    let mut network_changes = config
+        .polling
+        .check_on_network_change
+        .then(netwatch::watch)
+        .flatten();
+    let mut webdriver: Option<Child> = None;
+    let mut last_decision: Option<Instant> = None;
+    let mut webdriver_failures = 0;
+
+    // A failed canary check is reported, but the daemon runs anyway: it may
+    // be the portal that is down, not this update that broke it
+    if config.canary_on_change && canary::is_due(config) {
+        let canary = async {
+            ensure_webdriver(config, &mut webdriver).await?;
+            canary::run(config).await
+        };
+        if let Err(e) = canary.await {
+            warn!("{:#}", e);
+        }
+    }
+
+    loop {
+        let decision_due = match (decision_interval, last_decision) {
+            (Some(decision_interval), Some(last_decision)) => {
+                last_decision.elapsed() >= decision_interval
+            }
+            _ => true,
+        };
+
+        let poll_result = if decision_due {
+            Ok(true)
+        } else {
+            poll_usage(config, &mut webdriver).await
+        };
+
+        let result = match poll_result {
+            Ok(true) => {
+                last_decision = Some(Instant::now());
+                crate::begin_run(config);
+                let result = run_cycle(config, &mut webdriver, dry_run).await;
+                crate::finish_run(config, &result);
+                result
+            }
+            Ok(false) => Ok(()),
+            Err(e) => {
+                warn!("Usage poll failed: {}", e);
+                Err(e)
+            }
+        };
+        metrics::record_cycle();
+        wanip::check(config).await;
+
+        // Hold no browser processes while waiting for the next cycle
+        if !config.keep_browser_warm {
+            if let Some(child) = webdriver.take() {
+                crate::stop_webdriver(child);
+            }
+        }
+
+        if *shutdown.borrow() {
+            break;
+        }
+
+        webdriver_failures = match &result {
+            Err(e) if is_webdriver_error(e) => webdriver_failures + 1,
+            _ => 0,
+        };
+        let restart_after = config.retry.restart_browser_after;
+        if restart_after > 0 && webdriver_failures >= restart_after {
+            let restarted = restart_browser(config, &mut webdriver, webdriver_failures);
+            webdriver_failures = 0;
+            // A fresh browser is worth trying straight away
+            if restarted {
+                continue;
+            }
+        }
+
+        let mut delay = match result {
+            Ok(()) => interval,
+            Err(_) => interval.min(RETRY_AFTER_FAILURE),
+        };
+        // Wake up when the early switch window opens rather than sleep past it
+        if let Some(until_window) = forecast::until_early_switch_window(config) {
+            delay = delay.min(until_window);
+        }
+        info!("Next check in {}", format_duration(delay));
+
+        if let Some(network_changes) = &mut network_changes {
+            while network_changes.try_recv().is_ok() {}
+        }
+
+        tokio::select! {
+            _ = sleep(delay) => {}
+            Some(change) = next_network_change(&mut network_changes) => {
+                info!("Network changed ({}), checking now", change);
+                last_decision = None;
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    if let Some(child) = webdriver {
+        crate::stop_webdriver(child);
+    }
+    info!("Daemon stopped");
+
+    Ok(())
+}
+
+/// The next network change, or never if the network isn't watched
+///
+/// # Arguments
+/// * `network_changes` - Changes reported by `netwatch::watch`, if watched
+async fn next_network_change(
+    network_changes: &mut Option<mpsc::Receiver<String>>,
+) -> Option<String> {
+    match network_changes {
+        Some(network_changes) => network_changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether an error came from the WebDriver protocol, e.g. a lost session,
+/// rather than from the router or the portal
+///
+/// # Arguments
+/// * `error` - The error a cycle failed with
+fn is_webdriver_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<WebDriverError>())
+}
+
+/// Kill the browser driver and its browsers and clear their temporary
+/// profiles, so that the next cycle starts them afresh
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `webdriver` - The browser driver process kept between cycles
+/// * `failures` - Cycles in a row that failed with WebDriver errors
+///
+/// # Returns
+/// * Whether the browser was restarted. A remote WebDriver server isn't
+///   started by this tool, so it can't be.
+fn restart_browser(config: &Config, webdriver: &mut Option<Child>, failures: u32) -> bool {
+    if !crate::needs_webdriver(config) {
+        warn!(
+            "{} cycles in a row failed with WebDriver errors. The WebDriver server isn't run by this tool, so it can't be restarted.",
+            failures
+        );
+        return false;
+    }
+
+    warn!(
+        "{} cycles in a row failed with WebDriver errors. Restarting the browser...",
+        failures
+    );
+    if let Some(child) = webdriver.take() {
+        crate::stop_webdriver(child);
+    }
+    let cleared = driver::clear_temp_profiles(config.browser.name);
+    if cleared > 0 {
+        info!("Deleted {} temporary browser profile(s)", cleared);
+    }
+
+    events::emit(Event::BrowserRestarted { failures });
+    send_notification(
+        NotificationKind::Warning,
+        "Browser Restarted ⚠",
+        &format!(
+            "{} checks in a row failed with browser errors, so the browser was restarted.",
+            failures
+        ),
+    );
+    true
+}
+
+/// Run one full cycle, (re)starting the browser driver first if it isn't running
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `webdriver` - The browser driver process kept between cycles
+/// * `dry_run` - Only report switches and disables
+async fn run_cycle(config: &Config, webdriver: &mut Option<Child>, dry_run: bool) -> Result<()> {
+    ensure_webdriver(config, webdriver).await?;
+    automation::run_automation(config, dry_run).await
+}
+
+/// Read the running ID's usage from the portal, without touching the router
+///
+/// The running ID is the one the last full run found on the router. The
+/// router is only asked, over UPnP, whether its WAN connection is up.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `webdriver` - The browser driver process kept between cycles
+///
+/// # Returns
+/// * Whether a full run is needed now, because the ID is over the switch
+///   threshold or isn't known
+#[instrument(skip_all)]
+async fn poll_usage(config: &Config, webdriver: &mut Option<Child>) -> Result<bool> {
+    let state = State::load();
+    let Some(credential) = state.last_running_id().and_then(|running_id| {
+        config
+            .credentials
+            .iter()
+            .find(|credential| credential.id == running_id)
+    }) else {
+        return Ok(true);
+    };
+
+    // An unlimited ID has no threshold to cross; the next full run looks for
+    // a quota ID to switch back to
+    if credential.unlimited {
+        return Ok(false);
+    }
+
+    // A connection that dropped without being disabled needs the router
+    // looked at, which the portal can't be reached to tell anyway
+    if let Some(wan) = upnp::wan_status(config).await {
+        if !wan.connected && state.disabled.is_none() {
+            warn!("Router reports the WAN {}, evaluating now", wan.status);
+            return Ok(true);
+        }
+    }
+
+    ensure_webdriver(config, webdriver).await?;
+    let account = get_total_use(config, &credential.id, &credential.password)
+        .await
+        .context(format!("Failed to check usage of '{}'", credential.id))?;
+
+    info!(
+        "Usage of '{}': {}",
+        credential.id,
+        config.format_usage(&credential.id, account.total_use)
+    );
+    events::emit(Event::UsageChecked {
+        pppoe_id: &credential.id,
+        minutes: account.total_use,
+    });
+    record_usage(&credential.id, account.total_use);
+    record_usage_sample(&credential.id, account.total_use);
+
+    if account.total_use > config.thresholds_for(&credential.id).switch {
+        info!("Switch threshold crossed, evaluating now");
+        return Ok(true);
+    }
+    if forecast::early_switch_due(config, &credential.id).is_some() {
+        info!("Switch threshold forecast to be crossed before the next low-usage window, evaluating now");
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Start the browser driver if it isn't running, e.g. because it died, and
+/// if it is needed at all
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `webdriver` - The browser driver process kept between cycles
+async fn ensure_webdriver(config: &Config, webdriver: &mut Option<Child>) -> Result<()> {
+    if !crate::needs_webdriver(config) {
+        return Ok(());
+    }
+
+    if let Some(child) = webdriver {
+        if let Ok(Some(status)) = child.try_wait() {
+            warn!("The browser driver exited ({}), restarting it", status);
+            *webdriver = None;
+        }
+    }
+
+    if webdriver.is_none() {
+        *webdriver = Some(crate::start_webdriver(&config.browser).await?);
+    }
+
+    Ok(())
+}
+
+/// Listen for SIGINT/SIGTERM (Ctrl+C or the service being stopped on
+/// Windows) in the background
+///
+/// # Returns
+/// * A receiver whose value becomes `true` once shutdown has been requested
+fn shutdown_requested() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested, stopping after the current cycle...");
+        let _ = sender.send(true);
+    });
+
+    receiver
+}
+
+/// Stop the daemon after the current cycle, as SIGTERM would. This is how the
+/// Windows service manager's stop request reaches it.
+#[cfg(target_os = "windows")]
+pub fn request_shutdown() {
+    SHUTDOWN.notify_one();
+}
+
+/// Wait until the process is asked to stop
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    // A service has no console, so listening for Ctrl+C may fail there
+    #[cfg(target_os = "windows")]
+    {
+        tokio::select! {
+            Ok(()) = tokio::signal::ctrl_c() => {}
+            _ = SHUTDOWN.notified() => {}
+        }
+    }
+
+    #[cfg(not(any(unix, target_os = "windows")))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Parse an interval such as `90s`, `30m` or `2h`. A bare number is minutes.
+///
+/// # Arguments
+/// * `value` - The interval as given on the command line
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit_secs) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        _ => (value, 60),
+    };
+
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid interval '{}', expected e.g. 90s, 30m or 2h", value))?;
+    if number == 0 {
+        return Err("interval must be greater than zero".to_string());
+    }
+
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("interval '{}' is too long", value))
+}
+
+/// Format a duration the way intervals are written on the command line
+///
+/// # Arguments
+/// * `duration` - The duration to format
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
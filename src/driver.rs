@@ -0,0 +1,471 @@
+use crate::config::{BrowserConfig, BrowserName};
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Chrome for Testing's list of the latest ChromeDriver build per Chrome milestone
+const MILESTONES_URL: &str = "https://googlechromelabs.github.io/chrome-for-testing/latest-versions-per-milestone-with-downloads.json";
+
+/// Name of the ChromeDriver executable
+#[cfg(target_os = "windows")]
+const CHROMEDRIVER_BINARY: &str = "chromedriver.exe";
+
+#[cfg(not(target_os = "windows"))]
+const CHROMEDRIVER_BINARY: &str = "chromedriver";
+
+/// Name of the geckodriver executable
+#[cfg(target_os = "windows")]
+const GECKODRIVER_BINARY: &str = "geckodriver.exe";
+
+#[cfg(not(target_os = "windows"))]
+const GECKODRIVER_BINARY: &str = "geckodriver";
+
+/// Environment variable naming a WebDriver server to use instead of starting
+/// the browser's driver, e.g. `http://selenium:4444` for a
+/// selenium/standalone-chrome container
+const WEBDRIVER_URL_VAR: &str = "WEBDRIVER_URL";
+
+/// How long `check_webdriver` waits for an answer
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Port of the running browser driver, see `webdriver_url`
+static PORT: AtomicU16 = AtomicU16::new(9515);
+
+/// URL of the WebDriver server, for `WebDriver::new`: a remote one if set,
+/// otherwise the browser driver started by this process
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+pub fn webdriver_url(browser: &BrowserConfig) -> String {
+    remote_webdriver_url(browser)
+        .unwrap_or_else(|| format!("http://localhost:{}", PORT.load(Ordering::Relaxed)))
+}
+
+/// The WebDriver server set in `WEBDRIVER_URL` or, failing that, in
+/// `browser.webdriver_url`, if any
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+pub fn remote_webdriver_url(browser: &BrowserConfig) -> Option<String> {
+    env::var(WEBDRIVER_URL_VAR)
+        .ok()
+        .or_else(|| browser.webdriver_url.clone())
+        .filter(|url| !url.is_empty())
+}
+
+/// Check that the WebDriver server answers on its status endpoint
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+pub async fn check_webdriver(browser: &BrowserConfig) -> Result<()> {
+    let url = format!("{}/status", webdriver_url(browser).trim_end_matches('/'));
+
+    reqwest::Client::new()
+        .get(&url)
+        .timeout(STATUS_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context(format!("No answer from {}", url))?;
+
+    Ok(())
+}
+
+/// Record the port the browser driver was started on, so `webdriver_url`
+/// points at it
+///
+/// # Arguments
+/// * `port` - The port passed to the driver
+pub fn set_port(port: u16) {
+    PORT.store(port, Ordering::Relaxed);
+}
+
+/// Ask the OS for a local port nobody is listening on
+pub fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").context("Failed to find a free port")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// The driver executable to start for a browser
+///
+/// # Arguments
+/// * `browser` - Which browser to drive
+pub async fn driver_path(browser: BrowserName) -> Result<PathBuf> {
+    match browser {
+        BrowserName::Chrome => chromedriver_path().await,
+        // geckodriver works with any recent Firefox, so the one on the PATH will do
+        BrowserName::Firefox => Ok(PathBuf::from(GECKODRIVER_BINARY)),
+    }
+}
+
+/// Kill a browser driver along with the browsers it started, which a wedged
+/// driver would otherwise leave running
+///
+/// # Arguments
+/// * `child` - The driver process
+pub fn kill_tree(child: &mut Child) {
+    #[cfg(target_os = "linux")]
+    for pid in descendants(child.id()) {
+        // SAFETY: kill has no preconditions; a process that already exited
+        // only makes it fail
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .output();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The processes started by a process, and by those, and so on
+///
+/// # Arguments
+/// * `pid` - The process at the top
+#[cfg(target_os = "linux")]
+fn descendants(pid: u32) -> Vec<u32> {
+    let parents: Vec<(u32, u32)> = fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(entry.path().join("stat")).ok()?;
+            // The parent follows the state, after the command name in
+            // parentheses, which may itself contain spaces
+            let parent = stat.rsplit_once(')')?.1.split_whitespace().nth(1)?;
+            Some((pid, parent.parse().ok()?))
+        })
+        .collect();
+
+    let mut found = vec![pid];
+    let mut next = 0;
+    while let Some(&parent) = found.get(next) {
+        found.extend(
+            parents
+                .iter()
+                .filter(|(_, p)| *p == parent)
+                .map(|(child, _)| *child),
+        );
+        next += 1;
+    }
+
+    found.split_off(1)
+}
+
+/// Delete the temporary profiles browsers started by a driver leave behind
+/// when they are killed. Only call this once those browsers are gone.
+///
+/// # Arguments
+/// * `browser` - Which browser's profiles to delete
+///
+/// # Returns
+/// * How many profiles were deleted
+pub fn clear_temp_profiles(browser: BrowserName) -> usize {
+    let prefixes: &[&str] = match browser {
+        BrowserName::Chrome => &[
+            ".org.chromium.Chromium.",
+            ".com.google.Chrome.",
+            "scoped_dir",
+        ],
+        BrowserName::Firefox => &["rust_mozprofile"],
+    };
+
+    let Ok(entries) = fs::read_dir(env::temp_dir()) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| prefixes.iter().any(|prefix| name.starts_with(prefix)))
+        })
+        .filter(|entry| fs::remove_dir_all(entry.path()).is_ok())
+        .count()
+}
+
+/// Find a ChromeDriver that matches the installed Chrome, downloading one if needed
+///
+/// A `chromedriver` on the PATH is used if its major version matches Chrome's.
+/// Otherwise the matching build from Chrome for Testing is downloaded once into
+/// the cache directory. If Chrome's version can't be found or the download
+/// fails, the `chromedriver` on the PATH is used as is.
+///
+/// # Returns
+/// * The ChromeDriver executable to start
+async fn chromedriver_path() -> Result<PathBuf> {
+    let on_path = PathBuf::from(CHROMEDRIVER_BINARY);
+
+    let Some(chrome_version) = chrome_version() else {
+        warn!("Couldn't find the installed Chrome's version; using chromedriver from the PATH");
+        return Ok(on_path);
+    };
+    let milestone = major_version(&chrome_version);
+    info!("Found Chrome {}", chrome_version);
+
+    if let Some(driver_version) = version_of(&on_path) {
+        if major_version(&driver_version) == milestone {
+            return Ok(on_path);
+        }
+        warn!(
+            "chromedriver {} on the PATH doesn't match Chrome {}",
+            driver_version, chrome_version
+        );
+    }
+
+    let Some(platform) = platform() else {
+        warn!("No ChromeDriver downloads for this platform; using chromedriver from the PATH");
+        return Ok(on_path);
+    };
+
+    let cached = cache_dir()?
+        .join(platform)
+        .join(milestone)
+        .join(CHROMEDRIVER_BINARY);
+    if cached.exists() {
+        return Ok(cached);
+    }
+
+    match download(platform, milestone, &cached).await {
+        Ok(()) => {
+            info!("✓ Downloaded ChromeDriver to {}", cached.display());
+            Ok(cached)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to download ChromeDriver {}: {:#}. Using chromedriver from the PATH",
+                milestone, e
+            );
+            Ok(on_path)
+        }
+    }
+}
+
+/// Wait until the browser driver accepts connections on its port
+///
+/// # Arguments
+/// * `port` - The port the driver was started on
+/// * `timeout` - How long to wait
+///
+/// # Returns
+/// * Whether the driver came up in time
+pub async fn wait_until_listening(port: u16, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    while tokio::time::Instant::now() < deadline {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    false
+}
+
+/// Where downloaded ChromeDriver builds are kept
+pub fn cache_dir() -> Result<PathBuf> {
+    let dir = dirs::cache_dir()
+        .context("Could not determine the cache directory")?
+        .join("auto_pppoe_quota_manager")
+        .join("chromedriver");
+    Ok(dir)
+}
+
+/// The installed Chrome or Chromium version, e.g. "120.0.6099.109"
+fn chrome_version() -> Option<String> {
+    #[cfg(target_os = "windows")]
+    {
+        // chrome.exe --version prints nothing, but the updater records the version
+        ["HKCU", "HKLM"].iter().find_map(|hive| {
+            command_version(
+                "reg",
+                &[
+                    "query",
+                    &format!("{}\\Software\\Google\\Chrome\\BLBeacon", hive),
+                    "/v",
+                    "version",
+                ],
+            )
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        [
+            "/Applications/Google Chrome.app/Contents/MacOS/Google Chrome",
+            "/Applications/Chromium.app/Contents/MacOS/Chromium",
+        ]
+        .iter()
+        .find_map(|browser| command_version(browser, &["--version"]))
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        [
+            "google-chrome",
+            "google-chrome-stable",
+            "chromium",
+            "chromium-browser",
+        ]
+        .iter()
+        .find_map(|browser| command_version(browser, &["--version"]))
+    }
+}
+
+/// Version reported by a ChromeDriver executable, e.g. "120.0.6099.109"
+///
+/// # Arguments
+/// * `chromedriver` - The executable
+fn version_of(chromedriver: &Path) -> Option<String> {
+    command_version(chromedriver.as_os_str().to_str()?, &["--version"])
+}
+
+/// Run a command and pick the first dotted version number out of its output
+///
+/// # Arguments
+/// * `program` - The command to run
+/// * `args` - Its arguments
+fn command_version(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .find(|word| word.contains('.') && word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+/// The major version, e.g. "120" for "120.0.6099.109"
+fn major_version(version: &str) -> &str {
+    version.split('.').next().unwrap_or(version)
+}
+
+/// Chrome for Testing's name for this platform
+fn platform() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Some("linux64"),
+        ("macos", "x86_64") => Some("mac-x64"),
+        ("macos", "aarch64") => Some("mac-arm64"),
+        ("windows", "x86_64") => Some("win64"),
+        ("windows", "x86") => Some("win32"),
+        _ => None,
+    }
+}
+
+/// Download the latest ChromeDriver for a Chrome milestone
+///
+/// # Arguments
+/// * `platform` - Chrome for Testing's name for this platform
+/// * `milestone` - Chrome's major version
+/// * `destination` - Where to put the executable
+async fn download(platform: &str, milestone: &str, destination: &Path) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()
+        .context("Failed to create HTTP client")?;
+
+    let milestones: Value = client
+        .get(MILESTONES_URL)
+        .send()
+        .await
+        .context("Failed to reach Chrome for Testing")?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Chrome for Testing sent an invalid version list")?;
+
+    let url = milestones["milestones"][milestone]["downloads"]["chromedriver"]
+        .as_array()
+        .and_then(|downloads| {
+            downloads
+                .iter()
+                .find(|download| download["platform"] == platform)
+        })
+        .and_then(|download| download["url"].as_str())
+        .context(format!(
+            "No ChromeDriver build for Chrome {} on {}",
+            milestone, platform
+        ))?;
+
+    info!("Downloading ChromeDriver from {}...", url);
+    let archive = client
+        .get(url)
+        .send()
+        .await
+        .context(format!("Failed to download {}", url))?
+        .error_for_status()?
+        .bytes()
+        .await
+        .context(format!("Failed to download {}", url))?;
+
+    let executable = extract_executable(&archive)?;
+
+    let dir = destination
+        .parent()
+        .context("Invalid ChromeDriver cache path")?;
+    fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+
+    // Write under another name first so an interrupted download isn't mistaken
+    // for a cached driver on the next start
+    let partial = destination.with_extension("partial");
+    fs::write(&partial, executable).context(format!("Failed to write {}", partial.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o755))
+            .context(format!("Failed to make {} executable", partial.display()))?;
+    }
+
+    fs::rename(&partial, destination).context(format!(
+        "Failed to move ChromeDriver to {}",
+        destination.display()
+    ))?;
+
+    Ok(())
+}
+
+/// Take the ChromeDriver executable out of a Chrome for Testing zip archive
+///
+/// # Arguments
+/// * `archive` - The zip archive, e.g. chromedriver-linux64.zip
+fn extract_executable(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut zip =
+        zip::ZipArchive::new(Cursor::new(archive)).context("Invalid ChromeDriver archive")?;
+
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        let is_executable = Path::new(file.name())
+            .file_name()
+            .is_some_and(|name| name == CHROMEDRIVER_BINARY);
+
+        if is_executable {
+            let mut executable = Vec::new();
+            file.read_to_end(&mut executable)
+                .context("Failed to unpack ChromeDriver")?;
+            return Ok(executable);
+        }
+    }
+
+    anyhow::bail!("No {} in the ChromeDriver archive", CHROMEDRIVER_BINARY)
+}
@@ -0,0 +1,215 @@
+use crate::report;
+use crate::state::Counters;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Something the tool did or observed, written as one JSON line to the event log
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStarted,
+    /// Lifetime totals, re-emitted on every start so consumers never see them reset
+    Counters(&'a Counters),
+    RunFinished,
+    RunFailed {
+        error: &'a str,
+    },
+    CurrentId {
+        pppoe_id: &'a str,
+    },
+    UsageChecked {
+        pppoe_id: &'a str,
+        minutes: i32,
+    },
+    UsageCheckFailed {
+        pppoe_id: &'a str,
+        error: &'a str,
+    },
+    WithinLimit {
+        pppoe_id: &'a str,
+        minutes: i32,
+    },
+    SwitchStarted {
+        from: &'a str,
+        to: &'a str,
+    },
+    SwitchSucceeded {
+        from: &'a str,
+        to: &'a str,
+        old_usage: Option<i32>,
+    },
+    SwitchFailed {
+        from: &'a str,
+        to: &'a str,
+        error: &'a str,
+        /// The PPPoE ID put back on the router after a switch that didn't work
+        rolled_back_to: Option<&'a str>,
+    },
+    SwitchSlow {
+        from: &'a str,
+        to: &'a str,
+        seconds: u64,
+        budget_seconds: u64,
+    },
+    /// The running ID is forecast to reach its switch threshold before the
+    /// next early switch window, so it is switched away from now
+    EarlySwitchDue {
+        pppoe_id: &'a str,
+        minutes: i32,
+        /// When the threshold would be reached (unix seconds)
+        switch_at: i64,
+    },
+    /// The running ID is used faster than `[schedule]` allows
+    AheadOfSchedule {
+        pppoe_id: &'a str,
+        minutes: i32,
+        /// What it may have used by the end of the day
+        allowed: i32,
+    },
+    AllIdsExhausted {
+        pppoe_id: &'a str,
+        minutes: i32,
+    },
+    ConnectionDisabled {
+        pppoe_id: &'a str,
+        minutes: i32,
+    },
+    DisableFailed {
+        pppoe_id: &'a str,
+        error: &'a str,
+    },
+    ConnectionReEnabled {
+        pppoe_id: &'a str,
+    },
+    BrowserRestarted {
+        failures: u32,
+    },
+    /// A router logged its PPPoE connection coming up, see `crate::syslog`
+    PppoeUp {
+        router: &'a str,
+        line: &'a str,
+    },
+    /// A router logged its PPPoE connection going down
+    PppoeDown {
+        router: &'a str,
+        line: &'a str,
+    },
+    /// A router logged its PPPoE login being rejected
+    PppoeLoginFailed {
+        router: &'a str,
+        line: &'a str,
+    },
+    /// The external IP address changed without a switch, see `crate::wanip`
+    ExternalIpChanged {
+        pppoe_id: &'a str,
+        from: &'a str,
+        to: &'a str,
+    },
+    /// The portal showed a usage that can't be right, see
+    /// `crate::policy::implausible_usage`
+    ImplausibleReading {
+        pppoe_id: &'a str,
+        minutes: i32,
+        last: Option<i32>,
+        reason: &'a str,
+    },
+    /// A satellite sent a usage reading, see `crate::satellite`
+    SatelliteReading {
+        pppoe_id: &'a str,
+        minutes: i32,
+        source: &'a str,
+    },
+    /// A canary check finished, see `crate::canary`
+    CanaryFinished {
+        passed: bool,
+        failures: &'a [String],
+    },
+    /// A PPPoE ID's password was changed by `rotate-pppoe-password`
+    PasswordRotated {
+        pppoe_id: &'a str,
+        router_updated: bool,
+    },
+    Notification {
+        title: &'a str,
+        message: &'a str,
+    },
+    /// Disabling the connection was put off because some IDs couldn't be
+    /// checked
+    DisableDeferred {
+        pppoe_id: &'a str,
+        unchecked: usize,
+        runs_in_a_row: u32,
+    },
+    /// What the run's browser sessions downloaded, see `crate::browser`
+    BrowserTraffic {
+        sessions: u64,
+        bytes: u64,
+    },
+}
+
+/// The open event log file, or `None` when no event log is configured
+static EVENT_LOG: OnceLock<Mutex<Option<File>>> = OnceLock::new();
+
+/// Open the event log so that subsequent `emit` calls write to it
+///
+/// The path may be a regular file (appended to) or a FIFO; note that opening a
+/// FIFO blocks until a reader (e.g. vector or fluent-bit) is attached.
+///
+/// # Arguments
+/// * `path` - Where to write events, or `None` to disable the event log
+pub fn init(path: Option<&str>) {
+    let file = path.and_then(|path| {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                info!("Writing JSONL event log to {}", path);
+                Some(file)
+            }
+            Err(e) => {
+                warn!("Failed to open event log {}: {}", path, e);
+                None
+            }
+        }
+    });
+
+    let _ = EVENT_LOG.set(Mutex::new(file));
+}
+
+/// Write an event to the event log, if one is configured, and to the report
+/// of the run in progress
+///
+/// # Arguments
+/// * `event` - The event to record
+pub fn emit(event: Event) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    // Flatten the event's fields next to the timestamp
+    let mut line = json!({ "timestamp": timestamp });
+    if let (Some(fields), Ok(serde_json::Value::Object(event_fields))) =
+        (line.as_object_mut(), serde_json::to_value(&event))
+    {
+        fields.extend(event_fields);
+    }
+    report::add_event(&line);
+
+    let Some(lock) = EVENT_LOG.get() else {
+        return;
+    };
+    let Ok(mut guard) = lock.lock() else {
+        return;
+    };
+    let Some(file) = guard.as_mut() else {
+        return;
+    };
+
+    if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+        warn!("Failed to write to event log: {}", e);
+    }
+}
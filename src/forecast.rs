@@ -0,0 +1,425 @@
+use crate::config::Config;
+use crate::storage::{daily_rate, History, UsageSample};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tracing::warn;
+
+/// Days of consumption needed to learn the weekly pattern: two of each weekday
+const MIN_WEEKLY_DAYS: usize = 14;
+
+/// Days of consumption needed before monthly patterns are trusted
+const MIN_MONTHLY_DAYS: usize = 180;
+
+/// Days of a month needed for that month to get a factor of its own
+const MIN_DAYS_PER_MONTH: usize = 14;
+
+/// How far ahead a seasonal forecast looks for the switch threshold
+const MAX_FORECAST_DAYS: u32 = 3660;
+
+/// Where a PPPoE ID's usage is heading, from its recorded readings
+#[derive(Debug, Clone)]
+pub struct Forecast {
+    /// The latest reading, in minutes or megabytes
+    pub usage: i32,
+    /// When the latest reading was taken
+    pub read_at: DateTime<Local>,
+    /// Average consumption per day since the usage counter last reset
+    pub daily_rate: f64,
+    /// When the switch threshold is reached at that rate. `None` for an
+    /// unlimited ID, one whose usage isn't rising, or one already above it.
+    pub switch_at: Option<DateTime<Local>>,
+    /// Whether `switch_at` allows for the busier and quieter days learned
+    /// from the history, rather than the same rate every day
+    pub seasonal: bool,
+}
+
+/// How much is used on a day compared to an average one, learned from the
+/// history over `forecast.seasonality_days`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seasonality {
+    /// By day of the week, Monday first
+    pub weekday: [f64; 7],
+    /// By month of the year, January first, once the readings go back far
+    /// enough to tell. A month with too few days of readings is 1.
+    pub month: Option<[f64; 12]>,
+}
+
+impl Seasonality {
+    /// How much is used on a day compared to an average one
+    ///
+    /// # Arguments
+    /// * `date` - The day
+    pub fn factor(&self, date: NaiveDate) -> f64 {
+        let weekday = self.weekday[date.weekday().num_days_from_monday() as usize];
+        let month = self
+            .month
+            .map_or(1.0, |month| month[date.month0() as usize]);
+        weekday * month
+    }
+}
+
+/// Forecast a PPPoE ID from its readings
+///
+/// With a `seasonality`, the rate is taken as what the last
+/// `forecast.lookback_days` called for, and each day ahead gets more or less
+/// of it as the pattern has it: a busy weekend comes sooner than a quiet week.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `samples` - Readings of that ID only, oldest first
+/// * `seasonality` - The pattern of usage to allow for, if one was learned
+///
+/// # Returns
+/// * `None` if the readings don't span long enough to estimate a rate
+pub fn forecast(
+    config: &Config,
+    pppoe_id: &str,
+    samples: &[UsageSample],
+    seasonality: Option<&Seasonality>,
+) -> Option<Forecast> {
+    let rate = daily_rate(samples)?;
+    let latest = samples.last()?;
+    let read_at = Local.timestamp_opt(latest.timestamp, 0).single()?;
+
+    let remaining = config.thresholds_for(pppoe_id).switch - latest.minutes;
+    let switch_at = if !config.is_unlimited(pppoe_id) && rate > 0.0 && remaining > 0 {
+        match seasonality {
+            Some(seasonality) => seasonal_switch_at(
+                seasonality,
+                rate,
+                config.forecast.lookback_days,
+                read_at,
+                f64::from(remaining),
+            ),
+            None => {
+                let days_left = f64::from(remaining) / rate;
+                Some(read_at + chrono::Duration::seconds((days_left * 86400.0) as i64))
+            }
+        }
+    } else {
+        None
+    };
+
+    Some(Forecast {
+        usage: latest.minutes,
+        read_at,
+        daily_rate: rate,
+        switch_at,
+        seasonal: seasonality.is_some(),
+    })
+}
+
+/// When `remaining` is used up, day by day, at a rate that was measured over
+/// the last `lookback_days` and goes up and down with the pattern
+///
+/// # Arguments
+/// * `seasonality` - The pattern of usage
+/// * `rate` - Consumption per day over the last `lookback_days`
+/// * `lookback_days` - How many days the rate was measured over
+/// * `read_at` - When the latest reading was taken
+/// * `remaining` - How much is left until the switch threshold
+fn seasonal_switch_at(
+    seasonality: &Seasonality,
+    rate: f64,
+    lookback_days: u32,
+    read_at: DateTime<Local>,
+    remaining: f64,
+) -> Option<DateTime<Local>> {
+    // The rate of an average day, taking out how busy the measured days were
+    let today = read_at.date_naive();
+    let measured: f64 = (0..lookback_days)
+        .filter_map(|days| today.checked_sub_days(Days::new(days.into())))
+        .map(|date| seasonality.factor(date))
+        .sum::<f64>()
+        / f64::from(lookback_days.max(1));
+    if measured <= 0.0 {
+        return None;
+    }
+    let average_rate = rate / measured;
+
+    let mut remaining = remaining;
+    let mut at = read_at;
+    for _ in 0..MAX_FORECAST_DAYS {
+        let date = at.date_naive();
+        let midnight = start_of(date.succ_opt()?)?;
+        // Not always 24 hours, where clocks change
+        let day_secs = (midnight - start_of(date)?).num_seconds() as f64;
+        let day_rate = average_rate * seasonality.factor(date);
+        let used = day_rate * (midnight - at).num_seconds() as f64 / day_secs;
+        if used >= remaining {
+            let secs = remaining / day_rate * day_secs;
+            return Some(at + chrono::Duration::seconds(secs as i64));
+        }
+        remaining -= used;
+        at = midnight;
+    }
+
+    None
+}
+
+/// Learn how much is used on each day of the week, and of the year once the
+/// readings go back far enough, compared to an average day
+///
+/// Consumption is what every ID measured like `pppoe_id` (in time or in data)
+/// used together, spread evenly over the time between readings. A counter
+/// that went down was reset, so the readings on either side aren't compared.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to forecast
+/// * `samples` - Readings of every ID, oldest first
+///
+/// # Returns
+/// * `None` with fewer than two weeks of consumption, or none at all
+pub fn seasonality(
+    config: &Config,
+    pppoe_id: &str,
+    samples: &[UsageSample],
+) -> Option<Seasonality> {
+    let kind = config.quota_unit(pppoe_id).kind();
+    let mut by_id: HashMap<&str, Vec<&UsageSample>> = HashMap::new();
+    for sample in samples {
+        if config.quota_unit(&sample.pppoe_id).kind() == kind {
+            by_id.entry(&sample.pppoe_id).or_default().push(sample);
+        }
+    }
+
+    let mut days = BTreeMap::new();
+    for id_samples in by_id.values() {
+        for pair in id_samples.windows(2) {
+            if pair[1].minutes >= pair[0].minutes {
+                spread(
+                    &mut days,
+                    pair[0].timestamp,
+                    pair[1].timestamp,
+                    f64::from(pair[1].minutes - pair[0].minutes),
+                );
+            }
+        }
+    }
+
+    // The first and the last day are only partly covered
+    let days: Vec<(NaiveDate, f64)> = days.into_iter().collect();
+    let days = days.get(1..days.len().saturating_sub(1))?;
+    if days.len() < MIN_WEEKLY_DAYS {
+        return None;
+    }
+    let mean = days.iter().map(|(_, used)| used).sum::<f64>() / days.len() as f64;
+    if mean <= 0.0 {
+        return None;
+    }
+
+    Some(Seasonality {
+        weekday: factors(days, mean, 2, |date| date.weekday().num_days_from_monday()),
+        month: (days.len() >= MIN_MONTHLY_DAYS)
+            .then(|| factors(days, mean, MIN_DAYS_PER_MONTH, |date| date.month0())),
+    })
+}
+
+/// Learn the pattern of usage to forecast a PPPoE ID with, from the readings
+/// over the last `forecast.seasonality_days`
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID to forecast
+///
+/// # Returns
+/// * `None` if `forecast.seasonality_days` is 0 or there isn't enough history
+pub fn load_seasonality(config: &Config, pppoe_id: &str) -> Result<Option<Seasonality>> {
+    if config.forecast.seasonality_days == 0 {
+        return Ok(None);
+    }
+
+    let since = Utc::now().timestamp() - i64::from(config.forecast.seasonality_days) * 86400;
+    let samples = History::open()?.usage_since(since)?;
+
+    Ok(seasonality(config, pppoe_id, &samples))
+}
+
+/// Add consumption to the days it happened on, evenly over the time between
+/// two readings
+///
+/// # Arguments
+/// * `days` - Consumption by day, added to
+/// * `from` - When the earlier reading was taken (unix seconds)
+/// * `to` - When the later reading was taken (unix seconds)
+/// * `used` - How much was used in between
+fn spread(days: &mut BTreeMap<NaiveDate, f64>, from: i64, to: i64, used: f64) {
+    if to <= from {
+        return;
+    }
+
+    let per_sec = used / (to - from) as f64;
+    let mut at = from;
+    while at < to {
+        let Some(date) = Local
+            .timestamp_opt(at, 0)
+            .single()
+            .map(|at| at.date_naive())
+        else {
+            return;
+        };
+        let next = date
+            .succ_opt()
+            .and_then(start_of)
+            .map_or(to, |midnight| midnight.timestamp().min(to));
+        if next <= at {
+            return;
+        }
+        *days.entry(date).or_default() += per_sec * (next - at) as f64;
+        at = next;
+    }
+}
+
+/// Average consumption in each slot (e.g. day of the week) compared to the
+/// average day, or 1 for a slot with fewer than `min_days` days
+///
+/// # Arguments
+/// * `days` - Consumption by day
+/// * `mean` - Consumption on an average day
+/// * `min_days` - Days a slot needs for a factor of its own
+/// * `slot` - Which slot a day is in
+fn factors<const N: usize>(
+    days: &[(NaiveDate, f64)],
+    mean: f64,
+    min_days: usize,
+    slot: impl Fn(NaiveDate) -> u32,
+) -> [f64; N] {
+    let mut sums = [0.0; N];
+    let mut counts = [0; N];
+    for (date, used) in days {
+        let slot = slot(*date) as usize;
+        sums[slot] += used;
+        counts[slot] += 1;
+    }
+
+    std::array::from_fn(|slot| {
+        if counts[slot] >= min_days {
+            sums[slot] / counts[slot] as f64 / mean
+        } else {
+            1.0
+        }
+    })
+}
+
+/// The first moment of a day in local time
+///
+/// # Arguments
+/// * `date` - The day
+fn start_of(date: NaiveDate) -> Option<DateTime<Local>> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+}
+
+/// Forecast a PPPoE ID from its readings over the last `forecast.lookback_days`,
+/// allowing for the pattern learned over `forecast.seasonality_days`
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+pub fn load(config: &Config, pppoe_id: &str) -> Result<Option<Forecast>> {
+    let since = Utc::now().timestamp() - i64::from(config.forecast.lookback_days) * 86400;
+    let samples: Vec<_> = History::open()?
+        .usage_since(since)?
+        .into_iter()
+        .filter(|sample| sample.pppoe_id == pppoe_id)
+        .collect();
+
+    let seasonality = load_seasonality(config, pppoe_id)?;
+
+    Ok(forecast(config, pppoe_id, &samples, seasonality.as_ref()))
+}
+
+/// Whether to switch away from the running ID now rather than wait for it to
+/// cross its switch threshold, likely at a busier time of day.
+///
+/// That is the case inside `forecast.early_switch_window` when the ID is
+/// forecast to reach the threshold before the window opens again.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The running PPPoE ID, whose latest reading is recorded
+///
+/// # Returns
+/// * When the threshold would be reached, if the switch should happen now
+pub fn early_switch_due(config: &Config, pppoe_id: &str) -> Option<DateTime<Local>> {
+    let window = config.forecast.early_switch_window?;
+    let now = Local::now();
+    if !window.contains(now.time()) {
+        return None;
+    }
+
+    let forecast = match load(config, pppoe_id) {
+        Ok(forecast) => forecast?,
+        Err(e) => {
+            warn!("Failed to forecast the usage of '{}': {:#}", pppoe_id, e);
+            return None;
+        }
+    };
+
+    forecast
+        .switch_at
+        .filter(|switch_at| *switch_at < window.next_start(now))
+}
+
+/// When an early switch is expected to happen: in the last opening of
+/// `forecast.early_switch_window` before the switch threshold is reached
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `switch_at` - When the running ID is forecast to reach its switch threshold
+///
+/// # Returns
+/// * `None` without a window, or if the threshold is reached before it opens.
+///   The current time if the switch is due now.
+pub fn planned_early_switch(
+    config: &Config,
+    switch_at: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let window = config.forecast.early_switch_window?;
+    let now = Local::now();
+
+    let mut opens = if window.contains(now.time()) {
+        now
+    } else {
+        window.next_start(now)
+    };
+    if opens >= switch_at {
+        return None;
+    }
+
+    loop {
+        let next = window.next_start(opens);
+        if next >= switch_at {
+            return Some(opens);
+        }
+        opens = next;
+    }
+}
+
+/// Whether the current time is in `forecast.early_switch_window`
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn in_early_switch_window(config: &Config) -> bool {
+    config
+        .forecast
+        .early_switch_window
+        .is_some_and(|window| window.contains(Local::now().time()))
+}
+
+/// How long until `forecast.early_switch_window` next opens, so the daemon
+/// can wake up for it
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn until_early_switch_window(config: &Config) -> Option<Duration> {
+    let window = config.forecast.early_switch_window?;
+    let now = Local::now();
+
+    (window.next_start(now) - now).to_std().ok()
+}
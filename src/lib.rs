@@ -0,0 +1,340 @@
+//! Rotates PPPoE IDs on the router as their ISP quota runs out
+//!
+//! The `auto-wifi` binary is a command line around this library. The
+//! switching logic itself is in `automation`, written against the
+//! `UsageProvider` and `RouterControl` traits so it can be run without a
+//! router or portal.
+//!
+//! The programs in `examples/` use this API against mock servers, as starting
+//! points for using it elsewhere.
+
+pub mod audit;
+pub mod automation;
+pub mod backup;
+pub mod bench;
+pub mod browser;
+pub mod canary;
+pub mod commands;
+pub mod config;
+pub mod container;
+pub mod daemon;
+pub mod driver;
+pub mod events;
+pub mod forecast;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod netwatch;
+pub mod notify;
+pub mod overview;
+pub mod policy;
+pub mod portal;
+pub mod recharge;
+pub mod report;
+pub mod retry;
+pub mod router;
+pub mod satellite;
+pub mod secrets;
+pub mod selectors;
+pub mod service;
+pub mod setup;
+pub mod ssid;
+pub mod state;
+pub mod storage;
+pub mod syslog;
+#[cfg(target_os = "windows")]
+pub mod toast;
+pub mod tunnel;
+pub mod vpn;
+pub mod wanip;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use config::{BrowserConfig, BrowserName, Config, NotificationKind};
+use events::Event;
+use notify::send_notification;
+use state::{bump_counters, should_notify_error, State};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::process::{Child, Command};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Keep running and check periodically instead of exiting after one check
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Time between checks in daemon mode, e.g. 90s, 30m or 2h
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "30m",
+        value_parser = daemon::parse_interval,
+        requires = "daemon"
+    )]
+    pub interval: Duration,
+
+    /// In daemon mode, only poll usage at each interval and decide on
+    /// switching this often, or when the switch threshold is crossed
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = daemon::parse_interval,
+        requires = "daemon"
+    )]
+    pub decision_interval: Option<Duration>,
+
+    /// In daemon mode, serve Prometheus metrics on /metrics and health checks
+    /// on /healthz and /readyz at this address, e.g. 127.0.0.1:9184, and take
+    /// satellite readings on /readings if [satellites] is set. In a container
+    /// they are served on 0.0.0.0:9184 unless this is given.
+    #[arg(long, value_name = "ADDR", requires = "daemon")]
+    pub metrics: Option<SocketAddr>,
+
+    /// In daemon mode, switch to this user once the metrics and syslog
+    /// listeners are bound, so they can use ports below 1024 without the
+    /// daemon staying root (Unix only)
+    #[arg(long, value_name = "USER", requires = "daemon")]
+    pub run_as: Option<String>,
+
+    /// Check everything, but only report the switches and disables that
+    /// would be made instead of changing the router
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Daemon options for `install-service`
+#[derive(Debug, Args)]
+pub struct ServiceArgs {
+    /// Time between checks, e.g. 90s, 30m or 2h
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "30m",
+        value_parser = daemon::parse_interval
+    )]
+    pub interval: Duration,
+
+    /// Only poll usage at each interval and decide on switching this often,
+    /// or when the switch threshold is crossed
+    #[arg(long, value_name = "DURATION", value_parser = daemon::parse_interval)]
+    pub decision_interval: Option<Duration>,
+
+    /// Serve Prometheus metrics on /metrics and health checks on /healthz
+    /// and /readyz at this address, e.g. 127.0.0.1:9184
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+}
+
+/// Start the browser's WebDriver server (ChromeDriver or geckodriver) as a
+/// subprocess
+///
+/// ChromeDriver is one that matches the installed Chrome, downloaded if
+/// needed; see the `driver` module. Either listens on a free port.
+///
+/// # Arguments
+/// * `browser` - The `[browser]` section of the config file
+///
+/// # Returns
+/// * A Child process handle for the driver
+pub async fn start_webdriver(browser: &BrowserConfig) -> Result<Child> {
+    let name = browser.name.driver_name();
+    info!("Starting {}...", name);
+
+    let executable = driver::driver_path(browser.name).await?;
+    let port = driver::free_port()?;
+
+    let mut child = Command::new(&executable)
+        .arg(format!("--port={}", port))
+        .spawn()
+        .context(match browser.name {
+            BrowserName::Chrome => format!(
+                "Failed to start ChromeDriver ({}). Make sure Chrome is installed, or a chromedriver matching it is on the PATH.",
+                executable.display()
+            ),
+            BrowserName::Firefox => format!(
+                "Failed to start geckodriver ({}). Make sure Firefox is installed and geckodriver is on the PATH.",
+                executable.display()
+            ),
+        })?;
+
+    if !driver::wait_until_listening(port, Duration::from_secs(10)).await {
+        let _ = child.kill();
+        let _ = child.wait();
+        anyhow::bail!("{} didn't start listening on port {}", name, port);
+    }
+
+    driver::set_port(port);
+    info!("{} started successfully on port {}", name, port);
+
+    Ok(child)
+}
+
+/// Stop the browser driver subprocess, and any browser it left running
+///
+/// # Arguments
+/// * `child` - The driver process handle
+pub fn stop_webdriver(mut child: Child) {
+    info!("Stopping the browser driver...");
+    driver::kill_tree(&mut child);
+    info!("Browser driver stopped");
+}
+
+/// Run the automation once, or repeatedly in daemon mode
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `args` - Options for the run
+pub async fn run(config: &Config, args: RunArgs) -> Result<()> {
+    if args.daemon {
+        return daemon::run(
+            config,
+            args.interval,
+            args.decision_interval,
+            args.metrics,
+            args.run_as.as_deref(),
+            args.dry_run,
+        )
+        .await;
+    }
+
+    begin_run(config);
+
+    let result = with_webdriver(config, automation::run_automation(config, args.dry_run)).await;
+
+    finish_run(config, &result);
+
+    result
+}
+
+/// Whether the browser driver has to be started: not for a `lightweight`
+/// config, which never uses the browser, nor when a remote WebDriver server
+/// is set
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn needs_webdriver(config: &Config) -> bool {
+    !config.lightweight && driver::remote_webdriver_url(&config.browser).is_none()
+}
+
+/// Run a one-off command with the browser driver up for its duration, if it
+/// is needed at all
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `command` - The command to run
+pub async fn with_webdriver<T>(
+    config: &Config,
+    command: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    if !needs_webdriver(config) {
+        return command.await;
+    }
+
+    let driver_process = start_webdriver(&config.browser).await?;
+    let result = command.await;
+    stop_webdriver(driver_process);
+    result
+}
+
+/// Record the start of a run
+pub fn begin_run(config: &Config) {
+    report::begin(config);
+    events::emit(Event::RunStarted);
+    bump_counters(|counters| counters.runs += 1);
+    events::emit(Event::Counters(&State::load().counters));
+}
+
+/// Record how a run ended and notify about it
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `result` - The outcome of the run
+pub fn finish_run(config: &Config, result: &Result<()>) {
+    report_browser_traffic();
+
+    match result {
+        Ok(()) => events::emit(Event::RunFinished),
+        Err(e) => {
+            error!("Run failed: {:#}", e);
+            events::emit(Event::RunFailed {
+                error: &e.to_string(),
+            });
+        }
+    }
+
+    report_run_outcome(result);
+    report::finish(config, result);
+}
+
+/// Log and record what the run's browser sessions downloaded, the tool's own
+/// use of the metered link
+fn report_browser_traffic() {
+    let (sessions, bytes) = browser::take_traffic();
+    if sessions == 0 {
+        return;
+    }
+
+    info!(
+        "{} browser session(s) downloaded {:.1} KB this run",
+        sessions,
+        bytes as f64 / 1024.0
+    );
+    events::emit(Event::BrowserTraffic { sessions, bytes });
+    bump_counters(|counters| counters.browser_bytes += bytes);
+}
+
+/// Notify about a failed run, suppressing repeats of the same error
+///
+/// Scheduled runs that keep failing the same way (e.g. portal unreachable)
+/// only notify on the 1st, 3rd, 10th, 30th, ... consecutive occurrence, and a
+/// recovery notice is sent once a run succeeds again.
+///
+/// # Arguments
+/// * `result` - The outcome of this run
+fn report_run_outcome(result: &Result<()>) {
+    let mut state = State::load();
+
+    match result {
+        Ok(()) => {
+            if let Some(streak) = state.record_success() {
+                info!(
+                    "✓ Recovered from error after {} failed run(s)",
+                    streak.count
+                );
+                send_notification(
+                    NotificationKind::Error,
+                    "WiFi Manager Recovered ✓",
+                    &format!(
+                        "Running normally again after {} failed run(s).\nLast error: {}",
+                        streak.count, streak.message
+                    ),
+                );
+            }
+        }
+        Err(e) => {
+            let message = e.to_string();
+            let count = state.record_error(&message);
+            state.counters.failed_runs += 1;
+
+            if should_notify_error(count) {
+                send_notification(
+                    NotificationKind::Error,
+                    "WiFi Manager Error ✗",
+                    &format!("{}\n(failed {} run(s) in a row)", message, count),
+                );
+            } else {
+                info!(
+                    "Same error for {} run(s) in a row, notification suppressed",
+                    count
+                );
+            }
+        }
+    }
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
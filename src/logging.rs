@@ -0,0 +1,128 @@
+use crate::config::{LogRotation, LoggingConfig};
+use crate::container;
+use crate::state::data_dir;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use tracing::level_filters::LevelFilter;
+use tracing::{warn, Subscriber};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Level used until the config file says otherwise
+const DEFAULT_LEVEL: &str = "info";
+
+/// A console-only logger for while the config file is being read, before
+/// `init` can set up the configured one
+///
+/// # Arguments
+/// * `level` - The `--log-level` given on the command line, if any
+pub fn console_only(level: Option<&str>) -> Result<impl Subscriber> {
+    Ok(tracing_subscriber::registry()
+        .with(filter(level.unwrap_or(DEFAULT_LEVEL))?)
+        .with(console_layer()))
+}
+
+/// Send diagnostics to the console and, if enabled, to rotating log files
+///
+/// Failing to open the log directory is not fatal: logging carries on to the
+/// console only, with a warning. In a container the console is collected by
+/// the container runtime, so files are only written if `logging.dir` is set.
+///
+/// # Arguments
+/// * `config` - The `[logging]` section of the config file
+/// * `level` - The `--log-level` given on the command line, which wins over the config file
+///
+/// # Returns
+/// * A guard that writes out buffered log lines when dropped, so it must be
+///   held until the program exits
+pub fn init(config: &LoggingConfig, level: Option<&str>) -> Result<Option<WorkerGuard>> {
+    let filter = filter(level.unwrap_or(&config.level))?;
+
+    let to_files = config.file && (config.dir.is_some() || !container::detected());
+    let (writer, guard, file_error) = match to_files.then(|| open_log_files(config)) {
+        Some(Ok((writer, guard))) => (Some(writer), Some(guard), None),
+        Some(Err(e)) => (None, None, Some(e)),
+        None => (None, None, None),
+    };
+    let file_layer = writer.map(|writer| fmt::layer().with_ansi(false).with_writer(writer));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(console_layer())
+        .with(file_layer)
+        .try_init()
+        .context("Failed to set up logging")?;
+
+    if let Some(e) = file_error {
+        warn!("Logging to the console only: {:#}", e);
+    }
+
+    Ok(guard)
+}
+
+/// The console part of the log, looking like the output of an interactive run:
+/// no timestamps or module names, and colours only on a terminal
+fn console_layer<S>() -> fmt::Layer<S, DefaultFields, Format<Full, ()>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_ansi(io::stdout().is_terminal())
+}
+
+/// Open the rotating log files, written to from a background thread
+///
+/// # Arguments
+/// * `config` - The `[logging]` section of the config file
+fn open_log_files(config: &LoggingConfig) -> Result<(NonBlocking, WorkerGuard)> {
+    let dir = match &config.dir {
+        Some(dir) => dir.clone(),
+        None => log_dir()?,
+    };
+    fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+
+    let rotation = match config.rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("auto-wifi")
+        .filename_suffix("log")
+        .max_log_files(config.keep)
+        .build(&dir)
+        .context(format!("Failed to open a log file in {}", dir.display()))?;
+
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+/// Where log files go when `logging.dir` isn't set
+fn log_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("logs"))
+}
+
+/// Build the filter for a level or filter directive
+///
+/// A bare level applies to this program only, so that `debug` doesn't also
+/// turn on the debug output of the HTTP and WebDriver libraries.
+///
+/// # Arguments
+/// * `level` - e.g. `debug` or `info,auto_wifi::router=trace`
+fn filter(level: &str) -> Result<EnvFilter> {
+    let directives = match level.parse::<LevelFilter>() {
+        Ok(level) => format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level),
+        Err(_) => level.to_string(),
+    };
+
+    EnvFilter::try_new(&directives).context(format!("Invalid log level '{}'", level))
+}
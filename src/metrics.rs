@@ -0,0 +1,330 @@
+use crate::config::{Config, UsageKind};
+use crate::driver;
+use crate::satellite::{self, Submission, READINGS_PATH};
+use crate::state::{unix_now, State};
+use anyhow::{Context, Result};
+use axum::extract;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::Router;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// Content type of the Prometheus text exposition format
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// When the daemon last finished a cycle (unix seconds), see `record_cycle`
+static LAST_CYCLE: AtomicU64 = AtomicU64::new(0);
+
+/// What the endpoints need to know about the daemon
+struct Daemon {
+    /// The runtime configuration
+    config: Config,
+    /// When the daemon started (unix seconds). Until the first cycle finishes
+    /// this counts as the last one, so a fresh daemon is ready.
+    started_at: u64,
+    /// The daemon isn't ready once no cycle has finished for this long
+    ready_within: Duration,
+}
+
+/// Start serving `/metrics`, `/healthz` and `/readyz` in the background, and
+/// `/readings` if `[satellites]` is set
+///
+/// The address is bound before returning, so a port that's taken fails the
+/// daemon's startup instead of going unnoticed.
+///
+/// # Arguments
+/// * `addr` - Where to listen, e.g. `127.0.0.1:9184`
+/// * `config` - The runtime configuration
+/// * `ready_within` - How long without a finished cycle makes `/readyz` fail
+///
+/// # Returns
+/// * The address listened on
+pub async fn serve(
+    addr: SocketAddr,
+    config: &Config,
+    ready_within: Duration,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to listen on {} for metrics", addr))?;
+    let addr = listener.local_addr()?;
+
+    let daemon = Arc::new(Daemon {
+        config: config.clone(),
+        started_at: unix_now(),
+        ready_within,
+    });
+    let mut app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    if config.satellites.is_some() {
+        app = app.route(READINGS_PATH, post(readings));
+    }
+    let app = app.with_state(daemon);
+
+    info!("Serving metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("Metrics server stopped: {}", e);
+        }
+    });
+
+    Ok(addr)
+}
+
+/// `/metrics`: usage, counters and the last success, for Prometheus to scrape
+async fn metrics(extract::State(daemon): extract::State<Arc<Daemon>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+        render(&daemon.config, &State::load()),
+    )
+}
+
+/// `/readings`: usage readings from a satellite, sent as a `Submission` with
+/// the `satellites.token`; answers what was made of them
+async fn readings(
+    extract::State(daemon): extract::State<Arc<Daemon>>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    let Some(satellites) = &daemon.config.satellites else {
+        return (StatusCode::NOT_FOUND, String::new());
+    };
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !satellite::is_authorized(authorization, &satellites.token) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Wrong or missing token\n".to_string(),
+        );
+    }
+
+    let submission: Submission = match serde_json::from_str(&body) {
+        Ok(submission) => submission,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    };
+    match satellite::merge(&daemon.config, satellites.max_age_mins, &submission) {
+        Ok(merged) => (
+            StatusCode::OK,
+            serde_json::to_string(&merged).unwrap_or_default(),
+        ),
+        Err(e) => {
+            warn!(
+                "Failed to merge readings from satellite '{}': {:#}",
+                submission.source, e
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}\n", e))
+        }
+    }
+}
+
+/// Record that the daemon finished a cycle, whatever its outcome
+pub fn record_cycle() {
+    LAST_CYCLE.store(unix_now(), Ordering::Relaxed);
+}
+
+/// `/healthz`: 200 as long as the process is up and answering
+async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+/// `/readyz`: 200 while the WebDriver server answers, the config file is valid
+/// and cycles keep finishing; otherwise 503 and what is wrong, one per line
+async fn readyz(extract::State(daemon): extract::State<Arc<Daemon>>) -> impl IntoResponse {
+    let mut problems = Vec::new();
+
+    if !daemon.config.lightweight {
+        if let Err(e) = driver::check_webdriver(&daemon.config.browser).await {
+            problems.push(format!("WebDriver: {:#}", e));
+        }
+    }
+
+    if let Err(e) = daemon.config.check_file() {
+        problems.push(format!("Config: {:#}", e));
+    }
+
+    let last_cycle = LAST_CYCLE.load(Ordering::Relaxed).max(daemon.started_at);
+    let age = unix_now().saturating_sub(last_cycle);
+    if age > daemon.ready_within.as_secs() {
+        problems.push(format!("No cycle finished for {} seconds", age));
+    }
+
+    if problems.is_empty() {
+        (StatusCode::OK, "ready\n".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{}\n", problems.join("\n")),
+        )
+    }
+}
+
+/// Write the metrics in the Prometheus text format
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `state` - The persisted state the metrics are read from
+fn render(config: &Config, state: &State) -> String {
+    let mut out = String::new();
+
+    family(
+        &mut out,
+        "auto_wifi_usage",
+        "gauge",
+        "Usage last read from the portal, in minutes or megabytes",
+    );
+    for credential in &config.credentials {
+        if let Some(reading) = state.usage_cache.get(&credential.id) {
+            let unit = match credential.unit.kind() {
+                UsageKind::Time => "minutes",
+                UsageKind::Data => "megabytes",
+            };
+            let _ = writeln!(
+                out,
+                "auto_wifi_usage{{pppoe_id=\"{}\",unit=\"{}\"}} {}",
+                escape(&credential.id),
+                unit,
+                reading.minutes
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "auto_wifi_usage_checked_timestamp_seconds",
+        "gauge",
+        "When the usage of each PPPoE ID was last read",
+    );
+    for credential in &config.credentials {
+        if let Some(reading) = state.usage_cache.get(&credential.id) {
+            let _ = writeln!(
+                out,
+                "auto_wifi_usage_checked_timestamp_seconds{{pppoe_id=\"{}\"}} {}",
+                escape(&credential.id),
+                reading.checked_at
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "auto_wifi_switch_threshold",
+        "gauge",
+        "Usage above which the tool switches away from a PPPoE ID",
+    );
+    for credential in config.credentials.iter().filter(|c| !c.unlimited) {
+        let _ = writeln!(
+            out,
+            "auto_wifi_switch_threshold{{pppoe_id=\"{}\"}} {}",
+            escape(&credential.id),
+            config.thresholds_for(&credential.id).switch
+        );
+    }
+
+    family(
+        &mut out,
+        "auto_wifi_running",
+        "gauge",
+        "1 for the PPPoE ID last seen running on the router",
+    );
+    let running_id = state.last_running_id();
+    for credential in &config.credentials {
+        let _ = writeln!(
+            out,
+            "auto_wifi_running{{pppoe_id=\"{}\"}} {}",
+            escape(&credential.id),
+            u8::from(running_id == Some(credential.id.as_str()))
+        );
+    }
+
+    family(
+        &mut out,
+        "auto_wifi_disabled",
+        "gauge",
+        "1 while the connection is disabled because every ID is used up",
+    );
+    let _ = writeln!(
+        out,
+        "auto_wifi_disabled {}",
+        u8::from(state.disabled.is_some())
+    );
+
+    let counters = &state.counters;
+    for (name, help, value) in [
+        ("runs", "Runs started", counters.runs),
+        ("failed_runs", "Runs that failed", counters.failed_runs),
+        (
+            "usage_checks",
+            "Successful portal usage checks",
+            counters.usage_checks,
+        ),
+        (
+            "usage_check_failures",
+            "Portal usage checks that failed",
+            counters.usage_check_failures,
+        ),
+        (
+            "switches",
+            "Switches to another PPPoE ID",
+            counters.switches,
+        ),
+        (
+            "switch_failures",
+            "Switches that failed",
+            counters.switch_failures,
+        ),
+        (
+            "disables",
+            "Times the connection was disabled",
+            counters.disables,
+        ),
+        (
+            "browser_bytes",
+            "Bytes downloaded by the tool's browser sessions",
+            counters.browser_bytes,
+        ),
+    ] {
+        let name = format!("auto_wifi_{}_total", name);
+        family(&mut out, &name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    if let Some(last_success) = state.last_success {
+        family(
+            &mut out,
+            "auto_wifi_last_success_timestamp_seconds",
+            "gauge",
+            "When a run last completed without error",
+        );
+        let _ = writeln!(
+            out,
+            "auto_wifi_last_success_timestamp_seconds {}",
+            last_success
+        );
+    }
+
+    out
+}
+
+/// Write the HELP and TYPE lines that start a metric family
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
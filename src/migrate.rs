@@ -0,0 +1,265 @@
+//! Upgrading config and state files written by older versions, so a new
+//! binary carries on with them instead of failing to read them
+//!
+//! Both files record the version of their format as `version`. Each step
+//! below upgrades a file by one version, and the file is rewritten once all
+//! steps have run, keeping the original as `<file>.v<version>.bak`. A file
+//! from a newer version is refused rather than read in part.
+//!
+//! Config file versions:
+//! 1. Settings as `KEY=VALUE` lines, as in .env
+//! 2. TOML, as in config.example.toml (also files without a `version`)
+//!
+//! State file versions:
+//! 1. No `version`, and one router fingerprint as `router_fingerprint`
+//! 2. Router fingerprints by router IP address, as `router_fingerprints`
+
+use crate::config::{Config, CONFIG_VERSION};
+use crate::state::{data_dir, STATE_FILE_NAME, STATE_VERSION};
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The first config file version in TOML, which files without a `version` are
+const FIRST_TOML_VERSION: u32 = 2;
+
+/// A step upgrading the state by one version, given its fields and the
+/// configuration
+type StateStep = fn(&mut Map<String, Value>, &Config);
+
+/// Steps upgrading the text of a config file, the first from version 1 to 2
+const CONFIG_STEPS: &[fn(&str) -> Result<String>] = &[env_to_toml];
+
+/// Steps upgrading the state, the first from version 1 to 2
+const STATE_STEPS: &[StateStep] = &[fingerprints_by_router];
+
+/// Bring a config file up to the current version, rewriting it if it was
+/// older
+///
+/// # Arguments
+/// * `path` - The config file
+/// * `content` - What it holds
+///
+/// # Returns
+/// * The upgraded content, or `content` as it was if it is current
+pub fn config_file(path: &Path, content: String) -> Result<String> {
+    let version = config_version(&content)?;
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "It is for a newer version of auto-wifi (config version {}, this one reads up to {}). Upgrade auto-wifi.",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(content);
+    }
+
+    let mut upgraded = content.clone();
+    for step in &CONFIG_STEPS[version as usize - 1..] {
+        upgraded = step(&upgraded)?;
+    }
+    Config::from_toml(&upgraded).context("The upgraded config isn't valid")?;
+
+    let backup = keep(path, version, &content)?;
+    write_like(path, &upgraded, path)?;
+    info!(
+        "✓ Upgraded config file {} from version {} to {}; the old one is kept as {}",
+        path.display(),
+        version,
+        CONFIG_VERSION,
+        backup.display()
+    );
+
+    Ok(upgraded)
+}
+
+/// Bring the state file up to the current version, rewriting it if it was
+/// older. Some steps need the configuration, e.g. the router's address.
+///
+/// A missing or unreadable state file is left to `State::load`, which starts
+/// over.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn state_file(config: &Config) -> Result<()> {
+    let path = data_dir()?.join(STATE_FILE_NAME);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let Ok(Value::Object(mut state)) = serde_json::from_str(&content) else {
+        return Ok(());
+    };
+
+    let version = match state.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .context(format!(
+                "{} has an invalid version: {}",
+                path.display(),
+                version
+            ))?,
+    };
+    if version > STATE_VERSION {
+        anyhow::bail!(
+            "{} was written by a newer version of auto-wifi (state version {}, this one reads up to {}). Upgrade auto-wifi, or move the file away to start over.",
+            path.display(),
+            version,
+            STATE_VERSION
+        );
+    }
+    if version == STATE_VERSION {
+        return Ok(());
+    }
+
+    for step in &STATE_STEPS[version as usize - 1..] {
+        step(&mut state, config);
+    }
+    state.insert("version".to_string(), STATE_VERSION.into());
+
+    let backup = keep(&path, version, &content)?;
+    write_like(&path, &serde_json::to_string_pretty(&state)?, &path)
+        .context(format!("Failed to write state file {}", path.display()))?;
+    info!(
+        "✓ Upgraded state file {} from version {} to {}; the old one is kept as {}",
+        path.display(),
+        version,
+        STATE_VERSION,
+        backup.display()
+    );
+
+    Ok(())
+}
+
+/// Which version of the config file format a file is in
+///
+/// # Arguments
+/// * `content` - The config file
+fn config_version(content: &str) -> Result<u32> {
+    let table = match content.parse::<toml::Table>() {
+        Ok(table) => table,
+        // .env lines mostly aren't valid TOML, as their values aren't quoted
+        Err(_) if env_settings(content).contains_key("PPPOE_CREDENTIALS") => return Ok(1),
+        Err(e) => return Err(e.into()),
+    };
+
+    match table.get("version") {
+        None => Ok(FIRST_TOML_VERSION),
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= FIRST_TOML_VERSION)
+            .context(format!(
+                "version must be a whole number from {}, not {}",
+                FIRST_TOML_VERSION, version
+            )),
+    }
+}
+
+/// The `KEY=VALUE` settings of a .env file, without comments and empty values
+///
+/// # Arguments
+/// * `content` - The .env file
+fn env_settings(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+/// Config version 1 to 2: turn .env settings into a TOML config file
+///
+/// # Arguments
+/// * `content` - The .env file
+fn env_to_toml(content: &str) -> Result<String> {
+    let settings = env_settings(content);
+    let mut table = Config::settings_table(|key| settings.get(key).cloned())?
+        .context("A .env file needs ROUTER_IP, ROUTER_PASSWORD and PPPOE_CREDENTIALS")?;
+    table.insert("version".to_string(), 2.into());
+
+    Ok(format!(
+        "# Converted from .env settings by auto-wifi. See config.example.toml for the other settings.\n\n{}",
+        toml::to_string(&table)?
+    ))
+}
+
+/// State version 1 to 2: the router fingerprint, recorded before there could
+/// be several routers, belongs to the router in the config file
+///
+/// # Arguments
+/// * `state` - The state file's fields
+/// * `config` - The runtime configuration
+fn fingerprints_by_router(state: &mut Map<String, Value>, config: &Config) {
+    let Some(fingerprint) = state.remove("router_fingerprint") else {
+        return;
+    };
+    if fingerprint.is_null() {
+        return;
+    }
+
+    let fingerprints = state
+        .entry("router_fingerprints")
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let Value::Object(fingerprints) = fingerprints {
+        fingerprints
+            .entry(config.router.ip.clone())
+            .or_insert(fingerprint);
+    }
+}
+
+/// Keep a copy of a file as it was before an upgrade, next to it
+///
+/// # Arguments
+/// * `path` - The file
+/// * `version` - The version it is in
+/// * `content` - What it holds
+///
+/// # Returns
+/// * Where the copy is
+fn keep(path: &Path, version: u32, content: &str) -> Result<PathBuf> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+
+    write_like(&backup, content, path)?;
+
+    Ok(backup)
+}
+
+/// Write a file with the same permissions as another, since config and state
+/// files can hold passwords that only their owner may read
+///
+/// It is written next to its place first, so a failure leaves the old file
+/// as it was.
+///
+/// # Arguments
+/// * `path` - Where the file goes
+/// * `content` - The file
+/// * `like` - The file whose permissions it gets
+pub fn write_like(path: &Path, content: &str, like: &Path) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let permissions = fs::metadata(like)
+        .context(format!("Failed to read {}", like.display()))?
+        .permissions();
+    fs::write(&partial, content).context(format!("Failed to write {}", partial.display()))?;
+    fs::set_permissions(&partial, permissions).context(format!(
+        "Failed to set the permissions of {}",
+        partial.display()
+    ))?;
+    fs::rename(&partial, path).context(format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Noticing when this machine's network changes, such as an interface going
+//! up or down or joining another network, which is usually when the
+//! connection has just been switched or has dropped
+
+use futures::StreamExt;
+use if_watch::tokio::IfWatcher;
+use if_watch::IfEvent;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+/// How long the network has to stay quiet before a change is reported, since
+/// one change comes as a burst of events
+const SETTLE: Duration = Duration::from_secs(10);
+
+/// Watch for network changes in the background
+///
+/// The addresses present when watching starts are not a change. A change is
+/// reported once the network has settled, with what changed first.
+///
+/// # Returns
+/// * A receiver of each change, or `None` if the network can't be watched
+pub fn watch() -> Option<mpsc::Receiver<String>> {
+    let mut watcher = match IfWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Can't watch for network changes: {}", e);
+            return None;
+        }
+    };
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        // The addresses already there are reported first
+        while let Ok(Some(_)) = timeout(SETTLE, watcher.next()).await {}
+
+        loop {
+            let change = match watcher.next().await {
+                Some(Ok(event)) => describe(&event),
+                Some(Err(e)) => {
+                    warn!("Stopped watching for network changes: {}", e);
+                    return;
+                }
+                None => return,
+            };
+            debug!("Network change: {}", change);
+
+            // Wait for the rest of the burst
+            while let Ok(Some(Ok(event))) = timeout(SETTLE, watcher.next()).await {
+                debug!("Network change: {}", describe(&event));
+            }
+
+            // A change already waiting to be picked up covers this one
+            if let Err(mpsc::error::TrySendError::Closed(_)) = sender.try_send(change) {
+                return;
+            }
+        }
+    });
+
+    Some(receiver)
+}
+
+/// What a network event is, for the log
+///
+/// # Arguments
+/// * `event` - The event
+fn describe(event: &IfEvent) -> String {
+    match event {
+        IfEvent::Up(address) => format!("address {} came up", address),
+        IfEvent::Down(address) => format!("address {} went away", address),
+    }
+}
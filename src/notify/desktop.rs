@@ -0,0 +1,66 @@
+use super::Notifier;
+use crate::config::NotificationKind;
+use anyhow::Result;
+use async_trait::async_trait;
+use notify_rust::Notification;
+#[cfg(not(target_os = "windows"))]
+use std::time::Duration;
+
+/// Local desktop notifications (a toast on Windows)
+pub struct Desktop;
+
+#[async_trait]
+impl Notifier for Desktop {
+    fn name(&self) -> &'static str {
+        "desktop"
+    }
+
+    async fn send(&self, _kind: NotificationKind, title: &str, message: &str) -> Result<()> {
+        show(title, message)
+    }
+}
+
+/// Show a desktop notification
+///
+/// # Arguments
+/// * `title` - The notification title
+/// * `message` - The notification message
+#[cfg(target_os = "windows")]
+pub fn show(title: &str, message: &str) -> Result<()> {
+    // Toasts are sent under our own registered AppUserModelID, which makes
+    // them reliable without any threading workarounds
+    Notification::new()
+        .summary(title)
+        .body(message)
+        .app_id(crate::toast::APP_ID)
+        .timeout(5000) // 5 seconds
+        .show()?;
+    Ok(())
+}
+
+/// Show a desktop notification from a separate thread
+///
+/// # Arguments
+/// * `title` - The notification title
+/// * `message` - The notification message
+#[cfg(not(target_os = "windows"))]
+pub fn show(title: &str, message: &str) -> Result<()> {
+    // Convert to owned strings before spawning thread
+    let title = title.to_string();
+    let message = message.to_string();
+
+    // Send the notification in a separate thread so a slow notification
+    // daemon can't block the automation
+    std::thread::spawn(move || {
+        let _ = Notification::new()
+            .summary(&title)
+            .body(&message)
+            .appname("Auto WiFi Manager")
+            .timeout(5000) // 5 seconds
+            .show();
+    });
+
+    // Give the notification thread a moment to start (prevents race condition)
+    std::thread::sleep(Duration::from_millis(100));
+    Ok(())
+}
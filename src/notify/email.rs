@@ -0,0 +1,86 @@
+use super::Notifier;
+use crate::config::{EmailConfig, NotificationKind, SmtpSecurity};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// Email through an SMTP server
+pub struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Email {
+    /// Prepare the SMTP connection settings
+    ///
+    /// # Arguments
+    /// * `config` - The `[notifications.email]` section of the config file
+    /// * `to` - The addresses to send to
+    pub fn new(config: &EmailConfig, to: &[String]) -> Result<Self> {
+        let host = config.smtp_host.as_str();
+        let mut transport = match config.security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                .context(format!("Invalid SMTP host: {}", host))?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+                .context(format!("Invalid SMTP host: {}", host))?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+
+        if let Some(port) = config.smtp_port {
+            transport = transport.port(port);
+        }
+        if let Some(username) = &config.username {
+            let password = config.password.clone().unwrap_or_default();
+            transport = transport.credentials(Credentials::new(username.clone(), password));
+        }
+
+        let from = config
+            .from
+            .parse()
+            .context(format!("Invalid sender address: {}", config.from))?;
+        let to = to
+            .iter()
+            .map(|address| {
+                address
+                    .parse()
+                    .context(format!("Invalid recipient address: {}", address))
+            })
+            .collect::<Result<Vec<Mailbox>>>()?;
+        if to.is_empty() {
+            anyhow::bail!("No recipient addresses");
+        }
+
+        Ok(Self {
+            transport: transport.build(),
+            from,
+            to,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Email {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    async fn send(&self, _kind: NotificationKind, title: &str, message: &str) -> Result<()> {
+        let mut builder = Message::builder().from(self.from.clone()).subject(title);
+        for recipient in &self.to {
+            builder = builder.to(recipient.clone());
+        }
+        let email = builder
+            .body(message.to_string())
+            .context("Failed to build email")?;
+
+        self.transport
+            .send(email)
+            .await
+            .context("SMTP server rejected the email")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+mod desktop;
+mod email;
+mod telegram;
+mod voice;
+mod webhook;
+
+use crate::config::{
+    Language, NotificationConfig, NotificationKind, Severity, TimeWindow, UserConfig,
+};
+use crate::events::{self, Event};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Local;
+use desktop::Desktop;
+use email::Email;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use telegram::Telegram;
+use tokio::task::JoinHandle;
+use tracing::warn;
+use webhook::Webhook;
+
+pub use voice::place_calls;
+
+/// How long `flush` waits for notifications that are still being delivered
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One way of delivering notifications.
+///
+/// Each channel in the `[notifications]` section of the config file is an
+/// implementation of this; which kinds of notification it gets is decided
+/// before `send` is called.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Name of the channel for log messages, e.g. "Telegram"
+    fn name(&self) -> &'static str;
+
+    /// Deliver one notification
+    ///
+    /// # Arguments
+    /// * `kind` - What the notification is about
+    /// * `title` - The notification title
+    /// * `message` - The notification message
+    async fn send(&self, kind: NotificationKind, title: &str, message: &str) -> Result<()>;
+}
+
+/// A notifier and the kinds of notification it gets
+struct Channel {
+    notifier: Arc<dyn Notifier>,
+    /// `None` means every kind
+    events: Option<Vec<NotificationKind>>,
+    /// The user the channel belongs to, for log messages
+    user: Option<String>,
+    /// Leave out notifications less severe than this
+    min_severity: Severity,
+    /// Only send critical notifications in this window
+    quiet_hours: Option<TimeWindow>,
+    /// Language of the titles
+    language: Language,
+}
+
+impl Channel {
+    /// Route some kinds of notification to a notifier
+    ///
+    /// # Arguments
+    /// * `notifier` - How to deliver notifications
+    /// * `events` - The kinds to deliver, `None` for every kind
+    fn new(notifier: impl Notifier + 'static, events: &Option<Vec<NotificationKind>>) -> Self {
+        Self {
+            notifier: Arc::new(notifier),
+            events: events.clone(),
+            user: None,
+            min_severity: Severity::Info,
+            quiet_hours: None,
+            language: Language::En,
+        }
+    }
+
+    /// Route notifications to one of a user's notifiers, as the user prefers
+    ///
+    /// # Arguments
+    /// * `notifier` - How to deliver notifications
+    /// * `user` - The user's `[[notifications.users]]` entry
+    /// * `events` - The kinds the notifier itself is limited to, if any
+    fn for_user(
+        notifier: impl Notifier + 'static,
+        user: &UserConfig,
+        events: &Option<Vec<NotificationKind>>,
+    ) -> Self {
+        // Both lists apply when both are given
+        let events = match (&user.events, events) {
+            (Some(wanted), Some(allowed)) => Some(
+                wanted
+                    .iter()
+                    .filter(|kind| allowed.contains(kind))
+                    .copied()
+                    .collect(),
+            ),
+            (wanted, allowed) => wanted.clone().or(allowed.clone()),
+        };
+
+        Self {
+            notifier: Arc::new(notifier),
+            events,
+            user: Some(user.name.clone()),
+            min_severity: user.min_severity,
+            quiet_hours: user.quiet_hours,
+            language: user.language,
+        }
+    }
+
+    /// Whether this channel gets notifications of a kind right now
+    fn wants(&self, kind: NotificationKind) -> bool {
+        let severity = kind.severity();
+        let quiet = self
+            .quiet_hours
+            .is_some_and(|window| window.contains(Local::now().time()));
+
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(&kind))
+            && severity >= self.min_severity
+            && (!quiet || severity == Severity::Critical)
+    }
+
+    /// Where a notification went, for log messages
+    fn describe(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{} notification to {}", self.notifier.name(), user),
+            None => format!("{} notification", self.notifier.name()),
+        }
+    }
+}
+
+/// The configured channels
+static CHANNELS: OnceLock<Vec<Channel>> = OnceLock::new();
+
+/// Deliveries that haven't finished yet, see `flush`
+static PENDING: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Set up the notification channels so that `send_notification` uses them
+///
+/// Until this is called, notifications only go to the desktop.
+///
+/// # Arguments
+/// * `config` - The `[notifications]` section of the config file
+pub fn init(config: &NotificationConfig) {
+    let mut channels = Vec::new();
+
+    // A container has no desktop to show notifications on
+    if config.desktop.enabled && !crate::container::detected() {
+        channels.push(Channel::new(Desktop, &config.desktop.events));
+    }
+
+    if let Some(telegram) = &config.telegram {
+        if let Some(chat_id) = &telegram.chat_id {
+            match Telegram::new(telegram, chat_id) {
+                Ok(notifier) => channels.push(Channel::new(notifier, &telegram.events)),
+                Err(e) => warn!("Telegram notifications disabled: {}", e),
+            }
+        }
+    }
+
+    if let Some(email) = &config.email {
+        if !email.to.is_empty() {
+            match Email::new(email, &email.to) {
+                Ok(notifier) => channels.push(Channel::new(notifier, &email.events)),
+                Err(e) => warn!("Email notifications disabled: {}", e),
+            }
+        }
+    }
+
+    for webhook in &config.webhooks {
+        match Webhook::new(webhook) {
+            Ok(notifier) => channels.push(Channel::new(notifier, &webhook.events)),
+            Err(e) => warn!("Webhook {} disabled: {}", webhook.url, e),
+        }
+    }
+
+    for user in &config.users {
+        add_user_channels(&mut channels, config, user);
+    }
+
+    let _ = CHANNELS.set(channels);
+}
+
+/// Set up the channels of one of `[[notifications.users]]`
+///
+/// # Arguments
+/// * `channels` - The channels set up so far
+/// * `config` - The `[notifications]` section of the config file
+/// * `user` - The user
+fn add_user_channels(channels: &mut Vec<Channel>, config: &NotificationConfig, user: &UserConfig) {
+    if let (Some(telegram), Some(chat_id)) = (&config.telegram, &user.telegram_chat_id) {
+        match Telegram::new(telegram, chat_id) {
+            Ok(notifier) => channels.push(Channel::for_user(notifier, user, &telegram.events)),
+            Err(e) => warn!("Telegram notifications to {} disabled: {}", user.name, e),
+        }
+    }
+
+    if let Some(email) = &config.email {
+        if !user.email.is_empty() {
+            match Email::new(email, &user.email) {
+                Ok(notifier) => channels.push(Channel::for_user(notifier, user, &email.events)),
+                Err(e) => warn!("Email notifications to {} disabled: {}", user.name, e),
+            }
+        }
+    }
+
+    for webhook in &user.webhooks {
+        match Webhook::new(webhook) {
+            Ok(notifier) => channels.push(Channel::for_user(notifier, user, &webhook.events)),
+            Err(e) => warn!("Webhook {} of {} disabled: {}", webhook.url, user.name, e),
+        }
+    }
+}
+
+/// The title of a notification in a language
+///
+/// # Arguments
+/// * `language` - The language to give it in
+/// * `kind` - What the notification is about
+/// * `title` - The title, in English
+fn localize_title(language: Language, kind: NotificationKind, title: &str) -> String {
+    let heading = match language {
+        Language::En => return title.to_string(),
+        Language::Bn => match kind {
+            NotificationKind::Status => "অবস্থা",
+            NotificationKind::SwitchSucceeded => "আইডি বদলানো হয়েছে",
+            NotificationKind::SwitchFailed => "আইডি বদলানো যায়নি",
+            NotificationKind::AllExhausted => "সব আইডির কোটা শেষ",
+            NotificationKind::Disabled => "সংযোগ বন্ধ/চালু",
+            NotificationKind::Error => "ত্রুটি",
+            NotificationKind::Warning => "সতর্কতা",
+        },
+    };
+
+    format!("{}: {}", heading, title)
+}
+
+/// Send a notification to every channel configured for its kind, and to every
+/// user who wants it
+///
+/// Delivery happens in the background so a slow mail server can't hold up
+/// the automation; call `flush` before exiting so nothing is lost.
+///
+/// # Arguments
+/// * `kind` - What the notification is about
+/// * `title` - The notification title
+/// * `message` - The notification message
+pub fn send_notification(kind: NotificationKind, title: &str, message: &str) {
+    events::emit(Event::Notification { title, message });
+
+    let Some(channels) = CHANNELS.get() else {
+        if let Err(e) = desktop::show(title, message) {
+            warn!("Failed to show notification: {}", e);
+        }
+        return;
+    };
+
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        warn!("Notification '{}' not sent: no async runtime", title);
+        return;
+    };
+
+    for channel in channels.iter().filter(|channel| channel.wants(kind)) {
+        let notifier = Arc::clone(&channel.notifier);
+        let title = localize_title(channel.language, kind, title);
+        let message = message.to_string();
+        let description = channel.describe();
+
+        let delivery = runtime.spawn(async move {
+            if let Err(e) = notifier.send(kind, &title, &message).await {
+                warn!("Failed to send {}: {}", description, e);
+            }
+        });
+
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.retain(|delivery| !delivery.is_finished());
+            pending.push(delivery);
+        }
+    }
+}
+
+/// Wait for notifications still being delivered, giving up after a while
+pub async fn flush() {
+    let deliveries = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => return,
+    };
+    if deliveries.is_empty() {
+        return;
+    }
+
+    let all_delivered = async {
+        for delivery in deliveries {
+            let _ = delivery.await;
+        }
+    };
+
+    if tokio::time::timeout(FLUSH_TIMEOUT, all_delivered)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Some notifications were still being sent after {} seconds",
+            FLUSH_TIMEOUT.as_secs()
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use super::Notifier;
+use crate::config::{NotificationKind, TelegramConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Messages from a Telegram bot, through the Bot API's `sendMessage`
+pub struct Telegram {
+    client: reqwest::Client,
+    url: String,
+    chat_id: String,
+}
+
+impl Telegram {
+    /// Prepare an HTTP client for the Bot API
+    ///
+    /// # Arguments
+    /// * `config` - The `[notifications.telegram]` section of the config file
+    /// * `chat_id` - The chat to message
+    pub fn new(config: &TelegramConfig, chat_id: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        Ok(Self {
+            client,
+            url: format!(
+                "https://api.telegram.org/bot{}/sendMessage",
+                config.bot_token
+            ),
+            chat_id: chat_id.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Telegram {
+    fn name(&self) -> &'static str {
+        "Telegram"
+    }
+
+    async fn send(&self, _kind: NotificationKind, title: &str, message: &str) -> Result<()> {
+        let response: Value = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "chat_id": self.chat_id,
+                "text": format!("{}\n{}", title, message),
+            }))
+            .send()
+            .await
+            // The URL holds the bot token, so keep it out of the error
+            .map_err(|e| anyhow::anyhow!("Failed to reach the Bot API: {}", e.without_url()))?
+            .json()
+            .await
+            .context("Bot API sent an invalid response")?;
+
+        if response.get("ok").and_then(Value::as_bool) != Some(true) {
+            let description = response
+                .get("description")
+                .and_then(Value::as_str)
+                .unwrap_or("no description");
+            anyhow::bail!("Bot API refused the message: {}", description);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use crate::config::VoiceConfig;
+use anyhow::{Context, Result};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Phone every configured number and read a message out, through Twilio's
+/// Calls API
+///
+/// # Arguments
+/// * `config` - The `[notifications.voice]` section of the config file
+/// * `message` - What to say when the call is answered
+///
+/// # Returns
+/// * An error if not a single call could be placed
+pub async fn place_calls(config: &VoiceConfig, message: &str) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .context("Failed to create HTTP client")?;
+    let url = format!(
+        "https://api.twilio.com/2010-04-01/Accounts/{}/Calls.json",
+        config.account_sid
+    );
+    // Say it twice, in case the first time is missed picking up
+    let twiml = format!(
+        "<Response><Say>{0}</Say><Pause length=\"2\"/><Say>{0}</Say></Response>",
+        xml_escape(message)
+    );
+
+    let mut placed = 0;
+    for number in &config.to {
+        let response = client
+            .post(&url)
+            .basic_auth(&config.account_sid, Some(&config.auth_token))
+            .form(&[
+                ("To", number.as_str()),
+                ("From", config.from.as_str()),
+                ("Twiml", twiml.as_str()),
+            ])
+            .send()
+            .await;
+
+        match response.and_then(|response| response.error_for_status()) {
+            Ok(_) => {
+                info!("✓ Calling {}", number);
+                placed += 1;
+            }
+            Err(e) => warn!("Failed to call {}: {}", number, e),
+        }
+    }
+
+    if placed == 0 {
+        anyhow::bail!("No call could be placed");
+    }
+
+    Ok(())
+}
+
+/// Escape text for use inside an XML element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
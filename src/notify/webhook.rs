@@ -0,0 +1,58 @@
+use super::Notifier;
+use crate::config::{NotificationKind, WebhookConfig, WebhookFormat};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use std::time::Duration;
+
+/// JSON POSTed to an HTTP endpoint, e.g. a Discord or Slack incoming webhook
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    format: WebhookFormat,
+}
+
+impl Webhook {
+    /// Prepare an HTTP client for the webhook
+    ///
+    /// # Arguments
+    /// * `config` - One `[[notifications.webhooks]]` entry of the config file
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        Ok(Self {
+            client,
+            url: config.url.clone(),
+            format: config.format,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, kind: NotificationKind, title: &str, message: &str) -> Result<()> {
+        let text = format!("{}\n{}", title, message);
+        let body = match self.format {
+            WebhookFormat::Json => json!({ "kind": kind, "title": title, "message": message }),
+            WebhookFormat::Discord => json!({ "content": text }),
+            WebhookFormat::Slack => json!({ "text": text }),
+        };
+
+        self.client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
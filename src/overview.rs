@@ -0,0 +1,256 @@
+//! Every PPPoE ID's usage at a glance, as a table for the terminal or an
+//! HTML page that can be emailed as it is, colored by how much of each
+//! quota is used
+
+use crate::config::{Config, Credential};
+use crate::portal::PortalAccount;
+use chrono::{DateTime, Local, NaiveDate};
+use std::fmt::Write;
+
+/// From this share of the switch threshold on, an ID is shown as running low
+const LOW_PERCENT: u32 = 75;
+
+/// How much of its quota an ID has left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Well within its switch threshold, or unlimited
+    Fine,
+    /// Past `LOW_PERCENT` of its switch threshold
+    Low,
+    /// At or past its switch threshold
+    Exhausted,
+    /// Its usage couldn't be read
+    Unknown,
+}
+
+impl Level {
+    /// ANSI color code for the terminal
+    fn ansi(self) -> &'static str {
+        match self {
+            Self::Fine => "32",
+            Self::Low => "33",
+            Self::Exhausted => "31",
+            Self::Unknown => "90",
+        }
+    }
+
+    /// Background color for the HTML page
+    fn background(self) -> &'static str {
+        match self {
+            Self::Fine => "#d4edda",
+            Self::Low => "#fff3cd",
+            Self::Exhausted => "#f8d7da",
+            Self::Unknown => "#e2e3e5",
+        }
+    }
+}
+
+/// One PPPoE ID's line of the overview
+#[derive(Debug, Clone)]
+pub struct Row {
+    /// The PPPoE ID
+    pub pppoe_id: String,
+    /// Whether it is the one on the router
+    pub running: bool,
+    /// Its usage, formatted in its unit
+    pub usage: Option<String>,
+    /// Its switch threshold, formatted in its unit; `None` for an unlimited ID
+    pub limit: Option<String>,
+    /// How much of its switch threshold is used
+    pub percent: Option<u32>,
+    /// When its account runs out
+    pub expiry: Option<NaiveDate>,
+    /// Anything else worth knowing, e.g. that it couldn't be read
+    pub note: String,
+    /// How much it has left, which its color shows
+    pub level: Level,
+}
+
+impl Row {
+    /// The line of a PPPoE ID
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    /// * `credential` - The PPPoE ID
+    /// * `running` - Whether it is the one on the router
+    /// * `account` - What the portal showed for it, or why it couldn't be read
+    pub fn new(
+        config: &Config,
+        credential: &Credential,
+        running: bool,
+        account: Result<&PortalAccount, String>,
+    ) -> Self {
+        let pppoe_id = credential.id.clone();
+        let account = match account {
+            Ok(account) => account,
+            Err(error) => {
+                return Self {
+                    pppoe_id,
+                    running,
+                    usage: None,
+                    limit: None,
+                    percent: None,
+                    expiry: None,
+                    note: error,
+                    level: Level::Unknown,
+                }
+            }
+        };
+
+        let usage = Some(config.format_usage(&credential.id, account.total_use));
+        let expiry = account.expiry;
+        let mut note = account.status.clone().unwrap_or_default();
+
+        if credential.unlimited {
+            if note.is_empty() {
+                note = "unlimited".to_string();
+            }
+            return Self {
+                pppoe_id,
+                running,
+                usage,
+                limit: None,
+                percent: None,
+                expiry,
+                note,
+                level: Level::Fine,
+            };
+        }
+
+        let switch = config.thresholds_for(&credential.id).switch;
+        let percent = (i64::from(account.total_use.max(0)) * 100 / i64::from(switch.max(1))) as u32;
+        let level = if percent >= 100 || !account.is_usable() {
+            Level::Exhausted
+        } else if percent >= LOW_PERCENT {
+            Level::Low
+        } else {
+            Level::Fine
+        };
+
+        Self {
+            pppoe_id,
+            running,
+            usage,
+            limit: Some(config.format_usage(&credential.id, switch)),
+            percent: Some(percent),
+            expiry,
+            note,
+            level,
+        }
+    }
+
+    /// The row's cells, in the order of `HEADINGS`
+    fn cells(&self) -> [String; 6] {
+        [
+            format!("{}{}", self.pppoe_id, if self.running { " *" } else { "" }),
+            self.usage.clone().unwrap_or_else(|| "-".to_string()),
+            self.limit.clone().unwrap_or_else(|| "-".to_string()),
+            self.percent
+                .map(|percent| format!("{}%", percent))
+                .unwrap_or_else(|| "-".to_string()),
+            self.expiry
+                .map(|expiry| expiry.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.note.clone(),
+        ]
+    }
+}
+
+/// Column headings
+const HEADINGS: [&str; 6] = ["PPPoE ID", "Usage", "Switch at", "Used", "Expires", "Note"];
+
+/// The overview as a table for the terminal
+///
+/// # Arguments
+/// * `rows` - The lines of the table
+/// * `color` - Color each line by its `Level`
+pub fn text(rows: &[Row], color: bool) -> String {
+    let cells: Vec<_> = rows.iter().map(Row::cells).collect();
+    let mut widths = HEADINGS.map(|heading| heading.chars().count());
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut table = String::new();
+    let _ = writeln!(table, "{}", line(&HEADINGS.map(String::from)));
+    for (row, cells) in rows.iter().zip(&cells) {
+        if color {
+            let _ = writeln!(table, "\x1b[{}m{}\x1b[0m", row.level.ansi(), line(cells));
+        } else {
+            let _ = writeln!(table, "{}", line(cells));
+        }
+    }
+    if rows.iter().any(|row| row.running) {
+        let _ = writeln!(table, "* running on the router");
+    }
+
+    table
+}
+
+/// The overview as an HTML page, styled inline so email clients show it as
+/// it is
+///
+/// # Arguments
+/// * `rows` - The lines of the table
+/// * `generated_at` - When the usage was read
+pub fn html(rows: &[Row], generated_at: DateTime<Local>) -> String {
+    let cell_style = "padding:6px 12px;border-bottom:1px solid #ccc;text-align:left";
+    let mut page = String::new();
+
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Internet usage</title></head>\n\
+         <body style=\"font-family:sans-serif\">\n<h2>Internet usage on {}</h2>\n\
+         <table style=\"border-collapse:collapse\">\n<tr>",
+        generated_at.format("%Y-%m-%d %H:%M")
+    );
+    for heading in HEADINGS {
+        let _ = write!(page, "<th style=\"{}\">{}</th>", cell_style, heading);
+    }
+    let _ = writeln!(page, "</tr>");
+
+    for row in rows {
+        let _ = write!(
+            page,
+            "<tr style=\"background:{}{}\">",
+            row.level.background(),
+            if row.running { ";font-weight:bold" } else { "" }
+        );
+        for cell in row.cells() {
+            let _ = write!(page, "<td style=\"{}\">{}</td>", cell_style, escape(&cell));
+        }
+        let _ = writeln!(page, "</tr>");
+    }
+
+    let _ = writeln!(page, "</table>");
+    if rows.iter().any(|row| row.running) {
+        let _ = writeln!(page, "<p>* running on the router</p>");
+    }
+    let _ = writeln!(page, "</body>\n</html>");
+
+    page
+}
+
+/// Text made safe to put in HTML
+///
+/// # Arguments
+/// * `text` - The text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
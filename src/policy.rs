@@ -0,0 +1,246 @@
+use crate::config::{CandidateOrder, Config, ScheduleConfig};
+use crate::state::{State, UsageReading};
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate};
+use std::cmp::Ordering;
+use std::iter;
+
+/// Decide which PPPoE IDs to check, and in which order, when looking for one to switch to.
+///
+/// The order is set by `polling.candidate_order`, and
+/// `polling.max_candidate_checks` caps how many are checked, which keeps a run
+/// on a long credential list from taking many minutes in the worst case. Ties
+/// are broken by a fixed order, so the same state always gives the same order.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `current_index` - Index of the currently running ID in `config.credentials`
+///
+/// # Returns
+/// * Indices into `config.credentials` to check, in order, never including the current one
+pub fn candidate_order(config: &Config, current_index: usize) -> Vec<usize> {
+    let pppoe_ids = &config.credentials;
+    let mut order = following(pppoe_ids.len(), current_index);
+
+    // Sorts below are stable, so ties keep the "next" order
+    match config.polling.candidate_order {
+        CandidateOrder::Next => {}
+        CandidateOrder::Priority => {
+            order.sort_by_key(|&index| (pppoe_ids[index].priority.unwrap_or(u32::MAX), index));
+        }
+        CandidateOrder::Lru => {
+            let state = State::load();
+            order.sort_by_key(|&index| {
+                state
+                    .last_used
+                    .get(&pppoe_ids[index].id)
+                    .copied()
+                    .unwrap_or(0)
+            });
+        }
+        CandidateOrder::LeastUsed => {
+            let state = State::load();
+            // IDs never read count as unused, so they get checked
+            order.sort_by_key(|&index| {
+                state
+                    .usage_cache
+                    .get(&pppoe_ids[index].id)
+                    .map_or(0, |reading| reading.minutes)
+            });
+        }
+        CandidateOrder::RoundRobin => {
+            let state = State::load();
+            let position = state
+                .rotation_position
+                .as_deref()
+                .and_then(|last| {
+                    pppoe_ids
+                        .iter()
+                        .position(|credential| credential.id == last)
+                })
+                .unwrap_or(current_index);
+            order = following(pppoe_ids.len(), position);
+            order.retain(|&index| index != current_index);
+            if position != current_index {
+                order.push(position);
+            }
+        }
+        CandidateOrder::Balanced => {
+            let state = State::load();
+            let share_used = |index: usize| {
+                let pppoe_id = &pppoe_ids[index].id;
+                let switch = config.thresholds_for(pppoe_id).switch.max(1);
+                state.usage_cache.get(pppoe_id).map_or(0.0, |reading| {
+                    f64::from(reading.minutes) / f64::from(switch)
+                })
+            };
+            order.sort_by(|&a, &b| {
+                share_used(a)
+                    .partial_cmp(&share_used(b))
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+    }
+
+    if let Some(limit) = config.polling.max_candidate_checks {
+        order.truncate(limit);
+    }
+
+    order
+}
+
+/// How much of a PPPoE ID's switch threshold `[schedule]` allows to be used
+/// by the end of a day
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `date` - The day
+///
+/// # Returns
+/// * In minutes or MB; `None` without a schedule, or for an unlimited ID
+pub fn scheduled_allowance(config: &Config, pppoe_id: &str, date: NaiveDate) -> Option<i32> {
+    let schedule = config.schedule.as_ref()?;
+    if config.is_unlimited(pppoe_id) {
+        return None;
+    }
+
+    let (day, cycle_days) = cycle_day(schedule.cycle_start_day, date);
+    let share = scheduled_share(schedule, day, cycle_days);
+
+    Some((f64::from(config.thresholds_for(pppoe_id).switch) * share).round() as i32)
+}
+
+/// Whether a PPPoE ID has been used faster than `[schedule]` allows
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `usage` - Its usage now
+/// * `date` - Today
+///
+/// # Returns
+/// * What it may have used by the end of today, if it is past that already
+pub fn ahead_of_schedule(
+    config: &Config,
+    pppoe_id: &str,
+    usage: i32,
+    date: NaiveDate,
+) -> Option<i32> {
+    scheduled_allowance(config, pppoe_id, date).filter(|&allowed| usage > allowed)
+}
+
+/// Why a usage reading can't be right, judged against the last one: usage is
+/// never negative, and only goes down when a new billing cycle starts
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `usage` - What the portal shows now
+/// * `last` - The last reading believed, if any
+/// * `now` - When it was read
+///
+/// # Returns
+/// * The reason, if the reading is implausible
+pub fn implausible_usage(
+    config: &Config,
+    pppoe_id: &str,
+    usage: i32,
+    last: Option<&UsageReading>,
+    now: DateTime<Local>,
+) -> Option<String> {
+    if usage < 0 {
+        return Some(format!("the usage is negative ({})", usage));
+    }
+    let last = last.filter(|last| usage < last.minutes)?;
+
+    let last_read = DateTime::from_timestamp(last.checked_at as i64, 0)?
+        .with_timezone(&Local)
+        .date_naive();
+    if last_read < cycle_start(config, now.date_naive()) {
+        return None;
+    }
+
+    Some(format!(
+        "the usage went down from {} to {} within the billing cycle",
+        config.format_usage(pppoe_id, last.minutes),
+        config.format_usage(pppoe_id, usage)
+    ))
+}
+
+/// The first day of the billing cycle a date is in, which starts on
+/// `schedule.cycle_start_day`, or on the 1st without a schedule
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `date` - The date
+pub fn cycle_start(config: &Config, date: NaiveDate) -> NaiveDate {
+    let start_day = config
+        .schedule
+        .as_ref()
+        .map_or(1, |schedule| schedule.cycle_start_day);
+    let (day, _) = cycle_day(start_day, date);
+
+    date - Days::new(u64::from(day - 1))
+}
+
+/// Which day of its billing cycle a date is
+///
+/// # Arguments
+/// * `start_day` - Day of the month the cycle starts, 1-28
+/// * `date` - The date
+///
+/// # Returns
+/// * The day of the cycle, counting from 1, and how many days the cycle has
+fn cycle_day(start_day: u32, date: NaiveDate) -> (u32, u32) {
+    let start = date.with_day(start_day).unwrap_or(date);
+    let start = if date.day() >= start_day {
+        start
+    } else {
+        start.checked_sub_months(Months::new(1)).unwrap_or(start)
+    };
+    let end = start.checked_add_months(Months::new(1)).unwrap_or(start);
+
+    (
+        (date - start).num_days() as u32 + 1,
+        (end - start).num_days() as u32,
+    )
+}
+
+/// The share of the quota a schedule allows by the end of a day of the cycle,
+/// growing evenly between its points
+///
+/// # Arguments
+/// * `schedule` - The `[schedule]` section of the config file
+/// * `day` - The day of the cycle, counting from 1
+/// * `cycle_days` - How many days the cycle has
+fn scheduled_share(schedule: &ScheduleConfig, day: u32, cycle_days: u32) -> f64 {
+    // Days past the end of a short cycle count as its last day
+    let points = schedule
+        .points
+        .iter()
+        .map(|point| (point.day.min(cycle_days), f64::from(point.percent) / 100.0))
+        .chain(iter::once((cycle_days, 1.0)));
+
+    let mut previous = (0, 0.0);
+    for (point_day, share) in points {
+        if day <= point_day {
+            if point_day == previous.0 {
+                return share;
+            }
+            let progress = f64::from(day - previous.0) / f64::from(point_day - previous.0);
+            return previous.1 + (share - previous.1) * progress;
+        }
+        previous = (point_day, share);
+    }
+
+    1.0
+}
+
+/// The indices after one, wrapping around, without it
+///
+/// # Arguments
+/// * `len` - Number of PPPoE IDs
+/// * `start` - The index to start after
+fn following(len: usize, start: usize) -> Vec<usize> {
+    (1..len).map(|offset| (start + offset) % len).collect()
+}
@@ -0,0 +1,134 @@
+use super::{parse_portal_date, parse_usage, PortalAccount, PortalScraper};
+use crate::browser;
+use crate::config::{Config, HistoryPageConfig};
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use thirtyfour::prelude::*;
+use tokio::time::sleep;
+use tracing::{info, instrument, warn};
+
+/// Log in to the portal and read the account and its per-day usage history
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `username` - The username for login
+/// * `password` - The password for login
+///
+/// # Returns
+/// * The account as shown after login, and the usage of each day listed on
+///   the history page, in minutes or megabytes
+#[instrument(skip_all, fields(pppoe_id = %username))]
+pub async fn read_daily_usage(
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<(PortalAccount, BTreeMap<NaiveDate, i32>)> {
+    let history = config
+        .portal
+        .history
+        .as_ref()
+        .context("No [portal.history] section in the config file")?;
+
+    let driver = browser::connect(&config.browser, None).await?;
+
+    let result = async {
+        let account = PortalScraper::new(config)
+            .read_account(&driver, username, password)
+            .await?;
+        let days = read_history_pages(config, history, &driver).await?;
+        Ok((account, days))
+    }
+    .await;
+
+    // Close the browser whatever happened, so no session outlives the import
+    browser::quit(driver).await;
+
+    result
+}
+
+/// Read every page of the history table, following the next-page link
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `history` - The `[portal.history]` section of the config file
+/// * `driver` - A WebDriver session logged in to the portal
+async fn read_history_pages(
+    config: &Config,
+    history: &HistoryPageConfig,
+    driver: &WebDriver,
+) -> Result<BTreeMap<NaiveDate, i32>> {
+    driver
+        .goto(&history.url)
+        .await
+        .context(format!("Failed to open {}", history.url))?;
+
+    let mut days = BTreeMap::new();
+    let mut last_page_rows = Vec::new();
+
+    for page in 1..=history.max_pages {
+        let rows = read_rows(history, driver).await?;
+        // Some portals keep showing the last page when asked for the next one
+        if rows.is_empty() || rows == last_page_rows {
+            break;
+        }
+
+        for (date, usage) in &rows {
+            match parse_usage(usage, config.portal.usage_unit) {
+                Ok((amount, _)) => *days.entry(*date).or_insert(0) += amount,
+                Err(e) => warn!("Skipping history row for {}: {}", date, e),
+            }
+        }
+        info!("Read history page {} ({} row(s))", page, rows.len());
+        last_page_rows = rows;
+
+        let Some(next_page) = &history.next_page else {
+            break;
+        };
+        let Some(link) = driver
+            .find_all(By::Css(next_page.as_str()))
+            .await?
+            .into_iter()
+            .next()
+        else {
+            break;
+        };
+        link.click()
+            .await
+            .context("Failed to open the next history page")?;
+        sleep(Duration::from_secs(2)).await;
+    }
+
+    Ok(days)
+}
+
+/// Read the date and usage text of each dated row on the current page
+///
+/// # Arguments
+/// * `history` - The `[portal.history]` section of the config file
+/// * `driver` - A WebDriver session showing a history page
+async fn read_rows(
+    history: &HistoryPageConfig,
+    driver: &WebDriver,
+) -> Result<Vec<(NaiveDate, String)>> {
+    let mut rows = Vec::new();
+
+    for row in driver.find_all(By::Css(history.rows.as_str())).await? {
+        let cells = row.find_all(By::Tag("td")).await?;
+        let (Some(date_cell), Some(usage_cell)) = (
+            cells.get(history.date_column),
+            cells.get(history.usage_column),
+        ) else {
+            continue;
+        };
+
+        // Header and summary rows have no date
+        let Some(date) = parse_portal_date(date_cell.text().await?.trim()) else {
+            continue;
+        };
+        rows.push((date, usage_cell.text().await?.trim().to_string()));
+    }
+
+    Ok(rows)
+}
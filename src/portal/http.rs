@@ -0,0 +1,421 @@
+use super::{
+    no_layout_matched, parse_portal_date, read_usage, total_usage, usage_parts, ExtractionRule,
+    PortalAccount,
+};
+use crate::config::{NavigationStep, PasswordChangeConfig, PortalConfig};
+use anyhow::{Context, Result};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A form on a portal page, as a browser would submit it
+struct Form {
+    /// Where the form is submitted
+    action: Url,
+    /// `get` or `post`
+    method: String,
+    /// Name and value of every field already in the form (hidden tokens and
+    /// the like)
+    fields: Vec<(String, String)>,
+}
+
+/// Reads an account from the portal with plain HTTP requests: the login form
+/// is fetched, filled in and posted with a cookie jar, and the account table
+/// is parsed from the page that comes back.
+///
+/// This works for portals whose login form and account table are plain HTML.
+/// Portals that build either with JavaScript need the browser.
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+/// * `username` - The username for login
+/// * `password` - The password for login
+pub async fn read_account(
+    portal: &PortalConfig,
+    username: &str,
+    password: &str,
+) -> Result<PortalAccount> {
+    let client = client()?;
+    let (page_url, page) = login(&client, portal, username, password).await?;
+    let (account_url, account_page) =
+        navigate(&client, &portal.after_login, page_url, page).await?;
+
+    // Usage parts shown on pages of their own
+    let mut part_pages = HashMap::new();
+    for (name, part) in usage_parts(portal) {
+        if let Some(page) = &part.page {
+            let url = account_url
+                .join(page)
+                .context(format!("Invalid portal URL: {}", page))?;
+            part_pages.insert(name, fetch(&client, url).await?.1);
+        }
+    }
+
+    let document = Html::parse_document(&account_page);
+
+    // The first layout whose usage label is on the page is the one shown
+    let rules = ExtractionRule::all(portal);
+    let mut matched = None;
+    for rule in &rules {
+        if let Some(value) = read_row(&document, rule.usage_label)? {
+            matched = Some((rule, value));
+            break;
+        }
+    }
+    let (rule, total_use_value) = matched.ok_or_else(|| no_layout_matched(&rules))?;
+    let (total_use, usage_kind) = read_usage(portal, rule, &total_use_value, username)?;
+
+    // These rows are optional, not every portal shows them
+    let status = read_row(&document, rule.status_label)?;
+    let expiry =
+        read_row(&document, rule.expiry_label)?.and_then(|value| parse_portal_date(&value));
+    let recharge_amount = read_row(&document, rule.recharge_label)?;
+
+    let mut parts = HashMap::new();
+    for (name, part) in usage_parts(portal) {
+        let value = match part_pages.get(name) {
+            Some(page) => read_row(&Html::parse_document(page), &part.label)?,
+            None => read_row(&document, &part.label)?,
+        };
+        parts.insert(name, value);
+    }
+    let total_use = total_usage(portal, rule, total_use, usage_kind, &parts, username)?;
+
+    Ok(PortalAccount {
+        total_use,
+        usage_kind,
+        status,
+        expiry,
+        recharge_amount,
+        variant: rule.name.to_string(),
+    })
+}
+
+/// Change an account's password on the portal's change password page with
+/// plain HTTP requests, logging in with the current password first
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+/// * `change` - The `[portal.password_change]` section of the config file
+/// * `username` - The username for login
+/// * `current` - The password now
+/// * `new` - The password to change to
+pub async fn change_password(
+    portal: &PortalConfig,
+    change: &PasswordChangeConfig,
+    username: &str,
+    current: &str,
+    new: &str,
+) -> Result<()> {
+    let client = client()?;
+    login(&client, portal, username, current).await?;
+
+    let page_url =
+        Url::parse(&change.url).context(format!("Invalid change password URL: {}", change.url))?;
+    let page = client
+        .get(page_url.clone())
+        .send()
+        .await
+        .context(format!("Failed to reach {}", page_url))?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let Form {
+        action,
+        method,
+        mut fields,
+    } = find_form(&page, &page_url, &change.new_field)?;
+    let mut filled = vec![
+        (change.current_field.clone(), current),
+        (change.new_field.clone(), new),
+    ];
+    if let Some(confirm_field) = &change.confirm_field {
+        filled.push((confirm_field.clone(), new));
+    }
+    fields.retain(|(name, _)| filled.iter().all(|(field, _)| field != name));
+    fields.extend(
+        filled
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string())),
+    );
+
+    let request = if method.eq_ignore_ascii_case("get") {
+        client.get(action.clone()).query(&fields)
+    } else {
+        client.post(action.clone()).form(&fields)
+    };
+    let answer = request
+        .send()
+        .await
+        .context(format!(
+            "Failed to submit the change password form to {}",
+            action
+        ))?
+        .error_for_status()
+        .context("The portal refused the password change")?
+        .text()
+        .await?;
+
+    if let Some(success_text) = &change.success_text {
+        if !answer.contains(success_text.as_str()) {
+            anyhow::bail!(
+                "The portal didn't confirm the password change for '{}' (no \"{}\" on the page)",
+                username,
+                success_text
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// An HTTP client keeping the portal's cookies, as a logged in session needs
+fn client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .cookie_store(true)
+        .timeout(Duration::from_secs(30))
+        .build()
+        .context("Failed to create HTTP client")
+}
+
+/// Log in to the portal: the login form is fetched, filled in and posted
+///
+/// # Arguments
+/// * `client` - The client, whose cookies then hold the session
+/// * `portal` - The `[portal]` section of the config file
+/// * `username` - The username for login
+/// * `password` - The password for login
+///
+/// # Returns
+/// * Where the page shown after logging in came from, and the page
+async fn login(
+    client: &reqwest::Client,
+    portal: &PortalConfig,
+    username: &str,
+    password: &str,
+) -> Result<(Url, String)> {
+    let login_url = Url::parse(&portal.login_url)
+        .context(format!("Invalid portal login URL: {}", portal.login_url))?;
+
+    let login_page = client
+        .get(login_url.clone())
+        .send()
+        .await
+        .context(format!("Failed to reach {}", login_url))?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let Form {
+        action,
+        method,
+        mut fields,
+    } = find_form(&login_page, &login_url, &portal.password_field)?;
+    fields.retain(|(name, _)| *name != portal.username_field && *name != portal.password_field);
+    fields.push((portal.username_field.clone(), username.to_string()));
+    fields.push((portal.password_field.clone(), password.to_string()));
+
+    let request = if method.eq_ignore_ascii_case("get") {
+        client.get(action.clone()).query(&fields)
+    } else {
+        client.post(action.clone()).form(&fields)
+    };
+
+    let response = request
+        .send()
+        .await
+        .context(format!("Failed to submit the login form to {}", action))?
+        .error_for_status()?;
+    let page_url = response.url().clone();
+    let account_page = response.text().await?;
+
+    // Still seeing the login form means the portal rejected the credentials,
+    // or that the form needs JavaScript, so this is left for the browser
+    if Html::parse_document(&account_page)
+        .select(&input_selector(&portal.password_field)?)
+        .next()
+        .is_some()
+    {
+        anyhow::bail!("Portal login failed for '{}'", username);
+    }
+
+    Ok((page_url, account_page))
+}
+
+/// Go from the page shown after logging in to the account table, following
+/// `portal.after_login`
+///
+/// # Arguments
+/// * `client` - The logged in client
+/// * `steps` - The steps to follow
+/// * `page_url` - Where the page shown came from
+/// * `page` - The page shown
+///
+/// # Returns
+/// * Where the page the last step led to came from, and the page
+async fn navigate(
+    client: &reqwest::Client,
+    steps: &[NavigationStep],
+    mut page_url: Url,
+    mut page: String,
+) -> Result<(Url, String)> {
+    for step in steps {
+        let next = match step {
+            NavigationStep::Goto(url) => page_url
+                .join(url)
+                .context(format!("Invalid portal URL: {}", url))?,
+            NavigationStep::Click(css) => {
+                let document = Html::parse_document(&page);
+                let element = document
+                    .select(&selector(css)?)
+                    .next()
+                    .context(format!("Nothing matches '{}' on {}", css, page_url))?;
+                // A button that runs a script can only be clicked in the browser
+                let href = element.value().attr("href").context(format!(
+                    "'{}' on {} isn't a link, so only the browser can click it",
+                    css, page_url
+                ))?;
+                page_url
+                    .join(href)
+                    .context(format!("Invalid link on {}: {}", page_url, href))?
+            }
+        };
+
+        (page_url, page) = fetch(client, next).await?;
+    }
+
+    Ok((page_url, page))
+}
+
+/// Fetch a page of the portal
+///
+/// # Arguments
+/// * `client` - The logged in client
+/// * `url` - The page
+///
+/// # Returns
+/// * Where the page came from after any redirects, and the page
+async fn fetch(client: &reqwest::Client, url: Url) -> Result<(Url, String)> {
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .context(format!("Failed to reach {}", url))?
+        .error_for_status()?;
+    let page_url = response.url().clone();
+
+    Ok((page_url, response.text().await?))
+}
+
+/// Find the form with a field on a portal page
+///
+/// # Arguments
+/// * `html` - The page
+/// * `page_url` - Where the page was fetched from, to resolve the form action
+/// * `field` - `name` attribute of an input the form has
+fn find_form(html: &str, page_url: &Url, field: &str) -> Result<Form> {
+    let document = Html::parse_document(html);
+    let field_input = input_selector(field)?;
+    let form_selector = selector("form")?;
+    let field_selector = selector("input[name], select[name], textarea[name]")?;
+
+    let form = document
+        .select(&form_selector)
+        .find(|form| form.select(&field_input).next().is_some())
+        .context(format!("No form with a '{}' field on {}", field, page_url))?;
+
+    // A form without an action posts back to the page itself
+    let action = match form.value().attr("action") {
+        Some(action) if !action.trim().is_empty() => page_url
+            .join(action.trim())
+            .context(format!("Invalid form action: {}", action))?,
+        _ => page_url.clone(),
+    };
+    let method = form.value().attr("method").unwrap_or("post").to_string();
+
+    let fields = form
+        .select(&field_selector)
+        .filter(|field| {
+            // Unticked boxes and buttons aren't submitted by a browser either
+            let kind = field
+                .value()
+                .attr("type")
+                .unwrap_or_default()
+                .to_lowercase();
+            match kind.as_str() {
+                "checkbox" | "radio" => field.value().attr("checked").is_some(),
+                "submit" | "button" | "image" | "reset" | "file" => false,
+                _ => true,
+            }
+        })
+        .filter_map(|field| {
+            let name = field.value().attr("name")?.to_string();
+            let value = match field.value().name() {
+                "textarea" => field.text().collect(),
+                _ => field.value().attr("value").unwrap_or_default().to_string(),
+            };
+            Some((name, value))
+        })
+        .collect();
+
+    Ok(Form {
+        action,
+        method,
+        fields,
+    })
+}
+
+/// Read the value next to a label in the portal's account table.
+///
+/// # Arguments
+/// * `document` - The post-login page
+/// * `label` - Text the label cell contains (e.g. "Status"). Empty means the
+///   portal doesn't show this row.
+///
+/// # Returns
+/// * The trimmed text of the cell after the label, if the label exists
+fn read_row(document: &Html, label: &str) -> Result<Option<String>> {
+    if label.is_empty() {
+        return Ok(None);
+    }
+
+    let cell_selector = selector("td")?;
+
+    // Like the browser's XPath, only match text directly inside the cell
+    let value = document
+        .select(&cell_selector)
+        .find(|cell| {
+            cell.children()
+                .filter_map(|child| child.value().as_text())
+                .any(|text| text.contains(label))
+        })
+        .and_then(|cell| {
+            cell.next_siblings()
+                .filter_map(ElementRef::wrap)
+                .find(|sibling| sibling.value().name() == "td")
+        })
+        .map(|cell| cell.text().collect::<String>().trim().to_string());
+
+    Ok(value)
+}
+
+/// Selector for an input by its `name` attribute
+///
+/// # Arguments
+/// * `name` - The field name from the config file
+fn input_selector(name: &str) -> Result<Selector> {
+    selector(&format!(
+        "input[name=\"{}\"]",
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Parse a CSS selector
+///
+/// # Arguments
+/// * `css` - The selector
+fn selector(css: &str) -> Result<Selector> {
+    Selector::parse(css).map_err(|e| anyhow::anyhow!("Invalid CSS selector '{}': {}", css, e))
+}
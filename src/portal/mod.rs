@@ -0,0 +1,690 @@
+mod history;
+mod http;
+mod password;
+mod sessions;
+
+use crate::browser;
+use crate::config::{
+    Config, NavigationStep, NotificationKind, ParseMode, PortalClient, PortalConfig, UsageKind,
+    UsagePart, UsageUnit,
+};
+use crate::events::{self, Event};
+use crate::notify::send_notification;
+use crate::policy::implausible_usage;
+use crate::retry::{with_retry, Permanent};
+use crate::selectors::{find_element, Locator};
+use crate::state::{
+    clear_implausible_readings, record_implausible_reading, record_portal_variant, State,
+};
+use crate::storage::record_sessions;
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDate};
+use std::collections::HashMap;
+use std::time::Duration;
+use thirtyfour::prelude::*;
+use tokio::time::sleep;
+use tracing::{instrument, warn};
+
+pub use history::read_daily_usage;
+pub use password::change_password;
+
+/// What the ISP portal reports for an account
+#[derive(Debug, Clone)]
+pub struct PortalAccount {
+    /// The total use in minutes (e.g., 3577 for "3577 Minute"), or in
+    /// megabytes for data quotas
+    pub total_use: i32,
+    /// Whether `total_use` is time or data
+    pub usage_kind: UsageKind,
+    /// The account status text (e.g. "Active"), if the portal shows one
+    pub status: Option<String>,
+    /// When the account's validity runs out, if the portal shows it
+    pub expiry: Option<NaiveDate>,
+    /// What it costs to recharge the account, if the portal shows it
+    pub recharge_amount: Option<String>,
+    /// Name of the portal layout the figures were read from
+    pub variant: String,
+}
+
+impl PortalAccount {
+    /// Whether the account can actually be used to connect
+    ///
+    /// An account the portal marks as expired, suspended, etc. would leave us
+    /// without connectivity no matter how little quota it has used.
+    pub fn is_usable(&self) -> bool {
+        const UNUSABLE_STATUSES: &[&str] = &[
+            "expired",
+            "suspended",
+            "inactive",
+            "blocked",
+            "disabled",
+            "terminated",
+        ];
+
+        match &self.status {
+            Some(status) => {
+                let status = status.to_lowercase();
+                !UNUSABLE_STATUSES.iter().any(|word| status.contains(word))
+            }
+            None => true,
+        }
+    }
+}
+
+/// Name of the layout described by the main `[portal]` labels
+const DEFAULT_VARIANT: &str = "default";
+
+/// Where to find each figure on one post-login layout of the portal
+struct ExtractionRule<'a> {
+    name: &'a str,
+    usage_label: &'a str,
+    usage_unit: UsageUnit,
+    status_label: &'a str,
+    expiry_label: &'a str,
+    recharge_label: &'a str,
+}
+
+impl<'a> ExtractionRule<'a> {
+    /// The layouts to try, in order: the main labels, then each variant
+    ///
+    /// # Arguments
+    /// * `portal` - The `[portal]` section of the config file
+    fn all(portal: &'a PortalConfig) -> Vec<Self> {
+        let main = Self {
+            name: DEFAULT_VARIANT,
+            usage_label: &portal.usage_label,
+            usage_unit: portal.usage_unit,
+            status_label: &portal.status_label,
+            expiry_label: &portal.expiry_label,
+            recharge_label: &portal.recharge_label,
+        };
+
+        let variants = portal.variants.iter().map(|variant| Self {
+            name: &variant.name,
+            usage_label: &variant.usage_label,
+            usage_unit: variant.usage_unit.unwrap_or(portal.usage_unit),
+            status_label: variant
+                .status_label
+                .as_deref()
+                .unwrap_or(&portal.status_label),
+            expiry_label: variant
+                .expiry_label
+                .as_deref()
+                .unwrap_or(&portal.expiry_label),
+            recharge_label: variant
+                .recharge_label
+                .as_deref()
+                .unwrap_or(&portal.recharge_label),
+        });
+
+        std::iter::once(main).chain(variants).collect()
+    }
+}
+
+/// Error for a post-login page none of the layouts match
+///
+/// # Arguments
+/// * `rules` - The layouts that were tried
+fn no_layout_matched(rules: &[ExtractionRule]) -> anyhow::Error {
+    let labels: Vec<String> = rules
+        .iter()
+        .map(|rule| format!("'{}' ({})", rule.usage_label, rule.name))
+        .collect();
+    anyhow::anyhow!("Usage cell not found, looked for {}", labels.join(", "))
+}
+
+/// Reads accounts from an ISP usage portal, following the `[portal]` section
+/// of the config file.
+///
+/// The portal is expected to have a login form and, after logging in (and
+/// any `portal.after_login` steps), a table where each figure sits in the
+/// cell after its label (`<td>Total Use:</td><td>3577 Minute</td>`), which is
+/// the layout most ISP self-care portals use.
+pub struct PortalScraper<'a> {
+    portal: &'a PortalConfig,
+    selector_recovery: bool,
+}
+
+impl<'a> PortalScraper<'a> {
+    /// Create a scraper for the configured portal
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            portal: &config.portal,
+            selector_recovery: config.selector_recovery,
+        }
+    }
+
+    /// Log in to the portal in an open session and read the account table.
+    ///
+    /// # Arguments
+    /// * `driver` - The WebDriver session to use
+    /// * `username` - The username for login
+    /// * `password` - The password for login
+    pub async fn read_account(
+        &self,
+        driver: &WebDriver,
+        username: &str,
+        password: &str,
+    ) -> Result<PortalAccount> {
+        // Navigate to login page
+        driver.goto(&self.portal.login_url).await?;
+
+        // Find and fill in login fields
+        let username_field = find_element(
+            driver,
+            Locator::Name(&self.portal.username_field),
+            "Username field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        let password_field = find_element(
+            driver,
+            Locator::Name(&self.portal.password_field),
+            "Password field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        username_field.send_keys(username).await?;
+        password_field.send_keys(password).await?;
+
+        // Try to find and click the sign-in button
+        let sign_in_result = driver
+            .query(By::Css(self.portal.submit_button.as_str()))
+            .first()
+            .await;
+
+        match sign_in_result {
+            Ok(button) => {
+                if button.click().await.is_err() {
+                    // If click fails, submit via ENTER
+                    password_field.send_keys(Key::Enter).await?;
+                }
+            }
+            Err(_) => {
+                // No submit button found, use ENTER
+                password_field.send_keys(Key::Enter).await?;
+            }
+        }
+
+        // Wait for the post-login page to load
+        sleep(Duration::from_secs(2)).await;
+
+        // Still seeing the login form means the portal rejected the credentials
+        if !driver
+            .find_all(By::Name(self.portal.password_field.as_str()))
+            .await?
+            .is_empty()
+        {
+            return Err(Permanent(format!("Portal login failed for '{}'", username)).into());
+        }
+
+        self.navigate(driver).await?;
+
+        // The first layout whose usage label is on the page is the one shown
+        let rules = ExtractionRule::all(self.portal);
+        let mut matched = None;
+        for rule in &rules {
+            if let Some(value) = self.read_row(driver, rule.usage_label).await? {
+                matched = Some((rule, value));
+                break;
+            }
+        }
+        let (rule, total_use_value) = matched.ok_or_else(|| no_layout_matched(&rules))?;
+        let (total_use, usage_kind) = read_usage(self.portal, rule, &total_use_value, username)?;
+
+        // These rows are optional, not every portal shows them
+        let status = self.read_row(driver, rule.status_label).await?;
+        let expiry = self
+            .read_row(driver, rule.expiry_label)
+            .await?
+            .and_then(|value| parse_portal_date(&value));
+        let recharge_amount = self.read_row(driver, rule.recharge_label).await?;
+
+        // Those on the account page come first, the browser leaves it for the others
+        let parts_to_read = usage_parts(self.portal);
+        let mut parts = HashMap::new();
+        if !parts_to_read.is_empty() {
+            let account_url = driver.current_url().await?;
+            for (name, part) in parts_to_read {
+                if let Some(page) = &part.page {
+                    let url = account_url
+                        .join(page)
+                        .context(format!("Invalid portal URL: {}", page))?;
+                    driver.goto(url.as_str()).await?;
+                    sleep(Duration::from_secs(2)).await;
+                }
+                parts.insert(name, self.read_row(driver, &part.label).await?);
+            }
+        }
+        let total_use = total_usage(self.portal, rule, total_use, usage_kind, &parts, username)?;
+
+        Ok(PortalAccount {
+            total_use,
+            usage_kind,
+            status,
+            expiry,
+            recharge_amount,
+            variant: rule.name.to_string(),
+        })
+    }
+
+    /// Go from the page shown after logging in to the account table,
+    /// following `portal.after_login`
+    ///
+    /// # Arguments
+    /// * `driver` - A WebDriver session showing the post-login page
+    async fn navigate(&self, driver: &WebDriver) -> Result<()> {
+        for step in &self.portal.after_login {
+            match step {
+                NavigationStep::Goto(url) => {
+                    let url = driver
+                        .current_url()
+                        .await?
+                        .join(url)
+                        .context(format!("Invalid portal URL: {}", url))?;
+                    driver.goto(url.as_str()).await?;
+                }
+                NavigationStep::Click(css) => {
+                    driver
+                        .query(By::Css(css.as_str()))
+                        .first()
+                        .await
+                        .context(format!("Nothing matches '{}' after logging in", css))?
+                        .click()
+                        .await?;
+                }
+            }
+
+            // Wait for the page to load
+            sleep(Duration::from_secs(2)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Read the value next to a label in the portal's account table.
+    ///
+    /// # Arguments
+    /// * `driver` - A WebDriver session showing the post-login page
+    /// * `label` - Text the label cell contains (e.g. "Status"). Empty means
+    ///   the portal doesn't show this row.
+    ///
+    /// # Returns
+    /// * The trimmed text of the cell after the label, if the label exists
+    async fn read_row(&self, driver: &WebDriver, label: &str) -> Result<Option<String>> {
+        if label.is_empty() {
+            return Ok(None);
+        }
+
+        let cells = driver
+            .find_all(By::XPath(format!(
+                "//td[contains(text(), {})]/following-sibling::td[1]",
+                xpath_literal(label)
+            )))
+            .await?;
+
+        match cells.first() {
+            Some(cell) => Ok(Some(cell.text().await?.trim().to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Log in to the portal and retrieve the usage value and account status.
+///
+/// With `portal.client = "http"` the portal is read with plain HTTP requests
+/// first, and through the browser only if that fails, unless the config is
+/// `lightweight` and the failure is returned instead. Browser checks are
+/// retried as set in the `[retry]` config section, each in a new session, and
+/// also store the sessions listed on `portal.sessions.url`, if set.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `username` - The username for login
+/// * `password` - The password for login
+///
+/// # Returns
+/// * The account's total use and status. Fails if the portal rejects the login.
+#[instrument(skip_all, fields(pppoe_id = %username))]
+pub async fn get_total_use(
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<PortalAccount> {
+    let mut account = None;
+    if config.portal.client == PortalClient::Http {
+        match http::read_account(&config.portal, username, password).await {
+            Ok(read) => account = Some(read),
+            // The page was read fine, the browser would see the same
+            Err(e) if e.downcast_ref::<Permanent>().is_some() => return Err(e),
+            Err(e) if config.lightweight => return Err(e),
+            Err(e) => warn!(
+                "Reading the portal over HTTP failed, using the browser: {}",
+                e
+            ),
+        }
+    }
+    let account = match account {
+        Some(account) => account,
+        None => {
+            with_retry(&config.retry, "Portal check", async || {
+                read_account_with_browser(config, username, password).await
+            })
+            .await?
+        }
+    };
+
+    if account.usage_kind != config.quota_unit(username).kind() {
+        let (shown, configured) = match account.usage_kind {
+            UsageKind::Time => ("time", "data"),
+            UsageKind::Data => ("data", "time"),
+        };
+        return Err(Permanent(format!(
+            "The portal shows {} usage for '{}', but its configured quota is {}",
+            shown, username, configured
+        ))
+        .into());
+    }
+
+    check_plausible(config, username, account.total_use)?;
+
+    // A layout change usually means the account type changed on the ISP side
+    if let Some(previous) = record_portal_variant(username, &account.variant) {
+        warn!(
+            "Portal shows the '{}' layout for '{}' (was '{}')",
+            account.variant, username, previous
+        );
+    }
+
+    Ok(account)
+}
+
+/// Read an account one way only: no fallback, retries or checks on what was
+/// read, unlike `get_total_use`
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `client` - How to read the portal
+/// * `username` - The username for login
+/// * `password` - The password for login
+pub async fn read_account_via(
+    config: &Config,
+    client: PortalClient,
+    username: &str,
+    password: &str,
+) -> Result<PortalAccount> {
+    match client {
+        PortalClient::Http => http::read_account(&config.portal, username, password).await,
+        PortalClient::Browser => read_account_with_browser(config, username, password).await,
+    }
+}
+
+/// Fail on a usage figure that can't be right (see `implausible_usage`), so a
+/// portal glitch showing e.g. 0 mid-month isn't taken for a fact. A drop the
+/// portal keeps showing for `polling.drop_confirmations` readings in a row is
+/// believed, as after a recharge that reset the usage.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `username` - The account the figure is for
+/// * `total_use` - The figure
+fn check_plausible(config: &Config, username: &str, total_use: i32) -> Result<()> {
+    let state = State::load();
+    let last = state.usage_cache.get(username);
+    let Some(reason) = implausible_usage(config, username, total_use, last, Local::now()) else {
+        clear_implausible_readings(username);
+        return Ok(());
+    };
+
+    let in_a_row = record_implausible_reading(username);
+    if total_use >= 0 && in_a_row >= config.polling.drop_confirmations {
+        warn!(
+            "The portal showed a lower usage for '{}' {} time(s) in a row, so it is believed: {}",
+            username, in_a_row, reason
+        );
+        clear_implausible_readings(username);
+        return Ok(());
+    }
+
+    events::emit(Event::ImplausibleReading {
+        pppoe_id: username,
+        minutes: total_use,
+        last: last.map(|last| last.minutes),
+        reason: &reason,
+    });
+    anyhow::bail!(
+        "The portal's reading for '{}' is implausible: {}",
+        username,
+        reason
+    )
+}
+
+/// Log in to the portal through the browser and read the account
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `username` - The username for login
+/// * `password` - The password for login
+async fn read_account_with_browser(
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<PortalAccount> {
+    let driver = browser::connect(&config.browser, None).await?;
+
+    let result = PortalScraper::new(config)
+        .read_account(&driver, username, password)
+        .await;
+
+    // Sessions are a bonus; failing to read them doesn't fail the check
+    if let (Ok(_), Some(sessions)) = (&result, &config.portal.sessions) {
+        match sessions::read_sessions(&config.portal, sessions, &driver, username).await {
+            Ok(read) => record_sessions(&read),
+            Err(e) => warn!("Failed to read sessions of '{}': {}", username, e),
+        }
+    }
+
+    // Close the browser whatever happened, so no session outlives the check
+    browser::quit(driver).await;
+
+    result
+}
+
+/// The usage parts `portal.usage_total` adds up, those on the account page
+/// first
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+fn usage_parts(portal: &PortalConfig) -> Vec<(&str, &UsagePart)> {
+    // The config was checked on load, so the terms parse and name parts
+    let mut parts: Vec<_> = portal
+        .usage_terms()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(_, name)| portal.usage_parts.get_key_value(name))
+        .map(|(name, part)| (name.as_str(), part))
+        .collect();
+    parts.sort_by_key(|(_, part)| part.page.is_some());
+    parts.dedup_by_key(|(name, _)| *name);
+
+    parts
+}
+
+/// Work out an account's usage as `portal.usage_total` says
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+/// * `rule` - The layout the figures were read with
+/// * `usage` - The figure next to the layout's usage label
+/// * `usage_kind` - Whether that is time or data
+/// * `parts` - The text of each usage part's cell, if it was found
+/// * `username` - The account the figures are for, used in messages
+fn total_usage(
+    portal: &PortalConfig,
+    rule: &ExtractionRule,
+    usage: i32,
+    usage_kind: UsageKind,
+    parts: &HashMap<&str, Option<String>>,
+    username: &str,
+) -> Result<i32> {
+    let mut total = 0;
+    for (subtract, name) in portal.usage_terms()? {
+        let value = match portal.usage_parts.get(name) {
+            Some(part) => {
+                let text = parts.get(name).cloned().flatten().context(format!(
+                    "'{}' (usage part '{}') not found for '{}'",
+                    part.label, name, username
+                ))?;
+                let (value, kind) = parse_usage(&text, part.unit.unwrap_or(rule.usage_unit))
+                    .context(format!("Usage part '{}' of '{}'", name, username))?;
+                if kind != usage_kind {
+                    anyhow::bail!(
+                        "Usage part '{}' of '{}' isn't the same kind of usage as '{}'",
+                        name,
+                        username,
+                        rule.usage_label
+                    );
+                }
+                value
+            }
+            None => usage,
+        };
+        total += if subtract { -value } else { value };
+    }
+
+    Ok(total)
+}
+
+/// Turn the usage figure shown by the portal into minutes or megabytes,
+/// following `portal.parse_mode` for figures that aren't numbers
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+/// * `rule` - The layout the figure was read with
+/// * `value` - The text of the usage cell
+/// * `username` - The account the figure is for, used in messages
+fn read_usage(
+    portal: &PortalConfig,
+    rule: &ExtractionRule,
+    value: &str,
+    username: &str,
+) -> Result<(i32, UsageKind)> {
+    let error = match parse_usage(value, rule.usage_unit) {
+        Ok(usage) => return Ok(usage),
+        Err(e) => e,
+    };
+
+    if portal.parse_mode == ParseMode::Lenient {
+        let sentinel = portal
+            .sentinels
+            .iter()
+            .find(|(text, _)| text.trim().eq_ignore_ascii_case(value.trim()));
+        if let Some((_, &minutes)) = sentinel {
+            warn!(
+                "Portal shows '{}' as the usage of '{}', treating it as {}",
+                value, username, minutes
+            );
+            return Ok((minutes, rule.usage_unit.kind()));
+        }
+    }
+
+    send_notification(
+        NotificationKind::Error,
+        "Unexpected Portal Value ✗",
+        &format!(
+            "The portal shows '{}' as the usage of '{}', which isn't a number.\nSet portal.parse_mode = \"lenient\" and a sentinel for it to carry on.",
+            value, username
+        ),
+    );
+
+    // Reading the page again would show the same
+    Err(Permanent(format!("Unexpected usage value for '{}': {:#}", username, error)).into())
+}
+
+/// Parse the usage figure as shown by the portal into minutes or megabytes
+///
+/// The figure starts with the number, optionally followed by its unit (e.g.
+/// "3577 Minute" -> 3577 minutes, "1,234.5 Hours" -> 74070 minutes, "12.5GB"
+/// -> 12800 MB); thousands separators are ignored.
+///
+/// # Arguments
+/// * `value` - The text of the usage cell
+/// * `default_unit` - The unit to assume when the figure doesn't name one
+fn parse_usage(value: &str, default_unit: UsageUnit) -> Result<(i32, UsageKind)> {
+    let value = value.trim();
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(value.len());
+    let (amount_str, rest) = value.split_at(number_end);
+    let amount_str = amount_str.replace(',', "");
+
+    if amount_str.is_empty() {
+        anyhow::bail!("Could not parse usage value: {}", value);
+    }
+    let amount = amount_str
+        .parse::<f64>()
+        .context(format!("Failed to parse amount: {}", amount_str))?;
+
+    let unit = rest
+        .split_whitespace()
+        .next()
+        .and_then(unit_from_suffix)
+        .unwrap_or(default_unit);
+
+    Ok((unit.to_base(amount).round() as i32, unit.kind()))
+}
+
+/// Recognise the unit word after a usage figure, e.g. "Minute" or "GB"
+///
+/// # Arguments
+/// * `suffix` - The word following the number
+fn unit_from_suffix(suffix: &str) -> Option<UsageUnit> {
+    let suffix = suffix.trim_end_matches(['.', ':', ')']).to_lowercase();
+
+    match suffix.as_str() {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(UsageUnit::Seconds),
+        "min" | "mins" | "minute" | "minutes" => Some(UsageUnit::Minutes),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(UsageUnit::Hours),
+        "mb" | "mib" | "megabyte" | "megabytes" => Some(UsageUnit::Megabytes),
+        "gb" | "gib" | "gigabyte" | "gigabytes" => Some(UsageUnit::Gigabytes),
+        _ => None,
+    }
+}
+
+/// Parse a date as shown by the portal (e.g. "2024-05-31", "31/05/2024" or "31 May 2024").
+///
+/// # Arguments
+/// * `value` - The date text, possibly followed by a time
+fn parse_portal_date(value: &str) -> Option<NaiveDate> {
+    const FORMATS: &[&str] = &["%Y-%m-%d", "%d-%m-%Y", "%d/%m/%Y", "%d %b %Y", "%d %B %Y", "%b %d, %Y"];
+
+    // Try progressively shorter prefixes so a trailing time is ignored
+    let words: Vec<&str> = value.split_whitespace().collect();
+    (1..=words.len()).rev().find_map(|count| {
+        let candidate = words[..count].join(" ");
+        FORMATS
+            .iter()
+            .find_map(|format| NaiveDate::parse_from_str(&candidate, format).ok())
+    })
+}
+
+/// Quote text for use as a string literal in an XPath expression
+///
+/// # Arguments
+/// * `text` - The text to quote, e.g. a label from the config file
+fn xpath_literal(text: &str) -> String {
+    if !text.contains('\'') {
+        format!("'{}'", text)
+    } else if !text.contains('"') {
+        format!("\"{}\"", text)
+    } else {
+        // XPath 1.0 has no escapes, so splice the single quotes in
+        let parts: Vec<String> = text.split('\'').map(|part| format!("'{}'", part)).collect();
+        format!("concat({})", parts.join(", \"'\", "))
+    }
+}
@@ -0,0 +1,121 @@
+use super::{http, PortalScraper};
+use crate::browser;
+use crate::config::{Config, PasswordChangeConfig, PortalClient};
+use crate::selectors::{find_element, Locator};
+use anyhow::{Context, Result};
+use std::time::Duration;
+use thirtyfour::prelude::*;
+use tokio::time::sleep;
+use tracing::instrument;
+
+/// Change an account's password on the portal's change password page
+///
+/// With `portal.client = "http"` the form is filled in with plain HTTP
+/// requests, otherwise through the browser. Unlike reading the account,
+/// neither falls back to the other or is retried: a form that was submitted
+/// may have changed the password even if the answer didn't say so.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `username` - The username for login
+/// * `current` - The password now
+/// * `new` - The password to change to
+#[instrument(skip_all, fields(pppoe_id = %username))]
+pub async fn change_password(
+    config: &Config,
+    username: &str,
+    current: &str,
+    new: &str,
+) -> Result<()> {
+    let change = config.portal.password_change.as_ref().context(
+        "The portal's change password page isn't configured; set [portal.password_change]",
+    )?;
+
+    match config.portal.client {
+        PortalClient::Http => {
+            http::change_password(&config.portal, change, username, current, new).await
+        }
+        PortalClient::Browser => change_with_browser(config, change, username, current, new).await,
+    }
+}
+
+/// Log in to the portal through the browser and fill in the change password
+/// form
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `change` - The `[portal.password_change]` section of the config file
+/// * `username` - The username for login
+/// * `current` - The password now
+/// * `new` - The password to change to
+async fn change_with_browser(
+    config: &Config,
+    change: &PasswordChangeConfig,
+    username: &str,
+    current: &str,
+    new: &str,
+) -> Result<()> {
+    let driver = browser::connect(&config.browser, None).await?;
+
+    let result = async {
+        // Reading the account is what logs in
+        PortalScraper::new(config)
+            .read_account(&driver, username, current)
+            .await?;
+        driver
+            .goto(&change.url)
+            .await
+            .context(format!("Failed to open {}", change.url))?;
+
+        // No selector recovery: a guessed field could take the wrong password
+        let mut fields = vec![
+            (change.current_field.as_str(), current, "Current password field"),
+            (change.new_field.as_str(), new, "New password field"),
+        ];
+        if let Some(confirm_field) = &change.confirm_field {
+            fields.push((confirm_field.as_str(), new, "Confirm password field"));
+        }
+        let mut last_field = None;
+        for (name, value, description) in fields {
+            let field = find_element(&driver, Locator::Name(name), description, false).await?;
+            field.clear().await?;
+            field.send_keys(value).await?;
+            last_field = Some(field);
+        }
+
+        let submit_button = change
+            .submit_button
+            .as_deref()
+            .unwrap_or(&config.portal.submit_button);
+        match driver.query(By::Css(submit_button)).first().await {
+            Ok(button) => button.click().await?,
+            // No submit button found, use ENTER
+            Err(_) => {
+                if let Some(field) = last_field {
+                    field.send_keys(Key::Enter).await?;
+                }
+            }
+        }
+
+        // Wait for the answer to load
+        sleep(Duration::from_secs(2)).await;
+
+        if let Some(success_text) = &change.success_text {
+            if !driver.source().await?.contains(success_text.as_str()) {
+                anyhow::bail!(
+                    "The portal didn't confirm the password change for '{}' (no \"{}\" on the page)",
+                    username,
+                    success_text
+                );
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Close the browser whatever happened, so no session outlives the change
+    browser::quit(driver).await;
+
+    result
+}
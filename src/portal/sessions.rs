@@ -0,0 +1,90 @@
+use super::parse_usage;
+use crate::config::{PortalConfig, SessionPageConfig};
+use crate::storage::Session;
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDateTime};
+use thirtyfour::prelude::*;
+
+/// Read the sessions listed on the first page of the portal's sessions page
+///
+/// # Arguments
+/// * `portal` - The `[portal]` section of the config file
+/// * `sessions` - The `[portal.sessions]` section of the config file
+/// * `driver` - A WebDriver session logged in to the portal
+/// * `username` - The account the sessions are for
+pub async fn read_sessions(
+    portal: &PortalConfig,
+    sessions: &SessionPageConfig,
+    driver: &WebDriver,
+    username: &str,
+) -> Result<Vec<Session>> {
+    driver
+        .goto(&sessions.url)
+        .await
+        .context(format!("Failed to open {}", sessions.url))?;
+
+    let mut read = Vec::new();
+    for row in driver.find_all(By::Css(sessions.rows.as_str())).await? {
+        let mut cells = Vec::new();
+        for cell in row.find_all(By::Tag("td")).await? {
+            cells.push(cell.text().await?.trim().to_string());
+        }
+        let column = |index: Option<usize>| index.and_then(|index| cells.get(index));
+
+        // Header and summary rows have no start time
+        let Some(start) = column(Some(sessions.start_column)).and_then(|cell| parse_time(cell))
+        else {
+            continue;
+        };
+        let end = column(sessions.end_column).and_then(|cell| parse_time(cell));
+
+        let minutes = match (column(sessions.usage_column), end) {
+            (Some(usage), _) => match parse_usage(usage, portal.usage_unit) {
+                Ok((minutes, _)) => minutes,
+                Err(_) => continue,
+            },
+            (None, Some(end)) => ((end - start).max(0) / 60) as i32,
+            // Still running, and the duration is the only usage there is
+            (None, None) => continue,
+        };
+
+        read.push(Session {
+            pppoe_id: username.to_string(),
+            start,
+            end,
+            minutes,
+            ip: column(sessions.ip_column)
+                .filter(|ip| !ip.is_empty())
+                .cloned(),
+        });
+    }
+
+    Ok(read)
+}
+
+/// Parse a date and time as shown by the portal (e.g. "2024-05-31 21:04:10"),
+/// in local time
+///
+/// # Arguments
+/// * `value` - The date and time text
+///
+/// # Returns
+/// * Unix seconds
+fn parse_time(value: &str) -> Option<i64> {
+    const FORMATS: &[&str] = &[
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%d-%m-%Y %H:%M:%S",
+        "%d-%m-%Y %H:%M",
+        "%d/%m/%Y %H:%M:%S",
+        "%d/%m/%Y %H:%M",
+        "%d %b %Y %H:%M:%S",
+        "%d %b %Y %H:%M",
+    ];
+
+    FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .map(|time| time.timestamp())
+}
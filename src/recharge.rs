@@ -0,0 +1,145 @@
+use crate::config::Config;
+use crate::state::State;
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::timeout;
+use tracing::{info, instrument, warn};
+
+/// Why an account needs recharging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// It expires within `alerts.expiry_reminder_days`
+    Expiring,
+    /// It has expired
+    Expired,
+    /// It is over its switch threshold
+    Exhausted,
+}
+
+impl Reason {
+    /// How the reason is passed to the recharge command
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Expiring => "expiring",
+            Self::Expired => "expired",
+            Self::Exhausted => "exhausted",
+        }
+    }
+}
+
+/// The recharge page for an account, if `recharge.url` is set
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+pub fn url(config: &Config, pppoe_id: &str) -> Option<String> {
+    config
+        .recharge
+        .url
+        .as_ref()
+        .map(|url| url.replace("{id}", pppoe_id))
+}
+
+/// A line to end a notification about an account with, pointing to where it
+/// can be recharged; empty if `recharge.url` isn't set
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+pub fn link(config: &Config, pppoe_id: &str) -> String {
+    url(config, pppoe_id)
+        .map(|url| format!("\nRecharge: {}", url))
+        .unwrap_or_default()
+}
+
+/// Run `recharge.command` for an account, at most once a day per account.
+///
+/// The command gets `AUTO_WIFI_REASON` (`expiring`, `expired` or
+/// `exhausted`), `AUTO_WIFI_PPPOE_ID`, `AUTO_WIFI_PPPOE_PASSWORD` (which is
+/// also the portal password) and, if set, `AUTO_WIFI_RECHARGE_URL`. It failing
+/// is logged, nothing more.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID
+/// * `reason` - Why it needs recharging
+#[instrument(skip_all, fields(pppoe_id = %pppoe_id))]
+pub async fn run_command(config: &Config, pppoe_id: &str, reason: Reason) {
+    let Some(command) = &config.recharge.command else {
+        return;
+    };
+
+    let today = Local::now().date_naive();
+    let mut state = State::load();
+    let details = state.accounts.entry(pppoe_id.to_string()).or_default();
+    if details.recharge_command_on == Some(today) {
+        return;
+    }
+    details.recharge_command_on = Some(today);
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+
+    info!(
+        "Running the recharge command for '{}' ({})...",
+        pppoe_id,
+        reason.as_str()
+    );
+    match run(config, command, pppoe_id, reason).await {
+        Ok(()) => info!("✓ Recharge command for '{}' finished", pppoe_id),
+        Err(e) => warn!("Recharge command for '{}' failed: {:#}", pppoe_id, e),
+    }
+}
+
+/// Run the recharge command and wait for it, up to `recharge.timeout_secs`
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `command` - The program to run
+/// * `pppoe_id` - The PPPoE ID
+/// * `reason` - Why it needs recharging
+async fn run(config: &Config, command: &str, pppoe_id: &str, reason: Reason) -> Result<()> {
+    let password = config
+        .credentials
+        .iter()
+        .find(|credential| credential.id == pppoe_id)
+        .map(|credential| credential.password.as_str())
+        .unwrap_or_default();
+
+    let mut process = Command::new(command);
+    process
+        .env("AUTO_WIFI_REASON", reason.as_str())
+        .env("AUTO_WIFI_PPPOE_ID", pppoe_id)
+        .env("AUTO_WIFI_PPPOE_PASSWORD", password)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if let Some(url) = url(config, pppoe_id) {
+        process.env("AUTO_WIFI_RECHARGE_URL", url);
+    }
+
+    let mut child = process
+        .spawn()
+        .context(format!("Failed to run {}", command))?;
+
+    let limit = Duration::from_secs(config.recharge.timeout_secs);
+    let status = match timeout(limit, child.wait()).await {
+        Ok(status) => status.context(format!("Failed to wait for {}", command))?,
+        Err(_) => {
+            let _ = child.kill().await;
+            anyhow::bail!(
+                "{} didn't finish within {} seconds",
+                command,
+                limit.as_secs()
+            );
+        }
+    };
+
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", command, status);
+    }
+
+    Ok(())
+}
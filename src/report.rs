@@ -0,0 +1,237 @@
+//! A JSON report of each run, written to the data directory: what the run
+//! started from, the usage it read, what it decided and did, when, and what
+//! went wrong. Reports are kept for `history.report_days`.
+
+use crate::config::Config;
+use crate::state::{data_dir, State};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+/// Directory (in the data directory) the reports are written to
+pub const REPORTS_DIR_NAME: &str = "reports";
+
+/// Events that are a usage reading
+const READINGS: &[&str] = &["usage_checked"];
+
+/// Events that are what a run decided, the last of which stands
+const DECISIONS: &[&str] = &[
+    "within_limit",
+    "early_switch_due",
+    "switch_started",
+    "all_ids_exhausted",
+    "disable_deferred",
+    "connection_disabled",
+    "connection_re_enabled",
+];
+
+/// Events that are a change made on the router
+const ACTIONS: &[&str] = &[
+    "switch_succeeded",
+    "switch_failed",
+    "connection_disabled",
+    "disable_failed",
+    "connection_re_enabled",
+];
+
+/// Events that are something going wrong
+const ERRORS: &[&str] = &[
+    "usage_check_failed",
+    "implausible_reading",
+    "switch_failed",
+    "disable_failed",
+    "run_failed",
+];
+
+/// The run in progress
+struct Run {
+    started_at: DateTime<Local>,
+    started: Instant,
+    /// What the run started from
+    inputs: Value,
+    /// Every event emitted so far, with the milliseconds since the start
+    events: Vec<Value>,
+}
+
+/// The run in progress, if any
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+/// Start collecting the report of a run
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub fn begin(config: &Config) {
+    if config.history.report_days == 0 {
+        return;
+    }
+
+    let run = Run {
+        started_at: Local::now(),
+        started: Instant::now(),
+        inputs: inputs(config),
+        events: Vec::new(),
+    };
+    if let Ok(mut current) = RUN.lock() {
+        *current = Some(run);
+    }
+}
+
+/// Add an event to the report of the run in progress, if any
+///
+/// # Arguments
+/// * `event` - The event, as written to the event log
+pub fn add_event(event: &Value) {
+    if let Ok(mut current) = RUN.lock() {
+        if let Some(run) = current.as_mut() {
+            let mut event = event.clone();
+            event["elapsed_ms"] = json!(run.started.elapsed().as_millis() as u64);
+            run.events.push(event);
+        }
+    }
+}
+
+/// Write the report of the run in progress and delete the ones past
+/// `history.report_days`, warning instead of failing
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `result` - The outcome of the run
+pub fn finish(config: &Config, result: &Result<()>) {
+    let Some(run) = RUN.lock().ok().and_then(|mut current| current.take()) else {
+        return;
+    };
+
+    match write(run, result) {
+        Ok(path) => debug!("Wrote run report {}", path),
+        Err(e) => warn!("Failed to write run report: {:#}", e),
+    }
+    if let Err(e) = prune(config.history.report_days) {
+        warn!("Failed to delete old run reports: {:#}", e);
+    }
+}
+
+/// What a run starts from: the limits and what was last known of each ID
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+fn inputs(config: &Config) -> Value {
+    let state = State::load();
+
+    let credentials: Vec<Value> = config
+        .credentials
+        .iter()
+        .map(|credential| {
+            let thresholds = config.thresholds_for(&credential.id);
+            json!({
+                "pppoe_id": credential.id,
+                "unlimited": credential.unlimited,
+                "switch": thresholds.switch,
+                "available": thresholds.available,
+                "disable": thresholds.disable,
+                "last_reading": state.usage_cache.get(&credential.id),
+            })
+        })
+        .collect();
+
+    json!({
+        "running_id": state.last_running_id(),
+        "disabled": state.disabled,
+        "credentials": credentials,
+    })
+}
+
+/// Write a run's report
+///
+/// # Arguments
+/// * `run` - The run
+/// * `result` - Its outcome
+///
+/// # Returns
+/// * Where it was written
+fn write(run: Run, result: &Result<()>) -> Result<String> {
+    let dir = data_dir()?.join(REPORTS_DIR_NAME);
+    fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+
+    let of_kinds = |kinds: &[&str]| -> Vec<Value> {
+        run.events
+            .iter()
+            .filter(|event| {
+                event["event"]
+                    .as_str()
+                    .is_some_and(|kind| kinds.contains(&kind))
+            })
+            .cloned()
+            .collect()
+    };
+
+    let mut report = Map::new();
+    report.insert("started_at".into(), json!(run.started_at.to_rfc3339()));
+    report.insert("finished_at".into(), json!(Local::now().to_rfc3339()));
+    report.insert(
+        "duration_ms".into(),
+        json!(run.started.elapsed().as_millis() as u64),
+    );
+    report.insert(
+        "outcome".into(),
+        json!(if result.is_ok() {
+            "succeeded"
+        } else {
+            "failed"
+        }),
+    );
+    report.insert(
+        "error".into(),
+        json!(result.as_ref().err().map(|e| format!("{:#}", e))),
+    );
+    report.insert("inputs".into(), run.inputs.clone());
+    report.insert("readings".into(), json!(of_kinds(READINGS)));
+    report.insert("decision".into(), json!(of_kinds(DECISIONS).pop()));
+    report.insert("actions".into(), json!(of_kinds(ACTIONS)));
+    report.insert("errors".into(), json!(of_kinds(ERRORS)));
+    report.insert(
+        "browser_traffic".into(),
+        json!(of_kinds(&["browser_traffic"]).pop()),
+    );
+    report.insert("events".into(), json!(run.events));
+
+    let path = dir.join(format!(
+        "run-{}.json",
+        run.started_at.format("%Y%m%d-%H%M%S")
+    ));
+    fs::write(&path, serde_json::to_string_pretty(&report)?)
+        .context(format!("Failed to write {}", path.display()))?;
+
+    Ok(path.display().to_string())
+}
+
+/// Delete the reports older than `report_days`
+///
+/// # Arguments
+/// * `report_days` - How many days reports are kept
+fn prune(report_days: u32) -> Result<()> {
+    let dir = data_dir()?.join(REPORTS_DIR_NAME);
+    let Some(cutoff) =
+        SystemTime::now().checked_sub(Duration::from_secs(u64::from(report_days) * 86400))
+    else {
+        return Ok(());
+    };
+
+    for entry in fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !(name.starts_with("run-") && name.ends_with(".json")) {
+            continue;
+        }
+
+        if entry.metadata()?.modified()? < cutoff {
+            fs::remove_file(entry.path())
+                .context(format!("Failed to delete {}", entry.path().display()))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use crate::config::RetryConfig;
+use anyhow::Result;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::warn;
+
+/// An error that retrying won't fix, such as rejected credentials.
+///
+/// `with_retry` gives up straight away when an operation fails with one of
+/// these anywhere in its error chain.
+#[derive(Debug)]
+pub struct Permanent(pub String);
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+/// Run an operation, retrying it with exponential backoff if it fails.
+///
+/// The operation should start from scratch on every attempt, e.g. open a new
+/// WebDriver session, so that a session ChromeDriver lost mid-run doesn't
+/// fail every attempt the same way.
+///
+/// # Arguments
+/// * `config` - How often and how long to retry
+/// * `what` - Human readable name of the operation, used in messages
+/// * `operation` - The operation to run
+///
+/// # Returns
+/// * The result of the first successful attempt, or the last error
+pub async fn with_retry<T>(
+    config: &RetryConfig,
+    what: &str,
+    mut operation: impl AsyncFnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+
+    loop {
+        let error = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        if attempt >= config.max_attempts || error.downcast_ref::<Permanent>().is_some() {
+            return Err(error);
+        }
+
+        let delay = backoff_delay(config, attempt);
+        warn!(
+            "{} failed (attempt {}/{}): {}. Retrying in {:.1} seconds...",
+            what,
+            attempt,
+            config.max_attempts,
+            error,
+            delay.as_secs_f64()
+        );
+        sleep(delay).await;
+
+        attempt += 1;
+    }
+}
+
+/// How long to wait after a failed attempt
+///
+/// # Arguments
+/// * `config` - How often and how long to retry
+/// * `attempt` - The attempt that just failed, starting at 1
+fn backoff_delay(config: &RetryConfig, attempt: u32) -> Duration {
+    let exponential = config
+        .initial_delay_secs
+        .saturating_mul(2u64.saturating_pow(attempt - 1))
+        .min(config.max_delay_secs);
+
+    Duration::from_secs(exponential).mul_f64(1.0 + config.jitter * (2.0 * random_unit() - 1.0))
+}
+
+/// A random number in `[0, 1)`, good enough to spread out retries
+fn random_unit() -> f64 {
+    // Every RandomState is keyed differently, so hashing nothing differs too
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,279 @@
+use super::{PppoeFields, RouterBackend};
+use crate::browser;
+use crate::config::{Config, NotificationKind};
+use crate::notify::send_notification;
+use crate::selectors::{find_element, locate, Locator};
+use crate::state::{RouterFingerprint, State};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::time::Duration;
+use thirtyfour::prelude::*;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+/// The D-Link web interface (`/info/Login.html`, `/Internet.html`) this tool
+/// was first written for, driven through the browser
+pub struct DLink {
+    driver: WebDriver,
+    router_ip: String,
+    selector_recovery: bool,
+    /// Whether the login page has been compared against the last run's yet
+    fingerprinted: bool,
+}
+
+impl DLink {
+    /// Open a browser session for the router
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    /// * `proxy` - Proxy through which the router is reached, if any
+    pub async fn connect(config: &Config, proxy: Option<&str>) -> Result<Self> {
+        let driver = browser::connect(&config.browser, proxy).await?;
+
+        Ok(Self {
+            driver,
+            router_ip: config.router.ip.clone(),
+            selector_recovery: config.selector_recovery,
+            fingerprinted: false,
+        })
+    }
+}
+
+#[async_trait]
+impl RouterBackend for DLink {
+    async fn login(&mut self, password: &str) -> Result<bool> {
+        // Navigate to router login page
+        self.driver
+            .goto(&format!("http://{}/info/Login.html", self.router_ip))
+            .await?;
+
+        if !self.fingerprinted {
+            self.fingerprinted = true;
+            if let Err(e) = check_router_fingerprint(&self.driver, &self.router_ip).await {
+                warn!("Could not fingerprint router login page: {}", e);
+            }
+        }
+
+        let password_field = find_element(
+            &self.driver,
+            Locator::Id("admin_Password"),
+            "Router password field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        password_field.send_keys(password).await?;
+
+        let login_button = find_element(
+            &self.driver,
+            Locator::Id("logIn_btn"),
+            "Login button",
+            self.selector_recovery,
+        )
+        .await?;
+
+        login_button.click().await?;
+
+        // Wait for login to complete
+        sleep(Duration::from_secs(2)).await;
+
+        // Still seeing the password field means the router rejected the password
+        Ok(self
+            .driver
+            .find_all(By::Id("admin_Password"))
+            .await?
+            .is_empty())
+    }
+
+    async fn current_pppoe_id(&mut self) -> Result<String> {
+        // Navigate to status page
+        self.driver
+            .goto(&format!("http://{}/Internet.html", self.router_ip))
+            .await?;
+
+        // Wait for page to fully load
+        sleep(Duration::from_secs(2)).await;
+
+        // Find the PPPoE ID field and get its value
+        let pppoe_id_field = find_element(
+            &self.driver,
+            Locator::Name("userName_PPPoE"),
+            "PPPoE username field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        Ok(pppoe_id_field.value().await?.unwrap_or_default())
+    }
+
+    async fn current_pppoe_credentials(&mut self) -> Result<(String, String)> {
+        self.driver
+            .goto(&format!("http://{}/Internet.html", self.router_ip))
+            .await?;
+
+        // Wait for page to fully load
+        sleep(Duration::from_secs(2)).await;
+
+        let pppoe_id_field = find_element(
+            &self.driver,
+            Locator::Name("userName_PPPoE"),
+            "PPPoE username field",
+            self.selector_recovery,
+        )
+        .await?;
+        let pppoe_password_field = find_element(
+            &self.driver,
+            Locator::Name("password_PPPoE"),
+            "PPPoE password field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        Ok((
+            pppoe_id_field.value().await?.unwrap_or_default(),
+            pppoe_password_field.value().await?.unwrap_or_default(),
+        ))
+    }
+
+    async fn pppoe_fields(&mut self) -> Result<PppoeFields> {
+        self.driver
+            .goto(&format!("http://{}/Internet.html", self.router_ip))
+            .await?;
+
+        // Wait for page to fully load
+        sleep(Duration::from_secs(2)).await;
+
+        let username = locate(&self.driver, Locator::Name("userName_PPPoE"))
+            .await?
+            .context("No PPPoE username field on the router's Internet page")?;
+        let password = locate(&self.driver, Locator::Name("password_PPPoE"))
+            .await?
+            .context("No PPPoE password field on the router's Internet page")?;
+
+        Ok(PppoeFields {
+            running_id: username.element.value().await?.unwrap_or_default(),
+            needs_recovery: username.renamed || password.renamed,
+            username: username.selector,
+            password: password.selector,
+            wan_interface: None,
+        })
+    }
+
+    async fn set_pppoe_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<()> {
+        // Navigate to PPPoE settings page
+        self.driver
+            .goto(&format!("http://{}/Internet.html", self.router_ip))
+            .await?;
+
+        // Find and fill in the PPPoE ID and password fields
+        let pppoe_id_field = find_element(
+            &self.driver,
+            Locator::Name("userName_PPPoE"),
+            "PPPoE username field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        sleep(Duration::from_secs(2)).await;
+
+        let pppoe_password_field = find_element(
+            &self.driver,
+            Locator::Name("password_PPPoE"),
+            "PPPoE password field",
+            self.selector_recovery,
+        )
+        .await?;
+
+        pppoe_id_field.clear().await?;
+        pppoe_id_field.send_keys(pppoe_id).await?;
+
+        sleep(Duration::from_secs(2)).await;
+
+        pppoe_password_field.clear().await?;
+        debug!("done_first");
+        pppoe_password_field.send_keys(password).await?;
+
+        // Submit the changes
+        let submit_button = find_element(
+            &self.driver,
+            Locator::Id("Save_btn"),
+            "Submit button",
+            self.selector_recovery,
+        )
+        .await?;
+
+        submit_button.click().await?;
+
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        // Saving makes the router redial by itself; wait for it to apply
+        // the changes and reconnect
+        sleep(Duration::from_secs(35)).await;
+        Ok(())
+    }
+
+    async fn close(self: Box<Self>) {
+        browser::quit(self.driver).await;
+    }
+}
+
+/// Compare the router's login page against the one seen on the previous run.
+///
+/// A firmware update is the most common reason the element IDs this tool
+/// relies on stop matching, so a changed page title or firmware version string
+/// is reported before anything has a chance to fail.
+///
+/// # Arguments
+/// * `driver` - A WebDriver session currently showing the router login page
+/// * `router_ip` - The router's address, to tell routers apart
+async fn check_router_fingerprint(driver: &WebDriver, router_ip: &str) -> Result<()> {
+    let title = driver.title().await?.trim().to_string();
+
+    // Collect any text that looks like a firmware/version string
+    let mut firmware_parts = Vec::new();
+    for element in driver
+        .find_all(By::XPath(
+            "//*[contains(text(), 'Firmware') or contains(text(), 'firmware') or contains(text(), 'Version')]",
+        ))
+        .await?
+        .iter()
+        .take(3)
+    {
+        let text = element.text().await?;
+        if !text.trim().is_empty() {
+            firmware_parts.push(text.trim().to_string());
+        }
+    }
+
+    let fingerprint = RouterFingerprint {
+        title,
+        firmware: firmware_parts.join(" | "),
+    };
+
+    let mut state = State::load();
+    if let Some(previous) = state.router_fingerprints.get(router_ip) {
+        if *previous == fingerprint {
+            return Ok(());
+        }
+
+        warn!(
+            "Router login page on {} changed: '{}' / '{}' -> '{}' / '{}'",
+            router_ip, previous.title, previous.firmware, fingerprint.title, fingerprint.firmware
+        );
+        send_notification(
+            NotificationKind::Warning,
+            "Router Firmware Changed? ⚠",
+            &format!(
+                "The login page of the router at {} looks different since the last run.\nBefore: {} {}\nNow: {} {}\nIf switching fails, the page selectors may need updating.",
+                router_ip, previous.title, previous.firmware, fingerprint.title, fingerprint.firmware
+            ),
+        );
+    }
+
+    state
+        .router_fingerprints
+        .insert(router_ip.to_string(), fingerprint);
+    state.save()
+}
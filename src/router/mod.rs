@@ -0,0 +1,727 @@
+mod dlink;
+mod openwrt;
+pub mod upnp;
+
+use crate::config::{Config, NotificationKind, RouterModel};
+use crate::notify::send_notification;
+use crate::retry::{with_retry, Permanent};
+use crate::state::{remember_router_password, remembered_router_password, State};
+use crate::tunnel::SshTunnel;
+use crate::vpn::{self, WireGuardSession};
+use crate::wanip;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use dlink::DLink;
+use openwrt::OpenWrt;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{info, instrument, warn};
+
+/// Password set on the router to keep it from connecting once every ID is used up
+pub const DISABLED_PASSWORD: &str = "DISABLED_EXCEEDED_LIMIT";
+
+/// How often to look again while waiting for the connection after a switch
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A switch the router was told to make, but that didn't end in a working
+/// connection
+#[derive(Debug)]
+pub struct SwitchNotVerified {
+    /// The PPPoE ID that was switched to
+    pub pppoe_id: String,
+    /// What the verification found
+    pub reason: String,
+    /// The PPPoE ID put back afterwards, if that worked
+    pub rolled_back_to: Option<String>,
+}
+
+impl fmt::Display for SwitchNotVerified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Switch to '{}' didn't work: {}",
+            self.pppoe_id, self.reason
+        )?;
+        match &self.rolled_back_to {
+            Some(previous_id) => write!(f, ". Rolled back to '{}'", previous_id),
+            None => write!(f, ". Nothing was rolled back"),
+        }
+    }
+}
+
+impl std::error::Error for SwitchNotVerified {}
+
+/// Where a router keeps the PPPoE credentials, as found by `discover_pppoe_fields`
+#[derive(Debug)]
+pub struct PppoeFields {
+    /// Where the PPPoE ID goes, e.g. `input[name=userName_PPPoE]`
+    pub username: String,
+    /// Where the PPPoE password goes
+    pub password: String,
+    /// The PPPoE ID currently set
+    pub running_id: String,
+    /// Whether the fields were only found by their likeness to the expected
+    /// ones, so `selector_recovery` is needed to use them
+    pub needs_recovery: bool,
+    /// The PPPoE interface, for routers that have several
+    pub wan_interface: Option<String>,
+}
+
+/// One session with a router's admin interface.
+///
+/// Each supported router model implements this; which one is used is set by
+/// `router.model` in the config file. A backend is created per operation and
+/// closed again afterwards, so implementations don't need to survive a
+/// router reboot or an expired login.
+#[async_trait]
+pub trait RouterBackend: Send {
+    /// Log in with one admin password
+    ///
+    /// # Returns
+    /// * `false` if the router rejected the password
+    async fn login(&mut self, password: &str) -> Result<bool>;
+
+    /// Read the PPPoE username the router is configured with
+    async fn current_pppoe_id(&mut self) -> Result<String>;
+
+    /// Read the PPPoE username and password the router is configured with
+    async fn current_pppoe_credentials(&mut self) -> Result<(String, String)>;
+
+    /// Find where the PPPoE credentials are kept, even if they aren't where
+    /// this tool expects them
+    async fn pppoe_fields(&mut self) -> Result<PppoeFields>;
+
+    /// Save new PPPoE credentials
+    async fn set_pppoe_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<()>;
+
+    /// Make the router redial with the saved credentials. Whether that
+    /// worked is up to `is_connected`.
+    async fn reconnect(&mut self) -> Result<()>;
+
+    /// Whether the WAN connection is up
+    ///
+    /// # Returns
+    /// * `None` if this router doesn't tell
+    async fn is_connected(&mut self) -> Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /// Rename a wireless network
+    ///
+    /// # Arguments
+    /// * `interface` - The router's name for the wireless interface
+    /// * `ssid` - The new network name
+    async fn set_wifi_ssid(&mut self, _interface: &str, _ssid: &str) -> Result<()> {
+        Err(Permanent("This router model can't rename WiFi networks".to_string()).into())
+    }
+
+    /// End the session, e.g. close the browser
+    async fn close(self: Box<Self>);
+}
+
+/// What it takes to reach and log in to the router
+///
+/// Any SSH tunnel or WireGuard session needed to reach the router is kept open
+/// for as long as this lives, and closed again when it is dropped.
+pub struct RouterAccess {
+    /// The admin passwords in priority order. The one that worked is moved to
+    /// the front so later logins try it first.
+    passwords: Vec<String>,
+    /// Proxy through which the router is reached, if any
+    proxy: Option<String>,
+    /// Only log in and read; report credential changes instead of saving them
+    dry_run: bool,
+    // Fields drop in order, so the tunnel is closed before the VPN goes down
+    _tunnel: Option<SshTunnel>,
+    _vpn_session: Option<WireGuardSession>,
+}
+
+impl RouterAccess {
+    /// Bring up whatever is needed to reach the router
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    /// * `dry_run` - Leave the router's settings alone, see `password_change_router`
+    pub fn open(config: &Config, dry_run: bool) -> Result<Self> {
+        // When the router is only reachable over WireGuard, bring the tunnel up
+        let vpn_session = match config.router.wireguard_interface.as_deref() {
+            Some(interface) => vpn::ensure_router_reachable(&config.router.ip, interface)?,
+            None => None,
+        };
+
+        // Reach the router through an SSH tunnel or proxy when running remotely
+        let tunnel = match config.router.ssh_jump_host.as_deref() {
+            Some(jump_host) => Some(SshTunnel::start(
+                jump_host,
+                config.router.ssh_key.as_deref(),
+            )?),
+            None => None,
+        };
+        let proxy = tunnel
+            .as_ref()
+            .map(SshTunnel::proxy_url)
+            .or(config.router.proxy.clone());
+
+        Ok(Self {
+            // The primary admin password first, then any fallbacks
+            passwords: config.router_passwords(),
+            proxy,
+            dry_run,
+            _tunnel: tunnel,
+            _vpn_session: vpn_session,
+        })
+    }
+
+    /// Whether changes to the router are only reported, not made
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Open a session with the configured router model
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    async fn connect(&self, config: &Config) -> Result<Box<dyn RouterBackend>> {
+        let proxy = self.proxy.as_deref();
+
+        Ok(match config.router.model {
+            RouterModel::DLink => Box::new(DLink::connect(config, proxy).await?),
+            RouterModel::OpenWrt => Box::new(OpenWrt::connect(config, proxy)?),
+        })
+    }
+}
+
+/// Change the PPPoE credentials on the router and let it reconnect.
+///
+/// Nothing is changed until the risks are acknowledged, see
+/// `check_risks_acknowledged`.
+///
+/// Failures are retried as set in the `[retry]` config section, with a fresh
+/// router session each time. In a dry run the router is logged in to, but the
+/// change is only reported.
+///
+/// A switch only counts once it is verified, see `verify_switch`. If it can't
+/// be, the PPPoE ID that was running before is put back and the switch fails
+/// with a `SwitchNotVerified`, which isn't retried. Disabling the connection is
+/// not verified, since it is meant to stay down.
+///
+/// Once the change has worked on the router, it is made on each of
+/// `router.secondary` as well; see `update_secondary_routers`.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - How to reach and log in to the router
+/// * `pppoe_id_name` - The PPPoE ID username
+/// * `pppoe_id_password` - The new PPPoE ID password
+///
+/// # Returns
+/// * `true` if the password change was successful, `false` otherwise
+#[instrument(skip_all, fields(pppoe_id = %pppoe_id_name))]
+pub async fn password_change_router(
+    config: &Config,
+    router: &mut RouterAccess,
+    pppoe_id_name: &str,
+    pppoe_id_password: &str,
+) -> Result<bool> {
+    let dry_run = router.dry_run;
+    if !dry_run {
+        check_risks_acknowledged()?;
+    }
+
+    let outcome = with_retry(&config.retry, "Router update", async || {
+        let mut backend = router.connect(config).await?;
+
+        let result = async {
+            login_router(backend.as_mut(), config, &mut router.passwords).await?;
+            if dry_run {
+                info!(
+                    "[dry run] Would set the PPPoE ID to '{}' and reconnect",
+                    pppoe_id_name
+                );
+                return Ok(Ok(()));
+            }
+
+            let previous_id = backend.current_pppoe_id().await?.trim().to_string();
+            backend
+                .set_pppoe_credentials(pppoe_id_name, pppoe_id_password)
+                .await?;
+            backend.reconnect().await?;
+
+            if pppoe_id_password == DISABLED_PASSWORD {
+                return Ok(Ok(()));
+            }
+
+            // Not an error for `with_retry`: switching again would most likely
+            // fail the same way, and the rollback already touched the router
+            match verify_switch(config, backend.as_mut(), pppoe_id_name).await {
+                Ok(()) => Ok(Ok(())),
+                Err(e) => {
+                    let reason = format!("{:#}", e);
+                    warn!("Switch to '{}' not verified: {}", pppoe_id_name, reason);
+                    let rolled_back_to =
+                        roll_back(config, backend.as_mut(), &previous_id, pppoe_id_name).await;
+                    Ok(Err(SwitchNotVerified {
+                        pppoe_id: pppoe_id_name.to_string(),
+                        reason,
+                        rolled_back_to,
+                    }))
+                }
+            }
+        }
+        .await;
+
+        // Close the session whatever happened. In daemon mode ChromeDriver
+        // stays up between cycles, so a browser left open here would never go
+        // away.
+        backend.close().await;
+
+        result
+    })
+    .await?;
+
+    outcome?;
+    if !dry_run && pppoe_id_password != DISABLED_PASSWORD {
+        wanip::record_connect(config, pppoe_id_name).await;
+    }
+    update_secondary_routers(config, dry_run, pppoe_id_name, pppoe_id_password).await;
+    Ok(true)
+}
+
+/// Make a credential change on every one of `router.secondary`, reporting
+/// each that couldn't be updated.
+///
+/// These routers only follow the main one: the change isn't verified on them,
+/// nor rolled back, and it failing on one doesn't fail the switch.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `dry_run` - Only log in, and report the change
+/// * `pppoe_id_name` - The PPPoE ID username
+/// * `pppoe_id_password` - The new PPPoE ID password
+async fn update_secondary_routers(
+    config: &Config,
+    dry_run: bool,
+    pppoe_id_name: &str,
+    pppoe_id_password: &str,
+) {
+    let mut failed = Vec::new();
+
+    for secondary in &config.router.secondary {
+        let name = secondary.name();
+        let secondary_config = config.for_secondary_router(secondary);
+
+        match update_secondary_router(&secondary_config, dry_run, pppoe_id_name, pppoe_id_password)
+            .await
+        {
+            Ok(()) if dry_run => {}
+            Ok(()) => info!("✓ Updated secondary router '{}'", name),
+            Err(e) => {
+                warn!("Failed to update secondary router '{}': {:#}", name, e);
+                failed.push(format!("{}: {:#}", name, e));
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        send_notification(
+            NotificationKind::Warning,
+            "Secondary Router Not Updated ⚠",
+            &format!(
+                "The main router was set to '{}', but {} secondary router(s) weren't:\n{}",
+                pppoe_id_name,
+                failed.len(),
+                failed.join("\n")
+            ),
+        );
+    }
+}
+
+/// Set new PPPoE credentials on one secondary router and let it reconnect.
+///
+/// Failures are retried as set in the `[retry]` config section.
+///
+/// # Arguments
+/// * `config` - The runtime configuration, as for that router
+/// * `dry_run` - Only log in, and report the change
+/// * `pppoe_id_name` - The PPPoE ID username
+/// * `pppoe_id_password` - The new PPPoE ID password
+#[instrument(skip_all, fields(router = %config.router.ip))]
+async fn update_secondary_router(
+    config: &Config,
+    dry_run: bool,
+    pppoe_id_name: &str,
+    pppoe_id_password: &str,
+) -> Result<()> {
+    let mut router = RouterAccess::open(config, dry_run)?;
+
+    with_retry(&config.retry, "Secondary router update", async || {
+        let mut backend = router.connect(config).await?;
+
+        let result = async {
+            login_router(backend.as_mut(), config, &mut router.passwords).await?;
+            if dry_run {
+                info!(
+                    "[dry run] Would set the PPPoE ID on {} to '{}' and reconnect",
+                    config.router.ip, pppoe_id_name
+                );
+                return Ok(());
+            }
+
+            backend
+                .set_pppoe_credentials(pppoe_id_name, pppoe_id_password)
+                .await?;
+            backend.reconnect().await
+        }
+        .await;
+
+        // Close the session whatever happened
+        backend.close().await;
+
+        result
+    })
+    .await
+}
+
+/// Check that a credential change took effect: the router shows the new
+/// PPPoE ID, reports the connection up if it can tell (over UPnP where it
+/// can), and the
+/// `router.connectivity_check_url` answers if one is set, all within
+/// `router.connect_timeout_secs`.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `backend` - The logged in router session the change was made in
+/// * `pppoe_id` - The PPPoE ID that was set
+async fn verify_switch(
+    config: &Config,
+    backend: &mut dyn RouterBackend,
+    pppoe_id: &str,
+) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(config.router.connect_timeout_secs);
+
+    let shown = backend.current_pppoe_id().await?;
+    if shown.trim() != pppoe_id {
+        anyhow::bail!("the router shows '{}' instead", shown.trim());
+    }
+
+    loop {
+        match is_connected(config, backend).await? {
+            Some(true) => break,
+            // Nothing to go by but the connectivity check
+            None => break,
+            Some(false) if Instant::now() >= deadline => anyhow::bail!(
+                "the connection didn't come up within {} seconds",
+                config.router.connect_timeout_secs
+            ),
+            Some(false) => sleep(CONNECTION_POLL_INTERVAL).await,
+        }
+    }
+
+    if let Some(url) = &config.router.connectivity_check_url {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        // Any answer will do; it only has to come through the new connection
+        loop {
+            match client.head(url).send().await {
+                Ok(_) => break,
+                Err(e) if Instant::now() >= deadline => {
+                    anyhow::bail!("{} didn't answer: {}", url, e)
+                }
+                Err(_) => sleep(CONNECTION_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the WAN connection is up, from the router's UPnP service if it has
+/// one, otherwise from the router session
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `backend` - The logged in router session
+///
+/// # Returns
+/// * `None` if the router doesn't tell
+async fn is_connected(config: &Config, backend: &mut dyn RouterBackend) -> Result<Option<bool>> {
+    if let Some(status) = upnp::wan_status(config).await {
+        return Ok(Some(status.connected));
+    }
+
+    backend.is_connected().await
+}
+
+/// Put the PPPoE ID that was running before a failed switch back on the router
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `backend` - The logged in router session the switch was made in
+/// * `previous_id` - The PPPoE ID the router showed before the switch
+/// * `failed_id` - The PPPoE ID that was switched to
+///
+/// # Returns
+/// * The PPPoE ID put back, or `None` if there was none to go back to or it failed
+async fn roll_back(
+    config: &Config,
+    backend: &mut dyn RouterBackend,
+    previous_id: &str,
+    failed_id: &str,
+) -> Option<String> {
+    if previous_id.is_empty() || previous_id == failed_id {
+        return None;
+    }
+
+    // A connection that was disabled on purpose goes back to being disabled
+    let password = if State::load()
+        .disabled
+        .is_some_and(|disabled| disabled.pppoe_id == previous_id)
+    {
+        DISABLED_PASSWORD.to_string()
+    } else {
+        match config
+            .credentials
+            .iter()
+            .find(|credential| credential.id == previous_id)
+        {
+            Some(credential) => credential.password.clone(),
+            None => {
+                warn!(
+                    "Can't roll back to '{}': it is not in the configuration",
+                    previous_id
+                );
+                return None;
+            }
+        }
+    };
+
+    info!("Rolling back to '{}'...", previous_id);
+    let result = async {
+        backend
+            .set_pppoe_credentials(previous_id, &password)
+            .await?;
+        backend.reconnect().await
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            info!("✓ Rolled back to '{}'", previous_id);
+            Some(previous_id.to_string())
+        }
+        Err(e) => {
+            warn!("Failed to roll back to '{}': {:#}", previous_id, e);
+            None
+        }
+    }
+}
+
+/// Check which PPPoE ID is currently running on the router.
+///
+/// Failures are retried as set in the `[retry]` config section.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - How to reach and log in to the router
+///
+/// # Returns
+/// * The PPPoE ID currently in use as a string
+#[instrument(skip_all)]
+pub async fn which_pppoe_id_running(config: &Config, router: &mut RouterAccess) -> Result<String> {
+    with_retry(&config.retry, "Router check", async || {
+        let mut backend = router.connect(config).await?;
+
+        let result = async {
+            login_router(backend.as_mut(), config, &mut router.passwords).await?;
+            let pppoe_id = backend.current_pppoe_id().await?;
+            Ok(pppoe_id.trim().to_string())
+        }
+        .await;
+
+        // Close the session whatever happened
+        backend.close().await;
+
+        result
+    })
+    .await
+}
+
+/// Read the PPPoE username and password the router is configured with, e.g.
+/// to put them back later.
+///
+/// Failures are retried as set in the `[retry]` config section.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - How to reach and log in to the router
+#[instrument(skip_all)]
+pub async fn read_pppoe_credentials(
+    config: &Config,
+    router: &mut RouterAccess,
+) -> Result<(String, String)> {
+    with_retry(&config.retry, "Router check", async || {
+        let mut backend = router.connect(config).await?;
+
+        let result = async {
+            login_router(backend.as_mut(), config, &mut router.passwords).await?;
+            let (username, password) = backend.current_pppoe_credentials().await?;
+            Ok((username.trim().to_string(), password))
+        }
+        .await;
+
+        // Close the session whatever happened
+        backend.close().await;
+
+        result
+    })
+    .await
+}
+
+/// Fail unless `init --acknowledge-risks` has been run, which is needed
+/// before this tool changes the router's PPPoE settings
+pub fn check_risks_acknowledged() -> Result<()> {
+    if State::load().risks_acknowledged_at.is_none() {
+        return Err(Permanent(
+            "The router's PPPoE settings aren't changed until `auto-wifi init --acknowledge-risks` has been run"
+                .to_string(),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Rename one of the router's wireless networks.
+///
+/// Failures are retried as set in the `[retry]` config section. In a dry run
+/// the rename is only reported.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - How to reach and log in to the router
+/// * `interface` - The router's name for the wireless interface
+/// * `ssid` - The new network name
+#[instrument(skip_all, fields(interface = %interface))]
+pub async fn set_wifi_ssid(
+    config: &Config,
+    router: &mut RouterAccess,
+    interface: &str,
+    ssid: &str,
+) -> Result<()> {
+    if router.dry_run {
+        info!(
+            "[dry run] Would rename WiFi network '{}' to '{}'",
+            interface, ssid
+        );
+        return Ok(());
+    }
+
+    with_retry(&config.retry, "WiFi rename", async || {
+        let mut backend = router.connect(config).await?;
+
+        let result = async {
+            login_router(backend.as_mut(), config, &mut router.passwords).await?;
+            backend.set_wifi_ssid(interface, ssid).await
+        }
+        .await;
+
+        // Close the session whatever happened
+        backend.close().await;
+
+        result
+    })
+    .await
+}
+
+/// Log in to the router and find where it keeps the PPPoE credentials, for
+/// setting up a new router.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - How to reach and log in to the router
+#[instrument(skip_all)]
+pub async fn discover_pppoe_fields(
+    config: &Config,
+    router: &mut RouterAccess,
+) -> Result<PppoeFields> {
+    let mut backend = router.connect(config).await?;
+
+    let result = async {
+        login_router(backend.as_mut(), config, &mut router.passwords).await?;
+        backend.pppoe_fields().await
+    }
+    .await;
+
+    // Close the session whatever happened
+    backend.close().await;
+
+    result
+}
+
+/// Log in to the router's admin interface, trying each known admin password in turn.
+///
+/// Firmware updates sometimes reset the admin password to the factory default,
+/// so fallback passwords are tried after the primary one. Attempts are spaced
+/// out to stay clear of the router's brute-force lockout.
+///
+/// # Arguments
+/// * `backend` - The router session to log in with
+/// * `config` - The runtime configuration
+/// * `router_passwords` - Admin passwords in order of priority. The one that
+///   worked is moved to the front so later logins in this run try it first.
+async fn login_router(
+    backend: &mut dyn RouterBackend,
+    config: &Config,
+    router_passwords: &mut Vec<String>,
+) -> Result<()> {
+    let attempt_delay = config.router.login_delay_secs;
+
+    // Start with the password that worked last time, so a router whose
+    // password was reset is only reported once
+    if let Some(index) = remembered_router_password(router_passwords) {
+        let password = router_passwords.remove(index);
+        router_passwords.insert(0, password);
+    }
+
+    for (index, router_password) in router_passwords.iter().enumerate() {
+        if index > 0 {
+            info!(
+                "Router login failed, trying fallback password #{} in {} seconds...",
+                index, attempt_delay
+            );
+            sleep(Duration::from_secs(attempt_delay)).await;
+        }
+
+        if !backend.login(router_password).await? {
+            continue;
+        }
+
+        if index > 0 {
+            warn!("Logged in to router with fallback password #{}", index);
+            send_notification(
+                NotificationKind::Warning,
+                "Router Password Reset? ⚠",
+                &format!(
+                    "The router rejected the admin password tried first, but fallback password #{} worked.\nThe router may have been reset by a firmware update.",
+                    index
+                ),
+            );
+            let working_password = router_passwords.remove(index);
+            router_passwords.insert(0, working_password);
+        }
+        remember_router_password(&router_passwords[0]);
+
+        return Ok(());
+    }
+
+    // Trying again would only risk the router's brute-force lockout
+    Err(Permanent(format!(
+        "Router rejected all {} configured admin password(s)",
+        router_passwords.len()
+    ))
+    .into())
+}
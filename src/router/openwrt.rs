@@ -0,0 +1,271 @@
+use super::{PppoeFields, RouterBackend};
+use crate::config::Config;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Session ID ubus accepts for calls made before logging in
+const ANONYMOUS_SESSION: &str = "00000000000000000000000000000000";
+
+/// OpenWrt (and derivatives) through the ubus JSON-RPC API that LuCI uses,
+/// at `http://<router>/ubus`. No browser is involved.
+///
+/// The PPPoE credentials are the `username` and `password` options of the
+/// WAN interface in `/etc/config/network`, and WiFi networks are renamed in
+/// `/etc/config/wireless`. Logging in needs an rpcd user with access to `uci`,
+/// `network` and `network.interface`, which `root` has by default.
+pub struct OpenWrt {
+    client: reqwest::Client,
+    url: String,
+    username: String,
+    wan_interface: String,
+    session: String,
+}
+
+impl OpenWrt {
+    /// Prepare an HTTP client for the router's ubus endpoint
+    ///
+    /// # Arguments
+    /// * `config` - The runtime configuration
+    /// * `proxy` - HTTP proxy through which the router is reached, if any
+    pub fn connect(config: &Config, proxy: Option<&str>) -> Result<Self> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(proxy) = proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy).context(format!(
+                "Unsupported proxy for the OpenWrt backend: {}",
+                proxy
+            ))?);
+        }
+
+        Ok(Self {
+            client: client.build().context("Failed to create HTTP client")?,
+            url: format!("http://{}/ubus", config.router.ip),
+            username: config.router.username.clone(),
+            wan_interface: config.router.wan_interface.clone(),
+            session: ANONYMOUS_SESSION.to_string(),
+        })
+    }
+
+    /// Call a ubus method
+    ///
+    /// # Arguments
+    /// * `object` - The ubus object, e.g. `uci`
+    /// * `method` - The method to call on it, e.g. `get`
+    /// * `args` - The method's arguments
+    ///
+    /// # Returns
+    /// * The ubus status code (0 is success) and the method's reply, if any
+    async fn call(&self, object: &str, method: &str, args: Value) -> Result<(i64, Value)> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "call",
+            "params": [self.session, object, method, args],
+        });
+
+        let response: Value = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Router sent an invalid ubus response")?;
+
+        if let Some(error) = response.get("error") {
+            anyhow::bail!("ubus {} {} failed: {}", object, method, error);
+        }
+
+        let result = response
+            .get("result")
+            .and_then(Value::as_array)
+            .context("Router sent a ubus response without a result")?;
+        let status = result.first().and_then(Value::as_i64).unwrap_or(-1);
+        let reply = result.get(1).cloned().unwrap_or(Value::Null);
+
+        Ok((status, reply))
+    }
+
+    /// Call a ubus method that must succeed
+    ///
+    /// # Arguments
+    /// * `object` - The ubus object, e.g. `uci`
+    /// * `method` - The method to call on it, e.g. `get`
+    /// * `args` - The method's arguments
+    async fn call_ok(&self, object: &str, method: &str, args: Value) -> Result<Value> {
+        let (status, reply) = self.call(object, method, args).await?;
+        if status != 0 {
+            anyhow::bail!("ubus {} {} failed with status {}", object, method, status);
+        }
+        Ok(reply)
+    }
+}
+
+#[async_trait]
+impl RouterBackend for OpenWrt {
+    async fn login(&mut self, password: &str) -> Result<bool> {
+        self.session = ANONYMOUS_SESSION.to_string();
+
+        let (status, reply) = self
+            .call(
+                "session",
+                "login",
+                json!({ "username": self.username, "password": password }),
+            )
+            .await?;
+
+        // ubus answers a wrong password with "permission denied" (6)
+        if status != 0 {
+            return Ok(false);
+        }
+
+        self.session = reply
+            .get("ubus_rpc_session")
+            .and_then(Value::as_str)
+            .context("Router login reply has no session")?
+            .to_string();
+
+        Ok(true)
+    }
+
+    async fn current_pppoe_id(&mut self) -> Result<String> {
+        let reply = self
+            .call_ok(
+                "uci",
+                "get",
+                json!({ "config": "network", "section": self.wan_interface, "option": "username" }),
+            )
+            .await?;
+
+        Ok(reply
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    async fn current_pppoe_credentials(&mut self) -> Result<(String, String)> {
+        let reply = self
+            .call_ok(
+                "uci",
+                "get",
+                json!({ "config": "network", "section": self.wan_interface }),
+            )
+            .await?;
+        let option = |name: &str| {
+            reply
+                .get("values")
+                .and_then(|values| values.get(name))
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        Ok((option("username"), option("password")))
+    }
+
+    async fn pppoe_fields(&mut self) -> Result<PppoeFields> {
+        let reply = self
+            .call_ok("uci", "get", json!({ "config": "network" }))
+            .await?;
+        let sections = reply
+            .get("values")
+            .and_then(Value::as_object)
+            .context("Router sent no network config")?;
+        let is_pppoe =
+            |section: &Value| section.get("proto").and_then(Value::as_str) == Some("pppoe");
+
+        // The configured interface if it is a PPPoE one, otherwise the first that is
+        let interface = match sections.get(&self.wan_interface) {
+            Some(section) if is_pppoe(section) => self.wan_interface.clone(),
+            _ => sections
+                .iter()
+                .find(|(_, section)| is_pppoe(section))
+                .map(|(name, _)| name.clone())
+                .context("No PPPoE interface in the router's network config")?,
+        };
+
+        Ok(PppoeFields {
+            username: format!("network.{}.username", interface),
+            password: format!("network.{}.password", interface),
+            running_id: sections[&interface]
+                .get("username")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            needs_recovery: false,
+            wan_interface: Some(interface),
+        })
+    }
+
+    async fn set_pppoe_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<()> {
+        self.call_ok(
+            "uci",
+            "set",
+            json!({
+                "config": "network",
+                "section": self.wan_interface,
+                "values": { "username": pppoe_id, "password": password },
+            }),
+        )
+        .await?;
+
+        self.call_ok("uci", "commit", json!({ "config": "network" }))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        let interface = format!("network.interface.{}", self.wan_interface);
+
+        self.call_ok(&interface, "down", json!({})).await?;
+        self.call_ok(&interface, "up", json!({})).await?;
+
+        Ok(())
+    }
+
+    async fn is_connected(&mut self) -> Result<Option<bool>> {
+        let interface = format!("network.interface.{}", self.wan_interface);
+        let status = self.call_ok(&interface, "status", json!({})).await?;
+
+        let up = status.get("up").and_then(Value::as_bool) == Some(true);
+
+        Ok(Some(up))
+    }
+
+    async fn set_wifi_ssid(&mut self, interface: &str, ssid: &str) -> Result<()> {
+        self.call_ok(
+            "uci",
+            "set",
+            json!({
+                "config": "wireless",
+                "section": interface,
+                "values": { "ssid": ssid },
+            }),
+        )
+        .await?;
+
+        self.call_ok("uci", "commit", json!({ "config": "wireless" }))
+            .await?;
+
+        // netifd picks up the changed wireless config on a reload
+        self.call_ok("network", "reload", json!({})).await?;
+
+        Ok(())
+    }
+
+    async fn close(self: Box<Self>) {
+        let _ = self
+            .call(
+                "session",
+                "destroy",
+                json!({ "ubus_rpc_session": self.session }),
+            )
+            .await;
+    }
+}
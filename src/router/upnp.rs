@@ -0,0 +1,354 @@
+//! The router's UPnP Internet Gateway Device (IGD) service, which tells the
+//! WAN connection's state and external IP address without logging in.
+//! Routers without it, or with UPnP turned off, are read through their admin
+//! interface instead.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use roxmltree::Document;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+/// Where SSDP searches are sent
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+/// The device type searched for
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// How long to wait for the router to answer a search
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Timeout of each request to the IGD
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a router found without the service isn't searched again
+const SEARCH_AGAIN_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Services telling the connection's state, PPP first since the WAN is PPPoE
+const CONNECTION_SERVICES: &[&str] = &["WANPPPConnection", "WANIPConnection"];
+
+/// The WAN connection as the IGD reports it
+#[derive(Debug, Clone)]
+pub struct WanStatus {
+    /// Whether the connection is up
+    pub connected: bool,
+    /// The state as reported, e.g. `Connected` or `Disconnected`
+    pub status: String,
+    /// Seconds since the connection came up, if reported
+    pub uptime_secs: Option<u64>,
+    /// The connection's public address, if it has one
+    pub external_ip: Option<IpAddr>,
+}
+
+/// Where the connection service takes requests
+#[derive(Debug, Clone)]
+struct Endpoint {
+    /// The service's control URL
+    control_url: Url,
+    /// The service's type, which names its actions
+    service_type: String,
+}
+
+/// What was found looking for a router's IGD service
+#[derive(Debug, Clone)]
+enum Discovery {
+    Found(Endpoint),
+    /// Not found, at this time
+    Missing(Instant),
+}
+
+/// What was found for each router, by address, so it isn't searched for on
+/// every check
+static DISCOVERIES: Mutex<Vec<(String, Discovery)>> = Mutex::new(Vec::new());
+
+/// The WAN connection's state from the router's IGD service
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+///
+/// # Returns
+/// * `None` if `router.upnp` is off, the router has no IGD service or it
+///   couldn't be read, in which case the router has to be asked
+pub async fn wan_status(config: &Config) -> Option<WanStatus> {
+    if !config.router.upnp {
+        return None;
+    }
+    let endpoint = endpoint(config).await?;
+
+    match read_status(&endpoint).await {
+        Ok(status) => Some(status),
+        Err(e) => {
+            debug!("Failed to read the WAN state over UPnP: {:#}", e);
+            // It may have moved, e.g. to another port after a reboot
+            forget(&config.router.ip);
+            None
+        }
+    }
+}
+
+/// The router's IGD service, searched for unless it was found or found
+/// missing recently
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+async fn endpoint(config: &Config) -> Option<Endpoint> {
+    let router = &config.router.ip;
+    let known = DISCOVERIES.lock().ok().and_then(|discoveries| {
+        discoveries
+            .iter()
+            .find(|(ip, _)| ip == router)
+            .map(|(_, discovery)| discovery.clone())
+    });
+    match known {
+        Some(Discovery::Found(endpoint)) => return Some(endpoint),
+        Some(Discovery::Missing(since)) if since.elapsed() < SEARCH_AGAIN_AFTER => return None,
+        _ => {}
+    }
+
+    let discovery = match discover(config).await {
+        Ok(endpoint) => {
+            info!(
+                "Reading the WAN state from UPnP at {}",
+                endpoint.control_url
+            );
+            Discovery::Found(endpoint)
+        }
+        Err(e) => {
+            debug!("No UPnP IGD service on the router: {:#}", e);
+            Discovery::Missing(Instant::now())
+        }
+    };
+
+    forget(router);
+    if let Ok(mut discoveries) = DISCOVERIES.lock() {
+        discoveries.push((router.clone(), discovery.clone()));
+    }
+
+    match discovery {
+        Discovery::Found(endpoint) => Some(endpoint),
+        Discovery::Missing(_) => None,
+    }
+}
+
+/// Drop what was found for a router, so it is searched for again
+///
+/// # Arguments
+/// * `router` - The router's address
+fn forget(router: &str) {
+    if let Ok(mut discoveries) = DISCOVERIES.lock() {
+        discoveries.retain(|(ip, _)| ip != router);
+    }
+}
+
+/// Find the router's WAN connection service from its device description
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+async fn discover(config: &Config) -> Result<Endpoint> {
+    let location = match &config.router.upnp_url {
+        Some(url) => url.clone(),
+        None => search(&config.router.ip).await?,
+    };
+    let location = Url::parse(&location).context(format!("Invalid UPnP URL {}", location))?;
+
+    let description = client()?
+        .get(location.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    parse_description(&location, &description)
+}
+
+/// Search the local network for the router's IGD over SSDP
+///
+/// # Arguments
+/// * `router_ip` - The router's address; answers from elsewhere are ignored
+///
+/// # Returns
+/// * The URL of its device description
+async fn search(router_ip: &str) -> Result<String> {
+    let router: IpAddr = router_ip
+        .parse()
+        .context("The router address isn't an IP address, so it can't be searched for")?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_ADDR, IGD_DEVICE
+    );
+    socket.send_to(request.as_bytes(), SSDP_ADDR).await?;
+
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut buffer = [0; 2048];
+    loop {
+        let (len, from) = timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            socket.recv_from(&mut buffer),
+        )
+        .await
+        .context("The router didn't answer the UPnP search")??;
+        if from.ip() != router {
+            continue;
+        }
+
+        let response = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(location) = header(&response, "location") {
+            return Ok(location);
+        }
+    }
+}
+
+/// A header of an HTTP-like message, such as an SSDP answer
+///
+/// # Arguments
+/// * `message` - The message
+/// * `name` - The header's name, in any case
+fn header(message: &str, name: &str) -> Option<String> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// Find the WAN connection service in a device description
+///
+/// # Arguments
+/// * `location` - Where the description was read from, which its URLs are
+///   relative to unless it gives a `URLBase`
+/// * `description` - The device description XML
+fn parse_description(location: &Url, description: &str) -> Result<Endpoint> {
+    let document =
+        Document::parse(description).context("The UPnP device description isn't valid XML")?;
+
+    let base = document
+        .descendants()
+        .find(|node| node.tag_name().name() == "URLBase")
+        .and_then(|node| node.text())
+        .and_then(|base| Url::parse(base.trim()).ok())
+        .unwrap_or_else(|| location.clone());
+
+    for wanted in CONNECTION_SERVICES {
+        for service in document
+            .descendants()
+            .filter(|node| node.tag_name().name() == "service")
+        {
+            let child = |name: &str| {
+                service
+                    .children()
+                    .find(|child| child.tag_name().name() == name)
+                    .and_then(|child| child.text())
+                    .map(str::trim)
+            };
+            let (Some(service_type), Some(control_url)) =
+                (child("serviceType"), child("controlURL"))
+            else {
+                continue;
+            };
+
+            if service_type.contains(wanted) {
+                return Ok(Endpoint {
+                    control_url: base.join(control_url)?,
+                    service_type: service_type.to_string(),
+                });
+            }
+        }
+    }
+
+    anyhow::bail!("The UPnP device has no WAN connection service")
+}
+
+/// Ask the connection service for the connection's state and external IP
+///
+/// # Arguments
+/// * `endpoint` - The connection service
+async fn read_status(endpoint: &Endpoint) -> Result<WanStatus> {
+    let status_info = call(endpoint, "GetStatusInfo").await?;
+    let status =
+        field(&status_info, "NewConnectionStatus").context("No connection state in the answer")?;
+
+    // A connection that is down has no external IP, which some routers
+    // answer with an error
+    let external_ip = call(endpoint, "GetExternalIPAddress")
+        .await
+        .ok()
+        .and_then(|answer| field(&answer, "NewExternalIPAddress"))
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+        .filter(|ip| !ip.is_unspecified());
+
+    Ok(WanStatus {
+        connected: status == "Connected",
+        uptime_secs: field(&status_info, "NewUptime").and_then(|uptime| uptime.parse().ok()),
+        status,
+        external_ip,
+    })
+}
+
+/// Call an action without arguments on the connection service
+///
+/// # Arguments
+/// * `endpoint` - The connection service
+/// * `action` - The action, e.g. `GetStatusInfo`
+///
+/// # Returns
+/// * The SOAP answer
+async fn call(endpoint: &Endpoint, action: &str) -> Result<String> {
+    let body = format!(
+        r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{service}"/></s:Body></s:Envelope>"#,
+        action = action,
+        service = endpoint.service_type
+    );
+
+    let answer = client()?
+        .post(endpoint.control_url.clone())
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header(
+            "SOAPAction",
+            format!("\"{}#{}\"", endpoint.service_type, action),
+        )
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()
+        .context(format!("{} failed", action))?
+        .text()
+        .await?;
+
+    Ok(answer)
+}
+
+/// The text of an element of a SOAP answer
+///
+/// # Arguments
+/// * `answer` - The SOAP answer
+/// * `name` - The element's name, without namespace
+fn field(answer: &str, name: &str) -> Option<String> {
+    let document = Document::parse(answer).ok()?;
+    let text = document
+        .descendants()
+        .find(|node| node.tag_name().name() == name)?
+        .text()?
+        .trim()
+        .to_string();
+
+    Some(text)
+}
+
+/// An HTTP client for the IGD, which is on the local network and so is never
+/// reached through a proxy
+fn client() -> Result<Client> {
+    Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .no_proxy()
+        .build()
+        .context("Failed to create HTTP client")
+}
@@ -0,0 +1,211 @@
+//! Usage readings from more than one place. The portal is sometimes only
+//! reachable from inside the ISP's network, and the machine the daemon runs
+//! on sometimes can't reach it at all. A "satellite" (another instance, e.g.
+//! on a phone or an office PC) reads the portal with `submit` and sends the
+//! readings to the daemon, which merges them into its history.
+
+use crate::config::{Config, UsageKind};
+use crate::events::{self, Event};
+use crate::portal::get_total_use;
+use crate::state::{record_usage_at, unix_now};
+use crate::storage::History;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Path the daemon takes readings on
+pub const READINGS_PATH: &str = "/readings";
+
+/// How far ahead of the daemon's clock a reading may be, in seconds, for
+/// clocks that are a little off
+const CLOCK_SLACK_SECS: u64 = 5 * 60;
+
+/// A usage reading a satellite took
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reading {
+    /// The PPPoE ID
+    pub pppoe_id: String,
+    /// Its total use, in minutes or megabytes
+    pub total_use: i32,
+    /// Whether `total_use` is time or data
+    pub usage_kind: UsageKind,
+    /// When it was read (unix seconds)
+    pub read_at: u64,
+}
+
+/// What a satellite sends the daemon
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submission {
+    /// The satellite's name
+    pub source: String,
+    /// The readings it took
+    pub readings: Vec<Reading>,
+}
+
+/// What the daemon made of a submission
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Merged {
+    /// How many readings went into the history
+    pub accepted: usize,
+    /// Why each of the others didn't
+    pub rejected: Vec<String>,
+}
+
+/// Merge a satellite's readings into the usage history. A reading newer than
+/// the last one known of its ID also becomes the cached usage, as if this
+/// instance had read it.
+///
+/// Readings of IDs that aren't configured, of the wrong kind, from the future
+/// or older than `satellites.max_age_mins` are refused.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `max_age_mins` - How old a reading may be
+/// * `submission` - What the satellite sent
+pub fn merge(config: &Config, max_age_mins: u64, submission: &Submission) -> Result<Merged> {
+    let history = History::open()?;
+    let now = unix_now();
+    let mut merged = Merged::default();
+
+    for reading in &submission.readings {
+        let refused = if !config
+            .credentials
+            .iter()
+            .any(|credential| credential.id == reading.pppoe_id)
+        {
+            Some("is not a configured PPPoE ID")
+        } else if reading.usage_kind != config.quota_unit(&reading.pppoe_id).kind() {
+            Some("is of the wrong kind of usage")
+        } else if reading.read_at > now + CLOCK_SLACK_SECS {
+            Some("is from the future; check the satellite's clock")
+        } else if now.saturating_sub(reading.read_at) > max_age_mins * 60 {
+            Some("is too old")
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            merged
+                .rejected
+                .push(format!("The reading of '{}' {}", reading.pppoe_id, reason));
+            continue;
+        }
+
+        history.add_usage_at(reading.read_at as i64, &reading.pppoe_id, reading.total_use)?;
+        if record_usage_at(&reading.pppoe_id, reading.total_use, reading.read_at) {
+            info!(
+                "✓ Took the usage of '{}' from satellite '{}': {}",
+                reading.pppoe_id,
+                submission.source,
+                config.format_usage(&reading.pppoe_id, reading.total_use)
+            );
+        }
+        events::emit(Event::SatelliteReading {
+            pppoe_id: &reading.pppoe_id,
+            minutes: reading.total_use,
+            source: &submission.source,
+        });
+        merged.accepted += 1;
+    }
+
+    for reason in &merged.rejected {
+        warn!("Satellite '{}': {}", submission.source, reason);
+    }
+
+    Ok(merged)
+}
+
+/// Read the usage of the configured PPPoE IDs (or one of them) from the
+/// portal and send it to the daemon set in `[satellite]`
+///
+/// IDs that can't be read are reported and left out; it only fails if none
+/// could be read, or the daemon doesn't take them.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - Only read this PPPoE ID
+pub async fn submit(config: &Config, pppoe_id: Option<&str>) -> Result<()> {
+    let satellite = config
+        .satellite
+        .as_ref()
+        .context("Set [satellite] to the daemon that readings go to")?;
+
+    let mut readings = Vec::new();
+    for credential in &config.credentials {
+        if pppoe_id.is_some_and(|pppoe_id| pppoe_id != credential.id) {
+            continue;
+        }
+        match get_total_use(config, &credential.id, &credential.password).await {
+            Ok(account) => readings.push(Reading {
+                pppoe_id: credential.id.clone(),
+                total_use: account.total_use,
+                usage_kind: account.usage_kind,
+                read_at: unix_now(),
+            }),
+            Err(e) => warn!("Failed to read the usage of '{}': {:#}", credential.id, e),
+        }
+    }
+    if readings.is_empty() {
+        anyhow::bail!("No usage could be read to submit");
+    }
+
+    let submission = Submission {
+        source: satellite.name.clone().unwrap_or_else(host_name),
+        readings,
+    };
+    let url = format!("{}{}", satellite.url.trim_end_matches('/'), READINGS_PATH);
+    let merged: Merged = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .context("Failed to create HTTP client")?
+        .post(&url)
+        .bearer_auth(&satellite.token)
+        .json(&submission)
+        .send()
+        .await
+        .context(format!("Failed to reach the daemon at {}", url))?
+        .error_for_status()
+        .context("The daemon refused the readings")?
+        .json()
+        .await
+        .context("The daemon's answer isn't readable")?;
+
+    for reason in &merged.rejected {
+        println!("{}", reason);
+    }
+    println!(
+        "✓ The daemon took {} of {} reading(s)",
+        merged.accepted,
+        submission.readings.len()
+    );
+
+    Ok(())
+}
+
+/// Whether a request's `Authorization` header holds the token, compared in
+/// constant time
+///
+/// # Arguments
+/// * `authorization` - The header, if the request had one
+/// * `token` - The `satellites.token`
+pub fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    let Some(given) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+        return false;
+    };
+
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// This machine's name, for satellites that aren't given one
+fn host_name() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| "satellite".to_string())
+}
@@ -0,0 +1,374 @@
+use crate::backup::write_private;
+use crate::config::Config;
+use crate::state::data_dir;
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use tracing::info;
+
+/// Name of the encrypted file (in the data directory) holding the secrets
+pub const SECRETS_FILE_NAME: &str = "secrets.bin";
+
+/// Start of the secrets file, to recognise it and its format
+const MAGIC: &[u8] = b"auto-wifi secrets v1\n";
+
+/// Length of the random salt the key is derived with, in bytes
+const SALT_LEN: usize = 16;
+
+/// Length of an XChaCha20-Poly1305 nonce, in bytes
+const NONCE_LEN: usize = 24;
+
+/// Environment variable holding the passphrase, for running unattended
+const PASSPHRASE_VAR: &str = "AUTO_WIFI_SECRETS_PASSPHRASE";
+
+/// A config value starting with this names a secret instead of giving it
+const REFERENCE_PREFIX: &str = "secret:";
+
+/// The secrets file, decrypted
+struct Store {
+    /// Each secret by name
+    secrets: BTreeMap<String, String>,
+    /// The passphrase the file is encrypted with
+    passphrase: String,
+}
+
+impl Store {
+    /// Decrypt the secrets file
+    ///
+    /// # Arguments
+    /// * `create` - Start an empty store if there is no secrets file yet,
+    ///   asking for a new passphrase
+    fn open(create: bool) -> Result<Self> {
+        let path = path()?;
+        if !path.exists() {
+            if !create {
+                anyhow::bail!(
+                    "There is no secrets file at {}. Add secrets with `auto-wifi secret set <name>`.",
+                    path.display()
+                );
+            }
+            return Ok(Self {
+                secrets: BTreeMap::new(),
+                passphrase: new_passphrase(PASSPHRASE_VAR, "New secrets passphrase: ")?,
+            });
+        }
+
+        let data =
+            fs::read(&path).context(format!("Failed to read secrets file {}", path.display()))?;
+        let passphrase = passphrase(PASSPHRASE_VAR, "Secrets passphrase: ")?;
+        let secrets =
+            decrypt(&data, &passphrase).context(format!("Failed to decrypt {}", path.display()))?;
+
+        Ok(Self {
+            secrets,
+            passphrase,
+        })
+    }
+
+    /// Encrypt the secrets back to the secrets file, readable by its owner only
+    fn save(&self) -> Result<()> {
+        let path = path()?;
+        let data = encrypt(&self.secrets, &self.passphrase)?;
+
+        // Never leaves a half-written or world-readable secrets file behind
+        write_private(&path, &data)
+            .context(format!("Failed to write secrets file {}", path.display()))
+    }
+}
+
+/// Replace the router and PPPoE passwords given as `secret:<name>` with the
+/// secrets of those names
+///
+/// The secrets file is only decrypted when there is such a password, asking
+/// for the passphrase unless `AUTO_WIFI_SECRETS_PASSPHRASE` is set.
+///
+/// # Arguments
+/// * `config` - The configuration just read
+pub fn resolve(config: &mut Config) -> Result<()> {
+    let mut passwords: Vec<&mut String> = vec![&mut config.router.password];
+    passwords.extend(config.router.fallback_passwords.iter_mut());
+    passwords.extend(
+        config
+            .router
+            .secondary
+            .iter_mut()
+            .map(|secondary| &mut secondary.password),
+    );
+    passwords.extend(
+        config
+            .credentials
+            .iter_mut()
+            .map(|credential| &mut credential.password),
+    );
+    passwords.extend(config.storage.password.as_mut());
+    passwords.retain(|password| password.starts_with(REFERENCE_PREFIX));
+
+    if passwords.is_empty() {
+        return Ok(());
+    }
+
+    let store = Store::open(false)?;
+    for password in passwords {
+        let name = &password[REFERENCE_PREFIX.len()..];
+        let secret = store.secrets.get(name).context(format!(
+            "There is no secret named '{}'. Add it with `auto-wifi secret set {}`.",
+            name, name
+        ))?;
+        *password = secret.clone();
+    }
+    info!("✓ Passwords read from the secrets file");
+
+    Ok(())
+}
+
+/// Add or replace a secret, reading its value from the terminal without
+/// echoing it (or from stdin, if that isn't a terminal)
+///
+/// # Arguments
+/// * `name` - The secret's name, as referred to by `secret:<name>`
+pub fn set(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_whitespace) {
+        anyhow::bail!("A secret's name must be non-empty and without spaces");
+    }
+
+    let mut store = Store::open(true)?;
+    let value = if io::stdin().is_terminal() {
+        rpassword::prompt_password(format!("Value of '{}': ", name))?
+    } else {
+        let mut value = String::new();
+        io::stdin().read_line(&mut value)?;
+        value.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if value.is_empty() {
+        anyhow::bail!("The secret's value must not be empty");
+    }
+
+    let replaced = store.secrets.insert(name.to_string(), value).is_some();
+    store.save()?;
+
+    if replaced {
+        println!("✓ Replaced secret '{}'", name);
+    } else {
+        println!("✓ Added secret '{}'", name);
+    }
+    println!(
+        "Refer to it in the config file as \"{}{}\"",
+        REFERENCE_PREFIX, name
+    );
+
+    Ok(())
+}
+
+/// Replace the value of an existing secret, e.g. with a password that was
+/// just changed
+///
+/// # Arguments
+/// * `name` - The secret's name
+/// * `value` - Its new value
+pub fn replace(name: &str, value: &str) -> Result<()> {
+    let mut store = Store::open(false)?;
+
+    match store.secrets.get_mut(name) {
+        Some(secret) => *secret = value.to_string(),
+        None => anyhow::bail!("There is no secret named '{}'", name),
+    }
+    store.save()
+}
+
+/// The name of the secret a config value refers to, if it is a reference
+///
+/// # Arguments
+/// * `value` - The value as written in the config file, e.g. `secret:id1`
+pub fn reference(value: &str) -> Option<&str> {
+    value.strip_prefix(REFERENCE_PREFIX)
+}
+
+/// Make up a random password of letters and digits, which every portal and
+/// router takes
+///
+/// # Arguments
+/// * `length` - How many characters
+pub fn generate_password(length: usize) -> String {
+    const CHARACTERS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789";
+
+    let mut password = String::with_capacity(length);
+    while password.len() < length {
+        let mut byte = [0u8; 1];
+        OsRng.fill_bytes(&mut byte);
+        // Drop the bytes past the last whole run of characters, so each is
+        // as likely as the next
+        let limit = 256 - 256 % CHARACTERS.len();
+        if usize::from(byte[0]) < limit {
+            password.push(char::from(
+                CHARACTERS[usize::from(byte[0]) % CHARACTERS.len()],
+            ));
+        }
+    }
+
+    password
+}
+
+/// List the names of the stored secrets, never their values
+pub fn list() -> Result<()> {
+    let store = Store::open(false)?;
+
+    if store.secrets.is_empty() {
+        println!("No secrets stored");
+    }
+    for name in store.secrets.keys() {
+        println!("{}", name);
+    }
+
+    Ok(())
+}
+
+/// Remove a secret
+///
+/// # Arguments
+/// * `name` - The secret's name
+pub fn remove(name: &str) -> Result<()> {
+    let mut store = Store::open(false)?;
+
+    if store.secrets.remove(name).is_none() {
+        anyhow::bail!("There is no secret named '{}'", name);
+    }
+    store.save()?;
+    println!("✓ Removed secret '{}'", name);
+
+    Ok(())
+}
+
+/// Where the secrets file is
+pub fn path() -> Result<PathBuf> {
+    Ok(data_dir()?.join(SECRETS_FILE_NAME))
+}
+
+/// The passphrase from an environment variable, or else asked for on the
+/// terminal
+///
+/// # Arguments
+/// * `var` - The environment variable, for running unattended
+/// * `prompt` - What to ask with
+pub fn passphrase(var: &str, prompt: &str) -> Result<String> {
+    if let Some(passphrase) = env::var(var).ok().filter(|p| !p.is_empty()) {
+        return Ok(passphrase);
+    }
+    if !io::stdin().is_terminal() {
+        anyhow::bail!(
+            "A passphrase is needed: set {} when running unattended",
+            var
+        );
+    }
+
+    rpassword::prompt_password(prompt).context("Failed to read the passphrase")
+}
+
+/// Ask for a new passphrase, twice, unless it is in an environment variable
+///
+/// # Arguments
+/// * `var` - The environment variable, for running unattended
+/// * `prompt` - What to ask with
+pub fn new_passphrase(var: &str, prompt: &str) -> Result<String> {
+    if let Some(passphrase) = env::var(var).ok().filter(|p| !p.is_empty()) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = passphrase(var, prompt)?;
+    if passphrase.is_empty() {
+        anyhow::bail!("The passphrase must not be empty");
+    }
+    if passphrase != self::passphrase(var, "Repeat the passphrase: ")? {
+        anyhow::bail!("The passphrases don't match");
+    }
+
+    Ok(passphrase)
+}
+
+/// Derive the encryption key from the passphrase with Argon2id
+///
+/// # Arguments
+/// * `passphrase` - The passphrase
+/// * `salt` - Random bytes stored alongside the secrets
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive the key: {}", e))?;
+
+    Ok(key)
+}
+
+/// Encrypt the secrets into the secrets file format: the magic line, a fresh
+/// salt and nonce, then the secrets as encrypted JSON
+///
+/// # Arguments
+/// * `secrets` - Each secret by name
+/// * `passphrase` - The passphrase to encrypt with
+fn encrypt(secrets: &BTreeMap<String, String>, passphrase: &str) -> Result<Vec<u8>> {
+    seal(MAGIC, &serde_json::to_vec(secrets)?, passphrase)
+}
+
+/// Decrypt the contents of the secrets file
+///
+/// # Arguments
+/// * `data` - The secrets file
+/// * `passphrase` - The passphrase it was encrypted with
+fn decrypt(data: &[u8], passphrase: &str) -> Result<BTreeMap<String, String>> {
+    let plaintext = unseal(MAGIC, data, passphrase)?;
+
+    serde_json::from_slice(&plaintext).context("The decrypted secrets aren't readable")
+}
+
+/// Encrypt data with a passphrase: the magic line, a fresh salt and nonce,
+/// then the encrypted data
+///
+/// # Arguments
+/// * `magic` - Start of the result, to recognise it and its format
+/// * `plaintext` - The data
+/// * `passphrase` - The passphrase to encrypt with
+pub fn seal(magic: &[u8], plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+
+    let mut data = magic.to_vec();
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+
+    Ok(data)
+}
+
+/// Decrypt data encrypted by `seal`
+///
+/// # Arguments
+/// * `magic` - What the data must start with
+/// * `data` - The encrypted data
+/// * `passphrase` - The passphrase it was encrypted with
+pub fn unseal(magic: &[u8], data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let rest = data
+        .strip_prefix(magic)
+        .context("Not a file of this kind, or one from a newer version")?;
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        anyhow::bail!("The file is cut short");
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the file is damaged"))
+}
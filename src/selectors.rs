@@ -0,0 +1,232 @@
+use anyhow::Result;
+use thirtyfour::prelude::*;
+use tracing::{info, warn};
+
+/// How an element is located on a page
+#[derive(Debug, Clone, Copy)]
+pub enum Locator<'a> {
+    /// Match on the element's `id` attribute
+    Id(&'a str),
+    /// Match on the element's `name` attribute
+    Name(&'a str),
+}
+
+impl<'a> Locator<'a> {
+    fn by(&self) -> By {
+        match self {
+            Locator::Id(id) => By::Id(*id),
+            Locator::Name(name) => By::Name(*name),
+        }
+    }
+
+    fn value(&self) -> &'a str {
+        match self {
+            Locator::Id(value) | Locator::Name(value) => value,
+        }
+    }
+}
+
+impl std::fmt::Display for Locator<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Locator::Id(id) => write!(f, "#{}", id),
+            Locator::Name(name) => write!(f, "[name={}]", name),
+        }
+    }
+}
+
+/// An element on the page that looks like it could be the one we were after
+struct Candidate {
+    element: WebElement,
+    selector: String,
+    similarity: f64,
+}
+
+/// Candidates less similar than this are not worth suggesting
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Find an element, suggesting likely replacements if it isn't on the page.
+///
+/// Router and portal UI updates tend to rename fields rather than remove them,
+/// so when the expected element is missing every input/button on the page is
+/// compared against it and the closest matches are logged. In recovery mode the
+/// best match is used in place of the missing element.
+///
+/// # Arguments
+/// * `driver` - The WebDriver session showing the page
+/// * `locator` - How the element is normally found
+/// * `description` - Human readable name of the element, used in messages
+/// * `recovery` - Whether to fall back to the best suggestion
+///
+/// # Returns
+/// * The matching element, or the best suggestion in recovery mode
+pub async fn find_element(
+    driver: &WebDriver,
+    locator: Locator<'_>,
+    description: &str,
+    recovery: bool,
+) -> Result<WebElement> {
+    let error = match driver.query(locator.by()).first().await {
+        Ok(element) => return Ok(element),
+        Err(e) => e,
+    };
+
+    let mut candidates = find_candidates(driver, locator.value())
+        .await
+        .unwrap_or_default();
+
+    if candidates.is_empty() {
+        anyhow::bail!("{} not found ({}): {}", description, locator, error);
+    }
+
+    let suggestions: Vec<String> = candidates
+        .iter()
+        .map(|candidate| candidate.selector.clone())
+        .collect();
+    warn!(
+        "{} not found ({}). Did you mean {}?",
+        description,
+        locator,
+        suggestions.join(" or ")
+    );
+
+    if recovery {
+        let best = candidates.remove(0);
+        info!("Recovery mode: using {} instead", best.selector);
+        return Ok(best.element);
+    }
+
+    anyhow::bail!(
+        "{} not found ({}). Likely candidates: {}",
+        description,
+        locator,
+        suggestions.join(", ")
+    )
+}
+
+/// Where `locate` found an element
+pub struct Located {
+    /// The element itself
+    pub element: WebElement,
+    /// What it is, e.g. `input[name=userName_PPPoE]`
+    pub selector: String,
+    /// Whether it is only the closest match to a missing element, which
+    /// needs `selector_recovery` to be used
+    pub renamed: bool,
+}
+
+/// Find an element, or the one most similar to it, without logging, to show
+/// what a page actually calls it
+///
+/// # Arguments
+/// * `driver` - The WebDriver session showing the page
+/// * `locator` - How the element is normally found
+///
+/// # Returns
+/// * The element or its closest match, or `None` if nothing on the page is alike
+pub async fn locate(driver: &WebDriver, locator: Locator<'_>) -> Result<Option<Located>> {
+    if let Ok(element) = driver.query(locator.by()).first().await {
+        let selector = format!("{}{}", element.tag_name().await?, locator);
+        return Ok(Some(Located {
+            element,
+            selector,
+            renamed: false,
+        }));
+    }
+
+    let best = find_candidates(driver, locator.value())
+        .await?
+        .into_iter()
+        .next();
+
+    Ok(best.map(|candidate| Located {
+        element: candidate.element,
+        selector: candidate.selector,
+        renamed: true,
+    }))
+}
+
+/// Find the interactive elements on the page most similar to a missing one
+///
+/// # Arguments
+/// * `driver` - The WebDriver session showing the page
+/// * `wanted` - The id/name that was expected
+///
+/// # Returns
+/// * Up to three candidates, best match first
+async fn find_candidates(driver: &WebDriver, wanted: &str) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+
+    for element in driver
+        .find_all(By::Css("input, button, select, textarea"))
+        .await?
+    {
+        let tag = element.tag_name().await?;
+
+        for attribute in ["id", "name"] {
+            let Some(value) = element.attr(attribute).await? else {
+                continue;
+            };
+
+            let similarity = similarity(wanted, &value);
+            if similarity >= MIN_SIMILARITY {
+                candidates.push(Candidate {
+                    element: element.clone(),
+                    selector: format!("{}[{}={}]", tag, attribute, value),
+                    similarity,
+                });
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    candidates.truncate(3);
+
+    Ok(candidates)
+}
+
+/// Score how alike two identifiers are, from 0.0 (nothing in common) to 1.0
+///
+/// Case, underscores and dashes are ignored, so `userName_PPPoE` and
+/// `username-pppoe` count as identical.
+fn similarity(a: &str, b: &str) -> f64 {
+    let normalize = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    };
+    let (a, b) = (normalize(a), normalize(b));
+
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    if a == b {
+        return 1.0;
+    }
+
+    // One name containing the other (e.g. "password" in "adminpassword")
+    let (shorter, longer) = if a.len() <= b.len() { (&a, &b) } else { (&b, &a) };
+    if longer.contains(shorter.as_str()) {
+        return 0.5 + 0.5 * shorter.len() as f64 / longer.len() as f64;
+    }
+
+    1.0 - edit_distance(&a, &b) as f64 / a.len().max(b.len()) as f64
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
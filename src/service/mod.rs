@@ -0,0 +1,126 @@
+//! Installing the daemon as a service that starts with the system and is
+//! restarted when it fails: a systemd unit on Linux, a Windows service on
+//! Windows
+
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "windows")]
+pub use windows::{enter_data_dir, run};
+
+use crate::config::default_config_path;
+use crate::ServiceArgs;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Name the service is registered under
+pub const SERVICE_NAME: &str = "auto-wifi";
+
+/// Name shown in the service list
+const DISPLAY_NAME: &str = "Auto WiFi Manager";
+
+/// What the service is, as shown in the service list
+const DESCRIPTION: &str = "Rotates PPPoE IDs on the router as their ISP quota runs out";
+
+/// Install the daemon as a service and start it
+///
+/// # Arguments
+/// * `config_path` - Config file given on the command line, if any
+/// * `args` - Daemon options for the service
+pub fn install(config_path: Option<&Path>, args: &ServiceArgs) -> Result<()> {
+    let config_path = service_config_path(config_path)?;
+
+    #[cfg(target_os = "linux")]
+    return systemd::install(config_path.as_deref(), args);
+
+    #[cfg(target_os = "windows")]
+    return windows::install(config_path.as_deref(), args);
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        let _ = (config_path, args);
+        anyhow::bail!("Installing a service is only supported on Linux (systemd) and Windows");
+    }
+}
+
+/// Stop the service and remove it
+pub fn uninstall() -> Result<()> {
+    #[cfg(target_os = "linux")]
+    return systemd::uninstall();
+
+    #[cfg(target_os = "windows")]
+    return windows::uninstall();
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    anyhow::bail!("Installing a service is only supported on Linux (systemd) and Windows");
+}
+
+/// The config file the service is to use, as an absolute path, since the
+/// service doesn't start in the current directory
+///
+/// # Arguments
+/// * `path` - Config file given on the command line, if any
+///
+/// # Returns
+/// * `None` if there is no config file at the default location, in which case
+///   the service uses the settings built into the binary
+fn service_config_path(path: Option<&Path>) -> Result<Option<PathBuf>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let path = default_config_path()?;
+            if !path.exists() {
+                println!(
+                    "No config file at {}, so the service will use the settings built into this binary",
+                    path.display()
+                );
+                return Ok(None);
+            }
+            path
+        }
+    };
+
+    let path = path
+        .canonicalize()
+        .context(format!("Failed to find config file {}", path.display()))?;
+
+    Ok(Some(path))
+}
+
+/// The options the service passes to `run` to start the daemon
+///
+/// # Arguments
+/// * `args` - Daemon options for the service
+fn daemon_args(args: &ServiceArgs) -> Vec<String> {
+    let mut daemon_args = vec![
+        "--daemon".to_string(),
+        "--interval".to_string(),
+        format!("{}s", args.interval.as_secs()),
+    ];
+    if let Some(decision_interval) = args.decision_interval {
+        daemon_args.push("--decision-interval".to_string());
+        daemon_args.push(format!("{}s", decision_interval.as_secs()));
+    }
+    if let Some(metrics) = args.metrics {
+        daemon_args.push("--metrics".to_string());
+        daemon_args.push(metrics.to_string());
+    }
+
+    daemon_args
+}
+
+/// Tell how to give the service the secrets passphrase, if there is a secrets
+/// file, since the service can't ask for it
+///
+/// # Arguments
+/// * `how` - Where the service reads `AUTO_WIFI_SECRETS_PASSPHRASE` from
+fn print_passphrase_hint(how: &str) {
+    if crate::secrets::path().is_ok_and(|path| path.exists()) {
+        println!(
+            "If the config file refers to the secrets file, the service needs its passphrase:"
+        );
+        println!("{}", how);
+    }
+}
@@ -0,0 +1,234 @@
+use super::{daemon_args, print_passphrase_hint, DESCRIPTION, DISPLAY_NAME, SERVICE_NAME};
+use crate::state::{data_dir, DATA_DIR_VAR};
+use crate::ServiceArgs;
+use anyhow::{Context, Result};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where system-wide units go
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// How long systemd waits before restarting the daemon after it failed
+const RESTART_SEC: u32 = 30;
+
+/// How long systemd waits for the daemon to stop before killing it. The
+/// daemon finishes its current cycle first, which can take a few minutes.
+const TIMEOUT_STOP_SEC: u32 = 300;
+
+/// Write a systemd unit running the daemon, then enable and start it
+///
+/// Run as root, it is a system unit started at boot. Otherwise it is a user
+/// unit of the current user, which only starts at boot with lingering enabled.
+///
+/// # Arguments
+/// * `config_path` - The config file, if there is one
+/// * `args` - Daemon options for the service
+pub fn install(config_path: Option<&Path>, args: &ServiceArgs) -> Result<()> {
+    let system = is_root();
+    let path = unit_path(system)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+
+    let env_file = match config_path {
+        Some(config_path) => config_path.with_file_name("service.env"),
+        None => crate::config::default_config_path()?.with_file_name("service.env"),
+    };
+    let unit = render(config_path, &env_file, args, system)?;
+    fs::write(&path, unit).context(format!("Failed to write {}", path.display()))?;
+    println!("✓ Wrote {}", path.display());
+
+    systemctl(system, &["daemon-reload"])?;
+    systemctl(system, &["enable", "--now", &unit_name()])?;
+    println!("✓ The {} service is enabled and running", SERVICE_NAME);
+    println!("It runs a canary check when it starts with a new binary or config file");
+
+    let user_flag = if system { "" } else { " --user" };
+    println!(
+        "Follow its log with `journalctl{} -u {} -f`",
+        user_flag, SERVICE_NAME
+    );
+    if !system {
+        println!(
+            "To have it start at boot rather than when you log in, run `loginctl enable-linger`"
+        );
+    }
+    print_passphrase_hint(&format!(
+        "put AUTO_WIFI_SECRETS_PASSPHRASE=... in {}, readable by you only, and run `systemctl{} restart {}`",
+        env_file.display(),
+        user_flag,
+        SERVICE_NAME
+    ));
+
+    Ok(())
+}
+
+/// Stop and disable the unit written by `install`, then remove it
+pub fn uninstall() -> Result<()> {
+    let system = if unit_path(false)?.exists() {
+        false
+    } else if unit_path(true)?.exists() {
+        if !is_root() {
+            anyhow::bail!(
+                "The {} service is installed system-wide: uninstall it as root",
+                SERVICE_NAME
+            );
+        }
+        true
+    } else {
+        anyhow::bail!("The {} service isn't installed", SERVICE_NAME);
+    };
+
+    let path = unit_path(system)?;
+    systemctl(system, &["disable", "--now", &unit_name()])?;
+    fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+    systemctl(system, &["daemon-reload"])?;
+    println!("✓ Stopped and removed the {} service", SERVICE_NAME);
+
+    Ok(())
+}
+
+/// The unit file
+///
+/// The daemon runs in the data directory, with the same data directory as
+/// the user installing it, and logs to the console, which systemd sends to
+/// the journal. `SIGTERM` lets it finish its current cycle before stopping.
+///
+/// # Arguments
+/// * `config_path` - The config file, if there is one
+/// * `env_file` - Optional file of extra environment variables
+/// * `args` - Daemon options for the service
+/// * `system` - Whether it is a system unit rather than a user unit
+fn render(
+    config_path: Option<&Path>,
+    env_file: &Path,
+    args: &ServiceArgs,
+    system: bool,
+) -> Result<String> {
+    let exe = env::current_exe().context("Failed to find this executable")?;
+    let data_dir = data_dir()?;
+
+    let mut command = vec![exe.display().to_string()];
+    if let Some(config_path) = config_path {
+        command.push("--config".to_string());
+        command.push(config_path.display().to_string());
+    }
+    command.push("run".to_string());
+    command.extend(daemon_args(args));
+    // Only the command line expands variables
+    let command: Vec<_> = command
+        .iter()
+        .map(|arg| quote(&arg.replace('$', "$$")))
+        .collect();
+
+    let mut unit = String::new();
+    let _ = writeln!(unit, "# Written by `auto-wifi install-service`");
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(unit, "Description={}: {}", DISPLAY_NAME, DESCRIPTION);
+    if system {
+        let _ = writeln!(unit, "Wants=network-online.target");
+        let _ = writeln!(unit, "After=network-online.target");
+    }
+
+    let _ = writeln!(unit, "\n[Service]");
+    let _ = writeln!(unit, "Type=simple");
+    let _ = writeln!(unit, "ExecStart={}", command.join(" "));
+    let _ = writeln!(
+        unit,
+        "WorkingDirectory={}",
+        escape(&data_dir.display().to_string())
+    );
+    let _ = writeln!(
+        unit,
+        "Environment={}",
+        quote(&format!("{}={}", DATA_DIR_VAR, data_dir.display()))
+    );
+    let _ = writeln!(
+        unit,
+        "EnvironmentFile=-{}",
+        escape(&env_file.display().to_string())
+    );
+    let _ = writeln!(unit, "Restart=on-failure");
+    let _ = writeln!(unit, "RestartSec={}", RESTART_SEC);
+    let _ = writeln!(unit, "KillSignal=SIGTERM");
+    let _ = writeln!(unit, "TimeoutStopSec={}", TIMEOUT_STOP_SEC);
+    let _ = writeln!(unit, "StandardOutput=journal");
+    let _ = writeln!(unit, "StandardError=journal");
+    let _ = writeln!(unit, "SyslogIdentifier={}", SERVICE_NAME);
+
+    let _ = writeln!(unit, "\n[Install]");
+    let target = if system {
+        "multi-user.target"
+    } else {
+        "default.target"
+    };
+    let _ = writeln!(unit, "WantedBy={}", target);
+
+    Ok(unit)
+}
+
+/// Where the unit file goes
+///
+/// # Arguments
+/// * `system` - Whether it is a system unit rather than a user unit
+fn unit_path(system: bool) -> Result<PathBuf> {
+    let dir = if system {
+        PathBuf::from(SYSTEM_UNIT_DIR)
+    } else {
+        dirs::config_dir()
+            .context("Could not determine the config directory")?
+            .join("systemd")
+            .join("user")
+    };
+
+    Ok(dir.join(unit_name()))
+}
+
+/// The unit's name
+fn unit_name() -> String {
+    format!("{}.service", SERVICE_NAME)
+}
+
+/// Run systemctl for the system or the user's service manager
+///
+/// # Arguments
+/// * `system` - Whether to talk to the system's service manager
+/// * `args` - The systemctl command
+fn systemctl(system: bool, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("systemctl");
+    if !system {
+        command.arg("--user");
+    }
+    let status = command
+        .args(args)
+        .status()
+        .context("Failed to run systemctl. Is this system using systemd?")?;
+
+    if !status.success() {
+        anyhow::bail!("systemctl {} failed ({})", args.join(" "), status);
+    }
+
+    Ok(())
+}
+
+/// Whether this runs as root
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Quote a value for a unit file, so spaces and quotes are kept as they are
+fn quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        escape(value).replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// Escape the `%` specifiers systemd would otherwise expand in a unit file value
+fn escape(value: &str) -> String {
+    value.replace('%', "%%")
+}
@@ -0,0 +1,300 @@
+use super::{daemon_args, print_passphrase_hint, DESCRIPTION, DISPLAY_NAME, SERVICE_NAME};
+use crate::config::Config;
+use crate::daemon;
+use crate::state::{data_dir, DATA_DIR_VAR};
+use crate::{RunArgs, ServiceArgs};
+use anyhow::{Context, Result};
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tracing::{info, warn};
+use windows_service::service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+    ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// How long the service manager waits before restarting the service after it
+/// failed
+const RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// Failures in a row the service is restarted after; it stays stopped after more
+const RESTART_ATTEMPTS: usize = 3;
+
+/// Time without failures after which the count of failures in a row is reset
+const FAILURE_RESET_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long stopping may take: the daemon finishes its current cycle first
+const STOP_WAIT_HINT: Duration = Duration::from_secs(5 * 60);
+
+/// How often to look whether the service has stopped while uninstalling it
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hands the status handle over from the service manager's thread to `run`
+static STARTED: Mutex<Option<oneshot::Sender<Result<ServiceStatusHandle>>>> = Mutex::new(None);
+
+/// The status handle, for the control handler to report stopping
+static STATUS_HANDLE: OnceLock<ServiceStatusHandle> = OnceLock::new();
+
+/// Notified once `run` has reported the service stopped
+static STOPPED: Notify = Notify::const_new();
+
+/// Register the daemon as a Windows service starting with the system, and
+/// start it
+///
+/// It runs as LocalSystem, with the data directory of the user installing it.
+/// The service manager restarts it when it fails, up to `RESTART_ATTEMPTS`
+/// times in a row. Logs go to the log files, as there is no console.
+///
+/// # Arguments
+/// * `config_path` - The config file, if there is one
+/// * `args` - Daemon options for the service
+pub fn install(config_path: Option<&Path>, args: &ServiceArgs) -> Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context(
+        "Failed to open the service manager. Installing a service needs an Administrator prompt.",
+    )?;
+
+    let mut launch_arguments: Vec<OsString> = Vec::new();
+    if let Some(config_path) = config_path {
+        launch_arguments.push("--config".into());
+        launch_arguments.push(config_path.into());
+    }
+    launch_arguments.push("run-service".into());
+    launch_arguments.push("--data-dir".into());
+    launch_arguments.push(data_dir()?.into());
+    launch_arguments.extend(daemon_args(args).into_iter().map(OsString::from));
+
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().context("Failed to find this executable")?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .context(format!(
+            "Failed to create the {} service. Is it installed already?",
+            SERVICE_NAME
+        ))?;
+
+    service.set_description(DESCRIPTION)?;
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(FAILURE_RESET_PERIOD),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![
+            ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: RESTART_DELAY,
+            };
+            RESTART_ATTEMPTS
+        ]),
+    })?;
+    // Also restart it when it stops with an error rather than crashing
+    service.set_failure_actions_on_non_crash_failures(true)?;
+    println!("✓ Installed the {} service", SERVICE_NAME);
+
+    service
+        .start::<&str>(&[])
+        .context(format!("Failed to start the {} service", SERVICE_NAME))?;
+    println!("✓ The {} service is running", SERVICE_NAME);
+    println!("It runs a canary check when it starts with a new binary or config file");
+    println!(
+        "Its log files are in {}",
+        data_dir()?.join("logs").display()
+    );
+    print_passphrase_hint(&format!(
+        r#"reg add HKLM\SYSTEM\CurrentControlSet\Services\{} /v Environment /t REG_MULTI_SZ /d AUTO_WIFI_SECRETS_PASSPHRASE=..."#,
+        SERVICE_NAME
+    ));
+
+    Ok(())
+}
+
+/// Stop the service, waiting for its current cycle to finish, and remove it
+pub fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("Failed to open the service manager")?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .context(format!(
+            "Failed to open the {} service. Is it installed, and is this an Administrator prompt?",
+            SERVICE_NAME
+        ))?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        println!(
+            "Stopping the {} service after its current check...",
+            SERVICE_NAME
+        );
+        service.stop()?;
+
+        let started = Instant::now();
+        while service.query_status()?.current_state != ServiceState::Stopped {
+            if started.elapsed() > STOP_WAIT_HINT {
+                anyhow::bail!("The {} service didn't stop", SERVICE_NAME);
+            }
+            thread::sleep(STOP_POLL_INTERVAL);
+        }
+    }
+
+    service
+        .delete()
+        .context(format!("Failed to remove the {} service", SERVICE_NAME))?;
+    println!("✓ Stopped and removed the {} service", SERVICE_NAME);
+
+    Ok(())
+}
+
+/// Use the data directory the service was installed with, and run in it.
+/// This has to happen before anything reads the data directory, the secrets
+/// file included.
+///
+/// # Arguments
+/// * `dir` - The data directory given to `run-service`
+pub fn enter_data_dir(dir: &Path) -> Result<()> {
+    env::set_var(DATA_DIR_VAR, dir);
+    env::set_current_dir(dir).context(format!("Failed to change to {}", dir.display()))
+}
+
+/// Run the daemon as the Windows service, reporting to the service manager
+/// when it is running and when it has stopped. A stop request from the
+/// service manager stops the daemon like SIGTERM does.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `args` - Options for the run, which include `--daemon`
+pub async fn run(config: &Config, args: RunArgs) -> Result<()> {
+    let (sender, started) = oneshot::channel();
+    if let Ok(mut pending) = STARTED.lock() {
+        *pending = Some(sender);
+    }
+
+    // Blocks until the service has stopped, calling `service_main` on a
+    // thread of its own
+    thread::spawn(|| {
+        if let Err(e) = service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+            hand_over(Err(anyhow::Error::new(e).context(
+                "run-service is for the Windows service manager only; install the service with `auto-wifi install-service`",
+            )));
+        }
+    });
+
+    let status_handle = started
+        .await
+        .context("The service manager didn't start the service")??;
+    report(
+        &status_handle,
+        ServiceState::Running,
+        ServiceExitCode::NO_ERROR,
+    );
+    info!("Running as the {} Windows service", SERVICE_NAME);
+
+    let result = crate::run(config, args).await;
+
+    // An exit code has the service manager restart the service
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(&status_handle, ServiceState::Stopped, exit_code);
+    STOPPED.notify_one();
+
+    result
+}
+
+/// The service's entry point, called by the service manager on a thread of
+/// its own. Registers for stop requests, then waits until `run` has finished.
+fn service_main(_arguments: Vec<OsString>) {
+    let status_handle = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(status_handle) = STATUS_HANDLE.get() {
+                report(
+                    status_handle,
+                    ServiceState::StopPending,
+                    ServiceExitCode::NO_ERROR,
+                );
+            }
+            daemon::request_shutdown();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    });
+
+    match status_handle {
+        Ok(status_handle) => {
+            let _ = STATUS_HANDLE.set(status_handle);
+            hand_over(Ok(status_handle));
+            futures::executor::block_on(STOPPED.notified());
+        }
+        Err(e) => hand_over(Err(
+            anyhow::Error::new(e).context("Failed to register for service requests")
+        )),
+    }
+}
+
+/// Give `run` the status handle, or why there is none
+///
+/// # Arguments
+/// * `status_handle` - The status handle, or the error getting it
+fn hand_over(status_handle: Result<ServiceStatusHandle>) {
+    let sender = STARTED.lock().ok().and_then(|mut pending| pending.take());
+    if let Some(sender) = sender {
+        let _ = sender.send(status_handle);
+    }
+}
+
+/// Tell the service manager what state the service is in
+///
+/// # Arguments
+/// * `status_handle` - The service's status handle
+/// * `state` - Its new state
+/// * `exit_code` - What it stopped with, for `Stopped`
+fn report(status_handle: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    let wait_hint = match state {
+        ServiceState::StopPending => STOP_WAIT_HINT,
+        _ => Duration::ZERO,
+    };
+
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    };
+    if let Err(e) = status_handle.set_service_status(status) {
+        warn!("Failed to report the service as {:?}: {}", state, e);
+    }
+}
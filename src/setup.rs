@@ -0,0 +1,423 @@
+use crate::commands::print_account;
+use crate::config::{default_config_path, BrowserConfig, Config, CONFIG_VERSION};
+use crate::driver;
+use crate::portal::get_total_use;
+use crate::router::{discover_pppoe_fields, PppoeFields, RouterAccess};
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Router IP offered when none is typed in
+const DEFAULT_ROUTER_IP: &str = "192.168.0.1";
+
+/// How long to wait for the router to answer the probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Share of a data quota used before switching away from the ID
+const DATA_SWITCH_PERCENT: i32 = 95;
+
+/// What has been answered so far, which becomes the config file
+#[derive(Debug, Default)]
+struct Answers {
+    model: String,
+    ip: String,
+    /// Admin username, asked for routers that have one
+    username: Option<String>,
+    password: String,
+    wan_interface: Option<String>,
+    selector_recovery: bool,
+    credentials: Vec<NewCredential>,
+}
+
+/// A PPPoE ID added during setup
+#[derive(Debug)]
+struct NewCredential {
+    id: String,
+    password: String,
+    /// `minutes`, `mb` or `gb`
+    unit: String,
+    /// The quota, for data quotas, which need thresholds of their own
+    quota: Option<i32>,
+}
+
+impl Answers {
+    /// Render the answers as a config file
+    fn to_toml(&self) -> String {
+        let mut out = String::from(
+            "# Written by `auto-wifi setup`. See config.example.toml for the other settings.\n\n",
+        );
+        let _ = writeln!(out, "version = {}\n", CONFIG_VERSION);
+
+        if self.selector_recovery {
+            out.push_str("selector_recovery = true\n\n");
+        }
+        // Only while the router is checked, before any ID has been added
+        if self.credentials.is_empty() {
+            out.push_str("credentials = []\n\n");
+        }
+
+        out.push_str("[router]\n");
+        let _ = writeln!(out, "model = {}", quote(&self.model));
+        let _ = writeln!(out, "ip = {}", quote(&self.ip));
+        if let Some(username) = &self.username {
+            let _ = writeln!(out, "username = {}", quote(username));
+        }
+        let _ = writeln!(out, "password = {}", quote(&self.password));
+        if let Some(wan_interface) = &self.wan_interface {
+            let _ = writeln!(out, "wan_interface = {}", quote(wan_interface));
+        }
+
+        for credential in &self.credentials {
+            out.push_str("\n[[credentials]]\n");
+            let _ = writeln!(out, "id = {}", quote(&credential.id));
+            let _ = writeln!(out, "password = {}", quote(&credential.password));
+            if let Some(quota) = credential.quota {
+                let switch = quota * DATA_SWITCH_PERCENT / 100;
+                let _ = writeln!(out, "unit = {}", quote(&credential.unit));
+                let _ = writeln!(
+                    out,
+                    "thresholds = {{ switch = {}, available = {}, disable = {} }}",
+                    switch, switch, quota
+                );
+            }
+        }
+
+        out
+    }
+
+    /// The configuration as answered so far, to check it against the router
+    /// and the portal. Not validated, since it may have no IDs yet.
+    fn draft(&self) -> Result<Config> {
+        toml::from_str(&self.to_toml()).context("Failed to read back the answers")
+    }
+}
+
+/// Walk a new user through writing the config file, checking the router and
+/// every PPPoE ID as they are entered
+///
+/// # Arguments
+/// * `path` - Config file given on the command line, if any
+pub async fn run(path: Option<&Path>) -> Result<()> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => default_config_path()?,
+    };
+
+    println!("Setting up {}", path.display());
+    if path.exists() && !confirm("It already exists. Replace it?", false)? {
+        println!("Left {} as it is", path.display());
+        return Ok(());
+    }
+
+    // The router and the portal are both read through the browser by default
+    let browser = BrowserConfig::default();
+    let driver_process = match driver::remote_webdriver_url(&browser) {
+        Some(_) => None,
+        None => Some(crate::start_webdriver(&browser).await?),
+    };
+
+    let result = interview(&path).await;
+
+    if let Some(driver_process) = driver_process {
+        crate::stop_webdriver(driver_process);
+    }
+
+    result
+}
+
+/// Ask the questions, then write the config file
+///
+/// # Arguments
+/// * `path` - Where the config file goes
+async fn interview(path: &Path) -> Result<()> {
+    let mut answers = Answers::default();
+
+    println!("\nRouter");
+    let fields = ask_router(&mut answers).await?;
+    print_fields(&fields);
+    answers.selector_recovery = fields.needs_recovery;
+    answers.wan_interface = fields.wan_interface;
+
+    println!("\nPPPoE IDs to rotate between. Leave the ID empty when done.");
+    let running_id = fields.running_id.trim();
+    loop {
+        let suggested =
+            (answers.credentials.is_empty() && !running_id.is_empty()).then_some(running_id);
+        let id = ask("PPPoE ID", suggested)?;
+        if id.is_empty() {
+            if answers.credentials.is_empty() {
+                println!("At least one PPPoE ID is needed");
+                continue;
+            }
+            break;
+        }
+        if answers
+            .credentials
+            .iter()
+            .any(|credential| credential.id == id)
+        {
+            println!("'{}' is already added", id);
+            continue;
+        }
+
+        add_credential(&mut answers, id).await?;
+    }
+
+    let content = answers.to_toml();
+    Config::from_toml(&content).context("The answers don't make a valid config")?;
+    write_config(path, &content)?;
+
+    println!("\n✓ Wrote {}", path.display());
+    println!("Run `auto-wifi status` to try it. Thresholds, notifications and the rest");
+    println!("can be added to it as shown in config.example.toml.");
+    println!("The router isn't switched until `auto-wifi init --acknowledge-risks` is run.");
+
+    Ok(())
+}
+
+/// Ask how to reach the router until logging in to it works, or the user
+/// gives up
+///
+/// # Arguments
+/// * `answers` - Filled in with the router's settings
+///
+/// # Returns
+/// * Where the router keeps the PPPoE credentials
+async fn ask_router(answers: &mut Answers) -> Result<PppoeFields> {
+    answers.model = "dlink".to_string();
+    answers.ip = DEFAULT_ROUTER_IP.to_string();
+
+    // Earlier answers are offered again when trying again
+    loop {
+        answers.model = ask_choice("Router model", &["dlink", "openwrt"], &answers.model)?;
+
+        answers.ip = ask_required("Router IP address", Some(&answers.ip))?;
+        if probe(&answers.ip).await {
+            println!("✓ {} answers", answers.ip);
+        } else {
+            println!("✗ Nothing answers on {}", answers.ip);
+            if !confirm("Use it anyway?", false)? {
+                continue;
+            }
+        }
+
+        answers.username = match answers.model.as_str() {
+            "openwrt" => Some(ask_required(
+                "Admin username",
+                Some(answers.username.as_deref().unwrap_or("root")),
+            )?),
+            _ => None,
+        };
+        answers.password = ask_required("Admin password", None)?;
+
+        println!("Logging in to the router...");
+        let draft = answers.draft()?;
+        let mut router = RouterAccess::open(&draft, true)?;
+        match discover_pppoe_fields(&draft, &mut router).await {
+            Ok(fields) => {
+                println!("✓ Logged in to the router");
+                return Ok(fields);
+            }
+            Err(e) => {
+                println!("✗ {:#}", e);
+                if !confirm("Change the router settings and try again?", true)? {
+                    anyhow::bail!("Setup stopped: the router couldn't be read");
+                }
+            }
+        }
+    }
+}
+
+/// Show where the router keeps the PPPoE credentials
+///
+/// # Arguments
+/// * `fields` - What `discover_pppoe_fields` found
+fn print_fields(fields: &PppoeFields) {
+    println!("PPPoE ID field: {}", fields.username);
+    println!("PPPoE password field: {}", fields.password);
+    match fields.running_id.trim() {
+        "" => println!("No PPPoE ID is set on the router"),
+        running_id => println!("Running PPPoE ID: {}", running_id),
+    }
+    if fields.needs_recovery {
+        println!(
+            "The fields aren't named as expected, so selector_recovery will be turned on to use them"
+        );
+    }
+}
+
+/// Ask for the rest of a PPPoE ID's settings and check it on the portal,
+/// adding it to the answers unless the check fails and the user drops it
+///
+/// # Arguments
+/// * `answers` - The answers so far
+/// * `id` - The PPPoE ID
+async fn add_credential(answers: &mut Answers, id: String) -> Result<()> {
+    let password = ask_required(&format!("Password for '{}'", id), None)?;
+    let unit = ask_choice("Quota unit", &["minutes", "mb", "gb"], "minutes")?;
+    let quota = match unit.as_str() {
+        "minutes" => None,
+        _ => Some(ask_number(&format!("Quota of '{}', in {}", id, unit))?),
+    };
+
+    answers.credentials.push(NewCredential {
+        id: id.clone(),
+        password: password.clone(),
+        unit,
+        quota,
+    });
+
+    println!("Checking '{}' on the portal...", id);
+    let draft = answers.draft()?;
+    match get_total_use(&draft, &id, &password).await {
+        Ok(account) => {
+            println!("✓ Logged in to the portal as '{}'", id);
+            print_account(&draft, &id, &account);
+        }
+        Err(e) => {
+            println!("✗ {:#}", e);
+            if !confirm(&format!("Keep '{}' anyway?", id), false)? {
+                answers.credentials.pop();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether anything answers on the router's web interface
+///
+/// # Arguments
+/// * `ip` - The router's address, optionally with a port
+async fn probe(ip: &str) -> bool {
+    let address = if ip.contains(':') {
+        ip.to_string()
+    } else {
+        format!("{}:80", ip)
+    };
+
+    matches!(
+        timeout(PROBE_TIMEOUT, TcpStream::connect(address.as_str())).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Write the config file, readable by its owner only since it holds passwords
+///
+/// # Arguments
+/// * `path` - Where the config file goes
+/// * `content` - The config file
+fn write_config(path: &Path, content: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+    fs::write(path, content).context(format!("Failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .context(format!("Failed to restrict access to {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Quote a string for the config file
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// Ask a question on the terminal
+///
+/// # Arguments
+/// * `question` - What to ask
+/// * `default` - The answer when the reply is empty
+///
+/// # Returns
+/// * The reply, or the default. Empty if there is neither.
+fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+
+    let mut reply = String::new();
+    if io::stdin().read_line(&mut reply)? == 0 {
+        anyhow::bail!("Setup cancelled");
+    }
+
+    Ok(match reply.trim() {
+        "" => default.unwrap_or_default().to_string(),
+        reply => reply.to_string(),
+    })
+}
+
+/// Ask a question until the reply isn't empty
+///
+/// # Arguments
+/// * `question` - What to ask
+/// * `default` - The answer when the reply is empty
+fn ask_required(question: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        let reply = ask(question, default)?;
+        if !reply.is_empty() {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Ask a question until the reply is one of the choices
+///
+/// # Arguments
+/// * `question` - What to ask
+/// * `choices` - The allowed replies
+/// * `default` - The answer when the reply is empty
+fn ask_choice(question: &str, choices: &[&str], default: &str) -> Result<String> {
+    let question = format!("{} ({})", question, choices.join(", "));
+    loop {
+        let reply = ask(&question, Some(default))?.to_lowercase();
+        if choices.contains(&reply.as_str()) {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Ask a question until the reply is a positive whole number
+///
+/// # Arguments
+/// * `question` - What to ask
+fn ask_number(question: &str) -> Result<i32> {
+    loop {
+        match ask(question, None)?.parse() {
+            Ok(number) if number > 0 => return Ok(number),
+            _ => println!("A whole number above 0 is needed"),
+        }
+    }
+}
+
+/// Ask a yes or no question
+///
+/// # Arguments
+/// * `question` - What to ask
+/// * `default` - The answer when the reply is empty
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match ask(&format!("{} [{}]", question, hint), None)?
+            .to_lowercase()
+            .as_str()
+        {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::automation::RouterControl;
+use crate::config::{Config, GuestSsidConfig, QuotaUnit};
+use crate::state::{GuestSsid, State};
+use tracing::{info, instrument, warn};
+
+/// Longest network name WiFi allows, in bytes
+const MAX_SSID_LEN: usize = 32;
+
+/// Rename the guest WiFi network to show what is left of the running ID, if
+/// `[guest_ssid]` is set and that has changed by at least `min_change` since
+/// the last rename (or the running ID changed).
+///
+/// This is only for show, so failures are logged and otherwise ignored.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `router` - The router the guest network is on
+/// * `pppoe_id` - The running PPPoE ID
+/// * `usage` - Its usage just read, in minutes or megabytes
+#[instrument(skip_all, fields(pppoe_id = %pppoe_id))]
+pub async fn update(config: &Config, router: &mut dyn RouterControl, pppoe_id: &str, usage: i32) {
+    let Some(guest_ssid) = &config.guest_ssid else {
+        return;
+    };
+
+    let left = (config.thresholds_for(pppoe_id).switch - usage).max(0);
+    let shown = State::load().guest_ssid;
+    if let Some(shown) = &shown {
+        if shown.pppoe_id == pppoe_id && (shown.left - left).abs() < guest_ssid.min_change {
+            return;
+        }
+    }
+
+    let ssid = render(config, guest_ssid, pppoe_id, left);
+    if shown.is_some_and(|shown| shown.ssid == ssid) {
+        return;
+    }
+
+    if let Err(e) = router.set_wifi_ssid(&guest_ssid.interface, &ssid).await {
+        warn!("Failed to rename the guest WiFi network: {:#}", e);
+        return;
+    }
+    if router.dry_run() {
+        return;
+    }
+    info!("✓ Guest WiFi network renamed to '{}'", ssid);
+
+    let mut state = State::load();
+    state.guest_ssid = Some(GuestSsid {
+        pppoe_id: pppoe_id.to_string(),
+        left,
+        ssid,
+    });
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Fill in the guest network name, cut down to what WiFi allows
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `guest_ssid` - The `[guest_ssid]` section of the config file
+/// * `pppoe_id` - The running PPPoE ID
+/// * `left` - What is left of it before the switch threshold
+fn render(config: &Config, guest_ssid: &GuestSsidConfig, pppoe_id: &str, left: i32) -> String {
+    let left = if config.is_unlimited(pppoe_id) {
+        "unlimited".to_string()
+    } else {
+        match config.quota_unit(pppoe_id) {
+            QuotaUnit::Minutes => format!("{}min", left),
+            QuotaUnit::Mb => format!("{}MB", left),
+            QuotaUnit::Gb => format!("{:.1}GB", f64::from(left) / 1024.0),
+        }
+    };
+
+    let mut ssid = guest_ssid
+        .template
+        .replace("{left}", &left)
+        .replace("{id}", pppoe_id);
+
+    while ssid.len() > MAX_SSID_LEN {
+        ssid.pop();
+    }
+
+    ssid
+}
@@ -0,0 +1,653 @@
+use crate::storage::{state_store, StateStore};
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Name of the directory (under the platform's local data dir) holding persistent files
+const APP_DIR_NAME: &str = "auto_pppoe_quota_manager";
+
+/// Environment variable overriding the data directory, e.g. a container volume
+pub const DATA_DIR_VAR: &str = "AUTO_WIFI_DATA_DIR";
+
+/// Name of the JSON file holding state carried over between runs
+pub const STATE_FILE_NAME: &str = "state.json";
+
+/// Version of the state file format, written as its `version`; older files
+/// are upgraded by `crate::migrate`
+pub const STATE_VERSION: u32 = 2;
+
+/// An error that has been seen on consecutive runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorStreak {
+    /// The error message, used to decide whether two failures are "the same"
+    pub message: String,
+    /// How many consecutive runs have failed with this message
+    pub count: u32,
+}
+
+/// What the router's login page looked like, used to notice firmware updates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouterFingerprint {
+    /// The login page's title
+    pub title: String,
+    /// Any firmware/version text found on the login page
+    pub firmware: String,
+}
+
+/// Running totals of what the tool has done, kept across restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Counters {
+    /// Runs started
+    #[serde(default)]
+    pub runs: u64,
+    /// Runs that failed with an error
+    #[serde(default)]
+    pub failed_runs: u64,
+    /// Successful portal usage checks
+    #[serde(default)]
+    pub usage_checks: u64,
+    /// Portal usage checks that failed
+    #[serde(default)]
+    pub usage_check_failures: u64,
+    /// Successful switches to another PPPoE ID
+    #[serde(default)]
+    pub switches: u64,
+    /// Switches that failed
+    #[serde(default)]
+    pub switch_failures: u64,
+    /// Times the connection was disabled because every ID was exhausted
+    #[serde(default)]
+    pub disables: u64,
+    /// Bytes downloaded by the tool's browser sessions
+    #[serde(default)]
+    pub browser_bytes: u64,
+}
+
+/// The last usage figure read from the portal for a PPPoE ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageReading {
+    /// Total use in minutes
+    pub minutes: i32,
+    /// When it was read (unix seconds)
+    pub checked_at: u64,
+}
+
+impl UsageReading {
+    /// How long ago this reading was taken, in seconds
+    pub fn age_secs(&self) -> u64 {
+        unix_now().saturating_sub(self.checked_at)
+    }
+}
+
+/// Account details read from the ISP portal for a PPPoE ID
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountDetails {
+    /// When the account's validity runs out
+    #[serde(default)]
+    pub expiry: Option<NaiveDate>,
+    /// What it costs to recharge the account
+    #[serde(default)]
+    pub recharge_amount: Option<String>,
+    /// The last day an expiry reminder was sent for this account
+    #[serde(default)]
+    pub reminded_on: Option<NaiveDate>,
+    /// Name of the portal layout the account was last read from
+    #[serde(default)]
+    pub portal_variant: Option<String>,
+    /// The last day the recharge command was run for this account
+    #[serde(default)]
+    pub recharge_command_on: Option<NaiveDate>,
+}
+
+/// The connection was disabled because every ID was used up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Disabled {
+    /// The PPPoE ID left on the router with the dummy password
+    pub pppoe_id: String,
+    /// When the connection was first disabled (unix seconds)
+    pub since: u64,
+    /// Whether the phone call about it has been made
+    #[serde(default)]
+    pub escalated: bool,
+}
+
+impl Disabled {
+    /// Seconds since the connection was disabled
+    pub fn age_secs(&self) -> u64 {
+        unix_now().saturating_sub(self.since)
+    }
+}
+
+/// What the guest WiFi network's name was last set to show
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestSsid {
+    /// The running PPPoE ID it showed
+    pub pppoe_id: String,
+    /// What was left of it, in minutes or MB
+    pub left: i32,
+    /// The name it was given
+    pub ssid: String,
+}
+
+/// The PPPoE settings found on the router before this tool changed them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OriginalPppoe {
+    /// The PPPoE username
+    pub username: String,
+    /// The PPPoE password
+    pub password: String,
+    /// When they were read (unix seconds)
+    pub read_at: u64,
+}
+
+/// State persisted between runs of the tool
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The error the last run(s) failed with, if any
+    #[serde(default)]
+    pub error_streak: Option<ErrorStreak>,
+    /// The login page fingerprint seen on the last run of each router, by IP
+    /// address
+    #[serde(default)]
+    pub router_fingerprints: HashMap<String, RouterFingerprint>,
+    /// Totals that should survive restarts and binary updates
+    #[serde(default)]
+    pub counters: Counters,
+    /// When each PPPoE ID was last seen in use (unix seconds)
+    #[serde(default)]
+    pub last_used: HashMap<String, u64>,
+    /// The most recent usage reading for each PPPoE ID
+    #[serde(default)]
+    pub usage_cache: HashMap<String, UsageReading>,
+    /// Expiry and recharge details for each PPPoE ID
+    #[serde(default)]
+    pub accounts: HashMap<String, AccountDetails>,
+    /// Set while the connection is disabled
+    #[serde(default)]
+    pub disabled: Option<Disabled>,
+    /// The PPPoE ID last switched to, where round-robin rotation carries on from
+    #[serde(default)]
+    pub rotation_position: Option<String>,
+    /// When a run last completed without error (unix seconds)
+    #[serde(default)]
+    pub last_success: Option<u64>,
+    /// What the guest WiFi network was last renamed to show
+    #[serde(default)]
+    pub guest_ssid: Option<GuestSsid>,
+    /// The last day the usage history was compacted
+    #[serde(default)]
+    pub history_compacted_on: Option<NaiveDate>,
+    /// The last day each PPPoE ID was warned about for being ahead of
+    /// `[schedule]`
+    #[serde(default)]
+    pub schedule_warned_on: HashMap<String, NaiveDate>,
+    /// How many implausible readings in a row each PPPoE ID has had
+    #[serde(default)]
+    pub implausible_readings: HashMap<String, u32>,
+    /// The binary and config file the last canary check passed with
+    #[serde(default)]
+    pub canary_fingerprint: Option<String>,
+    /// When `init --acknowledge-risks` was run (unix seconds); the router's
+    /// PPPoE settings aren't changed before
+    #[serde(default)]
+    pub risks_acknowledged_at: Option<u64>,
+    /// The router's PPPoE settings as `init` found them
+    #[serde(default)]
+    pub original_pppoe: Option<OriginalPppoe>,
+    /// How many runs in a row have put off disabling the connection because
+    /// some IDs couldn't be checked
+    #[serde(default)]
+    pub deferred_disables: u32,
+    /// A hash of the router admin password that last worked, so later runs
+    /// try it first
+    #[serde(default)]
+    pub router_password: Option<String>,
+}
+
+/// Get the directory where persistent files are stored, creating it if needed
+///
+/// # Returns
+/// * `AUTO_WIFI_DATA_DIR` if set, otherwise e.g.
+///   `~/.local/share/auto_pppoe_quota_manager` on Linux or
+///   `%LOCALAPPDATA%\auto_pppoe_quota_manager` on Windows
+pub fn data_dir() -> Result<PathBuf> {
+    let dir = match env::var_os(DATA_DIR_VAR).filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::data_local_dir()
+            .context("Could not determine the local data directory")?
+            .join(APP_DIR_NAME),
+    };
+
+    fs::create_dir_all(&dir)
+        .context(format!("Failed to create data directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// The state file in the data directory, where the state is kept unless
+/// `[storage]` says otherwise
+pub struct StateFile;
+
+impl StateStore for StateFile {
+    fn load_state(&self) -> Result<Option<String>> {
+        let path = data_dir()?.join(STATE_FILE_NAME);
+
+        // First run, nothing saved yet
+        Ok(fs::read_to_string(&path).ok())
+    }
+
+    fn save_state(&self, content: &str) -> Result<()> {
+        let path = data_dir()?.join(STATE_FILE_NAME);
+        fs::write(&path, content)
+            .context(format!("Failed to write state file {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+impl State {
+    /// Load the state from where it is kept, see `crate::storage::state_store`
+    ///
+    /// Missing or unreadable state is not fatal: the tool simply starts over
+    /// with empty state, as it would on its very first run.
+    pub fn load() -> Self {
+        let content = match state_store().load_state() {
+            Ok(Some(content)) => content,
+            Ok(None) => return Self::default(),
+            Err(e) => {
+                warn!("{:#}. Starting with empty state.", e);
+                return Self::default();
+            }
+        };
+
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!(
+                "Could not parse the saved state ({}). Starting with empty state.",
+                e
+            );
+            Self::default()
+        })
+    }
+
+    /// The PPPoE ID most recently seen running on the router
+    pub fn last_running_id(&self) -> Option<&str> {
+        self.last_used
+            .iter()
+            .max_by_key(|(_, last_used)| **last_used)
+            .map(|(pppoe_id, _)| pppoe_id.as_str())
+    }
+
+    /// Save the state where it is kept, see `crate::storage::state_store`
+    pub fn save(&self) -> Result<()> {
+        let mut state = serde_json::to_value(self)?;
+        state["version"] = STATE_VERSION.into();
+        let content = serde_json::to_string_pretty(&state)?;
+
+        state_store().save_state(&content)
+    }
+
+    /// Record a failed run
+    ///
+    /// # Arguments
+    /// * `message` - The error the run failed with
+    ///
+    /// # Returns
+    /// * How many consecutive runs have now failed with this same error
+    pub fn record_error(&mut self, message: &str) -> u32 {
+        match &mut self.error_streak {
+            Some(streak) if streak.message == message => streak.count += 1,
+            _ => {
+                self.error_streak = Some(ErrorStreak {
+                    message: message.to_string(),
+                    count: 1,
+                })
+            }
+        }
+
+        self.error_streak.as_ref().map_or(1, |streak| streak.count)
+    }
+
+    /// Record a successful run
+    ///
+    /// # Returns
+    /// * The error streak that this run ended, if the previous run(s) had failed
+    pub fn record_success(&mut self) -> Option<ErrorStreak> {
+        self.last_success = Some(unix_now());
+        self.error_streak.take()
+    }
+}
+
+/// Update the persisted counters
+///
+/// # Arguments
+/// * `update` - Modifies the counters, e.g. `|c| c.switches += 1`
+pub fn bump_counters(update: impl FnOnce(&mut Counters)) {
+    let mut state = State::load();
+    update(&mut state.counters);
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save counters: {}", e);
+    }
+}
+
+/// Record that a PPPoE ID is in use right now
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID currently running on the router
+pub fn mark_in_use(pppoe_id: &str) {
+    let mut state = State::load();
+    state.last_used.insert(pppoe_id.to_string(), unix_now());
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Record that a PPPoE ID was warned about for being ahead of schedule today
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID
+///
+/// # Returns
+/// * Whether it hadn't been yet today
+pub fn mark_schedule_warned(pppoe_id: &str) -> bool {
+    let today = Local::now().date_naive();
+    let mut state = State::load();
+    if state.schedule_warned_on.get(pppoe_id) == Some(&today) {
+        return false;
+    }
+    state.schedule_warned_on.insert(pppoe_id.to_string(), today);
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+    true
+}
+
+/// Remember a usage reading taken at another time, unless the one already
+/// remembered is newer
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the reading is for
+/// * `minutes` - The total use read from the portal
+/// * `checked_at` - When it was read (unix seconds)
+///
+/// # Returns
+/// * Whether it is now the reading remembered
+pub fn record_usage_at(pppoe_id: &str, minutes: i32, checked_at: u64) -> bool {
+    let mut state = State::load();
+    if state
+        .usage_cache
+        .get(pppoe_id)
+        .is_some_and(|reading| reading.checked_at >= checked_at)
+    {
+        return false;
+    }
+    state.usage_cache.insert(
+        pppoe_id.to_string(),
+        UsageReading {
+            minutes,
+            checked_at,
+        },
+    );
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+    true
+}
+
+/// Remember a usage reading so later runs can skip the portal when it's fresh
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the reading is for
+/// * `minutes` - The total use read from the portal
+pub fn record_usage(pppoe_id: &str, minutes: i32) {
+    let mut state = State::load();
+    state.usage_cache.insert(
+        pppoe_id.to_string(),
+        UsageReading {
+            minutes,
+            checked_at: unix_now(),
+        },
+    );
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Remember the expiry and recharge details the portal showed for an account
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the details are for
+/// * `expiry` - The account's expiry date, if shown
+/// * `recharge_amount` - The recharge amount, if shown
+pub fn record_account_details(
+    pppoe_id: &str,
+    expiry: Option<NaiveDate>,
+    recharge_amount: Option<String>,
+) {
+    let mut state = State::load();
+    let details = state.accounts.entry(pppoe_id.to_string()).or_default();
+
+    // A new expiry date means the account was recharged, so remind again next time
+    if details.expiry != expiry {
+        details.reminded_on = None;
+        details.recharge_command_on = None;
+    }
+    details.expiry = expiry;
+    details.recharge_amount = recharge_amount;
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Remember which portal layout an account was read from
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the account is for
+/// * `variant` - Name of the layout that matched
+///
+/// # Returns
+/// * The layout the account was read from before, if it was a different one
+pub fn record_portal_variant(pppoe_id: &str, variant: &str) -> Option<String> {
+    let mut state = State::load();
+    let details = state.accounts.entry(pppoe_id.to_string()).or_default();
+    if details.portal_variant.as_deref() == Some(variant) {
+        return None;
+    }
+
+    let previous = details.portal_variant.replace(variant.to_string());
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+    previous
+}
+
+/// Count an implausible usage reading of a PPPoE ID
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the reading is for
+///
+/// # Returns
+/// * How many it has had in a row, this one included
+pub fn record_implausible_reading(pppoe_id: &str) -> u32 {
+    let mut state = State::load();
+    let in_a_row = state
+        .implausible_readings
+        .entry(pppoe_id.to_string())
+        .or_default();
+    *in_a_row += 1;
+    let in_a_row = *in_a_row;
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+    in_a_row
+}
+
+/// Forget the implausible readings of a PPPoE ID, once one is believed
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID
+pub fn clear_implausible_readings(pppoe_id: &str) {
+    let mut state = State::load();
+    if state.implausible_readings.remove(pppoe_id).is_none() {
+        return;
+    }
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Remember the PPPoE ID just switched to, for round-robin rotation
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID switched to
+pub fn record_rotation(pppoe_id: &str) {
+    let mut state = State::load();
+    state.rotation_position = Some(pppoe_id.to_string());
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Record that the connection was disabled
+///
+/// A connection that is still disabled from an earlier run keeps its original
+/// time, so disabling it again on every run doesn't restart the clock.
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID left on the router with the dummy password
+pub fn record_disabled(pppoe_id: &str) {
+    let mut state = State::load();
+    if state.disabled.is_some() {
+        return;
+    }
+
+    state.disabled = Some(Disabled {
+        pppoe_id: pppoe_id.to_string(),
+        since: unix_now(),
+        escalated: false,
+    });
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Count a run that put off disabling the connection
+///
+/// # Returns
+/// * How many runs in a row have now put it off
+pub fn record_deferred_disable() -> u32 {
+    let mut state = State::load();
+    state.deferred_disables += 1;
+    let in_a_row = state.deferred_disables;
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+    in_a_row
+}
+
+/// Forget the runs that put off disabling the connection, once one doesn't
+pub fn clear_deferred_disables() {
+    let mut state = State::load();
+    if state.deferred_disables == 0 {
+        return;
+    }
+
+    state.deferred_disables = 0;
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Record that the connection works again, after a switch to an ID with quota
+pub fn clear_disabled() {
+    let mut state = State::load();
+    if state.disabled.take().is_none() {
+        return;
+    }
+
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Current time in unix seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Decide whether the Nth consecutive occurrence of an error deserves a notification
+///
+/// Notifies on occurrences 1, 3, 10, 30, 100, 300, ... so that an error that
+/// keeps recurring on every scheduled run doesn't flood the desktop.
+///
+/// # Arguments
+/// * `count` - How many consecutive runs have failed with the same error
+pub fn should_notify_error(count: u32) -> bool {
+    let mut milestone = 1;
+    while milestone <= count {
+        if count == milestone || count == milestone * 3 {
+            return true;
+        }
+        milestone = match milestone.checked_mul(10) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    false
+}
+
+/// Which of the router admin passwords worked last time, if any
+///
+/// # Arguments
+/// * `passwords` - The admin passwords to choose from
+///
+/// # Returns
+/// * The index of the password whose hash was remembered
+pub fn remembered_router_password(passwords: &[String]) -> Option<usize> {
+    let remembered = State::load().router_password?;
+    passwords
+        .iter()
+        .position(|password| password_hash(password) == remembered)
+}
+
+/// Remember the router admin password that worked, so later runs try it
+/// first. Only a hash of it goes into the state file.
+///
+/// # Arguments
+/// * `password` - The admin password the router accepted
+pub fn remember_router_password(password: &str) {
+    let hash = password_hash(password);
+    let mut state = State::load();
+    if state.router_password.as_ref() == Some(&hash) {
+        return;
+    }
+
+    state.router_password = Some(hash);
+    if let Err(e) = state.save() {
+        warn!("Failed to save state: {}", e);
+    }
+}
+
+/// Hex-encoded SHA-256 of a router admin password
+fn password_hash(password: &str) -> String {
+    format!("{:x}", Sha256::digest(password.as_bytes()))
+}
@@ -0,0 +1,474 @@
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+use crate::config::{HistoryConfig, StorageBackend, StorageConfig};
+use crate::state::{State, StateFile};
+use anyhow::Result;
+use chrono::{Local, Utc};
+use std::path::Path;
+#[cfg(feature = "postgres")]
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+/// Name of the SQLite database (in the data directory) holding usage history
+pub const HISTORY_FILE_NAME: &str = "history.db";
+
+/// Samples closer together than this don't give a meaningful consumption rate
+const MIN_RATE_SPAN_SECS: i64 = 60 * 60;
+
+/// One usage reading from the portal
+#[derive(Debug, Clone)]
+pub struct UsageSample {
+    /// When it was read (unix seconds)
+    pub timestamp: i64,
+    /// The PPPoE ID it is for
+    pub pppoe_id: String,
+    /// Total use in minutes
+    pub minutes: i32,
+}
+
+/// What `History::compact` removed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compaction {
+    /// Readings dropped for being more detailed than their age calls for
+    pub downsampled: usize,
+    /// Readings, switches, sessions, connection events and external IPs past
+    /// `history.retention_days`
+    pub expired: usize,
+}
+
+/// A change the tool made on the router
+#[derive(Debug, Clone)]
+pub struct RouterAction {
+    /// When it happened (unix seconds)
+    pub timestamp: i64,
+    /// `switch`, `disable` or `enable`
+    pub kind: String,
+    /// The PPPoE ID running before the change
+    pub from_id: String,
+    /// The PPPoE ID switched to, for switches
+    pub to_id: Option<String>,
+    /// Usage of `from_id` when the change was made, if known
+    pub minutes: Option<i32>,
+}
+
+/// A router's PPPoE connection coming up, going down or failing to log in,
+/// as it logged it
+#[derive(Debug, Clone)]
+pub struct LinkEvent {
+    /// When the line was received (unix seconds)
+    pub timestamp: i64,
+    /// The router's address
+    pub router: String,
+    /// `up`, `down` or `login_failed`
+    pub kind: String,
+    /// The log line
+    pub line: String,
+}
+
+/// An external IP address the WAN connection was seen with
+#[derive(Debug, Clone)]
+pub struct ExternalIp {
+    /// When it was seen (unix seconds)
+    pub timestamp: i64,
+    /// The PPPoE ID running at the time
+    pub pppoe_id: String,
+    /// The address
+    pub ip: String,
+    /// `connect` after a switch or re-enable, `changed` when it changed
+    /// without one, or `seen` when there was nothing to compare it to
+    pub kind: String,
+}
+
+/// One connection as listed on the portal's sessions page
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// The PPPoE ID it was on
+    pub pppoe_id: String,
+    /// When it started (unix seconds)
+    pub start: i64,
+    /// When it ended (unix seconds), if the portal shows it
+    pub end: Option<i64>,
+    /// What it used, in minutes (or MB for data quotas)
+    pub minutes: i32,
+    /// The IP address it was given, if the portal shows it
+    pub ip: Option<String>,
+}
+
+/// What the usage history is kept in. Each method does what the `History`
+/// method of the same name does, with the time things happened given.
+pub trait HistoryStore: Send {
+    fn add_usage_at(&self, timestamp: i64, pppoe_id: &str, minutes: i32) -> Result<()>;
+    fn first_usage_at(&self, pppoe_id: &str) -> Result<Option<i64>>;
+    fn add_action(
+        &self,
+        timestamp: i64,
+        kind: &str,
+        from_id: &str,
+        to_id: Option<&str>,
+        minutes: Option<i32>,
+    ) -> Result<()>;
+    fn add_link_event(&self, timestamp: i64, router: &str, kind: &str, line: &str) -> Result<()>;
+    fn add_external_ip(&self, timestamp: i64, pppoe_id: &str, ip: &str, kind: &str) -> Result<()>;
+    fn add_sessions(&self, sessions: &[Session]) -> Result<usize>;
+    fn compact(&self, config: &HistoryConfig) -> Result<Compaction>;
+    fn snapshot(&self, path: &Path) -> Result<()>;
+    fn sessions_since(&self, since: i64) -> Result<Vec<Session>>;
+    fn usage_since(&self, since: i64) -> Result<Vec<UsageSample>>;
+    fn actions_since(&self, since: i64) -> Result<Vec<RouterAction>>;
+    fn link_events_since(&self, since: i64) -> Result<Vec<LinkEvent>>;
+    fn external_ips_since(&self, since: i64) -> Result<Vec<ExternalIp>>;
+    fn last_external_ip(&self) -> Result<Option<ExternalIp>>;
+}
+
+/// What the state is kept in between runs, see `crate::state::State`
+pub trait StateStore: Send {
+    /// The state as last saved, as JSON, or `None` if it never was
+    fn load_state(&self) -> Result<Option<String>>;
+
+    /// Replace the saved state
+    ///
+    /// # Arguments
+    /// * `content` - The state, as JSON
+    fn save_state(&self, content: &str) -> Result<()>;
+}
+
+/// The database set in `[storage]`, once `init` has connected to it
+#[cfg(feature = "postgres")]
+static POSTGRES: OnceLock<PostgresStore> = OnceLock::new();
+
+/// Connect to the store set in the `[storage]` config section, so the usage
+/// history and state are kept there from now on. Without this they are kept
+/// in the data directory.
+///
+/// # Arguments
+/// * `config` - The `[storage]` section of the config file
+pub fn init(config: &StorageConfig) -> Result<()> {
+    match config.backend {
+        StorageBackend::Sqlite => Ok(()),
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres => {
+            if POSTGRES.get().is_none() {
+                let store = PostgresStore::connect(config)?;
+                info!(
+                    "✓ Keeping history and state in PostgreSQL as site '{}'",
+                    store.site()
+                );
+                let _ = POSTGRES.set(store);
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "postgres"))]
+        StorageBackend::Postgres => anyhow::bail!(
+            "storage.backend = \"postgres\" needs a build with the `postgres` feature (cargo build --features postgres)"
+        ),
+    }
+}
+
+/// Where the state is kept: the database set in `[storage]`, or the state
+/// file in the data directory
+pub fn state_store() -> Box<dyn StateStore> {
+    #[cfg(feature = "postgres")]
+    if let Some(store) = POSTGRES.get() {
+        return Box::new(store.clone());
+    }
+
+    Box::new(StateFile)
+}
+
+/// The usage history
+pub struct History {
+    store: Box<dyn HistoryStore>,
+}
+
+impl History {
+    /// Open the usage history: the database set in `[storage]`, or the SQLite
+    /// database in the data directory, created if needed
+    pub fn open() -> Result<Self> {
+        #[cfg(feature = "postgres")]
+        if let Some(store) = POSTGRES.get() {
+            return Ok(Self {
+                store: Box::new(store.clone()),
+            });
+        }
+
+        Ok(Self {
+            store: Box::new(SqliteStore::open()?),
+        })
+    }
+
+    /// Record a usage reading
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID the reading is for
+    /// * `minutes` - The total use read from the portal
+    pub fn add_usage(&self, pppoe_id: &str, minutes: i32) -> Result<()> {
+        self.add_usage_at(Utc::now().timestamp(), pppoe_id, minutes)
+    }
+
+    /// Record a usage reading taken at another time, e.g. one imported from
+    /// the portal's history page
+    ///
+    /// # Arguments
+    /// * `timestamp` - When the reading applies (unix seconds)
+    /// * `pppoe_id` - The PPPoE ID the reading is for
+    /// * `minutes` - The total use at that time
+    pub fn add_usage_at(&self, timestamp: i64, pppoe_id: &str, minutes: i32) -> Result<()> {
+        self.store.add_usage_at(timestamp, pppoe_id, minutes)
+    }
+
+    /// When the oldest usage reading of a PPPoE ID was taken (unix seconds)
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID
+    pub fn first_usage_at(&self, pppoe_id: &str) -> Result<Option<i64>> {
+        self.store.first_usage_at(pppoe_id)
+    }
+
+    /// Record a switch or disable
+    ///
+    /// # Arguments
+    /// * `kind` - `switch`, `disable` or `enable`
+    /// * `from_id` - The PPPoE ID running before the change
+    /// * `to_id` - The PPPoE ID switched to, for switches
+    /// * `minutes` - Usage of `from_id` when the change was made, if known
+    pub fn add_action(
+        &self,
+        kind: &str,
+        from_id: &str,
+        to_id: Option<&str>,
+        minutes: Option<i32>,
+    ) -> Result<()> {
+        self.store
+            .add_action(Utc::now().timestamp(), kind, from_id, to_id, minutes)
+    }
+
+    /// Record a router's PPPoE connection coming up, going down or failing
+    /// to log in
+    ///
+    /// # Arguments
+    /// * `router` - The router's address
+    /// * `kind` - `up`, `down` or `login_failed`
+    /// * `line` - The log line saying so
+    pub fn add_link_event(&self, router: &str, kind: &str, line: &str) -> Result<()> {
+        self.store
+            .add_link_event(Utc::now().timestamp(), router, kind, line)
+    }
+
+    /// Record the external IP address the WAN connection has
+    ///
+    /// # Arguments
+    /// * `pppoe_id` - The PPPoE ID running
+    /// * `ip` - The address
+    /// * `kind` - `connect`, `changed` or `seen`
+    pub fn add_external_ip(&self, pppoe_id: &str, ip: &str, kind: &str) -> Result<()> {
+        self.store
+            .add_external_ip(Utc::now().timestamp(), pppoe_id, ip, kind)
+    }
+
+    /// Store sessions read from the portal, skipping ones already stored
+    ///
+    /// # Arguments
+    /// * `sessions` - The sessions
+    ///
+    /// # Returns
+    /// * How many were new
+    pub fn add_sessions(&self, sessions: &[Session]) -> Result<usize> {
+        self.store.add_sessions(sessions)
+    }
+
+    /// Thin out old usage readings and delete what is past retention, as set
+    /// in the `[history]` config section, then shrink the database file.
+    ///
+    /// Readings older than `raw_days` are cut down to the last of each hour,
+    /// and those older than `hourly_days` to the last of each (UTC) day. The
+    /// last reading of a span is what the usage counter had reached by its
+    /// end, so daily totals and rates come out the same.
+    ///
+    /// # Arguments
+    /// * `config` - The `[history]` section of the config file
+    pub fn compact(&self, config: &HistoryConfig) -> Result<Compaction> {
+        self.store.compact(config)
+    }
+
+    /// Write a consistent copy of the database, even while it is in use
+    ///
+    /// # Arguments
+    /// * `path` - Where the copy goes; nothing may be there yet
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        self.store.snapshot(path)
+    }
+
+    /// All sessions started since a point in time, oldest first
+    ///
+    /// # Arguments
+    /// * `since` - Unix seconds
+    pub fn sessions_since(&self, since: i64) -> Result<Vec<Session>> {
+        self.store.sessions_since(since)
+    }
+
+    /// All usage readings taken since a point in time, oldest first
+    ///
+    /// # Arguments
+    /// * `since` - Unix seconds
+    pub fn usage_since(&self, since: i64) -> Result<Vec<UsageSample>> {
+        self.store.usage_since(since)
+    }
+
+    /// All switches and disables since a point in time, oldest first
+    ///
+    /// # Arguments
+    /// * `since` - Unix seconds
+    pub fn actions_since(&self, since: i64) -> Result<Vec<RouterAction>> {
+        self.store.actions_since(since)
+    }
+
+    /// All PPPoE connection events the routers logged since a point in time,
+    /// oldest first
+    ///
+    /// # Arguments
+    /// * `since` - Unix seconds
+    pub fn link_events_since(&self, since: i64) -> Result<Vec<LinkEvent>> {
+        self.store.link_events_since(since)
+    }
+
+    /// All external IP addresses recorded since a point in time, oldest first
+    ///
+    /// # Arguments
+    /// * `since` - Unix seconds
+    pub fn external_ips_since(&self, since: i64) -> Result<Vec<ExternalIp>> {
+        self.store.external_ips_since(since)
+    }
+
+    /// The external IP address recorded last, if any
+    pub fn last_external_ip(&self) -> Result<Option<ExternalIp>> {
+        self.store.last_external_ip()
+    }
+}
+
+/// Add a usage reading to the history, warning instead of failing
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID the reading is for
+/// * `minutes` - The total use read from the portal
+pub fn record_usage_sample(pppoe_id: &str, minutes: i32) {
+    if let Err(e) = History::open().and_then(|history| history.add_usage(pppoe_id, minutes)) {
+        warn!("Failed to record usage history: {}", e);
+    }
+}
+
+/// Store sessions read from the portal, warning instead of failing
+///
+/// # Arguments
+/// * `sessions` - The sessions
+pub fn record_sessions(sessions: &[Session]) {
+    match History::open().and_then(|history| history.add_sessions(sessions)) {
+        Ok(0) => {}
+        Ok(added) => info!("Stored {} new session(s)", added),
+        Err(e) => warn!("Failed to record sessions: {}", e),
+    }
+}
+
+/// Add a switch, disable or re-enable to the history, warning instead of failing
+///
+/// # Arguments
+/// * `kind` - `switch`, `disable` or `enable`
+/// * `from_id` - The PPPoE ID running before the change
+/// * `to_id` - The PPPoE ID switched to, for switches
+/// * `minutes` - Usage of `from_id` when the change was made, if known
+pub fn record_router_action(kind: &str, from_id: &str, to_id: Option<&str>, minutes: Option<i32>) {
+    if let Err(e) =
+        History::open().and_then(|history| history.add_action(kind, from_id, to_id, minutes))
+    {
+        warn!("Failed to record switch history: {}", e);
+    }
+}
+
+/// Add a router's PPPoE connection event to the history, warning instead of
+/// failing
+///
+/// # Arguments
+/// * `router` - The router's address
+/// * `kind` - `up`, `down` or `login_failed`
+/// * `line` - The log line saying so
+pub fn record_link_event(router: &str, kind: &str, line: &str) {
+    if let Err(e) = History::open().and_then(|history| history.add_link_event(router, kind, line)) {
+        warn!("Failed to record connection history: {}", e);
+    }
+}
+
+/// Add the external IP address the WAN connection has to the history,
+/// warning instead of failing
+///
+/// # Arguments
+/// * `pppoe_id` - The PPPoE ID running
+/// * `ip` - The address
+/// * `kind` - `connect`, `changed` or `seen`
+pub fn record_external_ip(pppoe_id: &str, ip: &str, kind: &str) {
+    if let Err(e) = History::open().and_then(|history| history.add_external_ip(pppoe_id, ip, kind))
+    {
+        warn!("Failed to record external IP history: {}", e);
+    }
+}
+
+/// Compact the usage history once a day, warning instead of failing
+///
+/// # Arguments
+/// * `config` - The `[history]` section of the config file
+pub fn compact_history_if_due(config: &HistoryConfig) {
+    let today = Local::now().date_naive();
+    let mut state = State::load();
+    if state.history_compacted_on == Some(today) {
+        return;
+    }
+
+    match History::open().and_then(|history| history.compact(config)) {
+        Ok(compaction) => {
+            if compaction.downsampled + compaction.expired > 0 {
+                info!(
+                    "✓ Compacted usage history: {} reading(s) downsampled, {} record(s) expired",
+                    compaction.downsampled, compaction.expired
+                );
+            }
+            state.history_compacted_on = Some(today);
+            if let Err(e) = state.save() {
+                warn!("Failed to save state: {}", e);
+            }
+        }
+        Err(e) => warn!("Failed to compact usage history: {}", e),
+    }
+}
+
+/// Average consumption of one PPPoE ID in minutes per day
+///
+/// Only readings since the usage counter was last reset (i.e. since it last
+/// went down) are considered.
+///
+/// # Arguments
+/// * `samples` - Readings for a single PPPoE ID, oldest first
+///
+/// # Returns
+/// * `None` if the readings don't span long enough to tell
+pub fn daily_rate(samples: &[UsageSample]) -> Option<f64> {
+    let cycle_start = samples
+        .windows(2)
+        .rposition(|pair| pair[1].minutes < pair[0].minutes)
+        .map(|index| index + 1)
+        .unwrap_or(0);
+
+    let first = samples.get(cycle_start)?;
+    let last = samples.last()?;
+
+    let span_secs = last.timestamp - first.timestamp;
+    if span_secs < MIN_RATE_SPAN_SECS {
+        return None;
+    }
+
+    let used = f64::from(last.minutes - first.minutes);
+    Some(used * 86400.0 / span_secs as f64)
+}
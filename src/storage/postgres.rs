@@ -0,0 +1,420 @@
+//! Usage history and state in a PostgreSQL database that several instances
+//! share, each keeping to the rows of its own `storage.site`
+
+use super::{
+    Compaction, ExternalIp, HistoryStore, LinkEvent, RouterAction, Session, StateStore, UsageSample,
+};
+use crate::config::{HistoryConfig, StorageConfig};
+use ::postgres::{Client, NoTls, Row};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// The tables, created if missing. Every row is marked with the site it
+/// belongs to.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS usage_samples (
+        id        BIGSERIAL PRIMARY KEY,
+        site      TEXT    NOT NULL,
+        timestamp BIGINT  NOT NULL,
+        pppoe_id  TEXT    NOT NULL,
+        minutes   INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS usage_samples_by_id
+        ON usage_samples (site, pppoe_id, timestamp);
+    CREATE TABLE IF NOT EXISTS router_actions (
+        id        BIGSERIAL PRIMARY KEY,
+        site      TEXT    NOT NULL,
+        timestamp BIGINT  NOT NULL,
+        kind      TEXT    NOT NULL,
+        from_id   TEXT    NOT NULL,
+        to_id     TEXT,
+        minutes   INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        site      TEXT    NOT NULL,
+        pppoe_id  TEXT    NOT NULL,
+        started   BIGINT  NOT NULL,
+        ended     BIGINT,
+        minutes   INTEGER NOT NULL,
+        ip        TEXT,
+        UNIQUE (site, pppoe_id, started)
+    );
+    CREATE TABLE IF NOT EXISTS link_events (
+        id        BIGSERIAL PRIMARY KEY,
+        site      TEXT    NOT NULL,
+        timestamp BIGINT  NOT NULL,
+        router    TEXT    NOT NULL,
+        kind      TEXT    NOT NULL,
+        line      TEXT    NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS external_ips (
+        id        BIGSERIAL PRIMARY KEY,
+        site      TEXT    NOT NULL,
+        timestamp BIGINT  NOT NULL,
+        pppoe_id  TEXT    NOT NULL,
+        ip        TEXT    NOT NULL,
+        kind      TEXT    NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS state (
+        site      TEXT    PRIMARY KEY,
+        content   TEXT    NOT NULL
+    );";
+
+/// A connection to the shared database, as one site. Clones share the
+/// connection.
+#[derive(Clone)]
+pub struct PostgresStore {
+    client: Arc<Mutex<Client>>,
+    site: String,
+}
+
+impl PostgresStore {
+    /// Connect to the database set in `[storage]`, creating the tables if
+    /// needed
+    ///
+    /// # Arguments
+    /// * `config` - The `[storage]` section of the config file
+    pub fn connect(config: &StorageConfig) -> Result<Self> {
+        let url = config.url.as_deref().context("storage.url isn't set")?;
+        let site = config.site.clone().context("storage.site isn't set")?;
+        let mut options: ::postgres::Config = url.parse().context("Invalid storage.url")?;
+        if let Some(password) = &config.password {
+            options.password(password);
+        }
+
+        let client = off_runtime(move || -> Result<Client> {
+            let mut client = options
+                .connect(NoTls)
+                .context("Failed to connect to the PostgreSQL database")?;
+            client
+                .batch_execute(SCHEMA)
+                .context("Failed to create history tables")?;
+            Ok(client)
+        })?;
+
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+            site,
+        })
+    }
+
+    /// The site this instance keeps its rows under
+    pub fn site(&self) -> &str {
+        &self.site
+    }
+
+    /// Run queries on the connection, as this site
+    ///
+    /// # Arguments
+    /// * `queries` - Given the connection and the site
+    fn with_client<T: Send>(
+        &self,
+        queries: impl FnOnce(&mut Client, &str) -> Result<T, ::postgres::Error> + Send,
+    ) -> Result<T> {
+        let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+        let client = &mut *client;
+        let site = self.site.as_str();
+
+        Ok(off_runtime(move || queries(client, site))?)
+    }
+}
+
+impl HistoryStore for PostgresStore {
+    fn add_usage_at(&self, timestamp: i64, pppoe_id: &str, minutes: i32) -> Result<()> {
+        self.with_client(|client, site| {
+            client.execute(
+                "INSERT INTO usage_samples (site, timestamp, pppoe_id, minutes)
+                 VALUES ($1, $2, $3, $4)",
+                &[&site, &timestamp, &pppoe_id, &minutes],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn first_usage_at(&self, pppoe_id: &str) -> Result<Option<i64>> {
+        self.with_client(|client, site| {
+            let row = client.query_one(
+                "SELECT MIN(timestamp) FROM usage_samples WHERE site = $1 AND pppoe_id = $2",
+                &[&site, &pppoe_id],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn add_action(
+        &self,
+        timestamp: i64,
+        kind: &str,
+        from_id: &str,
+        to_id: Option<&str>,
+        minutes: Option<i32>,
+    ) -> Result<()> {
+        self.with_client(|client, site| {
+            client.execute(
+                "INSERT INTO router_actions (site, timestamp, kind, from_id, to_id, minutes)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&site, &timestamp, &kind, &from_id, &to_id, &minutes],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn add_link_event(&self, timestamp: i64, router: &str, kind: &str, line: &str) -> Result<()> {
+        self.with_client(|client, site| {
+            client.execute(
+                "INSERT INTO link_events (site, timestamp, router, kind, line)
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[&site, &timestamp, &router, &kind, &line],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn add_external_ip(&self, timestamp: i64, pppoe_id: &str, ip: &str, kind: &str) -> Result<()> {
+        self.with_client(|client, site| {
+            client.execute(
+                "INSERT INTO external_ips (site, timestamp, pppoe_id, ip, kind)
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[&site, &timestamp, &pppoe_id, &ip, &kind],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn add_sessions(&self, sessions: &[Session]) -> Result<usize> {
+        self.with_client(|client, site| {
+            let mut added = 0;
+            for session in sessions {
+                added += client.execute(
+                    "INSERT INTO sessions (site, pppoe_id, started, ended, minutes, ip)
+                     VALUES ($1, $2, $3, $4, $5, $6)
+                     ON CONFLICT DO NOTHING",
+                    &[
+                        &site,
+                        &session.pppoe_id,
+                        &session.start,
+                        &session.end,
+                        &session.minutes,
+                        &session.ip,
+                    ],
+                )? as usize;
+            }
+            Ok(added)
+        })
+    }
+
+    fn compact(&self, config: &HistoryConfig) -> Result<Compaction> {
+        let now = Utc::now().timestamp();
+        let days_ago = |days: u32| now - i64::from(days) * 86400;
+
+        // The database reclaims the space by itself, with autovacuum
+        self.with_client(|client, site| {
+            let mut compaction = Compaction::default();
+
+            // Keep the newest reading of each ID in each bucket of the given length
+            for (since, until, bucket_secs) in [
+                (
+                    days_ago(config.hourly_days),
+                    days_ago(config.raw_days),
+                    3600_i64,
+                ),
+                (i64::MIN, days_ago(config.hourly_days), 86400),
+            ] {
+                compaction.downsampled += client.execute(
+                    "DELETE FROM usage_samples
+                     WHERE site = $1 AND timestamp >= $2 AND timestamp < $3
+                       AND id NOT IN (
+                           SELECT DISTINCT ON (pppoe_id, timestamp / $4) id
+                           FROM usage_samples
+                           WHERE site = $1 AND timestamp >= $2 AND timestamp < $3
+                           ORDER BY pppoe_id, timestamp / $4, timestamp DESC
+                       )",
+                    &[&site, &since, &until, &bucket_secs],
+                )? as usize;
+            }
+
+            if let Some(retention_days) = config.retention_days {
+                let cutoff = days_ago(retention_days);
+                for statement in [
+                    "DELETE FROM usage_samples WHERE site = $1 AND timestamp < $2",
+                    "DELETE FROM router_actions WHERE site = $1 AND timestamp < $2",
+                    "DELETE FROM sessions WHERE site = $1 AND started < $2",
+                    "DELETE FROM link_events WHERE site = $1 AND timestamp < $2",
+                    "DELETE FROM external_ips WHERE site = $1 AND timestamp < $2",
+                ] {
+                    compaction.expired += client.execute(statement, &[&site, &cutoff])? as usize;
+                }
+            }
+
+            Ok(compaction)
+        })
+    }
+
+    fn snapshot(&self, _path: &Path) -> Result<()> {
+        anyhow::bail!(
+            "The usage history is kept in PostgreSQL; back it up with the database's own tools, e.g. pg_dump"
+        )
+    }
+
+    fn sessions_since(&self, since: i64) -> Result<Vec<Session>> {
+        let rows = self.with_client(|client, site| {
+            client.query(
+                "SELECT pppoe_id, started, ended, minutes, ip FROM sessions
+                 WHERE site = $1 AND started >= $2 ORDER BY started",
+                &[&site, &since],
+            )
+        })?;
+
+        Ok(rows
+            .iter()
+            .map(|row| Session {
+                pppoe_id: row.get(0),
+                start: row.get(1),
+                end: row.get(2),
+                minutes: row.get(3),
+                ip: row.get(4),
+            })
+            .collect())
+    }
+
+    fn usage_since(&self, since: i64) -> Result<Vec<UsageSample>> {
+        let rows = self.with_client(|client, site| {
+            client.query(
+                "SELECT timestamp, pppoe_id, minutes FROM usage_samples
+                 WHERE site = $1 AND timestamp >= $2 ORDER BY timestamp, id",
+                &[&site, &since],
+            )
+        })?;
+
+        Ok(rows
+            .iter()
+            .map(|row| UsageSample {
+                timestamp: row.get(0),
+                pppoe_id: row.get(1),
+                minutes: row.get(2),
+            })
+            .collect())
+    }
+
+    fn actions_since(&self, since: i64) -> Result<Vec<RouterAction>> {
+        let rows = self.with_client(|client, site| {
+            client.query(
+                "SELECT timestamp, kind, from_id, to_id, minutes FROM router_actions
+                 WHERE site = $1 AND timestamp >= $2 ORDER BY timestamp, id",
+                &[&site, &since],
+            )
+        })?;
+
+        Ok(rows
+            .iter()
+            .map(|row| RouterAction {
+                timestamp: row.get(0),
+                kind: row.get(1),
+                from_id: row.get(2),
+                to_id: row.get(3),
+                minutes: row.get(4),
+            })
+            .collect())
+    }
+
+    fn link_events_since(&self, since: i64) -> Result<Vec<LinkEvent>> {
+        let rows = self.with_client(|client, site| {
+            client.query(
+                "SELECT timestamp, router, kind, line FROM link_events
+                 WHERE site = $1 AND timestamp >= $2 ORDER BY timestamp, id",
+                &[&site, &since],
+            )
+        })?;
+
+        Ok(rows
+            .iter()
+            .map(|row| LinkEvent {
+                timestamp: row.get(0),
+                router: row.get(1),
+                kind: row.get(2),
+                line: row.get(3),
+            })
+            .collect())
+    }
+
+    fn external_ips_since(&self, since: i64) -> Result<Vec<ExternalIp>> {
+        let rows = self.with_client(|client, site| {
+            client.query(
+                "SELECT timestamp, pppoe_id, ip, kind FROM external_ips
+                 WHERE site = $1 AND timestamp >= $2 ORDER BY timestamp, id",
+                &[&site, &since],
+            )
+        })?;
+
+        Ok(rows.iter().map(external_ip).collect())
+    }
+
+    fn last_external_ip(&self) -> Result<Option<ExternalIp>> {
+        let row = self.with_client(|client, site| {
+            client.query_opt(
+                "SELECT timestamp, pppoe_id, ip, kind FROM external_ips
+                 WHERE site = $1 ORDER BY timestamp DESC, id DESC LIMIT 1",
+                &[&site],
+            )
+        })?;
+
+        Ok(row.as_ref().map(external_ip))
+    }
+}
+
+impl StateStore for PostgresStore {
+    fn load_state(&self) -> Result<Option<String>> {
+        let row = self
+            .with_client(|client, site| {
+                client.query_opt("SELECT content FROM state WHERE site = $1", &[&site])
+            })
+            .context("Failed to read the state from PostgreSQL")?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    fn save_state(&self, content: &str) -> Result<()> {
+        self.with_client(|client, site| {
+            client.execute(
+                "INSERT INTO state (site, content) VALUES ($1, $2)
+                 ON CONFLICT (site) DO UPDATE SET content = EXCLUDED.content",
+                &[&site, &content],
+            )
+        })
+        .context("Failed to save the state to PostgreSQL")?;
+
+        Ok(())
+    }
+}
+
+/// An external IP address as read from its row
+///
+/// # Arguments
+/// * `row` - Its timestamp, PPPoE ID, address and kind, in that order
+fn external_ip(row: &Row) -> ExternalIp {
+    ExternalIp {
+        timestamp: row.get(0),
+        pppoe_id: row.get(1),
+        ip: row.get(2),
+        kind: row.get(3),
+    }
+}
+
+/// Run something on a thread of its own. The `postgres` client blocks on a
+/// runtime it brings along, which can't be done from within the tokio
+/// runtime the rest of this tool runs on.
+///
+/// # Arguments
+/// * `work` - What to run
+fn off_runtime<T: Send>(work: impl FnOnce() -> T + Send) -> T {
+    thread::scope(|scope| {
+        scope
+            .spawn(work)
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))
+    })
+}
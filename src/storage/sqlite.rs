@@ -0,0 +1,330 @@
+use super::{
+    Compaction, ExternalIp, HistoryStore, LinkEvent, RouterAction, Session, UsageSample,
+    HISTORY_FILE_NAME,
+};
+use crate::config::HistoryConfig;
+use crate::state::data_dir;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+/// The usage history in a SQLite database in the data directory, the default
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Open the history database, creating it if needed
+    pub fn open() -> Result<Self> {
+        let path = data_dir()?.join(HISTORY_FILE_NAME);
+        let conn = Connection::open(&path).context(format!(
+            "Failed to open history database {}",
+            path.display()
+        ))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage_samples (
+                 timestamp INTEGER NOT NULL,
+                 pppoe_id  TEXT    NOT NULL,
+                 minutes   INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS usage_samples_by_id
+                 ON usage_samples (pppoe_id, timestamp);
+             CREATE TABLE IF NOT EXISTS router_actions (
+                 timestamp INTEGER NOT NULL,
+                 kind      TEXT    NOT NULL,
+                 from_id   TEXT    NOT NULL,
+                 to_id     TEXT,
+                 minutes   INTEGER
+             );
+             CREATE TABLE IF NOT EXISTS sessions (
+                 pppoe_id  TEXT    NOT NULL,
+                 started   INTEGER NOT NULL,
+                 ended     INTEGER,
+                 minutes   INTEGER NOT NULL,
+                 ip        TEXT,
+                 UNIQUE (pppoe_id, started)
+             );
+             CREATE TABLE IF NOT EXISTS link_events (
+                 timestamp INTEGER NOT NULL,
+                 router    TEXT    NOT NULL,
+                 kind      TEXT    NOT NULL,
+                 line      TEXT    NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS external_ips (
+                 timestamp INTEGER NOT NULL,
+                 pppoe_id  TEXT    NOT NULL,
+                 ip        TEXT    NOT NULL,
+                 kind      TEXT    NOT NULL
+             );",
+        )
+        .context("Failed to create history tables")?;
+
+        Ok(Self { conn })
+    }
+}
+
+impl HistoryStore for SqliteStore {
+    fn add_usage_at(&self, timestamp: i64, pppoe_id: &str, minutes: i32) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO usage_samples (timestamp, pppoe_id, minutes) VALUES (?1, ?2, ?3)",
+            params![timestamp, pppoe_id, minutes],
+        )?;
+        Ok(())
+    }
+
+    fn first_usage_at(&self, pppoe_id: &str) -> Result<Option<i64>> {
+        let first = self.conn.query_row(
+            "SELECT MIN(timestamp) FROM usage_samples WHERE pppoe_id = ?1",
+            params![pppoe_id],
+            |row| row.get(0),
+        )?;
+        Ok(first)
+    }
+
+    fn add_action(
+        &self,
+        timestamp: i64,
+        kind: &str,
+        from_id: &str,
+        to_id: Option<&str>,
+        minutes: Option<i32>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO router_actions (timestamp, kind, from_id, to_id, minutes)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![timestamp, kind, from_id, to_id, minutes],
+        )?;
+        Ok(())
+    }
+
+    fn add_link_event(&self, timestamp: i64, router: &str, kind: &str, line: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO link_events (timestamp, router, kind, line) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp, router, kind, line],
+        )?;
+        Ok(())
+    }
+
+    fn add_external_ip(&self, timestamp: i64, pppoe_id: &str, ip: &str, kind: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO external_ips (timestamp, pppoe_id, ip, kind) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp, pppoe_id, ip, kind],
+        )?;
+        Ok(())
+    }
+
+    fn add_sessions(&self, sessions: &[Session]) -> Result<usize> {
+        let mut added = 0;
+        for session in sessions {
+            added += self.conn.execute(
+                "INSERT OR IGNORE INTO sessions (pppoe_id, started, ended, minutes, ip)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    session.pppoe_id,
+                    session.start,
+                    session.end,
+                    session.minutes,
+                    session.ip
+                ],
+            )?;
+        }
+        Ok(added)
+    }
+
+    fn compact(&self, config: &HistoryConfig) -> Result<Compaction> {
+        let now = Utc::now().timestamp();
+        let days_ago = |days: u32| now - i64::from(days) * 86400;
+        let mut compaction = Compaction::default();
+
+        // Keep the newest reading of each ID in each bucket of the given length
+        for (since, until, bucket_secs) in [
+            (
+                days_ago(config.hourly_days),
+                days_ago(config.raw_days),
+                3600,
+            ),
+            (i64::MIN, days_ago(config.hourly_days), 86400),
+        ] {
+            compaction.downsampled += self.conn.execute(
+                "DELETE FROM usage_samples
+                 WHERE timestamp >= ?1 AND timestamp < ?2
+                   AND rowid NOT IN (
+                       SELECT rowid FROM (
+                           SELECT rowid, MAX(timestamp) FROM usage_samples
+                           WHERE timestamp >= ?1 AND timestamp < ?2
+                           GROUP BY pppoe_id, timestamp / ?3
+                       )
+                   )",
+                params![since, until, bucket_secs],
+            )?;
+        }
+
+        if let Some(retention_days) = config.retention_days {
+            let cutoff = days_ago(retention_days);
+            compaction.expired += self.conn.execute(
+                "DELETE FROM usage_samples WHERE timestamp < ?1",
+                params![cutoff],
+            )?;
+            compaction.expired += self.conn.execute(
+                "DELETE FROM router_actions WHERE timestamp < ?1",
+                params![cutoff],
+            )?;
+            compaction.expired += self
+                .conn
+                .execute("DELETE FROM sessions WHERE started < ?1", params![cutoff])?;
+            compaction.expired += self.conn.execute(
+                "DELETE FROM link_events WHERE timestamp < ?1",
+                params![cutoff],
+            )?;
+            compaction.expired += self.conn.execute(
+                "DELETE FROM external_ips WHERE timestamp < ?1",
+                params![cutoff],
+            )?;
+        }
+
+        if compaction.downsampled + compaction.expired > 0 {
+            self.conn
+                .execute_batch("VACUUM")
+                .context("Failed to shrink the history database")?;
+        }
+
+        Ok(compaction)
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        self.conn
+            .execute(
+                "VACUUM INTO ?1",
+                params![path.to_string_lossy().to_string()],
+            )
+            .context(format!(
+                "Failed to copy the history database to {}",
+                path.display()
+            ))?;
+
+        Ok(())
+    }
+
+    fn sessions_since(&self, since: i64) -> Result<Vec<Session>> {
+        let mut statement = self.conn.prepare(
+            "SELECT pppoe_id, started, ended, minutes, ip FROM sessions
+             WHERE started >= ?1 ORDER BY started",
+        )?;
+
+        let sessions = statement
+            .query_map(params![since], |row| {
+                Ok(Session {
+                    pppoe_id: row.get(0)?,
+                    start: row.get(1)?,
+                    end: row.get(2)?,
+                    minutes: row.get(3)?,
+                    ip: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(sessions)
+    }
+
+    fn usage_since(&self, since: i64) -> Result<Vec<UsageSample>> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, pppoe_id, minutes FROM usage_samples
+             WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+
+        let samples = statement
+            .query_map(params![since], |row| {
+                Ok(UsageSample {
+                    timestamp: row.get(0)?,
+                    pppoe_id: row.get(1)?,
+                    minutes: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(samples)
+    }
+
+    fn actions_since(&self, since: i64) -> Result<Vec<RouterAction>> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, kind, from_id, to_id, minutes FROM router_actions
+             WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+
+        let actions = statement
+            .query_map(params![since], |row| {
+                Ok(RouterAction {
+                    timestamp: row.get(0)?,
+                    kind: row.get(1)?,
+                    from_id: row.get(2)?,
+                    to_id: row.get(3)?,
+                    minutes: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(actions)
+    }
+
+    fn link_events_since(&self, since: i64) -> Result<Vec<LinkEvent>> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, router, kind, line FROM link_events
+             WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+
+        let events = statement
+            .query_map(params![since], |row| {
+                Ok(LinkEvent {
+                    timestamp: row.get(0)?,
+                    router: row.get(1)?,
+                    kind: row.get(2)?,
+                    line: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(events)
+    }
+
+    fn external_ips_since(&self, since: i64) -> Result<Vec<ExternalIp>> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, pppoe_id, ip, kind FROM external_ips
+             WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+
+        let ips = statement
+            .query_map(params![since], |row| {
+                Ok(ExternalIp {
+                    timestamp: row.get(0)?,
+                    pppoe_id: row.get(1)?,
+                    ip: row.get(2)?,
+                    kind: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(ips)
+    }
+
+    fn last_external_ip(&self) -> Result<Option<ExternalIp>> {
+        let mut statement = self.conn.prepare(
+            "SELECT timestamp, pppoe_id, ip, kind FROM external_ips
+             ORDER BY timestamp DESC, rowid DESC LIMIT 1",
+        )?;
+
+        let mut ips = statement
+            .query_map([], |row| {
+                Ok(ExternalIp {
+                    timestamp: row.get(0)?,
+                    pppoe_id: row.get(1)?,
+                    ip: row.get(2)?,
+                    kind: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(ips.pop())
+    }
+}
@@ -0,0 +1,246 @@
+//! Taking the log lines routers forward over UDP syslog, which tell right
+//! away when their PPPoE connection comes up, goes down or fails to log in,
+//! without anything having to be read off the router
+
+use crate::config::{Config, NotificationKind, SyslogConfig};
+use crate::events::{self, Event};
+use crate::notify::send_notification;
+use crate::state::State;
+use crate::storage::record_link_event;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// Longest datagram taken in full; syslog over UDP is rarely longer
+const MAX_DATAGRAM_LEN: usize = 8192;
+
+/// What a router's log line says about its PPPoE connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkChange {
+    /// The connection came up
+    Up,
+    /// The connection went down
+    Down,
+    /// The PPPoE login was rejected
+    LoginFailed,
+}
+
+impl LinkChange {
+    /// How the change is named in the history
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Up => "up",
+            Self::Down => "down",
+            Self::LoginFailed => "login_failed",
+        }
+    }
+}
+
+/// Start taking syslog lines in the background
+///
+/// The address is bound before returning, so a port that's taken fails the
+/// daemon's startup instead of going unnoticed. Only lines from
+/// `syslog.senders` (or the routers) are taken. A router saying the same
+/// thing again, e.g. the login failing on every retry, is only reported the
+/// first time.
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `syslog` - The `[syslog]` section of the config file
+///
+/// # Returns
+/// * The address listened on
+pub async fn listen(config: &Config, syslog: &SyslogConfig) -> Result<SocketAddr> {
+    let senders = senders(config, syslog)?;
+    let socket = UdpSocket::bind(syslog.listen)
+        .await
+        .context(format!("Failed to listen on {} for syslog", syslog.listen))?;
+    let addr = socket.local_addr()?;
+    info!("Listening for router syslog on udp://{}", addr);
+
+    let syslog = syslog.clone();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+        let mut last_change: HashMap<IpAddr, LinkChange> = HashMap::new();
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Stopped listening for router syslog: {}", e);
+                    return;
+                }
+            };
+            if !senders.contains(&peer.ip()) {
+                debug!("Ignored a syslog line from {}", peer);
+                continue;
+            }
+
+            let line = message(&String::from_utf8_lossy(&buffer[..len]));
+            let Some(change) = classify(&syslog, &line) else {
+                continue;
+            };
+            if is_news(last_change.get(&peer.ip()).copied(), change) {
+                last_change.insert(peer.ip(), change);
+                report(&peer.ip().to_string(), change, &line);
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+/// What a log line says about the PPPoE connection, if anything
+///
+/// # Arguments
+/// * `syslog` - The `[syslog]` section of the config file
+/// * `line` - The log line
+pub fn classify(syslog: &SyslogConfig, line: &str) -> Option<LinkChange> {
+    let line = normalize(line);
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .any(|pattern| line.contains(&normalize(pattern)))
+    };
+
+    // A rejected login is followed by the connection ending, which says no more
+    if matches(&syslog.login_failed) {
+        Some(LinkChange::LoginFailed)
+    } else if matches(&syslog.down) {
+        Some(LinkChange::Down)
+    } else if matches(&syslog.up) {
+        Some(LinkChange::Up)
+    } else {
+        None
+    }
+}
+
+/// The addresses lines are taken from
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `syslog` - The `[syslog]` section of the config file
+fn senders(config: &Config, syslog: &SyslogConfig) -> Result<Vec<IpAddr>> {
+    if !syslog.senders.is_empty() {
+        return Ok(syslog.senders.clone());
+    }
+
+    let mut routers = vec![&config.router.ip];
+    routers.extend(
+        config
+            .router
+            .secondary
+            .iter()
+            .map(|secondary| &secondary.ip),
+    );
+
+    let mut senders = Vec::new();
+    for ip in routers {
+        let sender = ip.parse().context(format!(
+            "The router address {} isn't an IP address; set syslog.senders",
+            ip
+        ))?;
+        senders.push(sender);
+    }
+
+    Ok(senders)
+}
+
+/// Whether a change is worth reporting after the one the router reported
+/// before: not a repeat, nor the connection ending after a rejected login
+///
+/// # Arguments
+/// * `previous` - The change last reported by the router, if any
+/// * `change` - The change it reports now
+fn is_news(previous: Option<LinkChange>, change: LinkChange) -> bool {
+    !matches!(
+        (previous, change),
+        (Some(LinkChange::LoginFailed), LinkChange::Down)
+    ) && previous != Some(change)
+}
+
+/// Log, emit and record a change, telling whether a disable took effect
+///
+/// # Arguments
+/// * `router` - The router's address
+/// * `change` - What changed
+/// * `line` - The log line saying so
+fn report(router: &str, change: LinkChange, line: &str) {
+    let disabled = State::load().disabled.is_some();
+
+    match change {
+        LinkChange::Up => {
+            info!(
+                "Router {} reports the PPPoE connection up: {}",
+                router, line
+            );
+            events::emit(Event::PppoeUp { router, line });
+        }
+        LinkChange::Down => {
+            if disabled {
+                info!(
+                    "✓ Router {} reports the PPPoE connection down, so disabling it took effect",
+                    router
+                );
+            } else {
+                warn!(
+                    "Router {} reports the PPPoE connection down: {}",
+                    router, line
+                );
+            }
+            events::emit(Event::PppoeDown { router, line });
+        }
+        LinkChange::LoginFailed => {
+            if disabled {
+                info!(
+                    "✓ Router {} reports the PPPoE login refused, so disabling the connection took effect",
+                    router
+                );
+            } else {
+                warn!(
+                    "Router {} reports the PPPoE login refused: {}",
+                    router, line
+                );
+                send_notification(
+                    NotificationKind::Warning,
+                    "PPPoE Login Refused ⚠",
+                    &format!(
+                        "Router {} reports that the ISP refused its PPPoE login:\n{}",
+                        router, line
+                    ),
+                );
+            }
+            events::emit(Event::PppoeLoginFailed { router, line });
+        }
+    }
+
+    record_link_event(router, change.as_str(), line);
+}
+
+/// The text of a syslog datagram, without its priority and line end
+///
+/// # Arguments
+/// * `datagram` - The datagram, e.g. `<30>Jan  1 00:00:00 pppd[1234]: ...`
+fn message(datagram: &str) -> String {
+    let text = datagram.trim();
+    let text = match text.strip_prefix('<').and_then(|rest| rest.split_once('>')) {
+        Some((priority, rest)) if priority.chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => text,
+    };
+
+    text.trim().to_string()
+}
+
+/// Text lowercased and with runs of whitespace made single spaces, to match
+/// log lines loosely
+///
+/// # Arguments
+/// * `text` - A log line or the text looked for in it
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
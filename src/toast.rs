@@ -0,0 +1,44 @@
+//! Windows toast notification plumbing
+//!
+//! Toasts from an unpackaged executable are only shown reliably when they are
+//! sent under an AppUserModelID (AUMID) that Windows knows about. Without one,
+//! notify-rust falls back to borrowing PowerShell's AUMID, which regularly gets
+//! dropped when the tool runs from Task Scheduler in a non-interactive session.
+//! Registering our own AUMID under HKCU (no admin rights needed) fixes that.
+
+use anyhow::{Context, Result};
+use std::process::Command;
+
+/// The AppUserModelID toast notifications are sent under
+pub const APP_ID: &str = "AutoPPPoEQuotaManager.AutoWifi";
+
+/// Name shown as the sender of the toast in the Action Center
+const DISPLAY_NAME: &str = "Auto WiFi Manager";
+
+/// Register the AppUserModelID so Windows accepts toasts sent under it
+///
+/// This is idempotent and cheap, so it is simply done on every run.
+pub fn register_app_id() -> Result<()> {
+    let key = format!(r"HKCU\Software\Classes\AppUserModelId\{}", APP_ID);
+
+    let status = Command::new("reg")
+        .args(["add", &key, "/v", "DisplayName", "/t", "REG_SZ", "/d", DISPLAY_NAME, "/f"])
+        .output()
+        .context("Failed to run reg.exe")?
+        .status;
+
+    if !status.success() {
+        anyhow::bail!("reg.exe failed to register AppUserModelID {} ({})", APP_ID, status);
+    }
+
+    // Show the executable's icon next to our toasts
+    if let Ok(exe) = std::env::current_exe() {
+        let _ = Command::new("reg")
+            .args(["add", &key, "/v", "IconUri", "/t", "REG_SZ", "/d"])
+            .arg(exe)
+            .arg("/f")
+            .output();
+    }
+
+    Ok(())
+}
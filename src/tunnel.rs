@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long to wait for ssh to open the SOCKS port
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// An `ssh -D` SOCKS tunnel through a jump host, used to reach the router when
+/// the tool runs outside the home network (e.g. on a VPS).
+///
+/// The ssh process is killed when the tunnel is dropped.
+pub struct SshTunnel {
+    child: Child,
+    port: u16,
+}
+
+impl SshTunnel {
+    /// Start the tunnel and wait until its SOCKS port accepts connections
+    ///
+    /// # Arguments
+    /// * `jump_host` - The ssh destination, e.g. `user@home.example.com`
+    /// * `key_path` - Private key to authenticate with, if not the ssh default
+    pub fn start(jump_host: &str, key_path: Option<&str>) -> Result<Self> {
+        let port = free_local_port()?;
+        info!(
+            "Opening SSH tunnel to {} (SOCKS on 127.0.0.1:{})...",
+            jump_host, port
+        );
+
+        let mut command = Command::new("ssh");
+        command
+            .args(["-N", "-D", &format!("127.0.0.1:{}", port)])
+            // Never prompt: this runs unattended
+            .args(["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes"])
+            .stdin(Stdio::null());
+        if let Some(key_path) = key_path {
+            command.args(["-i", key_path]);
+        }
+        command.arg(jump_host);
+
+        let child = command
+            .spawn()
+            .context("Failed to start ssh. Make sure an OpenSSH client is installed.")?;
+        let mut tunnel = Self { child, port };
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if let Some(status) = tunnel.child.try_wait()? {
+                anyhow::bail!("SSH tunnel to {} exited ({})", jump_host, status);
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                anyhow::bail!("SSH tunnel to {} did not come up in time", jump_host);
+            }
+            std::thread::sleep(Duration::from_millis(250));
+        }
+
+        info!("SSH tunnel established");
+        Ok(tunnel)
+    }
+
+    /// The proxy URL to hand to the browser
+    pub fn proxy_url(&self) -> String {
+        format!("socks5://127.0.0.1:{}", self.port)
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        info!("SSH tunnel closed");
+    }
+}
+
+/// Ask the OS for a local TCP port that is currently free
+fn free_local_port() -> Result<u16> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).context("Failed to find a free port")?;
+    Ok(listener.local_addr()?.port())
+}
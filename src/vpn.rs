@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long to wait for the router to become reachable after bringing the tunnel up
+const TUNNEL_UP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A WireGuard tunnel brought up for the duration of a run.
+///
+/// The tunnel is taken down again when this is dropped, leaving the interface
+/// exactly as it was found.
+pub struct WireGuardSession {
+    interface: String,
+}
+
+impl Drop for WireGuardSession {
+    fn drop(&mut self) {
+        info!("Bringing WireGuard interface {} down...", self.interface);
+        match Command::new("wg-quick").args(["down", &self.interface]).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("wg-quick down {} failed ({})", self.interface, status),
+            Err(e) => warn!("Failed to run wg-quick: {}", e),
+        }
+    }
+}
+
+/// Make sure the router's admin page can be reached, bringing up the WireGuard
+/// tunnel to the home network if needed.
+///
+/// When the router is already reachable (on the LAN, or the tunnel is already
+/// up) nothing is changed. Otherwise `wg-quick up <interface>` is run, which
+/// usually requires root or a matching sudoers/polkit rule.
+///
+/// # Arguments
+/// * `router_ip` - The IP address of the router
+/// * `interface` - The WireGuard interface (wg-quick config name) leading home
+///
+/// # Returns
+/// * A session to keep alive while the router is used, if the tunnel was brought
+///   up by us, or `None` if the router was reachable already
+pub fn ensure_router_reachable(
+    router_ip: &str,
+    interface: &str,
+) -> Result<Option<WireGuardSession>> {
+    if router_reachable(router_ip) {
+        return Ok(None);
+    }
+
+    info!(
+        "Router {} is not reachable, bringing WireGuard interface {} up...",
+        router_ip, interface
+    );
+
+    let status = Command::new("wg-quick")
+        .args(["up", interface])
+        .status()
+        .context("VPN down: failed to run wg-quick. Is wireguard-tools installed?")?;
+    if !status.success() {
+        anyhow::bail!("VPN down: wg-quick up {} failed ({})", interface, status);
+    }
+
+    // From here on the tunnel is ours to take down again, even on failure
+    let session = WireGuardSession {
+        interface: interface.to_string(),
+    };
+
+    let started = Instant::now();
+    while !router_reachable(router_ip) {
+        if started.elapsed() > TUNNEL_UP_TIMEOUT {
+            anyhow::bail!(
+                "VPN down: router {} still unreachable with WireGuard interface {} up",
+                router_ip,
+                interface
+            );
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    info!("✓ Router reachable over WireGuard");
+    Ok(Some(session))
+}
+
+/// Check whether the router's web interface accepts connections
+///
+/// # Arguments
+/// * `router_ip` - The IP address of the router
+fn router_reachable(router_ip: &str) -> bool {
+    let Ok(address) = format!("{}:80", router_ip).parse::<SocketAddr>() else {
+        return false;
+    };
+
+    TcpStream::connect_timeout(&address, Duration::from_secs(3)).is_ok()
+}
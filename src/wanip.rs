@@ -0,0 +1,98 @@
+//! Keeping track of the external IP address the ISP gives the WAN
+//! connection. It only changes when the connection is re-established; if
+//! this tool didn't do that, the ISP reset the connection, which is worth
+//! lining up against the usage recorded around it.
+//!
+//! The address is read over UPnP (see `crate::router::upnp`), so routers
+//! without it aren't tracked.
+
+use crate::config::{Config, NotificationKind};
+use crate::events::{self, Event};
+use crate::notify::send_notification;
+use crate::router::upnp;
+use crate::state::State;
+use crate::storage::{record_external_ip, History};
+use anyhow::Result;
+use std::net::IpAddr;
+use tracing::{info, warn};
+
+/// Record the external IP address the connection came up with after a
+/// switch or re-enable
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+/// * `pppoe_id` - The PPPoE ID the connection came up with
+pub async fn record_connect(config: &Config, pppoe_id: &str) {
+    let Some(ip) = external_ip(config).await else {
+        return;
+    };
+
+    info!("External IP is now {}", ip);
+    record_external_ip(pppoe_id, &ip.to_string(), "connect");
+}
+
+/// Check whether the external IP address changed since it was last seen,
+/// and report it if this tool didn't change it, warning instead of failing
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+pub async fn check(config: &Config) {
+    let Some(ip) = external_ip(config).await else {
+        return;
+    };
+
+    if let Err(e) = compare(&ip.to_string()) {
+        warn!("Failed to check the external IP history: {}", e);
+    }
+}
+
+/// Record the external IP address if it isn't the one seen last, reporting
+/// a change no switch accounts for
+///
+/// # Arguments
+/// * `ip` - The address the connection has now
+fn compare(ip: &str) -> Result<()> {
+    let history = History::open()?;
+    let state = State::load();
+    let pppoe_id = state.last_running_id().unwrap_or_default();
+
+    let kind = match history.last_external_ip()? {
+        None => "seen",
+        Some(last) if last.ip == ip => return Ok(()),
+        // Switched since, but the address wasn't read right after
+        Some(last) if !history.actions_since(last.timestamp)?.is_empty() => "connect",
+        Some(last) => {
+            warn!(
+                "External IP changed from {} to {} without a switch; the ISP reset the connection",
+                last.ip, ip
+            );
+            send_notification(
+                NotificationKind::Warning,
+                "External IP Changed ⚠",
+                &format!(
+                    "The external IP of '{}' changed from {} to {} without a switch, so the ISP reset the connection.",
+                    pppoe_id, last.ip, ip
+                ),
+            );
+            events::emit(Event::ExternalIpChanged {
+                pppoe_id,
+                from: &last.ip,
+                to: ip,
+            });
+            "changed"
+        }
+    };
+
+    history.add_external_ip(pppoe_id, ip, kind)
+}
+
+/// The external IP address of the WAN connection, if the router tells
+///
+/// # Arguments
+/// * `config` - The runtime configuration
+async fn external_ip(config: &Config) -> Option<IpAddr> {
+    upnp::wan_status(config)
+        .await
+        .filter(|wan| wan.connected)?
+        .external_ip
+}
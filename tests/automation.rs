@@ -0,0 +1,282 @@
+//! The switching logic of `automation::run_with`, against a fake portal and
+//! router
+
+mod common;
+
+use auto_wifi::automation::run_with;
+use auto_wifi::policy::scheduled_allowance;
+use auto_wifi::report::REPORTS_DIR_NAME;
+use auto_wifi::router::DISABLED_PASSWORD;
+use auto_wifi::state::State;
+use chrono::Local;
+use common::{config, data_dir, FakePortal, FakeRouter};
+
+#[tokio::test]
+async fn stays_on_an_id_within_its_limit() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 5000), ("id2", 0), ("id3", 0)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert!(router.changes.is_empty());
+    assert_eq!(portal.checked(), ["id1"]);
+    assert_eq!(State::load().usage_cache["id1"].minutes, 5000);
+}
+
+#[tokio::test]
+async fn switches_to_the_next_available_id() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 10500), ("id2", 10200), ("id3", 3000)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(router.changes, [("id3".to_string(), "pass3".to_string())]);
+    assert_eq!(portal.checked(), ["id1", "id2", "id3"]);
+    assert_eq!(State::load().counters.switches, 1);
+}
+
+#[tokio::test]
+async fn skips_an_id_that_fails_to_read() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 10500), ("id3", 100)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(router.running_id, "id3");
+    assert_eq!(State::load().counters.usage_check_failures, 1);
+}
+
+#[tokio::test]
+async fn disables_the_connection_once_every_id_is_past_its_limit() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 11500), ("id2", 10500), ("id3", 10500)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(
+        router.changes,
+        [("id1".to_string(), DISABLED_PASSWORD.to_string())]
+    );
+    let state = State::load();
+    assert_eq!(state.disabled.unwrap().pppoe_id, "id1");
+    assert_eq!(state.counters.disables, 1);
+}
+
+#[tokio::test]
+async fn puts_off_disabling_while_another_id_cannot_be_checked() {
+    let _data_dir = data_dir().await;
+    let mut config = config("");
+    config.retry.defer_disable_runs = 2;
+    // id2 can't be read, so it may still have quota
+    let portal = FakePortal::with_usage(&[("id1", 11500), ("id3", 10500)]);
+    let mut router = FakeRouter::running("id1");
+
+    for runs_in_a_row in 1..=2 {
+        run_with(&config, &portal, &mut router).await.unwrap();
+        assert!(router.changes.is_empty());
+        assert_eq!(State::load().deferred_disables, runs_in_a_row);
+    }
+
+    // Out of runs to wait, it disables on what it could read
+    run_with(&config, &portal, &mut router).await.unwrap();
+    assert_eq!(
+        router.changes,
+        [("id1".to_string(), DISABLED_PASSWORD.to_string())]
+    );
+    assert_eq!(State::load().deferred_disables, 0);
+}
+
+#[tokio::test]
+async fn keeps_the_connection_below_the_disable_threshold() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 10500), ("id2", 10500), ("id3", 10500)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert!(router.changes.is_empty());
+    assert!(State::load().disabled.is_none());
+}
+
+#[tokio::test]
+async fn re_enables_the_connection_once_the_id_has_quota_again() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let mut router = FakeRouter::running("id1");
+
+    let exhausted = FakePortal::with_usage(&[("id1", 11500), ("id2", 10500), ("id3", 10500)]);
+    run_with(&config, &exhausted, &mut router).await.unwrap();
+    assert!(State::load().disabled.is_some());
+
+    // The billing cycle reset
+    let reset = FakePortal::with_usage(&[("id1", 0), ("id2", 0), ("id3", 0)]);
+    run_with(&config, &reset, &mut router).await.unwrap();
+
+    assert_eq!(router.running_id, "id1");
+    assert_eq!(router.password.as_deref(), Some("pass1"));
+    assert!(State::load().disabled.is_none());
+}
+
+#[tokio::test]
+async fn falls_back_to_an_unlimited_id() {
+    let _data_dir = data_dir().await;
+    let config = config(
+        r#"
+[[credentials]]
+id = "unlimited"
+password = "pass4"
+unlimited = true
+"#,
+    );
+    let portal = FakePortal::with_usage(&[("id1", 11500), ("id2", 10500), ("id3", 10500)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(
+        router.changes,
+        [("unlimited".to_string(), "pass4".to_string())]
+    );
+    // Unlimited IDs aren't read from the portal as candidates
+    assert!(!portal.checked().contains(&"unlimited".to_string()));
+}
+
+#[tokio::test]
+async fn uses_the_thresholds_of_each_id() {
+    let _data_dir = data_dir().await;
+    let config = config(
+        r#"
+[[credentials]]
+id = "small"
+password = "pass4"
+thresholds = { switch = 500, available = 400, disable = 600 }
+"#,
+    );
+    let portal = FakePortal::with_usage(&[("small", 550), ("id1", 9000)]);
+    let mut router = FakeRouter::running("small");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(router.running_id, "id1");
+}
+
+#[tokio::test]
+async fn only_reports_changes_in_a_dry_run() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 11500), ("id2", 10500), ("id3", 10500)]);
+    let mut router = FakeRouter::running("id1");
+    router.dry_run = true;
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert!(router.changes.is_empty());
+    assert!(State::load().disabled.is_none());
+}
+
+#[tokio::test]
+async fn leaves_expiry_reminders_to_a_real_run() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let mut portal = FakePortal::with_usage(&[("id1", 5000)]);
+    portal.accounts.get_mut("id1").unwrap().expiry = Some(Local::now().date_naive());
+    let mut router = FakeRouter::running("id1");
+    router.dry_run = true;
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+    assert!(State::load().accounts["id1"].reminded_on.is_none());
+
+    router.dry_run = false;
+    run_with(&config, &portal, &mut router).await.unwrap();
+    assert!(State::load().accounts["id1"].reminded_on.is_some());
+}
+
+#[tokio::test]
+async fn counts_a_switch_the_router_rejected() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 10500), ("id2", 100), ("id3", 100)]);
+    let mut router = FakeRouter::running("id1");
+    router.rejects = true;
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(router.running_id, "id1");
+    let counters = State::load().counters;
+    assert_eq!(counters.switches, 0);
+    assert_eq!(counters.switch_failures, 1);
+}
+
+#[tokio::test]
+async fn fails_when_the_running_ids_usage_cant_be_read() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id2", 100)]);
+    let mut router = FakeRouter::running("id1");
+
+    let result = run_with(&config, &portal, &mut router).await;
+
+    assert!(result.is_err());
+    assert!(router.changes.is_empty());
+}
+
+#[tokio::test]
+async fn writes_a_report_of_each_run() {
+    let data_dir = data_dir().await;
+    let config = config("");
+    let portal = FakePortal::with_usage(&[("id1", 10500), ("id2", 10200), ("id3", 3000)]);
+    let mut router = FakeRouter::running("id1");
+
+    auto_wifi::begin_run(&config);
+    let result = run_with(&config, &portal, &mut router).await;
+    auto_wifi::finish_run(&config, &result);
+
+    let reports: Vec<_> = std::fs::read_dir(data_dir.dir.path().join(REPORTS_DIR_NAME))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&reports[0]).unwrap()).unwrap();
+    assert_eq!(report["outcome"], "succeeded");
+    assert_eq!(report["readings"].as_array().unwrap().len(), 3);
+    assert_eq!(report["decision"]["event"], "switch_started");
+    assert_eq!(report["actions"][0]["event"], "switch_succeeded");
+    assert_eq!(report["actions"][0]["to"], "id3");
+}
+
+#[tokio::test]
+async fn allows_the_quota_to_be_used_as_scheduled() {
+    let config = config("[schedule]\ncycle_start_day = 5\npoints = [{ day = 15, percent = 40 }]\n");
+    let allowance = |date: &str| scheduled_allowance(&config, "id1", date.parse().unwrap());
+
+    // Day 15 of the cycle starting on the 5th, then evenly up to all of it
+    assert_eq!(allowance("2026-03-19"), Some(4000));
+    assert_eq!(allowance("2026-03-05"), Some(267));
+    assert_eq!(allowance("2026-03-04"), Some(10000));
+    assert_eq!(allowance("2026-02-04"), Some(10000));
+}
+
+#[tokio::test]
+async fn throttles_an_id_used_ahead_of_schedule() {
+    let _data_dir = data_dir().await;
+    // Nothing may be used until the last day of the cycle
+    let config =
+        config("[schedule]\npoints = [{ day = 31, percent = 0 }]\naction = \"throttle\"\n");
+    let portal = FakePortal::with_usage(&[("id1", 5000), ("id2", 200), ("id3", 0)]);
+    let mut router = FakeRouter::running("id1");
+
+    run_with(&config, &portal, &mut router).await.unwrap();
+
+    assert_eq!(router.changes, [("id3".to_string(), "pass3".to_string())]);
+    assert!(State::load().schedule_warned_on.contains_key("id1"));
+}
@@ -0,0 +1,256 @@
+//! In-memory fakes of the ISP portal and the router, a portal served by
+//! wiremock, and a data directory of their own for each test
+
+// Each test binary uses only some of these
+#![allow(dead_code)]
+
+use anyhow::Result;
+use async_trait::async_trait;
+use auto_wifi::automation::{RouterControl, UsageProvider};
+use auto_wifi::config::{Config, PortalClient, UsageKind};
+use auto_wifi::notify;
+use auto_wifi::portal::PortalAccount;
+use auto_wifi::state::DATA_DIR_VAR;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use tempfile::TempDir;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub const LOGIN_PAGE: &str = include_str!("../fixtures/portal/login.html");
+pub const ACCOUNT_PAGE: &str = include_str!("../fixtures/portal/account.html");
+
+pub const LOGIN_PATH: &str = "/index.php/home/login";
+
+/// The data directory is set for the whole process, so tests using it take
+/// turns
+static DATA_DIR_LOCK: AsyncMutex<()> = AsyncMutex::const_new(());
+
+/// Router, notifications and three PPPoE IDs with the default thresholds
+/// (switch above 10000, available at or below 10000, disable above 11000)
+const BASE_CONFIG: &str = r#"
+[router]
+ip = "192.168.1.1"
+password = "admin"
+
+[retry]
+max_attempts = 1
+
+[notifications.desktop]
+enabled = false
+
+[[credentials]]
+id = "id1"
+password = "pass1"
+
+[[credentials]]
+id = "id2"
+password = "pass2"
+
+[[credentials]]
+id = "id3"
+password = "pass3"
+"#;
+
+/// An empty data directory, in use until this is dropped
+pub struct DataDir {
+    pub dir: TempDir,
+    _turn: MutexGuard<'static, ()>,
+}
+
+/// Wait for the data directory, then point it at a new empty one
+pub async fn data_dir() -> DataDir {
+    let turn = DATA_DIR_LOCK.lock().await;
+    let dir = TempDir::new().expect("Failed to create a temporary directory");
+    env::set_var(DATA_DIR_VAR, dir.path());
+
+    DataDir { dir, _turn: turn }
+}
+
+/// The test config, with `extra` added at the end
+///
+/// # Arguments
+/// * `extra` - More TOML, e.g. a `[[credentials]]` or `[thresholds]` table
+pub fn config(extra: &str) -> Config {
+    let config = Config::from_toml(&format!("{}\n{}", BASE_CONFIG, extra))
+        .expect("The test config is invalid");
+    // Keep notifications off the desktop
+    notify::init(&config.notifications);
+
+    config
+}
+
+/// The test config, reading the portal at `server` without ever falling
+/// back to the browser
+///
+/// # Arguments
+/// * `server` - The portal, e.g. from `portal`
+/// * `extra` - More TOML, as for `config`
+pub fn portal_config(server: &MockServer, extra: &str) -> Config {
+    let mut config = config(extra);
+    use_portal(&mut config, server);
+
+    config
+}
+
+/// Read the portal at `server` over HTTP, without ever falling back to the
+/// browser
+pub fn use_portal(config: &mut Config, server: &MockServer) {
+    config.portal.client = PortalClient::Http;
+    config.portal.login_url = format!("{}{}", server.uri(), LOGIN_PATH);
+    config.lightweight = true;
+}
+
+/// A portal letting only `id1` in with `pass1`, showing 3577 minutes used
+pub async fn portal() -> MockServer {
+    let server = MockServer::start().await;
+    mount_portal(&server, &[("id1", "pass1")]).await;
+
+    server
+}
+
+/// Serve the portal's login form at `server`, showing the account page to
+/// these logins and the login page again to anyone else
+///
+/// # Arguments
+/// * `server` - Where to serve it, e.g. next to the router
+/// * `logins` - The PPPoE IDs let in, with their passwords
+pub async fn mount_portal(server: &MockServer, logins: &[(&str, &str)]) {
+    Mock::given(method("GET"))
+        .and(path(LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+        .mount(server)
+        .await;
+    for (pppoe_id, password) in logins {
+        Mock::given(method("POST"))
+            .and(path(LOGIN_PATH))
+            .and(body_string_contains(format!("username={}", pppoe_id)))
+            .and(body_string_contains(format!("password={}", password)))
+            .respond_with(ResponseTemplate::new(200).set_body_string(ACCOUNT_PAGE))
+            .with_priority(1)
+            .mount(server)
+            .await;
+    }
+    Mock::given(method("POST"))
+        .and(path(LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+        .mount(server)
+        .await;
+}
+
+/// An active account with this much used, in minutes
+pub fn account(total_use: i32) -> PortalAccount {
+    PortalAccount {
+        total_use,
+        usage_kind: UsageKind::Time,
+        status: Some("Active".to_string()),
+        expiry: None,
+        recharge_amount: None,
+        variant: "default".to_string(),
+    }
+}
+
+/// A portal that knows a fixed set of accounts
+#[derive(Default)]
+pub struct FakePortal {
+    /// Each account by PPPoE ID. Unknown IDs fail to read.
+    pub accounts: HashMap<String, PortalAccount>,
+    /// The PPPoE IDs read, in order
+    pub checked: Mutex<Vec<String>>,
+}
+
+impl FakePortal {
+    /// A portal showing these usages, in minutes, for active accounts
+    pub fn with_usage(usage: &[(&str, i32)]) -> Self {
+        Self {
+            accounts: usage
+                .iter()
+                .map(|(pppoe_id, total_use)| (pppoe_id.to_string(), account(*total_use)))
+                .collect(),
+            checked: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The PPPoE IDs read so far
+    pub fn checked(&self) -> Vec<String> {
+        self.checked.lock().unwrap().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl UsageProvider for FakePortal {
+    async fn account(&self, pppoe_id: &str, _password: &str) -> Result<PortalAccount> {
+        self.checked.lock().unwrap().push(pppoe_id.to_string());
+        self.accounts
+            .get(pppoe_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Portal login failed for '{}'", pppoe_id))
+    }
+}
+
+/// A router that remembers what it was set to
+pub struct FakeRouter {
+    /// The PPPoE ID it is set to
+    pub running_id: String,
+    /// The password it is set to, if it was changed
+    pub password: Option<String>,
+    /// Every credential change, as (PPPoE ID, password)
+    pub changes: Vec<(String, String)>,
+    /// Every WiFi rename, as (interface, SSID)
+    pub renames: Vec<(String, String)>,
+    /// Reject credential changes
+    pub rejects: bool,
+    /// Only report changes
+    pub dry_run: bool,
+}
+
+impl FakeRouter {
+    /// A router set to this PPPoE ID
+    pub fn running(pppoe_id: &str) -> Self {
+        Self {
+            running_id: pppoe_id.to_string(),
+            password: None,
+            changes: Vec::new(),
+            renames: Vec::new(),
+            rejects: false,
+            dry_run: false,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl RouterControl for FakeRouter {
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    async fn running_id(&mut self) -> Result<String> {
+        Ok(self.running_id.clone())
+    }
+
+    async fn set_credentials(&mut self, pppoe_id: &str, password: &str) -> Result<bool> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        if self.rejects {
+            return Ok(false);
+        }
+
+        self.running_id = pppoe_id.to_string();
+        self.password = Some(password.to_string());
+        self.changes
+            .push((pppoe_id.to_string(), password.to_string()));
+
+        Ok(true)
+    }
+
+    async fn set_wifi_ssid(&mut self, interface: &str, ssid: &str) -> Result<()> {
+        if !self.dry_run {
+            self.renames.push((interface.to_string(), ssid.to_string()));
+        }
+
+        Ok(())
+    }
+}
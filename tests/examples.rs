@@ -0,0 +1,43 @@
+//! The programs in examples/, run against their mock servers so they keep
+//! working as the library changes
+
+mod common;
+
+#[allow(dead_code)]
+#[path = "../examples/check_usage.rs"]
+mod check_usage;
+#[allow(dead_code)]
+#[path = "../examples/custom_policy.rs"]
+mod custom_policy;
+#[allow(dead_code)]
+#[path = "../examples/switch_id.rs"]
+mod switch_id;
+
+use common::data_dir;
+
+#[tokio::test]
+async fn check_usage_reads_the_portal() {
+    let _data_dir = data_dir().await;
+
+    let account = check_usage::run().await.unwrap();
+
+    assert_eq!(account.total_use, 3577);
+    assert_eq!(account.status.as_deref(), Some("Active"));
+}
+
+#[tokio::test]
+async fn switch_id_switches_the_router() {
+    let _data_dir = data_dir().await;
+
+    let switched = switch_id::run().await.unwrap();
+
+    assert_eq!(switched, ("id1".to_string(), "id2".to_string()));
+}
+
+#[tokio::test]
+async fn custom_policy_switches_early() {
+    let _data_dir = data_dir().await;
+
+    // 9500 is within the switch threshold, but not with the margin
+    assert_eq!(custom_policy::run().await.unwrap(), "id2");
+}
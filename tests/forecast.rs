@@ -0,0 +1,91 @@
+//! Forecasting when an ID reaches its switch threshold, allowing for weeks
+//! that are busier at the weekend
+
+mod common;
+
+use auto_wifi::forecast::{forecast, seasonality};
+use auto_wifi::storage::UsageSample;
+use chrono::{Datelike, Local, NaiveDate, TimeZone, Weekday};
+use common::config;
+
+/// Minutes used on a weekday, and on a Saturday or Sunday
+const WEEKDAY_USE: i32 = 60;
+const WEEKEND_USE: i32 = 180;
+
+/// Readings of `id1` every six hours from the start of `from` to the start of
+/// `to`, starting at `minutes`
+fn readings(from: NaiveDate, to: NaiveDate, minutes: i32) -> Vec<UsageSample> {
+    let mut samples = Vec::new();
+    let mut minutes = minutes;
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        for hour in [0, 6, 12, 18] {
+            let at = Local
+                .with_ymd_and_hms(date.year(), date.month(), date.day(), hour, 0, 0)
+                .earliest()
+                .unwrap();
+            samples.push(UsageSample {
+                timestamp: at.timestamp(),
+                pppoe_id: "id1".to_string(),
+                minutes,
+            });
+            if date == to {
+                return samples;
+            }
+
+            minutes += match date.weekday() {
+                Weekday::Sat | Weekday::Sun => WEEKEND_USE,
+                _ => WEEKDAY_USE,
+            } / 4;
+        }
+    }
+
+    samples
+}
+
+#[test]
+fn learns_that_weekends_are_busier() {
+    let config = config("");
+    let monday = NaiveDate::from_ymd_opt(2026, 3, 2).unwrap();
+    let samples = readings(monday, NaiveDate::from_ymd_opt(2026, 3, 28).unwrap(), 0);
+
+    let learned = seasonality(&config, "id1", &samples).unwrap();
+    // The first and the last day are left out, leaving Tuesday 3rd to
+    // Thursday 26th: three weeks and three weekdays, 90 minutes a day
+    assert!(
+        (learned.weekday[0] - 60.0 / 90.0).abs() < 0.01,
+        "{:?}",
+        learned
+    );
+    assert!((learned.weekday[5] - 2.0).abs() < 0.01, "{:?}", learned);
+    assert!(learned.month.is_none());
+
+    // Under two weeks isn't enough to tell
+    let samples = readings(monday, NaiveDate::from_ymd_opt(2026, 3, 14).unwrap(), 0);
+    assert!(seasonality(&config, "id1", &samples).is_none());
+}
+
+#[test]
+fn forecasts_a_busy_weekend_sooner_than_a_flat_rate() {
+    let config = config("");
+    let saturday = NaiveDate::from_ymd_opt(2026, 3, 28).unwrap();
+    let samples = readings(NaiveDate::from_ymd_opt(2026, 3, 2).unwrap(), saturday, 7360);
+    let learned = seasonality(&config, "id1", &samples).unwrap();
+
+    // The last week, read at the start of Saturday with 360 minutes left
+    let last_week: Vec<_> = samples[samples.len() - 29..].to_vec();
+    assert_eq!(last_week.last().unwrap().minutes, 10000 - 360);
+
+    let seasonal = forecast(&config, "id1", &last_week, Some(&learned)).unwrap();
+    let flat = forecast(&config, "id1", &last_week, None).unwrap();
+    assert!(seasonal.seasonal && !flat.seasonal);
+
+    // 180 minutes on Saturday and 180 on Sunday use it up by Monday
+    let monday = Local.with_ymd_and_hms(2026, 3, 30, 0, 0, 0).unwrap();
+    let switch_at = seasonal.switch_at.unwrap();
+    assert!(
+        (switch_at - monday).num_minutes().abs() < 5,
+        "{}",
+        switch_at
+    );
+    assert!(flat.switch_at.unwrap() > monday + chrono::Duration::days(1));
+}
@@ -0,0 +1,127 @@
+//! Config and state files from older versions are upgraded when read
+
+mod common;
+
+use auto_wifi::config::Config;
+use auto_wifi::migrate;
+use auto_wifi::state::{State, STATE_FILE_NAME, STATE_VERSION};
+use std::fs;
+
+const ENV_FILE: &str = "\
+# Router Configuration
+ROUTER_IP=192.168.0.1
+ROUTER_PASSWORD=secret
+ROUTER_FALLBACK_PASSWORDS=admin, password
+CANDIDATE_ORDER=lru
+MAX_CANDIDATE_CHECKS=not a number
+PPPOE_CREDENTIALS=id1:pass1,id2:pa:ss2
+";
+
+#[tokio::test]
+async fn converts_an_env_file_to_toml() {
+    let data = common::data_dir().await;
+    let path = data.dir.path().join("settings.env");
+    fs::write(&path, ENV_FILE).unwrap();
+
+    let config = Config::load(Some(&path)).unwrap();
+    assert_eq!(config.router.ip, "192.168.0.1");
+    assert_eq!(config.router.fallback_passwords, ["admin", "password"]);
+    assert_eq!(config.polling.max_candidate_checks, None);
+    let credentials: Vec<_> = config
+        .credentials
+        .iter()
+        .map(|credential| (credential.id.as_str(), credential.password.as_str()))
+        .collect();
+    assert_eq!(credentials, [("id1", "pass1"), ("id2", "pa:ss2")]);
+
+    // Rewritten as TOML, which reads the same from now on
+    let upgraded = fs::read_to_string(&path).unwrap();
+    assert!(upgraded.contains("version = 2"));
+    let reread = Config::from_toml(&upgraded).unwrap();
+    assert_eq!(reread.router.password, "secret");
+    assert_eq!(
+        fs::read_to_string(data.dir.path().join("settings.env.v1.bak")).unwrap(),
+        ENV_FILE
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn keeps_a_private_config_file_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let data = common::data_dir().await;
+    let path = data.dir.path().join("settings.env");
+    fs::write(&path, ENV_FILE).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    Config::load(Some(&path)).unwrap();
+
+    for file in [path.clone(), data.dir.path().join("settings.env.v1.bak")] {
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600, "{}", file.display());
+    }
+}
+
+#[tokio::test]
+async fn leaves_a_current_config_file_alone() {
+    let data = common::data_dir().await;
+    let path = data.dir.path().join("config.toml");
+    let content = "[router]\nip = \"192.168.1.1\"\npassword = \"admin\"\n\n[[credentials]]\nid = \"id1\"\npassword = \"pass1\"\n";
+    fs::write(&path, content).unwrap();
+
+    Config::load(Some(&path)).unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    assert_eq!(fs::read_dir(data.dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn refuses_a_config_file_from_a_newer_version() {
+    let data = common::data_dir().await;
+    let path = data.dir.path().join("config.toml");
+    fs::write(&path, "version = 99\nnew_setting = true\n").unwrap();
+
+    let error = format!("{:#}", Config::load(Some(&path)).unwrap_err());
+
+    assert!(error.contains("newer version"), "{}", error);
+}
+
+#[tokio::test]
+async fn moves_the_router_fingerprint_under_the_router_address() {
+    let data = common::data_dir().await;
+    let config = common::config("");
+    let path = data.dir.path().join(STATE_FILE_NAME);
+    let old_state = r#"{
+        "router_fingerprint": {"title": "D-Link", "firmware": "1.02"},
+        "last_used": {"id1": 1700000000}
+    }"#;
+    fs::write(&path, old_state).unwrap();
+
+    migrate::state_file(&config).unwrap();
+
+    let state = State::load();
+    let fingerprint = &state.router_fingerprints["192.168.1.1"];
+    assert_eq!(fingerprint.firmware, "1.02");
+    assert_eq!(state.last_running_id(), Some("id1"));
+    let upgraded: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(upgraded["version"], STATE_VERSION);
+    assert!(data.dir.path().join("state.json.v1.bak").exists());
+
+    // Saved state is stamped, so there is nothing to upgrade the next time
+    state.save().unwrap();
+    migrate::state_file(&config).unwrap();
+    assert!(!data.dir.path().join("state.json.v2.bak").exists());
+}
+
+#[tokio::test]
+async fn refuses_a_state_file_from_a_newer_version() {
+    let data = common::data_dir().await;
+    let path = data.dir.path().join(STATE_FILE_NAME);
+    fs::write(&path, r#"{"version": 99}"#).unwrap();
+
+    let error = migrate::state_file(&common::config("")).unwrap_err();
+
+    assert!(error.to_string().contains("newer version"), "{}", error);
+}
@@ -0,0 +1,41 @@
+//! The color-coded overview of every PPPoE ID
+
+mod common;
+
+use auto_wifi::overview::{html, text, Level, Row};
+use chrono::Local;
+use common::account;
+
+#[test]
+fn colors_each_id_by_how_much_of_its_quota_is_used() {
+    let config = common::config("");
+    let rows: Vec<_> = [
+        Ok(account(5000)),
+        Ok(account(8000)),
+        Err("<portal down>".to_string()),
+    ]
+    .iter()
+    .zip(&config.credentials)
+    .map(|(account, credential)| {
+        Row::new(
+            &config,
+            credential,
+            credential.id == "id1",
+            account.as_ref().map_err(String::clone),
+        )
+    })
+    .collect();
+
+    let levels: Vec<_> = rows.iter().map(|row| row.level).collect();
+    assert_eq!(levels, [Level::Fine, Level::Low, Level::Unknown]);
+    assert_eq!(rows[1].percent, Some(80));
+
+    let table = text(&rows, true);
+    assert!(table.contains("\x1b[32mid1 *"), "{}", table);
+    assert!(table.contains("\x1b[33mid2"), "{}", table);
+    assert!(!text(&rows, false).contains('\x1b'));
+
+    let page = html(&rows, Local::now());
+    assert!(page.contains("&lt;portal down&gt;"), "{}", page);
+    assert!(page.contains("#fff3cd"));
+}
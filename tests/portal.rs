@@ -0,0 +1,188 @@
+//! Reading accounts from a portal served by wiremock, over plain HTTP as
+//! `portal.client = "http"` does
+
+mod common;
+
+use auto_wifi::portal::get_total_use;
+use auto_wifi::state::{record_usage, record_usage_at, unix_now};
+use chrono::NaiveDate;
+use common::{data_dir, portal_config, ACCOUNT_PAGE, LOGIN_PAGE, LOGIN_PATH};
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const PREPAID_PAGE: &str = include_str!("fixtures/portal/account_prepaid.html");
+const DASHBOARD_PAGE: &str = include_str!("fixtures/portal/dashboard.html");
+const BONUS_PAGE: &str = include_str!("fixtures/portal/bonus.html");
+
+/// A portal that shows `page` to `username` logging in with `password`, and
+/// the login page again to anyone else
+async fn portal(username: &str, password: &str, page: &str) -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+        .mount(&server)
+        .await;
+    // The hidden token has to be sent back, and the unticked box must not be
+    Mock::given(method("POST"))
+        .and(path(LOGIN_PATH))
+        .and(body_string_contains("csrf_token=3f9a1c"))
+        .and(body_string_contains(format!("username={}", username)))
+        .and(body_string_contains(format!("password={}", password)))
+        .respond_with(ResponseTemplate::new(200).set_body_string(page))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+#[tokio::test]
+async fn reads_the_account_table() {
+    let _data_dir = data_dir().await;
+    let server = portal("id1", "pass1", ACCOUNT_PAGE).await;
+    let config = portal_config(&server, "");
+
+    let account = get_total_use(&config, "id1", "pass1").await.unwrap();
+
+    assert_eq!(account.total_use, 3577);
+    assert_eq!(account.status.as_deref(), Some("Active"));
+    assert_eq!(account.expiry, NaiveDate::from_ymd_opt(2024, 5, 31));
+    assert_eq!(account.recharge_amount.as_deref(), Some("500 Tk"));
+    assert_eq!(account.variant, "default");
+    assert!(account.is_usable());
+}
+
+#[tokio::test]
+async fn fails_when_the_login_is_rejected() {
+    let _data_dir = data_dir().await;
+    let server = portal("id1", "pass1", ACCOUNT_PAGE).await;
+    let config = portal_config(&server, "");
+
+    let error = get_total_use(&config, "id1", "wrong").await.unwrap_err();
+
+    assert!(error.to_string().contains("login failed"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn reads_another_layout_from_its_variant() {
+    let _data_dir = data_dir().await;
+    let server = portal("id2", "pass2", PREPAID_PAGE).await;
+    let config = portal_config(
+        &server,
+        r#"
+[[portal.variants]]
+name = "prepaid"
+usage_label = "Used Time"
+usage_unit = "hours"
+status_label = "Account State"
+"#,
+    );
+
+    let account = get_total_use(&config, "id2", "pass2").await.unwrap();
+
+    assert_eq!(account.variant, "prepaid");
+    // 12.5 hours
+    assert_eq!(account.total_use, 750);
+    assert!(!account.is_usable());
+}
+
+#[tokio::test]
+async fn fails_on_a_page_without_the_usage_label() {
+    let _data_dir = data_dir().await;
+    let server = portal("id2", "pass2", PREPAID_PAGE).await;
+    let config = portal_config(&server, "");
+
+    let error = get_total_use(&config, "id2", "pass2").await.unwrap_err();
+
+    assert!(
+        error.to_string().contains("Usage cell not found"),
+        "{:#}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn follows_the_steps_after_login_to_the_account_table() {
+    let _data_dir = data_dir().await;
+    // Logging in lands on a dashboard linking to the account table
+    let server = portal("id1", "pass1", DASHBOARD_PAGE).await;
+    Mock::given(method("GET"))
+        .and(path("/index.php/home/usage"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(ACCOUNT_PAGE))
+        .mount(&server)
+        .await;
+
+    let config = portal_config(&server, "");
+    let error = get_total_use(&config, "id1", "pass1").await.unwrap_err();
+    assert!(
+        error.to_string().contains("Usage cell not found"),
+        "{:#}",
+        error
+    );
+
+    let config = portal_config(
+        &server,
+        "[portal]\nafter_login = [{ click = \"a#usage-details\" }]",
+    );
+    let account = get_total_use(&config, "id1", "pass1").await.unwrap();
+    assert_eq!(account.total_use, 3577);
+
+    // Only links can be followed without the browser
+    let config = portal_config(&server, "[portal]\nafter_login = [{ click = \"ul.menu\" }]");
+    let error = get_total_use(&config, "id1", "pass1").await.unwrap_err();
+    assert!(error.to_string().contains("isn't a link"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn takes_a_drop_in_usage_for_a_glitch_until_it_repeats() {
+    let _data_dir = data_dir().await;
+    let server = portal("id1", "pass1", ACCOUNT_PAGE).await;
+    let config = portal_config(&server, "");
+
+    // A new cycle started since the last reading
+    record_usage_at("id1", 5000, unix_now() - 40 * 24 * 60 * 60);
+    let account = get_total_use(&config, "id1", "pass1").await.unwrap();
+    assert_eq!(account.total_use, 3577);
+
+    record_usage("id1", 5000);
+    let error = get_total_use(&config, "id1", "pass1").await.unwrap_err();
+    assert!(error.to_string().contains("implausible"), "{:#}", error);
+
+    // Shown again, so it is believed
+    let account = get_total_use(&config, "id1", "pass1").await.unwrap();
+    assert_eq!(account.total_use, 3577);
+}
+
+#[tokio::test]
+async fn adds_up_usage_split_across_pages() {
+    let _data_dir = data_dir().await;
+    let server = portal("id1", "pass1", ACCOUNT_PAGE).await;
+    Mock::given(method("GET"))
+        .and(path("/index.php/home/bonus"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(BONUS_PAGE))
+        .mount(&server)
+        .await;
+    let config = portal_config(
+        &server,
+        r#"
+[portal]
+usage_total = "usage + bonus_used"
+
+[portal.usage_parts.bonus_used]
+label = "Bonus Used:"
+page = "bonus"
+"#,
+    );
+
+    let account = get_total_use(&config, "id1", "pass1").await.unwrap();
+
+    // 3577 minutes on the account page, and 2 hours of bonus
+    assert_eq!(account.total_use, 3697);
+}
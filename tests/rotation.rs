@@ -0,0 +1,120 @@
+//! Changing a PPPoE ID's password at a portal served by wiremock, and storing
+//! the new one in the config file
+
+mod common;
+
+use auto_wifi::config::{Config, PasswordStore};
+use auto_wifi::portal::change_password;
+use common::{data_dir, portal_config};
+use std::fs;
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const CHANGE_PAGE: &str = include_str!("fixtures/portal/change_password.html");
+
+const CHANGE_PATH: &str = "/index.php/home/changepassword";
+
+/// A portal letting `id1` in with `pass1` and changing its password to
+/// `fresh`, and nothing else
+async fn portal() -> MockServer {
+    let server = common::portal().await;
+
+    Mock::given(method("GET"))
+        .and(path(CHANGE_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(CHANGE_PAGE))
+        .mount(&server)
+        .await;
+    // The form's token has to be sent back along with all three fields
+    Mock::given(method("POST"))
+        .and(path(CHANGE_PATH))
+        .and(body_string_contains("csrf_token=7d20be"))
+        .and(body_string_contains("old_password=pass1"))
+        .and(body_string_contains("new_password=fresh"))
+        .and(body_string_contains("confirm_password=fresh"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Password changed"))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(CHANGE_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(CHANGE_PAGE))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// The test config, changing passwords at `server` over HTTP
+fn change_config(server: &MockServer) -> Config {
+    portal_config(
+        server,
+        &format!(
+            r#"
+[portal.password_change]
+url = "{}{}"
+confirm_field = "confirm_password"
+success_text = "Password changed"
+"#,
+            server.uri(),
+            CHANGE_PATH
+        ),
+    )
+}
+
+#[tokio::test]
+async fn changes_the_password_on_the_portal() {
+    let _data_dir = data_dir().await;
+    let server = portal().await;
+    let config = change_config(&server);
+
+    change_password(&config, "id1", "pass1", "fresh")
+        .await
+        .unwrap();
+
+    // Without the confirmation the change doesn't count
+    let error = change_password(&config, "id1", "pass1", "other")
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("didn't confirm"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn stores_the_new_password_in_the_config_file() {
+    let data = data_dir().await;
+    let path = data.dir.path().join("config.toml");
+    fs::write(
+        &path,
+        "[router]\nip = \"192.168.1.1\"\npassword = \"shared\"\n\n\
+         [[credentials]]\nid = \"id1\"\npassword = \"shared\"\n\n\
+         # The backup line\n[[credentials]]\nid = \"id2\"\n  password = 'shared'\n",
+    )
+    .unwrap();
+    let config = Config::load(Some(&path)).unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    let store = config.password_store("id2").unwrap();
+    assert_eq!(store, PasswordStore::ConfigFile(path.clone()));
+    store.save("id2", "fresh").unwrap();
+
+    // Still only readable by its owner
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // Only that one line changed, the same password elsewhere didn't
+    let content = fs::read_to_string(&path).unwrap();
+    assert!(content
+        .contains("# The backup line\n[[credentials]]\nid = \"id2\"\n  password = \"fresh\"\n"));
+    let reread = Config::load(Some(&path)).unwrap();
+    assert_eq!(reread.router.password, "shared");
+    assert_eq!(reread.credentials[0].password, "shared");
+    assert_eq!(reread.credentials[1].password, "fresh");
+}
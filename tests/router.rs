@@ -0,0 +1,414 @@
+//! Driving an OpenWrt router whose ubus API is faked with wiremock, and a
+//! canary check against it
+
+mod common;
+
+use auto_wifi::config::{Config, RouterModel};
+use auto_wifi::router::{
+    discover_pppoe_fields, password_change_router, set_wifi_ssid, which_pppoe_id_running,
+    RouterAccess, SwitchNotVerified, DISABLED_PASSWORD,
+};
+use auto_wifi::state::{unix_now, State};
+use auto_wifi::{bench, canary, commands};
+use common::{config, data_dir, mount_portal, use_portal};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// `/etc/config/network` and `/etc/config/wireless`, as ubus shows them
+const UCI: &str = include_str!("fixtures/openwrt/uci.json");
+
+const ADMIN_PASSWORD: &str = "admin";
+const SESSION: &str = "c0ffee00c0ffee00c0ffee00c0ffee00";
+
+/// The ubus JSON-RPC endpoint of an OpenWrt router, keeping its uci config
+/// in memory
+#[derive(Clone)]
+struct Ubus {
+    /// The uci config, by config name
+    uci: Arc<Mutex<Value>>,
+    /// What the WAN interface reports as its state
+    wan_up: bool,
+    /// Every call made, as "object method"
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl Ubus {
+    fn new(wan_up: bool) -> Self {
+        Self {
+            uci: Arc::new(Mutex::new(serde_json::from_str(UCI).unwrap())),
+            wan_up,
+            calls: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// An option of the uci config, e.g. `("network", "wan", "username")`
+    fn option(&self, config: &str, section: &str, option: &str) -> Value {
+        self.uci.lock().unwrap()[config][section][option].clone()
+    }
+
+    fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The result of a call: the status code and the reply, if any
+    fn call(&self, session: &str, object: &str, method: &str, args: &Value) -> Value {
+        if (object, method) == ("session", "login") {
+            return if args["password"] == ADMIN_PASSWORD {
+                json!([0, { "ubus_rpc_session": SESSION }])
+            } else {
+                // Permission denied
+                json!([6])
+            };
+        }
+        if session != SESSION {
+            return json!([6]);
+        }
+
+        let mut uci = self.uci.lock().unwrap();
+        match (object, method) {
+            ("uci", "get") => {
+                let config = args["config"].as_str().unwrap();
+                match (args["section"].as_str(), args["option"].as_str()) {
+                    (Some(section), Some(option)) => {
+                        json!([0, { "value": uci[config][section][option] }])
+                    }
+                    (Some(section), None) => json!([0, { "values": uci[config][section] }]),
+                    _ => json!([0, { "values": uci[config] }]),
+                }
+            }
+            ("uci", "set") => {
+                let config = args["config"].as_str().unwrap();
+                let section = args["section"].as_str().unwrap();
+                for (option, value) in args["values"].as_object().unwrap() {
+                    uci[config][section][option] = value.clone();
+                }
+                json!([0])
+            }
+            ("network.interface.wan", "status") => json!([0, { "up": self.wan_up }]),
+            ("uci", "commit")
+            | ("network", "reload")
+            | ("network.interface.wan", "down" | "up")
+            | ("session", "destroy") => json!([0]),
+            // Not found
+            _ => json!([4]),
+        }
+    }
+}
+
+impl Respond for Ubus {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let request: Value = serde_json::from_slice(&request.body).unwrap();
+        let params = request["params"].as_array().unwrap();
+        let (object, method) = (params[1].as_str().unwrap(), params[2].as_str().unwrap());
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", object, method));
+
+        let result = self.call(params[0].as_str().unwrap(), object, method, &params[3]);
+        ResponseTemplate::new(200).set_body_json(json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }))
+    }
+}
+
+/// Let the router be changed, as `init --acknowledge-risks` does
+fn acknowledge_risks() {
+    let mut state = State::load();
+    state.risks_acknowledged_at = Some(unix_now());
+    state.save().unwrap();
+}
+
+/// Serve the router's ubus endpoint
+async fn router(ubus: &Ubus) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/ubus"))
+        .respond_with(ubus.clone())
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// The test config, for the OpenWrt router at `server`
+fn router_config(server: &MockServer) -> Config {
+    let mut config = config("");
+    config.router.model = RouterModel::OpenWrt;
+    config.router.ip = server.address().to_string();
+    config.router.login_delay_secs = 0;
+    config.router.connect_timeout_secs = 0;
+
+    config
+}
+
+#[tokio::test]
+async fn reads_the_running_id() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let running_id = which_pppoe_id_running(&config, &mut access).await.unwrap();
+
+    assert_eq!(running_id, "id1");
+    // The session is closed again
+    assert_eq!(ubus.calls().last().unwrap(), "session destroy");
+}
+
+#[tokio::test]
+async fn logs_in_with_a_fallback_password() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let mut config = router_config(&server);
+    config.router.password = "changed".to_string();
+    config.router.fallback_passwords = vec![ADMIN_PASSWORD.to_string()];
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    which_pppoe_id_running(&config, &mut access).await.unwrap();
+    // The password that worked is tried first from then on, in later runs too
+    which_pppoe_id_running(&config, &mut access).await.unwrap();
+    let mut access = RouterAccess::open(&config, false).unwrap();
+    which_pppoe_id_running(&config, &mut access).await.unwrap();
+
+    let logins = ubus
+        .calls()
+        .iter()
+        .filter(|call| *call == "session login")
+        .count();
+    assert_eq!(logins, 4);
+}
+
+#[tokio::test]
+async fn fails_when_every_admin_password_is_rejected() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let mut config = router_config(&server);
+    config.router.password = "changed".to_string();
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let error = which_pppoe_id_running(&config, &mut access)
+        .await
+        .unwrap_err();
+
+    assert!(error.to_string().contains("rejected"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn switches_and_reconnects() {
+    let _data_dir = data_dir().await;
+    acknowledge_risks();
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let switched = password_change_router(&config, &mut access, "id2", "pass2")
+        .await
+        .unwrap();
+
+    assert!(switched);
+    assert_eq!(ubus.option("network", "wan", "username"), "id2");
+    assert_eq!(ubus.option("network", "wan", "password"), "pass2");
+    let calls = ubus.calls();
+    assert!(calls.contains(&"uci commit".to_string()));
+    assert!(calls.contains(&"network.interface.wan up".to_string()));
+}
+
+#[tokio::test]
+async fn rolls_back_a_switch_whose_connection_stays_down() {
+    let _data_dir = data_dir().await;
+    acknowledge_risks();
+    let ubus = Ubus::new(false);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let error = password_change_router(&config, &mut access, "id2", "pass2")
+        .await
+        .unwrap_err();
+
+    let failure = error.downcast_ref::<SwitchNotVerified>().unwrap();
+    assert_eq!(failure.rolled_back_to.as_deref(), Some("id1"));
+    assert_eq!(ubus.option("network", "wan", "username"), "id1");
+    assert_eq!(ubus.option("network", "wan", "password"), "pass1");
+}
+
+#[tokio::test]
+async fn disables_without_waiting_for_the_connection() {
+    let _data_dir = data_dir().await;
+    acknowledge_risks();
+    let ubus = Ubus::new(false);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let disabled = password_change_router(&config, &mut access, "id1", DISABLED_PASSWORD)
+        .await
+        .unwrap();
+
+    assert!(disabled);
+    assert_eq!(ubus.option("network", "wan", "password"), DISABLED_PASSWORD);
+}
+
+#[tokio::test]
+async fn leaves_the_router_alone_in_a_dry_run() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, true).unwrap();
+
+    password_change_router(&config, &mut access, "id2", "pass2")
+        .await
+        .unwrap();
+    set_wifi_ssid(&config, &mut access, "guest", "Guest 5000min")
+        .await
+        .unwrap();
+
+    assert_eq!(ubus.option("network", "wan", "username"), "id1");
+    assert_eq!(ubus.option("wireless", "guest", "ssid"), "Guest");
+    assert!(!ubus.calls().contains(&"uci set".to_string()));
+}
+
+#[tokio::test]
+async fn renames_a_wifi_network() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    set_wifi_ssid(&config, &mut access, "guest", "Guest 5000min")
+        .await
+        .unwrap();
+
+    assert_eq!(ubus.option("wireless", "guest", "ssid"), "Guest 5000min");
+    assert_eq!(ubus.option("wireless", "default_radio0", "ssid"), "Home");
+}
+
+#[tokio::test]
+async fn finds_the_pppoe_interface() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let mut config = router_config(&server);
+    // Not a PPPoE interface, so the one that is gets picked
+    config.router.wan_interface = "lan".to_string();
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let fields = discover_pppoe_fields(&config, &mut access).await.unwrap();
+
+    assert_eq!(fields.wan_interface.as_deref(), Some("wan"));
+    assert_eq!(fields.username, "network.wan.username");
+    assert_eq!(fields.running_id, "id1");
+}
+
+#[tokio::test]
+async fn passes_a_canary_check_once_the_portal_reads() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    // The portal is served next to the router's ubus endpoint
+    let server = router(&ubus).await;
+    let mut config = router_config(&server);
+    use_portal(&mut config, &server);
+
+    let error = canary::run(&config).await.unwrap_err();
+    assert!(error.to_string().contains("Portal, 'id1'"), "{:#}", error);
+    assert!(canary::is_due(&config));
+
+    mount_portal(
+        &server,
+        &[("id1", "pass1"), ("id2", "pass2"), ("id3", "pass3")],
+    )
+    .await;
+
+    canary::run(&config).await.unwrap();
+    assert!(!canary::is_due(&config));
+    // Nothing was changed on the router
+    assert!(!ubus.calls().iter().any(|call| call.starts_with("uci set")));
+}
+
+#[tokio::test]
+async fn times_each_way_of_reading() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let mut config = router_config(&server);
+    // No browser, so only the HTTP portal and the ubus API are timed
+    use_portal(&mut config, &server);
+    mount_portal(&server, &[("id2", "pass2")]).await;
+
+    let timings = bench::measure(&config, Some("id2"), 2).await.unwrap();
+
+    let paths: Vec<_> = timings
+        .iter()
+        .map(|timing| (timing.target, timing.path))
+        .collect();
+    assert_eq!(paths, [("portal", "http"), ("router", "openwrt")]);
+    for timing in &timings {
+        assert_eq!(timing.result.as_ref().unwrap().len(), 2, "{:?}", timing);
+    }
+    assert!(!ubus.calls().iter().any(|call| call.starts_with("uci set")));
+}
+
+#[tokio::test]
+async fn changes_nothing_until_the_risks_are_acknowledged() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+    let mut access = RouterAccess::open(&config, false).unwrap();
+
+    let error = password_change_router(&config, &mut access, "id2", "pass2")
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("--acknowledge-risks"),
+        "{:#}",
+        error
+    );
+    assert!(commands::init(&config, false).await.is_err());
+    assert!(!ubus.calls().contains(&"uci set".to_string()));
+
+    commands::init(&config, true).await.unwrap();
+    let original = State::load().original_pppoe.unwrap();
+    assert_eq!(
+        (original.username.as_str(), original.password.as_str()),
+        ("id1", "pass1")
+    );
+
+    password_change_router(&config, &mut access, "id2", "pass2")
+        .await
+        .unwrap();
+    // Acknowledging again keeps the settings from before the first change
+    commands::init(&config, true).await.unwrap();
+    assert_eq!(State::load().original_pppoe, Some(original));
+}
+
+#[tokio::test]
+async fn restores_the_original_settings_and_leaves_the_router_alone_after() {
+    let _data_dir = data_dir().await;
+    let ubus = Ubus::new(true);
+    let server = router(&ubus).await;
+    let config = router_config(&server);
+
+    assert!(commands::restore_original(&config).await.is_err());
+
+    commands::init(&config, true).await.unwrap();
+    commands::switch(&config, "id2").await.unwrap();
+    assert_eq!(ubus.option("network", "wan", "username"), "id2");
+
+    commands::restore_original(&config).await.unwrap();
+    assert_eq!(ubus.option("network", "wan", "username"), "id1");
+    assert_eq!(ubus.option("network", "wan", "password"), "pass1");
+    assert!(State::load().risks_acknowledged_at.is_none());
+    assert!(commands::switch(&config, "id2").await.is_err());
+}
@@ -0,0 +1,102 @@
+//! A satellite reading the portal served by wiremock and sending the usage
+//! to a daemon's metrics address
+
+mod common;
+
+use auto_wifi::config::{Config, UsageKind};
+use auto_wifi::metrics;
+use auto_wifi::satellite::{self, Reading, Submission};
+use auto_wifi::state::{record_usage_at, unix_now, State};
+use auto_wifi::storage::History;
+use common::{config, data_dir, portal, portal_config};
+use std::net::SocketAddr;
+use std::time::Duration;
+use wiremock::MockServer;
+
+const TOKEN: &str = "0123456789abcdef";
+
+/// The config of a satellite reading `portal` and sending to `daemon`
+fn satellite_config(portal: &MockServer, daemon: SocketAddr, token: &str) -> Config {
+    portal_config(
+        portal,
+        &format!(
+            "[satellite]\nurl = \"http://{}\"\ntoken = \"{}\"\nname = \"phone\"\n",
+            daemon, token
+        ),
+    )
+}
+
+/// A reading of `pppoe_id` taken `age_secs` ago
+fn reading(pppoe_id: &str, total_use: i32, age_secs: u64) -> Reading {
+    Reading {
+        pppoe_id: pppoe_id.to_string(),
+        total_use,
+        usage_kind: UsageKind::Time,
+        read_at: unix_now() - age_secs,
+    }
+}
+
+#[tokio::test]
+async fn sends_readings_to_the_daemon() {
+    let _data_dir = data_dir().await;
+    let daemon = config(&format!("[satellites]\ntoken = \"{}\"\n", TOKEN));
+    let addr = metrics::serve(
+        "127.0.0.1:0".parse().unwrap(),
+        &daemon,
+        Duration::from_secs(60),
+    )
+    .await
+    .unwrap();
+    let portal = portal().await;
+
+    // The other IDs can't be read, and are left out
+    satellite::submit(&satellite_config(&portal, addr, TOKEN), None)
+        .await
+        .unwrap();
+
+    assert_eq!(State::load().usage_cache["id1"].minutes, 3577);
+    let samples = History::open().unwrap().usage_since(0).unwrap();
+    assert_eq!(samples.len(), 1);
+    assert_eq!(samples[0].pppoe_id, "id1");
+
+    let error = satellite::submit(
+        &satellite_config(&portal, addr, "not-the-token-at-all"),
+        Some("id1"),
+    )
+    .await
+    .unwrap_err();
+    assert!(error.to_string().contains("refused"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn keeps_the_newest_reading_and_refuses_bad_ones() {
+    let _data_dir = data_dir().await;
+    let config = config("");
+    record_usage_at("id2", 500, unix_now() - 60);
+
+    let merged = satellite::merge(
+        &config,
+        60,
+        &Submission {
+            source: "office".to_string(),
+            readings: vec![
+                reading("id1", 100, 120),
+                // Older than what is known, so only history
+                reading("id2", 400, 600),
+                reading("id4", 100, 0),
+                reading("id3", 100, 2 * 60 * 60),
+            ],
+        },
+    )
+    .unwrap();
+
+    assert_eq!(merged.accepted, 2);
+    assert_eq!(merged.rejected.len(), 2);
+    assert!(merged.rejected[0].contains("'id4' is not a configured"));
+    assert!(merged.rejected[1].contains("'id3' is too old"));
+
+    let state = State::load();
+    assert_eq!(state.usage_cache["id1"].minutes, 100);
+    assert_eq!(state.usage_cache["id2"].minutes, 500);
+    assert_eq!(History::open().unwrap().usage_since(0).unwrap().len(), 2);
+}
@@ -0,0 +1,72 @@
+//! The router's PPPoE log lines, forwarded over syslog
+
+mod common;
+
+use auto_wifi::storage::History;
+use auto_wifi::syslog::{self, classify, LinkChange};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+
+const SYSLOG_CONFIG: &str = r#"
+[syslog]
+listen = "127.0.0.1:0"
+senders = ["127.0.0.1"]
+"#;
+
+#[tokio::test]
+async fn recognises_what_pppd_logs() {
+    let config = common::config(SYSLOG_CONFIG);
+    let syslog = config.syslog.as_ref().unwrap();
+
+    for (line, change) in [
+        (
+            "Jan  1 00:00:00 OpenWrt pppd[1234]: local  IP address 100.64.1.2",
+            Some(LinkChange::Up),
+        ),
+        (
+            "pppd[1234]: PAP authentication failed",
+            Some(LinkChange::LoginFailed),
+        ),
+        ("pppd[1234]: Connection terminated.", Some(LinkChange::Down)),
+        ("dnsmasq[99]: query[A] example.com", None),
+    ] {
+        assert_eq!(classify(syslog, line), change, "{}", line);
+    }
+}
+
+#[tokio::test]
+async fn records_each_change_the_router_logs_once() {
+    let _data = common::data_dir().await;
+    let config = common::config(SYSLOG_CONFIG);
+    let addr = syslog::listen(&config, config.syslog.as_ref().unwrap())
+        .await
+        .unwrap();
+
+    let router = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for line in [
+        "<30>Jan  1 00:00:00 pppd[1]: CHAP authentication failed",
+        "<30>Jan  1 00:00:00 pppd[1]: Connection terminated.",
+        "<30>Jan  1 00:00:30 pppd[1]: CHAP authentication failed",
+        "<30>Jan  1 00:01:00 pppd[1]: local  IP address 100.64.1.2",
+    ] {
+        router.send_to(line.as_bytes(), addr).await.unwrap();
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..50 {
+        events = History::open().unwrap().link_events_since(0).unwrap();
+        if events.len() >= 2 {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let kinds: Vec<_> = events.iter().map(|event| event.kind.as_str()).collect();
+    assert_eq!(kinds, ["login_failed", "up"]);
+    assert_eq!(events[0].router, "127.0.0.1");
+    assert_eq!(
+        events[0].line,
+        "Jan  1 00:00:00 pppd[1]: CHAP authentication failed"
+    );
+}
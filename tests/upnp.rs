@@ -0,0 +1,127 @@
+//! Reading the WAN state and external IP from a router's UPnP IGD service
+//! faked with wiremock
+
+mod common;
+
+use auto_wifi::router::upnp;
+use auto_wifi::storage::History;
+use auto_wifi::wanip;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// The device description of a router with both WAN connection services
+const DESCRIPTION: &str = include_str!("fixtures/upnp/igd.xml");
+
+const PPP_SERVICE: &str = "urn:schemas-upnp-org:service:WANPPPConnection:1";
+
+/// A SOAP answer to `action` with the given fields
+fn answer(action: &str, fields: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_string(format!(
+        r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:{action}Response xmlns:u="{PPP_SERVICE}">{fields}</u:{action}Response></s:Body></s:Envelope>"#
+    ))
+}
+
+/// A config reading the IGD of `server` for `router`, which has to differ
+/// between tests as what is found is kept for the process
+fn config(server: &MockServer, router: &str) -> auto_wifi::config::Config {
+    let mut config = common::config("");
+    config.router.ip = router.to_string();
+    config.router.upnp_url = Some(format!("{}/rootDesc.xml", server.uri()));
+    config
+}
+
+/// Serve an IGD that is connected, answering with each external IP in turn
+/// and the last one from then on
+async fn igd(external_ips: &[&str]) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/rootDesc.xml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(DESCRIPTION))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/ctl/PPPConn"))
+        .and(header(
+            "SOAPAction",
+            format!("\"{}#GetStatusInfo\"", PPP_SERVICE).as_str(),
+        ))
+        .respond_with(answer(
+            "GetStatusInfo",
+            "<NewConnectionStatus>Connected</NewConnectionStatus><NewLastConnectionError>ERROR_NONE</NewLastConnectionError><NewUptime>3600</NewUptime>",
+        ))
+        .mount(&server)
+        .await;
+
+    for (index, ip) in external_ips.iter().enumerate() {
+        let mock = Mock::given(method("POST"))
+            .and(path("/ctl/PPPConn"))
+            .and(header(
+                "SOAPAction",
+                format!("\"{}#GetExternalIPAddress\"", PPP_SERVICE).as_str(),
+            ))
+            .respond_with(answer(
+                "GetExternalIPAddress",
+                &format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", ip),
+            ));
+        let mock = if index + 1 < external_ips.len() {
+            mock.up_to_n_times(1)
+        } else {
+            mock
+        };
+        mock.mount(&server).await;
+    }
+
+    server
+}
+
+#[tokio::test]
+async fn reads_the_ppp_connection_state_and_external_ip() {
+    let server = igd(&["100.64.1.2"]).await;
+
+    let wan = upnp::wan_status(&config(&server, "router-with-igd"))
+        .await
+        .unwrap();
+
+    assert!(wan.connected);
+    assert_eq!(wan.status, "Connected");
+    assert_eq!(wan.uptime_secs, Some(3600));
+    assert_eq!(wan.external_ip, Some("100.64.1.2".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn falls_back_when_the_router_has_no_igd() {
+    let server = MockServer::start().await;
+
+    // Nothing answers, so the router has to be asked instead
+    assert!(upnp::wan_status(&config(&server, "router-without-igd"))
+        .await
+        .is_none());
+
+    // Nor is it asked when UPnP is turned off
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(DESCRIPTION))
+        .expect(0)
+        .mount(&server)
+        .await;
+    let mut config = config(&server, "router-with-upnp-off");
+    config.router.upnp = false;
+    assert!(upnp::wan_status(&config).await.is_none());
+}
+
+#[tokio::test]
+async fn notices_the_external_ip_changing_without_a_switch() {
+    let _data = common::data_dir().await;
+    let server = igd(&["100.64.1.2", "100.64.1.2", "100.64.9.9"]).await;
+    let config = config(&server, "router-with-changing-ip");
+
+    wanip::record_connect(&config, "id1").await;
+    wanip::check(&config).await;
+    wanip::check(&config).await;
+
+    let ips = History::open().unwrap().external_ips_since(0).unwrap();
+    let seen: Vec<_> = ips
+        .iter()
+        .map(|ip| (ip.ip.as_str(), ip.kind.as_str()))
+        .collect();
+    assert_eq!(seen, [("100.64.1.2", "connect"), ("100.64.9.9", "changed")]);
+}